@@ -0,0 +1,61 @@
+//! `--adaptive`: waiting longer between refreshes while nothing changes, so
+//! amdtop left running on a laptop hardly ever wakes it, and going back to
+//! `--delay` as soon as something does.
+
+use std::time::Duration;
+
+/// How many times `--delay` the wait may grow to.
+const MAX_FACTOR: u32 = 8;
+
+pub struct Pacing {
+    delay: Duration,
+    /// How many refreshes in a row have to change nothing for the wait to
+    /// double.
+    idle_after: u32,
+    wait: Duration,
+    unchanged: u32,
+}
+
+impl Pacing {
+    pub fn new(delay: Duration, idle_after: u32) -> Self {
+        Pacing {
+            delay,
+            idle_after,
+            wait: delay,
+            unchanged: 0,
+        }
+    }
+
+    /// How long to wait for the next refresh, after one that `changed`
+    /// something or not.
+    pub fn next(&mut self, changed: bool) -> Duration {
+        if changed {
+            self.wait = self.delay;
+            self.unchanged = 0;
+            return self.wait;
+        }
+        self.unchanged += 1;
+        if self.unchanged >= self.idle_after {
+            self.unchanged = 0;
+            self.wait = (self.wait * 2).min(self.delay * MAX_FACTOR);
+        }
+        self.wait
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_while_idle() {
+        let mut pacing = Pacing::new(Duration::from_secs(1), 2);
+        let waits = [
+            true, false, false, false, false, false, false, false, false, true,
+        ]
+        .iter()
+        .map(|changed| pacing.next(*changed).as_secs())
+        .collect::<Vec<_>>();
+        assert_eq!(waits, [1, 1, 2, 2, 4, 4, 8, 8, 8, 1]);
+    }
+}
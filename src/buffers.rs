@@ -0,0 +1,512 @@
+//! `amdtop histogram` and `amdtop buffers`: the buffer objects in
+//! `amdgpu_gem_info`, for a look at how memory is allocated rather than how
+//! much. A process holding thousands of tiny buffers, or VRAM split into many
+//! mid-sized ones, looks the same as any other in the totals; and the largest
+//! buffers are the ones to find in an application's own logs.
+
+use crate::{
+    cli::{BufferArgs, BuffersArgs, GlobalArgs, OutputFormat},
+    error::{self, Error},
+    gem_info::{self, GemObject},
+    output,
+    source::{self, Device},
+    FormatBytes,
+};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+};
+
+/// The smallest size past the first bucket; each bucket holds sizes up to 16
+/// times its lower bound.
+const FIRST_BUCKET_BYTES: u64 = 4 << 10;
+const BUCKETS: usize = 7;
+
+/// How wide the bar of the fullest bucket is.
+const BAR_WIDTH: usize = 40;
+
+#[derive(Serialize, Default, Clone)]
+pub struct Bucket {
+    pub min_bytes: u64,
+    /// Exclusive; `None` for the last bucket.
+    pub max_bytes: Option<u64>,
+    pub objects: u64,
+    pub vram_bytes: u64,
+    pub gtt_bytes: u64,
+    /// In system memory or anywhere not VRAM or GTT.
+    pub other_bytes: u64,
+}
+
+impl Bucket {
+    fn label(&self) -> String {
+        match self.max_bytes {
+            None => format!(">= {}", FormatBytes::new(self.min_bytes)),
+            Some(max) if self.min_bytes == 0 => format!("< {}", FormatBytes::new(max)),
+            Some(max) => format!(
+                "{} - {}",
+                FormatBytes::new(self.min_bytes),
+                FormatBytes::new(max)
+            ),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Histogram {
+    pub device: Device,
+    /// The process looked at, or `None` for the whole device.
+    pub pid: Option<i32>,
+    pub objects: u64,
+    pub buckets: Vec<Bucket>,
+}
+
+impl Histogram {
+    fn new(device: Device, pid: Option<i32>) -> Self {
+        let buckets = (0..BUCKETS)
+            .map(|bucket| Bucket {
+                min_bytes: match bucket {
+                    0 => 0,
+                    _ => FIRST_BUCKET_BYTES << (4 * (bucket - 1)),
+                },
+                max_bytes: Some(FIRST_BUCKET_BYTES << (4 * bucket))
+                    .filter(|_| bucket + 1 < BUCKETS),
+                ..Bucket::default()
+            })
+            .collect();
+        Histogram {
+            device,
+            pid,
+            objects: 0,
+            buckets,
+        }
+    }
+
+    fn add(&mut self, object: &GemObject) {
+        let bucket = self
+            .buckets
+            .iter_mut()
+            .rev()
+            .find(|bucket| object.bytes >= bucket.min_bytes)
+            .expect("the first bucket starts at zero");
+        bucket.objects += 1;
+        match object.memory_type.as_str() {
+            "VRAM" => bucket.vram_bytes += object.bytes,
+            "GTT" => bucket.gtt_bytes += object.bytes,
+            _ => bucket.other_bytes += object.bytes,
+        }
+        self.objects += 1;
+    }
+}
+
+/// Objects with the process they belong to.
+type Objects = Vec<(i32, GemObject)>;
+
+/// The objects of `pid` and its threads, or all of them, with each dma-buf
+/// shared between clients only once, under the first client listed.
+fn select<F>(objects: Vec<GemObject>, pid: Option<i32>, mut tgid: F) -> Objects
+where
+    F: FnMut(i32) -> i32,
+{
+    let mut tgids = HashMap::new();
+    let mut dma_bufs = HashSet::new();
+    objects
+        .into_iter()
+        .filter_map(|object| {
+            let tgid = *tgids.entry(object.pid).or_insert_with(|| {
+                if object.pid > 0 {
+                    tgid(object.pid)
+                } else {
+                    object.pid
+                }
+            });
+            if pid.is_some_and(|pid| pid != tgid) {
+                return None;
+            }
+            if let Some(dma_buf) = object.dma_buf {
+                if !dma_bufs.insert(dma_buf) {
+                    return None;
+                }
+            }
+            Some((tgid, object))
+        })
+        .collect()
+}
+
+fn histogram(device: Device, objects: &[(i32, GemObject)], pid: Option<i32>) -> Histogram {
+    let mut histogram = Histogram::new(device, pid);
+    for (_, object) in objects {
+        histogram.add(object);
+    }
+    histogram
+}
+
+/// The selected objects of every device `--gpu` picks.
+fn read_objects(global: &GlobalArgs, pid: Option<i32>) -> error::Result<Vec<(Device, Objects)>> {
+    let devices = source::read_device_gem_infos(&global.debugfs_path)?
+        .into_iter()
+        .filter(|(device, _, _)| global.selects(*device))
+        .map(|(device, format, contents)| {
+            let (objects, diagnostics) = gem_info::objects(&contents, format)?;
+            if global.strict {
+                gem_info::fail_strictly(diagnostics.strict_report(device).into_iter().collect())?;
+            }
+            Ok((device, select(objects, pid, gem_info::tgid)))
+        })
+        .collect::<error::Result<Vec<_>>>()?;
+    if devices.is_empty() {
+        return Err(Error::NoDevice(
+            "no amdgpu device matches --gpu".to_string(),
+        ));
+    }
+    Ok(devices)
+}
+
+fn write_table<W: Write>(out: &mut W, histogram: &Histogram) -> io::Result<()> {
+    let mut header = histogram.device.to_string();
+    if let Some(pid) = histogram.pid {
+        header += &format!(" | pid {}", pid);
+    }
+    writeln!(out, "{} | {} objects", header, histogram.objects)?;
+    writeln!(
+        out,
+        "{: <22} | {: >7} | {: >15} | {: >15} | {: >15}",
+        "SIZE", "OBJECTS", "VRAM", "GTT", "OTHER"
+    )?;
+    writeln!(out, "{:-^1$}", "", 84 + BAR_WIDTH + 3)?;
+    let most = histogram
+        .buckets
+        .iter()
+        .map(|bucket| bucket.objects)
+        .max()
+        .unwrap_or_default();
+    for bucket in &histogram.buckets {
+        let bar = match most {
+            0 => 0,
+            most => ((bucket.objects * BAR_WIDTH as u64).div_ceil(most)) as usize,
+        };
+        let line = format!(
+            "{: <22} | {: >7} | {: >15} | {: >15} | {: >15} | {}",
+            bucket.label(),
+            bucket.objects,
+            FormatBytes::new(bucket.vram_bytes),
+            FormatBytes::new(bucket.gtt_bytes),
+            FormatBytes::new(bucket.other_bytes),
+            "#".repeat(bar)
+        );
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+pub fn run_histogram(global: &GlobalArgs, options: &BufferArgs) -> error::Result<()> {
+    let stdout = io::stdout();
+
+    crate::refresh_loop(global, |iteration| {
+        let histograms = read_objects(global, options.pid)?
+            .iter()
+            .map(|(device, objects)| histogram(*device, objects, options.pid))
+            .collect::<Vec<_>>();
+        let mut out = stdout.lock();
+
+        match global.output {
+            OutputFormat::Table => {
+                if iteration > 0 {
+                    writeln!(out)?;
+                }
+                for histogram in &histograms {
+                    write_table(&mut out, histogram)?;
+                }
+            }
+            OutputFormat::Json => output::write_json(&mut out, &histograms)?,
+            OutputFormat::Csv => {
+                if iteration == 0 {
+                    output::write_csv_row(
+                        &mut out,
+                        &[
+                            "device",
+                            "min_bytes",
+                            "max_bytes",
+                            "objects",
+                            "vram_bytes",
+                            "gtt_bytes",
+                            "other_bytes",
+                        ],
+                    )?;
+                }
+                for histogram in &histograms {
+                    let device = histogram.device.to_string();
+                    for bucket in &histogram.buckets {
+                        output::write_csv_row(
+                            &mut out,
+                            &[
+                                device.clone(),
+                                bucket.min_bytes.to_string(),
+                                bucket
+                                    .max_bytes
+                                    .map(|max| max.to_string())
+                                    .unwrap_or_default(),
+                                bucket.objects.to_string(),
+                                bucket.vram_bytes.to_string(),
+                                bucket.gtt_bytes.to_string(),
+                                bucket.other_bytes.to_string(),
+                            ],
+                        )?;
+                    }
+                }
+            }
+            format => return Err(output::unsupported(format, "histogram")),
+        }
+        Ok(())
+    })
+}
+
+/// One line of `amdtop buffers`.
+#[derive(Serialize)]
+pub struct Buffer {
+    pub device: Device,
+    pub pid: i32,
+    pub name: String,
+    /// The client's GEM handle, where the kernel prints it.
+    pub handle: Option<u32>,
+    pub bytes: u64,
+    pub domain: String,
+    /// Whether it's in CPU-visible VRAM, where the kernel says.
+    pub visible: Option<bool>,
+    pub flags: Vec<String>,
+    /// Pinned in place, so never evicted.
+    pub pinned: bool,
+    /// The dma-buf it's shared through.
+    pub dma_buf: Option<u64>,
+}
+
+impl Buffer {
+    /// The domain, and for VRAM which part of it.
+    fn placement(&self) -> String {
+        match self.visible {
+            Some(true) => format!("{} visible", self.domain),
+            Some(false) => format!("{} invisible", self.domain),
+            None => self.domain.clone(),
+        }
+    }
+}
+
+/// One device's buffers, and how much of the small CPU-visible window of
+/// VRAM all of them take.
+struct DeviceBuffers {
+    device: Device,
+    /// `None` when the kernel doesn't say which buffers are visible.
+    visible_vram_bytes: Option<u64>,
+    buffers: Vec<Buffer>,
+}
+
+/// The `top` largest buffers of a device.
+fn largest(device: Device, objects: Objects, top: usize) -> DeviceBuffers {
+    let visible_vram_bytes = objects
+        .iter()
+        .filter_map(|(_, object)| Some((object.visible?, object.bytes)))
+        .map(|(visible, bytes)| if visible { bytes } else { 0 })
+        .reduce(|total, bytes| total + bytes);
+    let mut buffers = objects
+        .into_iter()
+        .map(|(pid, object)| Buffer {
+            device,
+            pid,
+            name: object.command,
+            handle: object.handle,
+            bytes: object.bytes,
+            domain: object.memory_type,
+            visible: object.visible,
+            flags: object.flags,
+            pinned: object.pinned,
+            dma_buf: object.dma_buf,
+        })
+        .collect::<Vec<_>>();
+    buffers.sort_by_key(|buffer| std::cmp::Reverse(buffer.bytes));
+    buffers.truncate(top);
+    DeviceBuffers {
+        device,
+        visible_vram_bytes,
+        buffers,
+    }
+}
+
+/// The table, then what the flags in it mean.
+fn write_buffers_table<W: Write>(out: &mut W, device: &DeviceBuffers) -> io::Result<()> {
+    let mut header = device.device.to_string();
+    if let Some(visible) = device.visible_vram_bytes {
+        header += &format!(" | visible VRAM {}", FormatBytes::new(visible));
+    }
+    writeln!(out, "{}", header)?;
+    writeln!(
+        out,
+        "{: <10} | {: <20} | {: >10} | {: >15} | {: <14} | FLAGS",
+        "PID", "PROCESS", "HANDLE", "SIZE", "PLACEMENT"
+    )?;
+    writeln!(out, "{:-^1$}", "", 110)?;
+    let mut seen = Vec::new();
+    for buffer in &device.buffers {
+        let mut flags = buffer.flags.join(" ");
+        if buffer.pinned {
+            flags.insert_str(0, "pinned ");
+        }
+        if let Some(dma_buf) = buffer.dma_buf {
+            flags += &format!(" dma-buf:{}", dma_buf);
+        }
+        let line = format!(
+            "{: <10} | {: <20} | {: >10} | {: >15} | {: <14} | {}",
+            buffer.pid,
+            buffer.name,
+            buffer
+                .handle
+                .map_or_else(|| "-".to_string(), |handle| format!("{:#x}", handle)),
+            FormatBytes::new(buffer.bytes),
+            buffer.placement(),
+            flags.trim_start()
+        );
+        writeln!(out, "{}", line.trim_end())?;
+        for flag in &buffer.flags {
+            if !seen.contains(flag) {
+                seen.push(flag.clone());
+            }
+        }
+    }
+    let meanings = seen
+        .iter()
+        .filter_map(|flag| Some((flag, gem_info::flag_meaning(flag)?)))
+        .collect::<Vec<_>>();
+    if !meanings.is_empty() {
+        writeln!(out)?;
+        for (flag, meaning) in meanings {
+            writeln!(out, "  {: <20} {}", flag, meaning)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn run_buffers(global: &GlobalArgs, options: &BuffersArgs) -> error::Result<()> {
+    let stdout = io::stdout();
+
+    crate::refresh_loop(global, |iteration| {
+        let devices = read_objects(global, options.selection.pid)?
+            .into_iter()
+            .map(|(device, objects)| largest(device, objects, options.top))
+            .collect::<Vec<_>>();
+        let mut out = stdout.lock();
+
+        match global.output {
+            OutputFormat::Table => {
+                if iteration > 0 {
+                    writeln!(out)?;
+                }
+                for device in &devices {
+                    write_buffers_table(&mut out, device)?;
+                }
+            }
+            OutputFormat::Json => {
+                let buffers = devices
+                    .iter()
+                    .flat_map(|device| &device.buffers)
+                    .collect::<Vec<_>>();
+                output::write_json(&mut out, &buffers)?
+            }
+            OutputFormat::Csv => {
+                if iteration == 0 {
+                    output::write_csv_row(
+                        &mut out,
+                        &[
+                            "device", "pid", "name", "handle", "bytes", "domain", "visible",
+                            "pinned", "flags", "dma_buf",
+                        ],
+                    )?;
+                }
+                for buffer in devices.iter().flat_map(|device| &device.buffers) {
+                    output::write_csv_row(
+                        &mut out,
+                        &[
+                            buffer.device.to_string(),
+                            buffer.pid.to_string(),
+                            buffer.name.clone(),
+                            buffer
+                                .handle
+                                .map(|handle| handle.to_string())
+                                .unwrap_or_default(),
+                            buffer.bytes.to_string(),
+                            buffer.domain.clone(),
+                            buffer
+                                .visible
+                                .map(|visible| visible.to_string())
+                                .unwrap_or_default(),
+                            buffer.pinned.to_string(),
+                            buffer.flags.join(" "),
+                            buffer
+                                .dma_buf
+                                .map(|dma_buf| dma_buf.to_string())
+                                .unwrap_or_default(),
+                        ],
+                    )?;
+                }
+            }
+            format => return Err(output::unsupported(format, "buffers")),
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(pid: i32, bytes: u64, memory_type: &str, dma_buf: Option<u64>) -> GemObject {
+        GemObject {
+            pid,
+            command: String::new(),
+            handle: None,
+            bytes,
+            memory_type: memory_type.to_string(),
+            dma_buf,
+            visible: None,
+            flags: Vec::new(),
+            pinned: false,
+        }
+    }
+
+    #[test]
+    fn buckets_objects_by_size() {
+        let objects = vec![
+            object(1, 4095, "GTT", None),
+            object(1, 4096, "VRAM", None),
+            object(1, 1 << 20, "VRAM", Some(7)),
+            object(2, 1 << 20, "VRAM", Some(7)),
+            object(2, 8 << 30, "VRAM", None),
+        ];
+        let objects = select(objects, None, |pid| pid);
+        let histogram = histogram(Device { minor: 0 }, &objects, None);
+        let counts = histogram
+            .buckets
+            .iter()
+            .map(|bucket| bucket.objects)
+            .collect::<Vec<_>>();
+        assert_eq!(counts, [1, 1, 0, 1, 0, 0, 1]);
+        assert_eq!(histogram.objects, 4);
+        assert_eq!(histogram.buckets[0].gtt_bytes, 4095);
+        assert_eq!(histogram.buckets[3].label(), "1.00 MiB - 16.00 MiB");
+        assert_eq!(histogram.buckets[6].label(), ">= 4.00 GiB");
+        assert_eq!(histogram.buckets[6].max_bytes, None);
+    }
+
+    #[test]
+    fn selects_a_process_and_its_threads() {
+        let objects = vec![
+            object(10, 4096, "VRAM", None),
+            object(11, 8192, "VRAM", Some(7)),
+            object(12, 8192, "VRAM", Some(7)),
+        ];
+        let selected = select(objects, Some(10), |pid| if pid == 11 { 10 } else { pid });
+        let sizes = selected
+            .iter()
+            .map(|(pid, object)| (*pid, object.bytes))
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [(10, 4096), (10, 8192)]);
+    }
+}
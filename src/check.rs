@@ -0,0 +1,275 @@
+//! `amdtop check`: compares device usage against limits and fails when one
+//! is exceeded, for cron jobs and monitoring scripts. With `--output nagios`
+//! it behaves as a Nagios (or Icinga, Naemon, ...) plugin instead: one status
+//! line with performance data, and the plugin exit statuses.
+
+use crate::{
+    cli::{CheckArgs, GlobalArgs, OutputFormat},
+    error::{self, Error},
+    output,
+    sensors::Sensors,
+    source::{Device, Sources},
+};
+use serde::Serialize;
+use std::io::{self, Write};
+
+#[derive(Serialize)]
+pub struct CheckResult {
+    pub device: Device,
+    pub check: &'static str,
+    pub value: f64,
+    pub limit: f64,
+    /// Where a warning starts, if one was asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warning: Option<f64>,
+    pub unit: &'static str,
+    pub ok: bool,
+}
+
+impl CheckResult {
+    fn state(&self) -> State {
+        if !self.ok {
+            State::Critical
+        } else if self.warning.is_some_and(|warning| self.value > warning) {
+            State::Warning
+        } else {
+            State::Ok
+        }
+    }
+}
+
+/// A plugin's result, in the order of its exit status.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum State {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+impl State {
+    fn name(self) -> &'static str {
+        match self {
+            State::Ok => "OK",
+            State::Warning => "WARNING",
+            State::Critical => "CRITICAL",
+            State::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+fn check(
+    device: Device,
+    check: &'static str,
+    value: f64,
+    (warning, limit): (Option<f64>, f64),
+    unit: &'static str,
+) -> CheckResult {
+    CheckResult {
+        device,
+        check,
+        value,
+        limit,
+        warning,
+        unit,
+        ok: value <= limit,
+    }
+}
+
+pub fn evaluate(global: &GlobalArgs, options: &CheckArgs) -> error::Result<Vec<CheckResult>> {
+    let mut sources = Sources::select_device_totals(&global.source_config())?;
+    let mut results = Vec::new();
+
+    for sample in sources.sample()? {
+        let device = sample.device;
+        if !global.selects(device) {
+            continue;
+        }
+
+        if let Some(usage) = sample.usage.filter(|usage| usage.vram_total_bytes > 0) {
+            let percent = usage.vram_used_bytes as f64 * 100.0 / usage.vram_total_bytes as f64;
+            let limits = (options.warn_vram, options.max_vram);
+            results.push(check(device, "vram", percent, limits, "%"));
+        }
+
+        if options.max_temperature.is_some() || options.warn_temperature.is_some() {
+            if let Some(celsius) = Sensors::read(device).hottest_celsius() {
+                let limit = options.max_temperature.unwrap_or(f64::INFINITY);
+                let limits = (options.warn_temperature, limit);
+                results.push(check(device, "temperature", celsius, limits, "°C"));
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+fn write_table<W: Write>(out: &mut W, result: &CheckResult) -> io::Result<()> {
+    writeln!(
+        out,
+        "{} | {} {:.1} {} (limit {} {}) | {}",
+        result.device,
+        result.check,
+        result.value,
+        result.unit,
+        result.limit,
+        result.unit,
+        match result.state() {
+            State::Ok => "OK",
+            State::Warning => "WARNING",
+            _ => "EXCEEDED",
+        }
+    )
+}
+
+/// The plugin output line: the overall state, what caused it, and
+/// performance data for every check.
+fn nagios_line(results: &[CheckResult]) -> (State, String) {
+    let state = results
+        .iter()
+        .map(CheckResult::state)
+        .max()
+        .unwrap_or(State::Unknown);
+    let describe = |result: &CheckResult| {
+        format!(
+            "{} {} {:.1} {}",
+            result.device, result.check, result.value, result.unit
+        )
+    };
+    let message = match state {
+        State::Unknown => "no devices to check".to_string(),
+        State::Ok => results.iter().map(describe).collect::<Vec<_>>().join(", "),
+        _ => results
+            .iter()
+            .filter(|result| result.state() != State::Ok)
+            .map(|result| {
+                let limit = match result.state() {
+                    State::Warning => result.warning.unwrap_or(result.limit),
+                    _ => result.limit,
+                };
+                format!("{} over {} {}", describe(result), limit, result.unit)
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+
+    // `label=value[UOM];warn;crit;min;max`, where the only units are s, %,
+    // B, KB, MB, TB and c. Temperatures go without.
+    let threshold = |value: Option<f64>| {
+        value
+            .filter(|value| value.is_finite())
+            .map(|value| value.to_string())
+            .unwrap_or_default()
+    };
+    let perfdata = results
+        .iter()
+        .map(|result| {
+            let (unit, range) = match result.unit {
+                "%" => ("%", ";0;100"),
+                _ => ("", ""),
+            };
+            format!(
+                "{}_{}={:.1}{};{};{}{}",
+                result.device,
+                result.check,
+                result.value,
+                unit,
+                threshold(result.warning),
+                threshold(Some(result.limit)),
+                range
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut line = format!("AMDTOP {} - {}", state.name(), message);
+    if !perfdata.is_empty() {
+        line.push_str(" | ");
+        line.push_str(&perfdata);
+    }
+    (state, line)
+}
+
+/// Runs as a plugin. Plugins report through their exit status alone, with
+/// 3 for anything that kept the check from running.
+fn run_nagios(global: &GlobalArgs, options: &CheckArgs) -> ! {
+    let (state, line) = match evaluate(global, options) {
+        Ok(results) => nagios_line(&results),
+        Err(err) => (State::Unknown, format!("AMDTOP UNKNOWN - {}", err)),
+    };
+    println!("{}", line);
+    let _ = io::stdout().flush();
+    std::process::exit(state as i32)
+}
+
+pub fn run(global: &GlobalArgs, options: &CheckArgs) -> error::Result<()> {
+    if global.output == OutputFormat::Nagios {
+        run_nagios(global, options);
+    }
+    let results = evaluate(global, options)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match global.output {
+        OutputFormat::Table => {
+            for result in &results {
+                write_table(&mut out, result)?;
+            }
+        }
+        OutputFormat::Json => output::write_json(&mut out, &results)?,
+        OutputFormat::Csv => {
+            output::write_csv_row(
+                &mut out,
+                &["device", "check", "value", "limit", "unit", "ok"],
+            )?;
+            for result in &results {
+                output::write_csv_row(
+                    &mut out,
+                    &[
+                        result.device.to_string(),
+                        result.check.to_string(),
+                        result.value.to_string(),
+                        result.limit.to_string(),
+                        result.unit.to_string(),
+                        result.ok.to_string(),
+                    ],
+                )?;
+            }
+        }
+        OutputFormat::Nagios => unreachable!("handled by run_nagios"),
+        format => return Err(output::unsupported(format, "check")),
+    }
+
+    let failed = results.iter().filter(|result| !result.ok).count();
+    if failed > 0 {
+        return Err(Error::LimitExceeded(format!(
+            "{} check(s) over their limit",
+            failed
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_nagios_lines() {
+        let device = Device { minor: 0 };
+        let results = [
+            check(device, "vram", 85.25, (Some(80.0), 90.0), "%"),
+            check(device, "temperature", 52.0, (None, f64::INFINITY), "°C"),
+        ];
+        assert_eq!(
+            nagios_line(&results),
+            (
+                State::Warning,
+                "AMDTOP WARNING - card0 vram 85.2 % over 80 % | \
+                 card0_vram=85.2%;80;90;0;100 card0_temperature=52.0;;"
+                    .to_string()
+            )
+        );
+        assert_eq!(nagios_line(&[]).0, State::Unknown);
+    }
+}
@@ -0,0 +1,152 @@
+//! Recorded sessions as Chrome's Trace Event JSON, which ui.perfetto.dev and
+//! chrome://tracing open: counter tracks of VRAM and GTT per device and per
+//! process, and of busy per engine where `--show-busy` recorded it.
+//! Timestamps are microseconds since the Unix epoch, of when it was recorded.
+
+use crate::{output, recording::Snapshot};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Write},
+};
+
+/// Devices get tracks of their own, numbered from above the largest pid
+/// Linux hands out so they can't land on a process's.
+const DEVICE_TRACKS: i64 = 1 << 22;
+
+fn counter(name: &str, pid: i64, ts: f64, args: Value) -> Value {
+    json!({ "name": name, "ph": "C", "pid": pid, "ts": ts, "args": args })
+}
+
+fn metadata(name: &str, pid: i64, args: Value) -> Value {
+    json!({ "name": name, "ph": "M", "pid": pid, "args": args })
+}
+
+pub fn write<W: Write>(out: &mut W, snapshots: &[Snapshot]) -> io::Result<()> {
+    let mut events = Vec::new();
+    let mut devices = Vec::<&str>::new();
+    let mut named = BTreeSet::new();
+    let mut present = BTreeMap::<i32, BTreeMap<&str, u64>>::new();
+    for snapshot in snapshots {
+        let ts = (snapshot.time * 1e6).round();
+        let mut vram = BTreeMap::<i32, BTreeMap<&str, u64>>::new();
+        let mut gtt = BTreeMap::<i32, BTreeMap<&str, u64>>::new();
+        let mut busy = BTreeMap::<i32, BTreeMap<String, f64>>::new();
+        for device in &snapshot.devices {
+            let name = device.device.as_str();
+            let track = match devices.iter().position(|known| *known == name) {
+                Some(index) => DEVICE_TRACKS + index as i64,
+                None => {
+                    devices.push(name);
+                    let track = DEVICE_TRACKS + devices.len() as i64 - 1;
+                    events.push(metadata("process_name", track, json!({ "name": name })));
+                    // Above the processes.
+                    events.push(metadata(
+                        "process_sort_index",
+                        track,
+                        json!({ "sort_index": -1 }),
+                    ));
+                    track
+                }
+            };
+            if let Some(usage) = device.usage {
+                events.push(counter(
+                    "VRAM",
+                    track,
+                    ts,
+                    json!({ "used": usage.vram_used_bytes }),
+                ));
+                if let Some(gtt) = usage.gtt_used_bytes {
+                    events.push(counter("GTT", track, ts, json!({ "used": gtt })));
+                }
+            }
+            for process in device.processes.iter().flatten() {
+                if named.insert(process.pid) {
+                    let label = match &process.name {
+                        Some(name) => format!("{} {}", name, process.pid),
+                        None => process.pid.to_string(),
+                    };
+                    events.push(metadata(
+                        "process_name",
+                        process.pid.into(),
+                        json!({ "name": label }),
+                    ));
+                }
+                vram.entry(process.pid)
+                    .or_default()
+                    .insert(name, process.vram_bytes);
+                gtt.entry(process.pid)
+                    .or_default()
+                    .insert(name, process.gtt_bytes);
+                for (engine, percent) in &process.engine_busy {
+                    let series = if snapshot.devices.len() > 1 {
+                        format!("{} {}", name, engine)
+                    } else {
+                        engine.clone()
+                    };
+                    busy.entry(process.pid)
+                        .or_default()
+                        .insert(series, *percent);
+                }
+            }
+        }
+        for (pid, bytes) in &vram {
+            events.push(counter("VRAM", (*pid).into(), ts, json!(bytes)));
+        }
+        for (pid, bytes) in &gtt {
+            events.push(counter("GTT", (*pid).into(), ts, json!(bytes)));
+        }
+        for (pid, percents) in &busy {
+            events.push(counter("busy", (*pid).into(), ts, json!(percents)));
+        }
+        // Otherwise the tracks of processes that are gone hold their last
+        // value to the end.
+        for (pid, bytes) in present.iter().filter(|(pid, _)| !vram.contains_key(pid)) {
+            let zeros = bytes
+                .keys()
+                .map(|device| (*device, 0))
+                .collect::<BTreeMap<_, _>>();
+            events.push(counter("VRAM", (*pid).into(), ts, json!(zeros)));
+            events.push(counter("GTT", (*pid).into(), ts, json!(zeros)));
+        }
+        present = vram;
+    }
+    output::write_json_line(
+        out,
+        &json!({ "traceEvents": events, "displayTimeUnit": "ms" }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_counter_tracks() {
+        let snapshots = [
+            r#"{"time": 2.5, "devices": [{"device": "card0",
+                "usage": {"vram_used_bytes": 300, "vram_total_bytes": 1000, "gtt_used_bytes": 20},
+                "processes": [{"pid": 42, "name": "game", "path": null, "vram_bytes": 200,
+                               "gtt_bytes": 10, "engine_busy": {"gfx": 60.0}}]}]}"#,
+            r#"{"time": 3.5, "devices": [{"device": "card0", "usage": null, "processes": []}]}"#,
+        ]
+        .map(|line| serde_json::from_str::<Snapshot>(line).unwrap());
+        let mut out = Vec::new();
+        write(&mut out, &snapshots).unwrap();
+        let trace: Value = serde_json::from_slice(&out).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        let find = |name: &str, pid: i64, ts: f64| {
+            events
+                .iter()
+                .find(|event| event["name"] == name && event["pid"] == pid && event["ts"] == ts)
+                .map(|event| event["args"].clone())
+        };
+        assert_eq!(
+            find("VRAM", DEVICE_TRACKS, 2.5e6),
+            Some(json!({ "used": 300 }))
+        );
+        assert_eq!(find("VRAM", 42, 2.5e6), Some(json!({ "card0": 200 })));
+        assert_eq!(find("busy", 42, 2.5e6), Some(json!({ "gfx": 60.0 })));
+        assert_eq!(find("GTT", 42, 3.5e6), Some(json!({ "card0": 0 })));
+    }
+}
@@ -0,0 +1,1067 @@
+//! Command line definition. Every option can also be set through the
+//! environment variable named after it, e.g. `AMDTOP_DELAY=2`.
+
+use crate::{
+    helper::Elevate,
+    mem::{ComputedColumn, ProcessIdentity},
+    parse_size,
+    power::{FanSpeed, PerfLevel},
+    priority::Priority,
+    recording::TimeSpec,
+    smoothing::Smoothing,
+    source::{Device, SourceConfig, SourceKind},
+    trace::TraceEvent,
+};
+use clap::{
+    builder::RangedU64ValueParser, error::ErrorKind, parser::ValueSource, Args, CommandFactory,
+    FromArgMatches, Parser, Subcommand, ValueEnum,
+};
+use regex::Regex;
+use std::{path::PathBuf, time::Duration};
+
+const EXIT_STATUS: &str = "\
+Exit status:
+  0  success
+  1  other I/O errors
+  2  invalid arguments
+  3  no amdgpu device found
+  4  permission denied
+  5  couldn't parse what the kernel reported
+  6  the kernel doesn't provide what we need
+  7  `amdtop check` found a limit exceeded";
+
+fn parse_delay(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| *seconds > 0.0 && seconds.is_finite())
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| "expected a positive number of seconds".to_string())
+}
+
+/// Durations like `10s`, `500ms`, `2m` or `1h`; bare numbers are seconds.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let scale = match unit {
+        "ms" => 1e-3,
+        "" | "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => return Err("expected a duration like 10s, 500ms or 2m".to_string()),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .map(|number| number * scale)
+        .filter(|seconds| *seconds > 0.0 && seconds.is_finite())
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| "expected a positive duration like 10s, 500ms or 2m".to_string())
+}
+
+fn parse_size_arg(value: &str) -> Result<u64, String> {
+    parse_size(value).ok_or_else(|| "expected a size like 512, 64K or 16MiB".to_string())
+}
+
+fn parse_source(value: &str) -> Result<SourceKind, String> {
+    SourceKind::from_name(value)
+        .ok_or_else(|| "expected one of debugfs, fdinfo, kfd, sysfs or ioctl".to_string())
+}
+
+fn parse_elevate(value: &str) -> Result<Elevate, String> {
+    Elevate::from_name(value).ok_or_else(|| "expected one of pkexec, sudo or exec".to_string())
+}
+
+fn parse_smoothing(value: &str) -> Result<Smoothing, String> {
+    Smoothing::from_name(value).ok_or_else(|| {
+        "expected none, avg:N with N above 0 or ema:ALPHA with ALPHA in (0, 1]".to_string()
+    })
+}
+
+fn parse_time(value: &str) -> Result<TimeSpec, String> {
+    TimeSpec::from_name(value).ok_or_else(|| {
+        "expected Unix seconds, a time like 2026-10-14T09:30:00 or a time of day like 09:30"
+            .to_string()
+    })
+}
+
+const COLUMN_HELP: &str = "\
+Add a column NAME computed for each process from EXPR, like
+`vram_pct=vram/device.vram_total*100`; repeat for more, or separate them with
+`;` in AMDTOP_COLUMN.
+
+EXPR is numbers and fields with + - * / and parentheses. Sizes are in bytes
+and times in seconds. The fields are pid, vram, gtt, total, peak_vram,
+peak_gtt, evicted, rss (with --show-rss), footprint (--show-footprint), cpu
+(--show-cpu), busy (--show-busy), clients, queues, age, on_gpu,
+device.vram_used, device.vram_total and device.gtt_used. A row shows - where
+a field has no value or EXPR divides by zero.";
+
+fn parse_column(value: &str) -> Result<ComputedColumn, String> {
+    ComputedColumn::parse(value)
+}
+
+fn parse_watts(value: &str) -> Result<f64, String> {
+    value
+        .trim_end_matches('W')
+        .parse::<f64>()
+        .ok()
+        .filter(|watts| *watts > 0.0 && watts.is_finite())
+        .ok_or_else(|| "expected a positive number of watts".to_string())
+}
+
+fn parse_fan_speed(value: &str) -> Result<FanSpeed, String> {
+    if value == "auto" {
+        return Ok(FanSpeed::Auto);
+    }
+    parse_percent(value)
+        .map(FanSpeed::Percent)
+        .map_err(|_| "expected auto or a percentage between 0 and 100".to_string())
+}
+
+fn parse_percent(value: &str) -> Result<f64, String> {
+    value
+        .trim_end_matches('%')
+        .parse::<f64>()
+        .ok()
+        .filter(|percent| (0.0..=100.0).contains(percent))
+        .ok_or_else(|| "expected a percentage between 0 and 100".to_string())
+}
+
+#[derive(Parser)]
+#[command(
+    version,
+    disable_version_flag = true,
+    about = "Show which processes use memory on AMD GPUs",
+    after_help = EXIT_STATUS
+)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
+    /// Options for `mem`, which runs when no command is given. An error
+    /// before any command, `mem` included.
+    #[command(flatten)]
+    pub mem: MemArgs,
+
+    /// Print the version, with the kernel, the driver and which data sources
+    /// work here, for bug reports
+    #[arg(short = 'V', long)]
+    pub version: bool,
+
+    /// Serve samples to a parent amdtop over stdin and stdout
+    #[arg(long, hide = true)]
+    pub helper: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    /// Parses `args` like `Parser::try_parse_from`, but rejects options of
+    /// `mem` given before a command instead of ignoring them.
+    pub fn try_parse_args<I, T>(args: I) -> Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let mut command = Cli::command();
+        let matches = command.try_get_matches_from_mut(args)?;
+        if let Some(name) = matches.subcommand_name() {
+            let mem = MemArgs::augment_args(clap::Command::new("mem"));
+            let given = mem.get_arguments().find(|arg| {
+                matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine)
+            });
+            if let Some(arg) = given {
+                let flag = arg
+                    .get_long()
+                    .map_or_else(|| arg.get_id().to_string(), |long| format!("--{}", long));
+                let takes_it = command
+                    .find_subcommand(name)
+                    .is_some_and(|sub| sub.get_arguments().any(|sub| sub.get_id() == arg.get_id()));
+                let message = if takes_it {
+                    format!("{} goes after {}, not before it", flag, name)
+                } else {
+                    format!("{} is an option of mem, not of {}", flag, name)
+                };
+                return Err(command.error(ErrorKind::ArgumentConflict, message));
+            }
+        }
+        Cli::from_arg_matches(&matches).map_err(|err| err.format(&mut command))
+    }
+
+    /// `try_parse_args` of our own arguments, exiting with usage or help
+    /// like `Parser::parse` does.
+    pub fn parse_args() -> Self {
+        Cli::try_parse_args(std::env::args_os()).unwrap_or_else(|err| err.exit())
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Show memory use per process (the default)
+    Mem(MemArgs),
+    /// Show load, clocks, temperatures, power and fans
+    Sensors(SensorsArgs),
+    /// Interactive, full screen view of memory use
+    Top(MemArgs),
+    /// Serve Prometheus metrics and JSON snapshots over HTTP
+    Export(ExportArgs),
+    /// Sample for viewers on other machines started with --connect
+    Agent(AgentArgs),
+    /// Write a systemd unit that runs `amdtop export` (needs root)
+    InstallService(InstallServiceArgs),
+    /// Serve snapshots and limit signals on D-Bus as org.amdtop.Monitor
+    Dbus(DbusArgs),
+    /// Compare usage against limits, for scripts and monitoring
+    Check(CheckArgs),
+    /// Low-level discovery and item values for a Zabbix agent
+    Zabbix(ZabbixArgs),
+    /// Show how many buffer objects there are of each size (needs debugfs)
+    Histogram(BufferArgs),
+    /// List the largest buffer objects (needs debugfs)
+    Buffers(BuffersArgs),
+    /// Show firmware versions
+    Fw,
+    /// Show what each ring has queued and whether it's keeping up (needs
+    /// debugfs)
+    Rings,
+    /// Show XGMI hive membership, links and error counters
+    Xgmi,
+    /// Record amdgpu's buffer object events for a while and sum them up per
+    /// process (needs tracefs)
+    Trace(TraceArgs),
+    /// Change power management settings (needs root)
+    Set(SetArgs),
+    /// Step through a session recorded with `--output ndjson`
+    Replay(ReplayArgs),
+    /// Compare two recorded sessions, e.g. from before and after a driver
+    /// update
+    Compare(CompareArgs),
+    /// Check what amdtop needs from the system, and say how to fix what's
+    /// missing
+    Doctor,
+    /// Print the JSON Schema of mem's JSON and NDJSON output
+    Schema,
+    /// Print a completion script for SHELL
+    Completions {
+        #[arg(value_name = "SHELL")]
+        shell: clap_complete::Shell,
+    },
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+    /// A Nagios or Icinga plugin status line, for check
+    Nagios,
+    /// Influx line protocol for Telegraf's exec plugin, for mem and sensors
+    Telegraf,
+    /// PUTVAL lines for collectd's exec plugin, for mem and sensors
+    Collectd,
+    /// JSON for a waybar or i3status-rust custom module, for mem
+    Waybar,
+    /// One JSON object per line, for mem: see --record
+    Ndjson,
+    /// Chrome's Trace Event JSON, for replay: open it in ui.perfetto.dev
+    ChromeTrace,
+    /// MangoHud's CSV log, for sensors, to plot and compare with its tools
+    Mangohud,
+}
+
+/// Picks a device by card name (`card1`), minor (`1`) or PCI address
+/// (`0000:03:00.0`).
+#[derive(Clone)]
+pub struct GpuSelector(String);
+
+impl GpuSelector {
+    pub fn matches(&self, device: Device) -> bool {
+        let selector = self.0.strip_prefix("card").unwrap_or(&self.0);
+        selector.parse() == Ok(device.minor) || device.pci_slot().as_deref() == Some(&self.0)
+    }
+}
+
+impl std::fmt::Display for GpuSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Options every command shares.
+#[derive(Args)]
+pub struct GlobalArgs {
+    /// Refresh every SECONDS until interrupted
+    #[arg(
+        short,
+        long,
+        global = true,
+        value_name = "SECONDS",
+        env = "AMDTOP_DELAY",
+        value_parser = parse_delay
+    )]
+    pub delay: Option<Duration>,
+
+    /// Redraw the table in place every refresh, like watch(1) (mem and
+    /// sensors only)
+    #[arg(long, global = true, env = "AMDTOP_WATCH")]
+    pub watch: bool,
+
+    /// Stop after COUNT refreshes
+    #[arg(
+        short = 'n',
+        long,
+        global = true,
+        value_name = "COUNT",
+        env = "AMDTOP_ITERATIONS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub iterations: Option<u64>,
+
+    /// With --delay, double the wait each time COUNT refreshes in a row
+    /// change nothing, up to 8 times --delay, and go back to --delay once
+    /// something does (mem only)
+    #[arg(
+        long,
+        global = true,
+        value_name = "COUNT",
+        env = "AMDTOP_ADAPTIVE",
+        num_args = 0..=1,
+        default_missing_value = "3",
+        requires = "delay",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub adaptive: Option<u32>,
+
+    /// Smooth busy percentages and allocation rates over refreshes: none, avg:N for the mean of
+    /// the last N or ema:ALPHA for an exponential moving average
+    #[arg(
+        long,
+        global = true,
+        value_name = "HOW",
+        env = "AMDTOP_SMOOTHING",
+        default_value = "none",
+        value_parser = parse_smoothing
+    )]
+    pub smoothing: Smoothing,
+
+    /// Only show GPU, given as a card name, minor or PCI address; repeat for
+    /// more than one
+    #[arg(
+        long,
+        global = true,
+        value_name = "GPU",
+        env = "AMDTOP_GPU",
+        value_delimiter = ',',
+        value_parser = |value: &str| Ok::<_, String>(GpuSelector(value.to_string()))
+    )]
+    pub gpu: Vec<GpuSelector>,
+
+    /// How to print results
+    #[arg(
+        long,
+        global = true,
+        value_name = "FORMAT",
+        env = "AMDTOP_OUTPUT",
+        default_value = "table"
+    )]
+    pub output: OutputFormat,
+
+    /// Write a labeled `key: value` line for each value instead of tables,
+    /// without color, bars or rules, for screen readers and dumb terminals
+    /// (mem; elsewhere it turns color off)
+    #[arg(long, global = true, env = "AMDTOP_PLAIN")]
+    pub plain: bool,
+
+    /// With --output csv, end every field with a NUL instead of separating
+    /// them with commas and newlines, for paths that contain them
+    #[arg(short = '0', long, global = true, env = "AMDTOP_NUL")]
+    pub nul: bool,
+
+    /// Report which data sources are used and gem_info lines that couldn't be
+    /// parsed
+    #[arg(long, global = true, env = "AMDTOP_DIAGNOSTICS")]
+    pub diagnostics: bool,
+
+    /// Fail when any gem_info line can't be parsed, instead of showing
+    /// totals that may be wrong, with a JSON report of the lines on stderr
+    #[arg(long, global = true, env = "AMDTOP_STRICT")]
+    pub strict: bool,
+
+    /// Only read from SOURCE, one of debugfs, fdinfo, kfd, sysfs or ioctl
+    #[arg(
+        long,
+        global = true,
+        value_name = "SOURCE",
+        env = "AMDTOP_SOURCE",
+        value_parser = parse_source
+    )]
+    pub source: Option<SourceKind>,
+
+    /// Where debugfs is mounted, if not in /proc/mounts
+    #[arg(long, global = true, value_name = "PATH", env = "AMDTOP_DEBUGFS_PATH")]
+    pub debugfs_path: Option<PathBuf>,
+
+    /// Read /proc, /sys and debugfs from under DIR
+    #[arg(long, global = true, value_name = "DIR", env = "AMDTOP_ROOT")]
+    pub root: Option<PathBuf>,
+
+    /// Read debugfs through a privileged helper started with METHOD, one of
+    /// pkexec, sudo or exec
+    #[arg(
+        long,
+        global = true,
+        value_name = "METHOD",
+        env = "AMDTOP_ELEVATE",
+        value_parser = parse_elevate
+    )]
+    pub elevate: Option<Elevate>,
+
+    /// Show what the `amdtop agent` on HOST:PORT samples instead (top and
+    /// mem only)
+    #[arg(long, global = true, value_name = "HOST:PORT", env = "AMDTOP_CONNECT")]
+    pub connect: Option<String>,
+
+    /// Talk to the --connect agent over TLS, checking its certificate
+    #[cfg(feature = "tls")]
+    #[arg(long, global = true, env = "AMDTOP_TLS", requires = "connect")]
+    pub tls: bool,
+
+    /// Check the agent's certificate against the CAs in this PEM file
+    /// rather than the system's
+    #[cfg(feature = "tls")]
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        env = "AMDTOP_TLS_CA",
+        requires = "tls"
+    )]
+    pub tls_ca: Option<PathBuf>,
+}
+
+impl GlobalArgs {
+    /// Whether we keep sampling rather than printing a single snapshot.
+    pub fn continuous(&self) -> bool {
+        self.watch || self.delay.is_some() || self.iterations.is_some_and(|count| count > 1)
+    }
+
+    /// Whether `device` passes `--gpu`.
+    pub fn selects(&self, device: Device) -> bool {
+        self.gpu.is_empty() || self.gpu.iter().any(|selector| selector.matches(device))
+    }
+
+    pub fn source_config(&self) -> SourceConfig {
+        SourceConfig {
+            forced: self.source,
+            debugfs_path: self.debugfs_path.clone(),
+            elevate: self.elevate,
+            keep_root: false,
+            report_only: false,
+        }
+    }
+}
+
+/// Options for the per-process memory views.
+#[derive(Args, Clone, Default)]
+pub struct MemArgs {
+    /// Only show processes whose name, path or command line matches REGEX
+    #[arg(long, value_name = "REGEX", env = "AMDTOP_FILTER_REGEX")]
+    pub filter_regex: Option<Regex>,
+
+    /// Hide matching processes instead
+    #[arg(long, env = "AMDTOP_INVERT_FILTER")]
+    pub invert_filter: bool,
+
+    /// Hide processes using less than SIZE of VRAM and GTT combined, e.g. 16MiB
+    #[arg(
+        long,
+        value_name = "SIZE",
+        env = "AMDTOP_MIN_SIZE",
+        default_value = "0",
+        value_parser = parse_size_arg
+    )]
+    pub min_size: u64,
+
+    /// Only print processes whose VRAM or GTT moved by more than SIZE, 0
+    /// without one, since the last refresh, and ones that appeared or
+    /// exited; for long logs of mostly idle machines
+    #[arg(
+        long,
+        value_name = "SIZE",
+        env = "AMDTOP_CHANGES_ONLY",
+        num_args = 0..=1,
+        default_missing_value = "0",
+        value_parser = parse_size_arg
+    )]
+    pub changes_only: Option<u64>,
+
+    /// Only show the COUNT largest processes
+    #[arg(
+        long,
+        value_name = "COUNT",
+        env = "AMDTOP_TOP",
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub top: Option<usize>,
+
+    /// Order processes by KEYS, separated by commas, each breaking the ties
+    /// of the one before; the pid breaks any left, so rows stay put between
+    /// refreshes. Sizes go largest first, pids and names up
+    #[arg(long, value_name = "KEYS", env = "AMDTOP_SORT", value_delimiter = ',')]
+    pub sort: Vec<SortKey>,
+
+    /// Keep these PIDS, separated by commas, at the top of the table,
+    /// whatever the order, and shown whatever --top leaves out. `p` pins
+    /// the selected process in `top`
+    #[arg(long, value_name = "PIDS", env = "AMDTOP_PIN", value_delimiter = ',')]
+    pub pin: Vec<i32>,
+
+    /// Don't show memory not attributed to any process
+    #[arg(long, env = "AMDTOP_NO_KERNEL_ROW")]
+    pub no_kernel_row: bool,
+
+    /// Keep showing processes for COUNT refreshes after they exit
+    #[arg(
+        long,
+        value_name = "COUNT",
+        env = "AMDTOP_KEEP_EXITED",
+        default_value_t = 0
+    )]
+    pub keep_exited: u32,
+
+    /// Add a column with each process's systemd unit
+    #[arg(long, env = "AMDTOP_SHOW_UNIT")]
+    pub show_unit: bool,
+
+    /// Add columns with how long each process has run, and how long it has
+    /// held GPU memory since amdtop first saw it
+    #[arg(long, env = "AMDTOP_SHOW_AGE")]
+    pub show_age: bool,
+
+    /// Add a column with how busy each process kept the CPU since the last
+    /// refresh, to spot a GPU starved by its feeder
+    #[arg(long, env = "AMDTOP_SHOW_CPU")]
+    pub show_cpu: bool,
+
+    /// Add a column with the system memory each process has resident, the
+    /// rest of its footprint
+    #[arg(long, env = "AMDTOP_SHOW_RSS")]
+    pub show_rss: bool,
+
+    /// Add a column with each process's RSS, VRAM and GTT together
+    #[arg(long, env = "AMDTOP_SHOW_FOOTPRINT")]
+    pub show_footprint: bool,
+
+    /// Add a column with the engine each process kept busiest since the
+    /// last refresh, from fdinfo; --smoothing averages it over refreshes
+    #[arg(long, env = "AMDTOP_SHOW_BUSY")]
+    pub show_busy: bool,
+
+    /// Add a column with how fast each process's VRAM and GTT together grew
+    /// since the last refresh; --smoothing averages it over refreshes
+    #[arg(long, env = "AMDTOP_SHOW_RATE")]
+    pub show_rate: bool,
+
+    /// Add a column NAME computed for each process from EXPR, like
+    /// `vram_pct=vram/device.vram_total*100`; repeat for more, or separate
+    /// them with `;` in AMDTOP_COLUMN
+    #[arg(
+        long = "column",
+        value_name = "NAME=EXPR",
+        env = "AMDTOP_COLUMN",
+        value_delimiter = ';',
+        value_parser = parse_column,
+        long_help = COLUMN_HELP
+    )]
+    pub columns: Vec<ComputedColumn>,
+
+    /// Pipe each refresh as JSON to COMMAND, run with `sh -c`, and add the
+    /// fields it prints for each pid as columns, like
+    /// `{"3301": {"job": "slurm-4242"}}`
+    #[arg(long, value_name = "COMMAND", env = "AMDTOP_ENRICH")]
+    pub enrich: Option<String>,
+
+    /// Read each process's smaps to take the GTT it has mapped out of its
+    /// RSS, instead of counting it twice in the footprint
+    #[arg(long, env = "AMDTOP_ACCURATE", requires = "show_footprint")]
+    pub accurate: bool,
+
+    /// Sum processes up by systemd unit instead of listing them
+    #[arg(long, value_name = "KEY", env = "AMDTOP_GROUP_BY")]
+    pub group_by: Option<GroupBy>,
+
+    /// Start each device's table with meters for VRAM, GTT, GPU busy and
+    /// temperature
+    #[arg(long, env = "AMDTOP_METERS")]
+    pub meters: bool,
+
+    /// Print a single line for all devices instead of the tables, for
+    /// status bars
+    #[arg(long, env = "AMDTOP_ONELINE", conflicts_with = "meters")]
+    pub oneline: bool,
+
+    /// What each line of --output ndjson is: a whole refresh, or one process
+    #[arg(
+        long,
+        value_name = "KIND",
+        env = "AMDTOP_RECORD",
+        default_value = "snapshot"
+    )]
+    pub record: Record,
+
+    /// Compare what amdgpu_gem_info and fdinfo say each process holds,
+    /// instead of showing usage
+    #[arg(long, env = "AMDTOP_VERIFY", conflicts_with_all = ["meters", "oneline"])]
+    pub verify: bool,
+
+    /// Follow amdgpu's and DRM's kernel log lines under the tables, each
+    /// placed after the refresh before it
+    #[arg(long, env = "AMDTOP_KERNEL_LOG", conflicts_with = "oneline")]
+    pub kernel_log: bool,
+
+    /// Copy the coredumps amdgpu leaves when a GPU hangs into DIR as they
+    /// appear, before the kernel drops them. Only root can read them, so
+    /// under sudo amdtop stays root rather than switching to the user
+    #[arg(long, value_name = "DIR", env = "AMDTOP_SAVE_COREDUMPS")]
+    pub save_coredumps: Option<PathBuf>,
+
+    /// Also show GPUs of other drivers, like i915, xe or nouveau, from
+    /// what every DRM driver's fdinfo has: memory per process and engine
+    /// time, but no device totals
+    #[arg(long, env = "AMDTOP_GENERIC_DRM")]
+    pub generic_drm: bool,
+}
+
+/// What `--record` makes a line of `--output ndjson`.
+#[derive(Copy, Clone, Default, PartialEq, Eq, ValueEnum)]
+pub enum Record {
+    /// Every device of a refresh, like --output json
+    #[default]
+    Snapshot,
+    /// Each process on each device, with the device's name
+    Process,
+}
+
+/// What `--group-by` sums processes up by.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum GroupBy {
+    /// The systemd service or scope, from the process's cgroup
+    Unit,
+}
+
+/// What `--sort` orders processes by.
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum SortKey {
+    /// VRAM and GTT together
+    Total,
+    Vram,
+    Gtt,
+    PeakVram,
+    PeakGtt,
+    Pid,
+    /// The process name, or the unit's with --group-by unit, ignoring case
+    Name,
+}
+
+impl MemArgs {
+    /// Whether something shown needs each process's start time or RSS:
+    /// its age, CPU, RSS or footprint, or a `--column` that may read them.
+    pub fn reads_usage(&self) -> bool {
+        self.show_age
+            || self.show_cpu
+            || self.show_rss
+            || self.show_footprint
+            || !self.columns.is_empty()
+    }
+
+    /// Whether a process passes `--filter-regex`, honouring `--invert-filter`.
+    pub fn matches(&self, identity: &ProcessIdentity) -> bool {
+        let regex = match &self.filter_regex {
+            Some(regex) => regex,
+            None => return true,
+        };
+
+        // Sandboxed apps can be picked by their ID too.
+        let app = identity.app.as_ref().map(|app| app.id().to_string());
+        let matched = [&identity.name, &identity.path, &identity.cmdline, &app]
+            .iter()
+            .any(|field| field.as_deref().is_some_and(|field| regex.is_match(field)));
+        matched != self.invert_filter
+    }
+}
+
+#[derive(Args)]
+pub struct SensorsArgs {
+    /// Also show what amdgpu_pm_info in debugfs reports, to compare against
+    #[arg(long, env = "AMDTOP_PM_INFO")]
+    pub pm_info: bool,
+
+    /// Also show the overdrive clock and voltage table (read-only)
+    #[arg(long, env = "AMDTOP_DETAIL")]
+    pub detail: bool,
+
+    /// Also sample the GRBM status registers through debugfs to show which
+    /// graphics blocks and shader engines are busy
+    #[arg(long, env = "AMDTOP_GRBM")]
+    pub grbm: bool,
+}
+
+#[derive(Args)]
+pub struct SetArgs {
+    #[command(subcommand)]
+    pub setting: Setting,
+}
+
+#[derive(Subcommand)]
+pub enum Setting {
+    /// Force clocks up or down, e.g. to pin them while measuring
+    PerfLevel {
+        #[arg(value_name = "LEVEL")]
+        level: PerfLevel,
+    },
+    /// Limit average board power to WATTS, within what the device allows
+    PowerCap {
+        #[arg(value_name = "WATTS", value_parser = parse_watts)]
+        watts: f64,
+    },
+    /// Hand the fan back to the firmware with `auto`, or hold it at PERCENT
+    /// of its range (never below 20 %)
+    Fan {
+        #[arg(value_name = "auto|PERCENT", value_parser = parse_fan_speed)]
+        speed: FanSpeed,
+    },
+    /// Switch the power profile, by name (e.g. COMPUTE) or number
+    PowerProfile {
+        #[arg(value_name = "PROFILE")]
+        profile: String,
+    },
+    /// Move the GPU work of PID ahead of or behind other clients', for all
+    /// of its contexts
+    Priority {
+        #[arg(value_name = "PID")]
+        pid: i32,
+        #[arg(value_name = "LEVEL")]
+        level: Priority,
+    },
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    /// Address to serve /metrics and /snapshot on
+    #[arg(
+        long,
+        value_name = "ADDR",
+        env = "AMDTOP_LISTEN",
+        default_value = "127.0.0.1:9858"
+    )]
+    pub listen: String,
+
+    /// Label per-process metrics with LABELS, any of pid, name and unit;
+    /// processes that share them are summed
+    #[arg(
+        long,
+        value_name = "LABELS",
+        env = "AMDTOP_PROCESS_LABELS",
+        value_delimiter = ',',
+        default_value = "pid,name"
+    )]
+    pub process_labels: Vec<ProcessLabel>,
+
+    /// Keep at most COUNT per-process series per device, the largest; the
+    /// rest are summed into one labelled "other"
+    #[arg(
+        long,
+        value_name = "COUNT",
+        env = "AMDTOP_MAX_PROCESS_SERIES",
+        default_value_t = 50
+    )]
+    pub max_process_series: usize,
+
+    /// Also publish every --delay to the MQTT broker at URL, as
+    /// mqtt://[user:password@]host[:port]
+    #[arg(long, value_name = "URL", env = "AMDTOP_MQTT")]
+    pub mqtt: Option<String>,
+
+    /// Publish under TOPIC/<card> and TOPIC/<card>/processes
+    #[arg(
+        long,
+        value_name = "TOPIC",
+        env = "AMDTOP_MQTT_TOPIC",
+        default_value = "amdtop"
+    )]
+    pub mqtt_topic: String,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ProcessLabel {
+    Pid,
+    Name,
+    /// The systemd service or scope
+    Unit,
+}
+
+#[derive(Args)]
+pub struct AgentArgs {
+    /// Address to take viewers' requests on
+    #[arg(
+        long,
+        value_name = "ADDR",
+        env = "AMDTOP_AGENT_LISTEN",
+        default_value = "127.0.0.1:9859"
+    )]
+    pub listen: String,
+
+    /// Encrypt viewers' connections with the certificate chain in this PEM
+    /// file, leaf first
+    #[cfg(feature = "tls")]
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "AMDTOP_TLS_CERT",
+        requires = "tls_key"
+    )]
+    pub tls_cert: Option<PathBuf>,
+
+    /// The --tls-cert certificate's private key, as PEM
+    #[cfg(feature = "tls")]
+    #[arg(
+        long,
+        value_name = "FILE",
+        env = "AMDTOP_TLS_KEY",
+        requires = "tls_cert"
+    )]
+    pub tls_key: Option<PathBuf>,
+
+    /// What viewers are shown
+    #[command(flatten)]
+    pub mem: MemArgs,
+}
+
+#[derive(Args)]
+pub struct DbusArgs {
+    /// Use the system bus instead of the session bus
+    #[arg(long, env = "AMDTOP_DBUS_SYSTEM")]
+    pub system: bool,
+
+    /// When to emit LimitExceeded
+    #[command(flatten)]
+    pub limits: CheckArgs,
+}
+
+#[derive(Args)]
+pub struct InstallServiceArgs {
+    /// Address the service serves /metrics and /snapshot on
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:9858")]
+    pub listen: String,
+
+    /// Where to write the unit
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = "/etc/systemd/system/amdtop.service"
+    )]
+    pub path: PathBuf,
+
+    /// Print the unit instead of installing it
+    #[arg(long)]
+    pub print: bool,
+}
+
+#[derive(Args)]
+pub struct BufferArgs {
+    /// Only look at the buffers of process PID instead of the whole device
+    #[arg(long, value_name = "PID", env = "AMDTOP_PID")]
+    pub pid: Option<i32>,
+}
+
+#[derive(Args)]
+pub struct BuffersArgs {
+    #[command(flatten)]
+    pub selection: BufferArgs,
+
+    /// How many buffers to list per device
+    #[arg(
+        long,
+        value_name = "COUNT",
+        env = "AMDTOP_BUFFERS_TOP",
+        default_value_t = 20,
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub top: usize,
+}
+
+#[derive(Args)]
+pub struct TraceArgs {
+    /// Which of amdgpu's tracepoints to record, separated by commas
+    #[arg(
+        long,
+        value_name = "EVENTS",
+        env = "AMDTOP_TRACE_EVENTS",
+        value_delimiter = ',',
+        default_value = "bo_create,bo_move"
+    )]
+    pub events: Vec<TraceEvent>,
+
+    /// How long to record for, e.g. 10s or 2m; Ctrl-C stops early
+    #[arg(
+        long,
+        value_name = "DURATION",
+        env = "AMDTOP_TRACE_DURATION",
+        default_value = "10s",
+        value_parser = parse_duration
+    )]
+    pub duration: Duration,
+
+    /// Attach to the tracepoints with eBPF rather than reading
+    /// trace_pipe, printing each event as it comes above the table
+    #[cfg(feature = "ebpf")]
+    #[arg(long, env = "AMDTOP_TRACE_EBPF")]
+    pub ebpf: bool,
+}
+
+#[derive(Args)]
+pub struct ReplayArgs {
+    /// The recording, from `amdtop -d 1 --output ndjson > FILE`
+    #[arg(value_name = "FILE")]
+    pub path: PathBuf,
+
+    /// Start at TIME: Unix seconds, RFC 3339 like 2026-10-14T09:30:00, or a
+    /// time of day like 09:30 on the day the recording starts
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    pub from: Option<TimeSpec>,
+
+    /// Stop after TIME, given like --from
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    pub to: Option<TimeSpec>,
+}
+
+#[derive(Args)]
+pub struct CompareArgs {
+    /// The first recording, from `amdtop -d 1 --output ndjson > FILE`
+    #[arg(value_name = "A")]
+    pub a: PathBuf,
+
+    /// The recording to compare it with
+    #[arg(value_name = "B")]
+    pub b: PathBuf,
+}
+
+#[derive(Args)]
+pub struct ZabbixArgs {
+    #[command(subcommand)]
+    pub query: ZabbixQuery,
+}
+
+#[derive(Subcommand)]
+pub enum ZabbixQuery {
+    /// Discover GPUs, as {#GPU} and {#PCI}
+    Devices,
+    /// Discover the processes using them, as {#GPU}, {#PID} and {#NAME}
+    Processes,
+    /// Print ITEM of GPU, or of process PID on it
+    Get {
+        #[arg(value_name = "GPU", value_parser = |value: &str| Ok::<_, String>(GpuSelector(value.to_string())))]
+        gpu: GpuSelector,
+        #[arg(value_name = "ITEM")]
+        item: String,
+        #[arg(value_name = "PID")]
+        pid: Option<i32>,
+    },
+}
+
+#[derive(Args)]
+pub struct CheckArgs {
+    /// Fail when a device has more than PERCENT of its VRAM in use
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        env = "AMDTOP_MAX_VRAM",
+        default_value = "90",
+        value_parser = parse_percent
+    )]
+    pub max_vram: f64,
+
+    /// Fail when a device is hotter than CELSIUS
+    #[arg(long, value_name = "CELSIUS", env = "AMDTOP_MAX_TEMPERATURE")]
+    pub max_temperature: Option<f64>,
+
+    /// Warn when a device has more than PERCENT of its VRAM in use
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        env = "AMDTOP_WARN_VRAM",
+        value_parser = parse_percent
+    )]
+    pub warn_vram: Option<f64>,
+
+    /// Warn when a device is hotter than CELSIUS
+    #[arg(long, value_name = "CELSIUS", env = "AMDTOP_WARN_TEMPERATURE")]
+    pub warn_temperature: Option<f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_args(std::iter::once("amdtop").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn parses_options() {
+        let cli = parse(&[
+            "-d",
+            "0.5",
+            "--top",
+            "3",
+            "--min-size",
+            "1M",
+            "--no-kernel-row",
+        ])
+        .unwrap();
+        assert_eq!(cli.global.delay, Some(Duration::from_millis(500)));
+        assert_eq!(cli.mem.top, Some(3));
+        assert_eq!(cli.mem.min_size, 1 << 20);
+        assert!(cli.mem.no_kernel_row);
+        assert!(cli.global.continuous());
+
+        assert!(parse(&["--top"]).is_err());
+        assert!(parse(&["--top", "0"]).is_err());
+        assert!(parse(&["--delay", "-1"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+    }
+
+    #[test]
+    fn global_options_follow_commands() {
+        let cli = parse(&["sensors", "--gpu", "card1", "--output", "json"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Sensors(_))));
+        assert!(cli.global.output == OutputFormat::Json);
+        assert!(cli.global.selects(Device { minor: 1 }));
+        assert!(!cli.global.selects(Device { minor: 0 }));
+
+        let cli = parse(&["mem", "--top", "2"]).unwrap();
+        match cli.command {
+            Some(Command::Mem(mem)) => assert_eq!(mem.top, Some(2)),
+            _ => panic!("expected mem"),
+        }
+
+        // Not left for mem, or whatever command, to silently ignore.
+        assert!(parse(&["--top", "3", "mem"]).is_err());
+        assert!(parse(&["--show-cpu", "sensors"]).is_err());
+        assert!(parse(&["-d", "1", "sensors"]).is_ok());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1.5"), Ok(Duration::from_millis(1500)));
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("10 days").is_err());
+    }
+}
@@ -0,0 +1,285 @@
+//! `--output telegraf` and `--output collectd`, for collectors that run
+//! amdtop from their exec plugin: Telegraf's `inputs.exec` with
+//! `data_format = "influx"`, and collectd's `Exec`.
+//!
+//! Both are meant to be run once per collection interval, so every line of
+//! one run carries the same timestamp. The names are fixed: measurements
+//! `amdtop_device`, `amdtop_process` and `amdtop_sensors` for Telegraf,
+//! plugins `amdtop` and `amdtop_process` for collectd.
+
+use crate::{mem::DeviceView, sensors::Sensors, sysroot};
+use std::{
+    io::{self, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Escapes a tag value of the line protocol.
+fn escape_tag(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+fn write_line<W: Write>(
+    out: &mut W,
+    measurement: &str,
+    tags: &[(&str, String)],
+    fields: &[(&str, String)],
+    timestamp: Duration,
+) -> io::Result<()> {
+    if fields.is_empty() {
+        return Ok(());
+    }
+    let tags = tags
+        .iter()
+        .map(|(key, value)| format!(",{}={}", key, escape_tag(value)))
+        .collect::<String>();
+    let fields = fields
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",");
+    writeln!(
+        out,
+        "{}{} {} {}",
+        measurement,
+        tags,
+        fields,
+        timestamp.as_nanos()
+    )
+}
+
+/// An integer field.
+fn integer(value: u64) -> String {
+    format!("{}i", value)
+}
+
+pub fn write_influx_mem<W: Write>(out: &mut W, views: &[DeviceView]) -> io::Result<()> {
+    let timestamp = now();
+    for view in views {
+        let tags = [("device", view.device.to_string())];
+        let mut fields = Vec::new();
+        if let Some(usage) = view.usage {
+            fields.push(("vram_used_bytes", integer(usage.vram_used_bytes)));
+            fields.push(("vram_total_bytes", integer(usage.vram_total_bytes)));
+            if let Some(gtt) = usage.gtt_used_bytes {
+                fields.push(("gtt_used_bytes", integer(gtt)));
+            }
+        }
+        if let Some(unattributed) = view.unattributed {
+            fields.push(("unattributed_vram_bytes", integer(unattributed.vram_bytes)));
+        }
+        write_line(out, "amdtop_device", &tags, &fields, timestamp)?;
+
+        for process in view.processes.iter().flatten() {
+            let tags = [
+                ("device", view.device.to_string()),
+                ("pid", process.pid.to_string()),
+                (
+                    "name",
+                    process
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| "unknown".to_string()),
+                ),
+            ];
+            let fields = [
+                ("vram_bytes", integer(process.vram_bytes)),
+                ("gtt_bytes", integer(process.gtt_bytes)),
+            ];
+            write_line(out, "amdtop_process", &tags, &fields, timestamp)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_influx_sensors<W: Write>(out: &mut W, all_sensors: &[Sensors]) -> io::Result<()> {
+    let timestamp = now();
+    for sensors in all_sensors {
+        let fields = sensors
+            .readings()
+            .into_iter()
+            .map(|reading| (reading.key, reading.value.to_string()))
+            .collect::<Vec<_>>();
+        let tags = [("device", sensors.device.to_string())];
+        write_line(out, "amdtop_sensors", &tags, &fields, timestamp)?;
+    }
+    Ok(())
+}
+
+/// What collectd's identifiers are made of. `/` separates their parts and
+/// `-` the instance from the rest, so neither can be in a name.
+fn identifier_part(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Who collectd's lines are from and how often they come.
+pub struct Putval {
+    host: String,
+    interval: Duration,
+    timestamp: u64,
+}
+
+impl Putval {
+    /// Uses what the Exec plugin tells its children, falling back to the
+    /// machine's name and `--delay`, or ten seconds like collectd.
+    pub fn new(delay: Option<Duration>) -> Self {
+        let host = std::env::var("COLLECTD_HOSTNAME")
+            .ok()
+            .or_else(|| Some(sysroot::host_name()))
+            .filter(|host| !host.is_empty())
+            .unwrap_or_else(|| "localhost".to_string());
+        let interval = std::env::var("COLLECTD_INTERVAL")
+            .ok()
+            .and_then(|interval| interval.parse::<f64>().ok())
+            .filter(|interval| *interval > 0.0 && interval.is_finite())
+            .map(Duration::from_secs_f64)
+            .or(delay)
+            .unwrap_or(Duration::from_secs(10));
+        Putval {
+            host,
+            interval,
+            timestamp: now().as_secs(),
+        }
+    }
+
+    fn write<W: Write>(
+        &self,
+        out: &mut W,
+        plugin: &str,
+        instance: &str,
+        kind: &str,
+        type_instance: &str,
+        value: f64,
+    ) -> io::Result<()> {
+        writeln!(
+            out,
+            "PUTVAL \"{}/{}-{}/{}-{}\" interval={} {}:{}",
+            self.host,
+            plugin,
+            identifier_part(instance),
+            kind,
+            type_instance,
+            self.interval.as_secs_f64(),
+            self.timestamp,
+            value
+        )
+    }
+
+    pub fn write_mem<W: Write>(&self, out: &mut W, views: &[DeviceView]) -> io::Result<()> {
+        for view in views {
+            let device = view.device.to_string();
+            if let Some(usage) = view.usage {
+                self.write(
+                    out,
+                    "amdtop",
+                    &device,
+                    "memory",
+                    "vram_used",
+                    usage.vram_used_bytes as f64,
+                )?;
+                self.write(
+                    out,
+                    "amdtop",
+                    &device,
+                    "memory",
+                    "vram_total",
+                    usage.vram_total_bytes as f64,
+                )?;
+                if let Some(gtt) = usage.gtt_used_bytes {
+                    self.write(out, "amdtop", &device, "memory", "gtt_used", gtt as f64)?;
+                }
+            }
+            if let Some(unattributed) = view.unattributed {
+                self.write(
+                    out,
+                    "amdtop",
+                    &device,
+                    "memory",
+                    "unattributed_vram",
+                    unattributed.vram_bytes as f64,
+                )?;
+            }
+            for process in view.processes.iter().flatten() {
+                let instance = format!(
+                    "{}_{}_{}",
+                    device,
+                    process.pid,
+                    process.name.as_deref().unwrap_or("unknown")
+                );
+                self.write(
+                    out,
+                    "amdtop_process",
+                    &instance,
+                    "memory",
+                    "vram",
+                    process.vram_bytes as f64,
+                )?;
+                self.write(
+                    out,
+                    "amdtop_process",
+                    &instance,
+                    "memory",
+                    "gtt",
+                    process.gtt_bytes as f64,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_sensors<W: Write>(&self, out: &mut W, all_sensors: &[Sensors]) -> io::Result<()> {
+        for sensors in all_sensors {
+            let device = sensors.device.to_string();
+            for reading in sensors.readings() {
+                // collectd's types.db wants base units.
+                let (kind, scale) = match reading.unit {
+                    "%" => ("percent", 1.0),
+                    "MHz" => ("frequency", 1e6),
+                    "mV" => ("voltage", 1e-3),
+                    "°C" => ("temperature", 1.0),
+                    "W" => ("power", 1.0),
+                    "RPM" => ("fanspeed", 1.0),
+                    _ => ("gauge", 1.0),
+                };
+                self.write(
+                    out,
+                    "amdtop",
+                    &device,
+                    kind,
+                    reading.key,
+                    reading.value * scale,
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_tags_and_identifiers() {
+        assert_eq!(escape_tag("Web Content,x=1"), "Web\\ Content\\,x\\=1");
+        assert_eq!(
+            identifier_part("card0_42_Web Content/2-x"),
+            "card0_42_Web_Content_2_x"
+        );
+    }
+}
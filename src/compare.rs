@@ -0,0 +1,375 @@
+//! `amdtop compare`: two recorded sessions side by side, as when trying a
+//! driver update or a game patch. Devices are matched by name and
+//! processes by name and path, since their pids never match across runs.
+
+use crate::{
+    cli::{CompareArgs, GlobalArgs, OutputFormat},
+    error::{self, Error},
+    output,
+    recording::{self, Snapshot},
+    FormatBytes, FormatDuration,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+
+#[derive(Copy, Clone, Default)]
+struct Average {
+    sum: f64,
+    count: usize,
+}
+
+impl Average {
+    fn add(&mut self, value: f64) {
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn get(self) -> Option<f64> {
+        Some(self.sum / self.count as f64).filter(|_| self.count > 0)
+    }
+}
+
+#[derive(Default)]
+struct ProcessSummary {
+    max_vram_bytes: u64,
+    max_gtt_bytes: u64,
+    /// Of its busiest engine, over the snapshots it had figures in.
+    busy: Average,
+}
+
+/// Processes by name and path.
+type ProcessKey = (String, String);
+
+#[derive(Default)]
+struct DeviceSummary {
+    peak_vram_bytes: Option<u64>,
+    /// Of the busiest engine, with every process's share of it added up.
+    busy: Average,
+    processes: BTreeMap<ProcessKey, ProcessSummary>,
+}
+
+/// What a session was, in the terms it's compared in.
+#[derive(Serialize)]
+struct Session {
+    path: String,
+    /// Seconds since the Unix epoch.
+    start: f64,
+    seconds: f64,
+    snapshots: usize,
+    #[serde(skip)]
+    devices: BTreeMap<String, DeviceSummary>,
+}
+
+impl Session {
+    fn read(path: &Path) -> error::Result<Session> {
+        let snapshots = recording::read(path)?;
+        let (first, last) = match (snapshots.first(), snapshots.last()) {
+            (Some(first), Some(last)) => (first.time, last.time),
+            _ => {
+                return Err(Error::InvalidArgument(format!(
+                    "{} has no snapshots",
+                    path.display()
+                )))
+            }
+        };
+        Ok(Session {
+            path: path.display().to_string(),
+            start: first,
+            seconds: last - first,
+            snapshots: snapshots.len(),
+            devices: summarize(&snapshots),
+        })
+    }
+}
+
+fn summarize(snapshots: &[Snapshot]) -> BTreeMap<String, DeviceSummary> {
+    let mut devices = BTreeMap::<String, DeviceSummary>::new();
+    for snapshot in snapshots {
+        for device in &snapshot.devices {
+            let summary = devices.entry(device.device.clone()).or_default();
+            if let Some(usage) = device.usage {
+                summary.peak_vram_bytes = summary.peak_vram_bytes.max(Some(usage.vram_used_bytes));
+            }
+            let mut engines = BTreeMap::<&str, f64>::new();
+            for process in device.processes.iter().flatten() {
+                let key = (
+                    process
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| "<exited>".to_string()),
+                    process
+                        .path
+                        .clone()
+                        .unwrap_or_else(|| "unknown".to_string()),
+                );
+                let entry = summary.processes.entry(key).or_default();
+                entry.max_vram_bytes = entry.max_vram_bytes.max(process.vram_bytes);
+                entry.max_gtt_bytes = entry.max_gtt_bytes.max(process.gtt_bytes);
+                if let Some(busiest) = process.engine_busy.values().copied().reduce(f64::max) {
+                    entry.busy.add(busiest);
+                }
+                for (engine, percent) in &process.engine_busy {
+                    *engines.entry(engine).or_default() += percent;
+                }
+            }
+            if let Some(busiest) = engines.into_values().reduce(f64::max) {
+                summary.busy.add(busiest.min(100.0));
+            }
+        }
+    }
+    devices
+}
+
+/// A figure from each session, where it had one.
+#[derive(Serialize)]
+struct Pair<T> {
+    a: Option<T>,
+    b: Option<T>,
+}
+
+#[derive(Serialize)]
+struct ProcessComparison {
+    name: String,
+    path: String,
+    max_vram_bytes: Pair<u64>,
+    max_gtt_bytes: Pair<u64>,
+    average_busy_percent: Pair<f64>,
+}
+
+#[derive(Serialize)]
+struct DeviceComparison {
+    device: String,
+    peak_vram_bytes: Pair<u64>,
+    average_busy_percent: Pair<f64>,
+    processes: Vec<ProcessComparison>,
+}
+
+#[derive(Serialize)]
+struct Comparison {
+    a: Session,
+    b: Session,
+    devices: Vec<DeviceComparison>,
+}
+
+fn compare(a: Session, b: Session) -> Comparison {
+    let names = a
+        .devices
+        .keys()
+        .chain(b.devices.keys())
+        .cloned()
+        .collect::<BTreeSet<_>>();
+    let devices = names
+        .into_iter()
+        .map(|name| {
+            let (in_a, in_b) = (a.devices.get(&name), b.devices.get(&name));
+            let keys = in_a
+                .into_iter()
+                .chain(in_b)
+                .flat_map(|device| device.processes.keys())
+                .cloned()
+                .collect::<BTreeSet<_>>();
+            let mut processes = keys
+                .into_iter()
+                .map(|key| {
+                    let (of_a, of_b) = (
+                        in_a.and_then(|device| device.processes.get(&key)),
+                        in_b.and_then(|device| device.processes.get(&key)),
+                    );
+                    let pair = |figure: fn(&ProcessSummary) -> u64| Pair {
+                        a: of_a.map(figure),
+                        b: of_b.map(figure),
+                    };
+                    ProcessComparison {
+                        max_vram_bytes: pair(|process| process.max_vram_bytes),
+                        max_gtt_bytes: pair(|process| process.max_gtt_bytes),
+                        average_busy_percent: Pair {
+                            a: of_a.and_then(|process| process.busy.get()),
+                            b: of_b.and_then(|process| process.busy.get()),
+                        },
+                        name: key.0,
+                        path: key.1,
+                    }
+                })
+                .collect::<Vec<_>>();
+            // Largest first, in whichever session it was larger.
+            processes.sort_by_key(|process| {
+                std::cmp::Reverse(
+                    [&process.max_vram_bytes, &process.max_gtt_bytes]
+                        .iter()
+                        .map(|pair| pair.a.max(pair.b).unwrap_or_default())
+                        .sum::<u64>(),
+                )
+            });
+            DeviceComparison {
+                peak_vram_bytes: Pair {
+                    a: in_a.and_then(|device| device.peak_vram_bytes),
+                    b: in_b.and_then(|device| device.peak_vram_bytes),
+                },
+                average_busy_percent: Pair {
+                    a: in_a.and_then(|device| device.busy.get()),
+                    b: in_b.and_then(|device| device.busy.get()),
+                },
+                processes,
+                device: name,
+            }
+        })
+        .collect();
+    Comparison { a, b, devices }
+}
+
+fn bytes(bytes: Option<u64>) -> String {
+    bytes.map_or_else(
+        || "-".to_string(),
+        |bytes| FormatBytes::new(bytes).to_string(),
+    )
+}
+
+fn bytes_change(pair: &Pair<u64>) -> String {
+    match (pair.a, pair.b) {
+        (Some(a), Some(b)) if a == b => "0".to_string(),
+        (Some(a), Some(b)) if b > a => format!("+{}", FormatBytes::new(b - a)),
+        (Some(a), Some(b)) => format!("-{}", FormatBytes::new(a - b)),
+        (None, Some(_)) => "new".to_string(),
+        (Some(_), None) => "gone".to_string(),
+        (None, None) => "-".to_string(),
+    }
+}
+
+fn percent(percent: Option<f64>) -> String {
+    percent.map_or_else(|| "-".to_string(), |percent| format!("{:.0}%", percent))
+}
+
+fn write_session<W: Write>(out: &mut W, label: &str, session: &Session) -> io::Result<()> {
+    writeln!(
+        out,
+        "{} | {} | {} | {} | {} snapshots",
+        label,
+        session.path,
+        output::local_time(Duration::try_from_secs_f64(session.start).unwrap_or_default()),
+        FormatDuration::new(Duration::try_from_secs_f64(session.seconds).unwrap_or_default()),
+        session.snapshots
+    )
+}
+
+fn write_device<W: Write>(out: &mut W, device: &DeviceComparison) -> io::Result<()> {
+    let mut header = format!(
+        "{} | peak VRAM {} -> {} ({})",
+        device.device,
+        bytes(device.peak_vram_bytes.a),
+        bytes(device.peak_vram_bytes.b),
+        bytes_change(&device.peak_vram_bytes)
+    );
+    let busy = &device.average_busy_percent;
+    if busy.a.is_some() || busy.b.is_some() {
+        header += &format!(" | average busy {} -> {}", percent(busy.a), percent(busy.b));
+        if let (Some(a), Some(b)) = (busy.a, busy.b) {
+            header += &format!(" ({:+.0})", b - a);
+        }
+    }
+    writeln!(out, "{}", header)?;
+
+    let show_busy = device.processes.iter().any(|process| {
+        process.average_busy_percent.a.is_some() || process.average_busy_percent.b.is_some()
+    });
+    let mut header = format!(
+        "{0: <20} | {1: <60} | {2: >15} | {3: >15} | {4: >15} | {5: >15} | {6: >15} | {7: >15}",
+        "PROCESS", "PATH", "MAX VRAM A", "MAX VRAM B", "CHANGE", "MAX GTT A", "MAX GTT B", "CHANGE"
+    );
+    let mut width = 191;
+    if show_busy {
+        header += &format!(" | {: >6} | {: >6}", "BUSY A", "BUSY B");
+        width += 18;
+    }
+    writeln!(out, "{}", header)?;
+    writeln!(out, "{:-^1$}", "", width)?;
+    for process in &device.processes {
+        let mut line = format!(
+            "{0: <20} | {1: <60} | {2: >15} | {3: >15} | {4: >15} | {5: >15} | {6: >15} | {7: >15}",
+            process.name,
+            process.path,
+            bytes(process.max_vram_bytes.a),
+            bytes(process.max_vram_bytes.b),
+            bytes_change(&process.max_vram_bytes),
+            bytes(process.max_gtt_bytes.a),
+            bytes(process.max_gtt_bytes.b),
+            bytes_change(&process.max_gtt_bytes),
+        );
+        if show_busy {
+            let busy = &process.average_busy_percent;
+            line += &format!(" | {: >6} | {: >6}", percent(busy.a), percent(busy.b));
+        }
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+pub fn run(global: &GlobalArgs, options: &CompareArgs) -> error::Result<()> {
+    let comparison = compare(Session::read(&options.a)?, Session::read(&options.b)?);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match global.output {
+        OutputFormat::Table => {
+            write_session(&mut out, "a", &comparison.a)?;
+            write_session(&mut out, "b", &comparison.b)?;
+            for device in &comparison.devices {
+                writeln!(out)?;
+                write_device(&mut out, device)?;
+            }
+        }
+        OutputFormat::Json => output::write_json(&mut out, &comparison)?,
+        format => return Err(output::unsupported(format, "compare")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(line: &str) -> Snapshot {
+        serde_json::from_str(line).unwrap()
+    }
+
+    #[test]
+    fn sums_up_sessions() {
+        let snapshots = [
+            snapshot(
+                r#"{"time": 1.0, "devices": [{"device": "card0",
+                    "usage": {"vram_used_bytes": 300, "vram_total_bytes": 1000, "gtt_used_bytes": null},
+                    "processes": [
+                        {"pid": 1, "name": "game", "path": "/usr/bin/game", "vram_bytes": 200, "gtt_bytes": 10,
+                         "engine_busy": {"gfx": 60.0, "compute": 5.0}},
+                        {"pid": 2, "name": "obs", "path": "/usr/bin/obs", "vram_bytes": 50, "gtt_bytes": 0,
+                         "engine_busy": {"gfx": 50.0}}]}]}"#,
+            ),
+            snapshot(
+                r#"{"time": 2.0, "devices": [{"device": "card0",
+                    "usage": {"vram_used_bytes": 250, "vram_total_bytes": 1000, "gtt_used_bytes": null},
+                    "processes": [
+                        {"pid": 3, "name": "game", "path": "/usr/bin/game", "vram_bytes": 220, "gtt_bytes": 5,
+                         "engine_busy": {"gfx": 20.0}}]}]}"#,
+            ),
+        ];
+        let devices = summarize(&snapshots);
+        let card0 = &devices["card0"];
+        assert_eq!(card0.peak_vram_bytes, Some(300));
+        // 110% of gfx counts as 100.
+        assert_eq!(card0.busy.get(), Some(60.0));
+        let game = &card0.processes[&("game".to_string(), "/usr/bin/game".to_string())];
+        assert_eq!((game.max_vram_bytes, game.max_gtt_bytes), (220, 10));
+        assert_eq!(game.busy.get(), Some(40.0));
+        assert_eq!(
+            bytes_change(&Pair {
+                a: Some(2048),
+                b: Some(1024)
+            }),
+            "-1.00 KiB"
+        );
+    }
+}
@@ -0,0 +1,715 @@
+//! `amdtop dbus`: serves `org.amdtop.Monitor` on the session or system bus,
+//! so desklets, widgets and scripts can ask for snapshots without parsing
+//! our output.
+//!
+//! `/org/amdtop/Monitor` has two methods, `Snapshot` and `Check`, which
+//! return the same JSON as `export`'s `/snapshot` and `check --output json`.
+//! The `LimitExceeded` signal is emitted when a device goes over one of the
+//! `check` limits, once each time it crosses.
+//!
+//! We only need a sliver of the protocol, so it's spoken directly here:
+//! EXTERNAL authentication over a Unix socket, and messages whose bodies
+//! are made of basic types.
+
+use crate::{
+    check,
+    cli::{DbusArgs, GlobalArgs},
+    error::{self, Error},
+    export::Exporter,
+    source::Device,
+    systemd, INTERRUPTED,
+};
+use std::{
+    collections::HashSet,
+    convert::TryInto,
+    io::{self, Read, Write},
+    os::unix::{io::AsRawFd, net::UnixStream},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+pub const BUS_NAME: &str = "org.amdtop.Monitor";
+const OBJECT_PATH: &str = "/org/amdtop/Monitor";
+const INTERFACE: &str = "org.amdtop.Monitor";
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.amdtop.Monitor">
+    <method name="Snapshot">
+      <arg name="json" type="s" direction="out"/>
+    </method>
+    <method name="Check">
+      <arg name="json" type="s" direction="out"/>
+    </method>
+    <signal name="LimitExceeded">
+      <arg name="device" type="s"/>
+      <arg name="check" type="s"/>
+      <arg name="value" type="d"/>
+      <arg name="limit" type="d"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml" type="s" direction="out"/>
+    </method>
+  </interface>
+  <interface name="org.freedesktop.DBus.Peer">
+    <method name="Ping"/>
+  </interface>
+</node>
+"#;
+
+/// Message types.
+const METHOD_CALL: u8 = 1;
+const METHOD_RETURN: u8 = 2;
+const ERROR: u8 = 3;
+const SIGNAL: u8 = 4;
+
+/// The caller doesn't want a reply.
+const NO_REPLY_EXPECTED: u8 = 0x1;
+
+/// `RequestName` flag: fail instead of waiting in line for the name.
+const DO_NOT_QUEUE: u32 = 0x4;
+const PRIMARY_OWNER: u32 = 1;
+
+/// The values we send and understand.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Byte(u8),
+    U32(u32),
+    F64(f64),
+    Str(String),
+    ObjectPath(String),
+    Signature(String),
+}
+
+impl Value {
+    fn signature(&self) -> &'static str {
+        match self {
+            Value::Byte(_) => "y",
+            Value::U32(_) => "u",
+            Value::F64(_) => "d",
+            Value::Str(_) => "s",
+            Value::ObjectPath(_) => "o",
+            Value::Signature(_) => "g",
+        }
+    }
+}
+
+/// Appends values, padding each to its alignment from the start of the
+/// message.
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    fn pad(&mut self, alignment: usize) {
+        while !self.buf.len().is_multiple_of(alignment) {
+            self.buf.push(0);
+        }
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.pad(4);
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn value(&mut self, value: &Value) {
+        match value {
+            Value::Byte(byte) => self.buf.push(*byte),
+            Value::U32(value) => self.u32(*value),
+            Value::F64(value) => {
+                self.pad(8);
+                self.buf.extend_from_slice(&value.to_le_bytes());
+            }
+            Value::Str(string) | Value::ObjectPath(string) => {
+                self.u32(string.len() as u32);
+                self.buf.extend_from_slice(string.as_bytes());
+                self.buf.push(0);
+            }
+            Value::Signature(signature) => {
+                self.buf.push(signature.len() as u8);
+                self.buf.extend_from_slice(signature.as_bytes());
+                self.buf.push(0);
+            }
+        }
+    }
+}
+
+/// Reads values back, in either byte order.
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn align(&mut self, alignment: usize) {
+        self.pos = self.pos.div_ceil(alignment) * alignment;
+    }
+
+    fn bytes(&mut self, count: usize) -> Option<&[u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(count)?)?;
+        self.pos += count;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.align(4);
+        let bytes = self.bytes(4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn string(&mut self, length: usize) -> Option<String> {
+        let string = String::from_utf8(self.bytes(length)?.to_vec()).ok();
+        self.u8()?;
+        string
+    }
+
+    /// One value of a basic type, `None` for anything else.
+    fn value(&mut self, signature: u8) -> Option<Value> {
+        Some(match signature {
+            b'y' => Value::Byte(self.u8()?),
+            b'u' | b'b' => Value::U32(self.u32()?),
+            b'd' => {
+                self.align(8);
+                let bytes = self.bytes(8)?.try_into().ok()?;
+                Value::F64(if self.big_endian {
+                    f64::from_be_bytes(bytes)
+                } else {
+                    f64::from_le_bytes(bytes)
+                })
+            }
+            b's' | b'o' => {
+                let length = self.u32()? as usize;
+                let string = self.string(length)?;
+                if signature == b's' {
+                    Value::Str(string)
+                } else {
+                    Value::ObjectPath(string)
+                }
+            }
+            b'g' => {
+                let length = self.u8()? as usize;
+                Value::Signature(self.string(length)?)
+            }
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Message {
+    pub kind: u8,
+    pub flags: u8,
+    pub serial: u32,
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub error_name: Option<String>,
+    pub reply_serial: Option<u32>,
+    pub destination: Option<String>,
+    pub sender: Option<String>,
+    pub body: Vec<Value>,
+}
+
+impl Message {
+    fn method_call(destination: &str, path: &str, interface: &str, member: &str) -> Self {
+        Message {
+            kind: METHOD_CALL,
+            destination: Some(destination.to_string()),
+            path: Some(path.to_string()),
+            interface: Some(interface.to_string()),
+            member: Some(member.to_string()),
+            ..Message::default()
+        }
+    }
+
+    /// A reply to `call`, or an error if `error_name` is set.
+    fn reply(call: &Message, error_name: Option<&str>, body: Vec<Value>) -> Self {
+        Message {
+            kind: if error_name.is_some() {
+                ERROR
+            } else {
+                METHOD_RETURN
+            },
+            flags: NO_REPLY_EXPECTED,
+            error_name: error_name.map(str::to_string),
+            reply_serial: Some(call.serial),
+            destination: call.sender.clone(),
+            body,
+            ..Message::default()
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Writer::default();
+        for value in &self.body {
+            body.value(value);
+        }
+        let signature = self.body.iter().map(Value::signature).collect::<String>();
+
+        let mut fields = Vec::new();
+        let strings = [
+            (1, self.path.clone().map(Value::ObjectPath)),
+            (2, self.interface.clone().map(Value::Str)),
+            (3, self.member.clone().map(Value::Str)),
+            (4, self.error_name.clone().map(Value::Str)),
+            (5, self.reply_serial.map(Value::U32)),
+            (6, self.destination.clone().map(Value::Str)),
+        ];
+        for (code, value) in strings {
+            fields.extend(value.map(|value| (code, value)));
+        }
+        if !signature.is_empty() {
+            fields.push((8, Value::Signature(signature)));
+        }
+
+        let mut message = Writer::default();
+        message
+            .buf
+            .extend_from_slice(&[b'l', self.kind, self.flags, 1]);
+        message.u32(body.buf.len() as u32);
+        message.u32(self.serial);
+        message.u32(0);
+        let fields_start = message.buf.len();
+        for (code, value) in fields {
+            message.pad(8);
+            message.value(&Value::Byte(code));
+            message.value(&Value::Signature(value.signature().to_string()));
+            message.value(&value);
+        }
+        let fields_length = (message.buf.len() - fields_start) as u32;
+        message.buf[12..16].copy_from_slice(&fields_length.to_le_bytes());
+        message.pad(8);
+        message.buf.extend_from_slice(&body.buf);
+        message.buf
+    }
+
+    /// Decodes a whole message. Bodies with other than basic types are left
+    /// empty; nothing we're sent needs them.
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let big_endian = match buf.first()? {
+            b'l' => false,
+            b'B' => true,
+            _ => return None,
+        };
+        let mut reader = Reader {
+            buf,
+            pos: 4,
+            big_endian,
+        };
+        let body_length = reader.u32()? as usize;
+        let mut message = Message {
+            kind: buf[1],
+            flags: *buf.get(2)?,
+            serial: reader.u32()?,
+            ..Message::default()
+        };
+        let fields_end = reader.u32()? as usize + 16;
+        let mut signature = String::new();
+
+        while reader.pos < fields_end {
+            reader.align(8);
+            let code = reader.u8()?;
+            let field_signature = match reader.value(b'g')? {
+                Value::Signature(signature) => signature,
+                _ => return None,
+            };
+            let value = reader.value(*field_signature.as_bytes().first()?)?;
+            match (code, value) {
+                (1, Value::ObjectPath(path)) => message.path = Some(path),
+                (2, Value::Str(interface)) => message.interface = Some(interface),
+                (3, Value::Str(member)) => message.member = Some(member),
+                (4, Value::Str(error_name)) => message.error_name = Some(error_name),
+                (5, Value::U32(serial)) => message.reply_serial = Some(serial),
+                (6, Value::Str(destination)) => message.destination = Some(destination),
+                (7, Value::Str(sender)) => message.sender = Some(sender),
+                (8, Value::Signature(body_signature)) => signature = body_signature,
+                _ => {}
+            }
+        }
+
+        reader.align(8);
+        let body_end = reader.pos.checked_add(body_length)?;
+        if buf.len() < body_end {
+            return None;
+        }
+        for byte in signature.bytes() {
+            match reader.value(byte) {
+                Some(value) => message.body.push(value),
+                None => {
+                    message.body.clear();
+                    break;
+                }
+            }
+        }
+        Some(message)
+    }
+}
+
+/// Where a bus address like `unix:path=/run/user/1000/bus;...` says to
+/// connect. Only Unix sockets are supported.
+pub fn parse_address(address: &str) -> Option<UnixAddress> {
+    address.split(';').find_map(|address| {
+        let options = address.strip_prefix("unix:")?;
+        options.split(',').find_map(|option| {
+            let (key, value) = option.split_once('=')?;
+            let value = unescape(value)?;
+            match key {
+                "path" => Some(UnixAddress::Path(value)),
+                "abstract" => Some(UnixAddress::Abstract(value)),
+                _ => None,
+            }
+        })
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum UnixAddress {
+    Path(String),
+    Abstract(String),
+}
+
+/// Undoes the `%xx` escaping of address values.
+fn unescape(value: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+pub struct Connection {
+    stream: UnixStream,
+    serial: u32,
+}
+
+impl Connection {
+    fn open(address: &UnixAddress) -> io::Result<UnixStream> {
+        match address {
+            UnixAddress::Path(path) => UnixStream::connect(path),
+            UnixAddress::Abstract(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                UnixStream::connect_addr(&address)
+            }
+        }
+    }
+
+    /// Connects and authenticates to the system or the session bus.
+    pub fn connect(system: bool) -> io::Result<Self> {
+        let address = if system {
+            std::env::var("DBUS_SYSTEM_BUS_ADDRESS")
+                .unwrap_or_else(|_| "unix:path=/run/dbus/system_bus_socket".to_string())
+        } else {
+            std::env::var("DBUS_SESSION_BUS_ADDRESS")
+                .or_else(|_| {
+                    std::env::var("XDG_RUNTIME_DIR").map(|dir| format!("unix:path={}/bus", dir))
+                })
+                .map_err(|_| {
+                    io::Error::other("no session bus, DBUS_SESSION_BUS_ADDRESS is unset")
+                })?
+        };
+        let address = parse_address(&address)
+            .ok_or_else(|| io::Error::other(format!("can't use bus address {}", address)))?;
+
+        let mut stream = Connection::open(&address)?;
+        let uid = unsafe { libc::geteuid() }.to_string();
+        let hex_uid = uid
+            .bytes()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        write!(stream, "\0AUTH EXTERNAL {}\r\n", hex_uid)?;
+        let response = read_line(&mut stream)?;
+        if !response.starts_with("OK ") {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("the bus refused us: {}", response),
+            ));
+        }
+        stream.write_all(b"BEGIN\r\n")?;
+
+        let mut connection = Connection { stream, serial: 0 };
+        connection.call(Message::method_call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "Hello",
+        ))?;
+        Ok(connection)
+    }
+
+    pub fn send(&mut self, mut message: Message) -> io::Result<u32> {
+        self.serial += 1;
+        message.serial = self.serial;
+        self.stream.write_all(&message.encode())?;
+        Ok(self.serial)
+    }
+
+    pub fn receive(&mut self) -> io::Result<Message> {
+        let mut header = [0; 16];
+        self.stream.read_exact(&mut header)?;
+        let field = |offset: usize| {
+            let bytes = [
+                header[offset],
+                header[offset + 1],
+                header[offset + 2],
+                header[offset + 3],
+            ];
+            if header[0] == b'B' {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        let fields_length = field(12) as usize;
+        let length = (16 + fields_length).div_ceil(8) * 8 + field(4) as usize;
+        let mut buf = header.to_vec();
+        buf.resize(length, 0);
+        self.stream.read_exact(&mut buf[16..])?;
+        Message::decode(&buf)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad D-Bus message"))
+    }
+
+    /// Sends a method call and waits for its reply, dropping whatever else
+    /// arrives meanwhile.
+    pub fn call(&mut self, message: Message) -> io::Result<Message> {
+        let serial = self.send(message)?;
+        loop {
+            let reply = self.receive()?;
+            if reply.reply_serial == Some(serial) {
+                return Ok(reply);
+            }
+        }
+    }
+
+    /// Waits up to `timeout` for something to read.
+    fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        let mut poll = libc::pollfd {
+            fd: self.stream.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ready = unsafe { libc::poll(&mut poll, 1, timeout.as_millis() as libc::c_int) };
+        match ready {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    Ok(false)
+                } else {
+                    Err(err)
+                }
+            }
+            0 => Ok(false),
+            _ => Ok(true),
+        }
+    }
+}
+
+fn read_line(stream: &mut UnixStream) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while !line.ends_with(b"\r\n") {
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+struct Service<'a> {
+    global: &'a GlobalArgs,
+    options: &'a DbusArgs,
+    exporter: Exporter,
+}
+
+impl Service<'_> {
+    /// The reply to a method call: its error name if it failed, and body.
+    fn answer(&mut self, call: &Message) -> (Option<&'static str>, Vec<Value>) {
+        let member = call.member.as_deref().unwrap_or_default();
+        if call.path.as_deref() != Some(OBJECT_PATH) {
+            return (
+                Some("org.freedesktop.DBus.Error.UnknownObject"),
+                vec![Value::Str(format!("no object at {:?}", call.path))],
+            );
+        }
+
+        let result = match (call.interface.as_deref(), member) {
+            (Some("org.freedesktop.DBus.Introspectable"), "Introspect") => {
+                Ok(vec![Value::Str(INTROSPECTION.to_string())])
+            }
+            (Some("org.freedesktop.DBus.Peer"), "Ping") => Ok(Vec::new()),
+            (Some(INTERFACE) | None, "Snapshot") => self
+                .exporter
+                .snapshot(self.global)
+                .map(|json| vec![Value::Str(json)]),
+            (Some(INTERFACE) | None, "Check") => check::evaluate(self.global, &self.options.limits)
+                .map(|results| {
+                    vec![Value::Str(
+                        serde_json::to_string(&results).unwrap_or_default(),
+                    )]
+                }),
+            _ => {
+                return (
+                    Some("org.freedesktop.DBus.Error.UnknownMethod"),
+                    vec![Value::Str(format!("no method {}", member))],
+                )
+            }
+        };
+        match result {
+            Ok(body) => (None, body),
+            Err(err) => (
+                Some("org.amdtop.Monitor.Error.Failed"),
+                vec![Value::Str(err.to_string())],
+            ),
+        }
+    }
+}
+
+pub fn run(global: &GlobalArgs, options: &DbusArgs) -> error::Result<()> {
+    let bus_name = if options.system { "system" } else { "session" };
+    let mut bus = Connection::connect(options.system).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("can't connect to the {} bus: {}", bus_name, err),
+        )
+    })?;
+
+    let mut request = Message::method_call(
+        "org.freedesktop.DBus",
+        "/org/freedesktop/DBus",
+        "org.freedesktop.DBus",
+        "RequestName",
+    );
+    request.body = vec![Value::Str(BUS_NAME.to_string()), Value::U32(DO_NOT_QUEUE)];
+    let reply = bus.call(request)?;
+    if let Some(error_name) = reply.error_name {
+        return Err(Error::PermissionDenied(format!(
+            "the {} bus won't let us own {}: {}",
+            bus_name, BUS_NAME, error_name
+        )));
+    }
+    if reply.body.first() != Some(&Value::U32(PRIMARY_OWNER)) {
+        return Err(Error::Io(io::Error::other(format!(
+            "{} is already owned on the {} bus",
+            BUS_NAME, bus_name
+        ))));
+    }
+
+    let mut service = Service {
+        global,
+        options,
+        exporter: Exporter::new(global)?,
+    };
+    eprintln!("amdtop: serving {} on the {} bus", BUS_NAME, bus_name);
+
+    let delay = global.delay.unwrap_or(Duration::from_secs(1));
+    let mut exceeded = HashSet::<(Device, &str)>::new();
+    let mut next_check = Instant::now();
+
+    crate::catch_interrupts();
+    systemd::notify("READY=1");
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        systemd::keep_alive();
+
+        if Instant::now() >= next_check {
+            let results = check::evaluate(global, &options.limits)?;
+            for result in &results {
+                let key = (result.device, result.check);
+                if result.ok {
+                    exceeded.remove(&key);
+                } else if exceeded.insert(key) {
+                    bus.send(Message {
+                        kind: SIGNAL,
+                        flags: NO_REPLY_EXPECTED,
+                        path: Some(OBJECT_PATH.to_string()),
+                        interface: Some(INTERFACE.to_string()),
+                        member: Some("LimitExceeded".to_string()),
+                        body: vec![
+                            Value::Str(result.device.to_string()),
+                            Value::Str(result.check.to_string()),
+                            Value::F64(result.value),
+                            Value::F64(result.limit),
+                        ],
+                        ..Message::default()
+                    })?;
+                }
+            }
+            next_check = Instant::now() + delay;
+        }
+
+        if !bus.wait(Duration::from_millis(100))? {
+            continue;
+        }
+        let message = bus.receive()?;
+        if message.kind != METHOD_CALL {
+            continue;
+        }
+        let (error_name, body) = service.answer(&message);
+        if message.flags & NO_REPLY_EXPECTED == 0 {
+            bus.send(Message::reply(&message, error_name, body))?;
+        }
+    }
+    systemd::notify("STOPPING=1");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_messages() {
+        let message = Message {
+            kind: SIGNAL,
+            flags: NO_REPLY_EXPECTED,
+            serial: 7,
+            path: Some(OBJECT_PATH.to_string()),
+            interface: Some(INTERFACE.to_string()),
+            member: Some("LimitExceeded".to_string()),
+            body: vec![
+                Value::Str("card0".to_string()),
+                Value::Str("vram".to_string()),
+                Value::F64(93.5),
+                Value::F64(90.0),
+            ],
+            ..Message::default()
+        };
+        let encoded = message.encode();
+        assert_eq!(encoded.len() % 8, 0);
+        assert_eq!(Message::decode(&encoded), Some(message));
+    }
+
+    #[test]
+    fn parses_bus_addresses() {
+        assert_eq!(
+            parse_address("unix:path=/run/user/1000/bus"),
+            Some(UnixAddress::Path("/run/user/1000/bus".to_string()))
+        );
+        assert_eq!(
+            parse_address("tcp:host=localhost;unix:abstract=/tmp/dbus-a%2cb,guid=1234"),
+            Some(UnixAddress::Abstract("/tmp/dbus-a,b".to_string()))
+        );
+        assert_eq!(parse_address("tcp:host=localhost,port=1"), None);
+    }
+}
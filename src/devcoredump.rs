@@ -0,0 +1,73 @@
+//! Device coredumps from `/sys/class/devcoredump`, which amdgpu writes
+//! when it resets a hung GPU: the rings, their last fences and the IP
+//! blocks' registers at the time. The kernel drops each one after five
+//! minutes, so a hang during a long session is easy to lose.
+
+use crate::{privileges, sysroot};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A dump the kernel still has for one of our devices.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Coredump {
+    /// `devcd1` and up, counting every dump since boot.
+    pub name: String,
+    /// Where we copied it, once we have.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub saved_to: Option<PathBuf>,
+    /// Why copying it failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Every dump waiting, with the PCI slot of the device that failed.
+pub fn list() -> Vec<(String, String)> {
+    let entries = match std::fs::read_dir(sysroot::path("/sys/class/devcoredump")) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut dumps = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let target = std::fs::read_link(entry.path().join("failing_device")).ok()?;
+            let slot = target.file_name()?.to_string_lossy().into_owned();
+            Some((entry.file_name().to_string_lossy().into_owned(), slot))
+        })
+        .collect::<Vec<_>>();
+    dumps.sort();
+    dumps
+}
+
+/// Copies dump `name` of the device in `slot` into `dir`, as
+/// `amdgpu-<slot>-<unix time>-<name>.devcoredump`. The dump stays where it
+/// is; the kernel frees it on time.
+pub fn save(name: &str, slot: &str, dir: &Path) -> io::Result<PathBuf> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("amdgpu-{}-{}-{}.devcoredump", slot, time, name));
+    let mut data = File::open(
+        sysroot::path("/sys/class/devcoredump")
+            .join(name)
+            .join("data"),
+    )
+    .map_err(|err| {
+        if err.kind() == io::ErrorKind::PermissionDenied && privileges::dropped() {
+            io::Error::new(
+                err.kind(),
+                "only root can read coredumps; start with --save-coredumps DIR to stay root",
+            )
+        } else {
+            err
+        }
+    })?;
+    io::copy(&mut data, &mut File::create(&path)?)?;
+    Ok(path)
+}
@@ -0,0 +1,333 @@
+//! `amdtop doctor`: checks what amdtop needs from the system, one thing at
+//! a time, and says how to fix what's missing. Most reports of amdtop
+//! showing nothing, or less than expected, come down to one of these.
+
+use crate::{
+    cli::{GlobalArgs, OutputFormat},
+    error, output,
+    sensors::hwmon_dir,
+    source::{self, Device, SourceConfig, SourceKind},
+    sysroot,
+};
+use serde::Serialize;
+use std::io::{self, Write};
+
+/// Where amdgpu's fdinfo started to carry memory, and engine time.
+const FDINFO_MEMORY: (u32, u32) = (5, 14);
+const FDINFO_ENGINES: (u32, u32) = (5, 19);
+
+#[derive(Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    /// Missing, but only matters on some machines.
+    Note,
+    /// Missing, and amdtop shows less for it.
+    Warning,
+    /// Missing, and amdtop can't work without it.
+    Failure,
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Note => "note",
+            Status::Warning => "warn",
+            Status::Failure => "FAIL",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Finding {
+    pub check: String,
+    pub status: Status,
+    pub detail: String,
+    /// What to do about it.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub advice: Vec<String>,
+}
+
+impl Finding {
+    fn new(check: impl Into<String>, status: Status, detail: impl Into<String>) -> Self {
+        Finding {
+            check: check.into(),
+            status,
+            detail: detail.into(),
+            advice: Vec::new(),
+        }
+    }
+
+    fn advise(mut self, advice: impl Into<String>) -> Self {
+        self.advice.push(advice.into());
+        self
+    }
+
+    /// A source's probe failing: its first line says what's wrong, and the
+    /// lines after it, where there are any, what to do.
+    fn from_error(check: impl Into<String>, status: Status, err: &error::Error) -> Self {
+        let message = err.to_string();
+        let mut lines = message.lines();
+        let mut finding = Finding::new(check, status, lines.next().unwrap_or_default());
+        finding.advice = lines.map(str::to_string).collect();
+        finding
+    }
+}
+
+/// `6.6.0-arch1` as `(6, 6)`.
+pub fn kernel_version(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+fn check_kernel() -> Finding {
+    let release = match sysroot::kernel_release() {
+        Some(release) => release,
+        None => {
+            return Finding::new("kernel", Status::Warning, "can't read its version")
+                .advise("/proc/sys/kernel/osrelease is missing; is /proc mounted?")
+        }
+    };
+    match kernel_version(&release) {
+        Some(version) if version < FDINFO_MEMORY => Finding::new(
+            "kernel",
+            Status::Warning,
+            format!("{}, older than 5.14", release),
+        )
+        .advise("its fdinfo has no memory, so only debugfs, as root, shows processes"),
+        Some(version) if version < FDINFO_ENGINES => Finding::new(
+            "kernel",
+            Status::Note,
+            format!("{}, older than 5.19", release),
+        )
+        .advise("its fdinfo has no engine time, so --show-busy needs a newer kernel"),
+        _ => Finding::new("kernel", Status::Ok, release),
+    }
+}
+
+fn check_devices(devices: &[Device]) -> Finding {
+    if devices.is_empty() {
+        return Finding::new("devices", Status::Failure, "no amdgpu devices found")
+            .advise("is the amdgpu driver loaded? check with `lsmod | grep amdgpu`")
+            .advise("a GPU passed to a VM is bound to vfio-pci instead, and can't be seen");
+    }
+    let names = devices
+        .iter()
+        .map(|device| match device.pci_slot() {
+            Some(slot) => format!("{} ({})", device, slot),
+            None => device.to_string(),
+        })
+        .collect::<Vec<_>>();
+    Finding::new("devices", Status::Ok, names.join(", "))
+}
+
+/// What to know about a source that failed without saying more.
+fn hint(kind: SourceKind) -> &'static str {
+    match kind {
+        SourceKind::Debugfs => "mount debugfs and run as root, or try `--source fdinfo`",
+        SourceKind::Fdinfo => "reading /proc failed; is it mounted?",
+        SourceKind::Kfd => "/sys/class/kfd is only there with the compute driver ROCm uses",
+        SourceKind::Sysfs => "the device has no mem_info_* files; is amdgpu too old?",
+        SourceKind::Ioctl => "opening the render node failed; are you in the render group?",
+    }
+}
+
+/// The sources as configured, but only probed: checking mustn't switch us
+/// to the user or start a helper, or change what's checked after.
+fn report_config(global: &GlobalArgs) -> SourceConfig {
+    SourceConfig {
+        report_only: true,
+        ..global.source_config()
+    }
+}
+
+/// How each source fares, and which amdtop would pick.
+fn check_sources(global: &GlobalArgs) -> Vec<Finding> {
+    let config = report_config(global);
+    let probe = |kinds: &[SourceKind]| {
+        kinds
+            .iter()
+            .map(|kind| (*kind, source::probe(*kind, &config)))
+            .collect::<Vec<_>>()
+    };
+    let per_process = probe(SourceKind::PER_PROCESS);
+    let device = probe(SourceKind::DEVICE);
+    let first = |probes: &[(SourceKind, error::Result<()>)]| {
+        probes
+            .iter()
+            .find(|(_, result)| result.is_ok())
+            .map(|(kind, _)| *kind)
+    };
+    let (per_process_pick, device_pick) = (first(&per_process), first(&device));
+
+    let mut findings = Vec::new();
+    for (kind, result) in per_process.iter().chain(&device) {
+        let finding = match result {
+            Ok(()) if Some(*kind) == per_process_pick || Some(*kind) == device_pick => {
+                Finding::new(kind.to_string(), Status::Ok, "works, and is used")
+            }
+            Ok(()) => Finding::new(kind.to_string(), Status::Ok, "works"),
+            Err(err) => {
+                let status = match kind {
+                    // Only there with ROCm's compute driver.
+                    SourceKind::Kfd => Status::Note,
+                    _ if SourceKind::PER_PROCESS.contains(kind) && per_process_pick.is_none() => {
+                        Status::Failure
+                    }
+                    _ if SourceKind::DEVICE.contains(kind) && device_pick.is_none() => {
+                        Status::Failure
+                    }
+                    // What the better source shows, a worse one may not.
+                    SourceKind::Debugfs => Status::Warning,
+                    _ => Status::Note,
+                };
+                let mut finding = Finding::from_error(kind.to_string(), status, err);
+                if finding.advice.is_empty() {
+                    finding.advice.push(hint(*kind).to_string());
+                }
+                finding
+            }
+        };
+        findings.push(finding);
+    }
+    if let Some(kind) = per_process_pick.filter(|kind| *kind != SourceKind::Debugfs) {
+        let name = kind.to_string();
+        let finding = findings
+            .iter_mut()
+            .find(|finding| finding.check == name)
+            .expect("probed");
+        finding.advice.push(format!(
+            "without debugfs, {} only shows the processes it's allowed to inspect",
+            kind
+        ));
+    }
+    findings
+}
+
+fn check_hwmon(device: Device) -> Finding {
+    let check = format!("hwmon {}", device);
+    match hwmon_dir(device) {
+        Some(dir) => Finding::new(check, Status::Ok, dir.display().to_string()),
+        None => Finding::new(check, Status::Warning, "no hwmon directory")
+            .advise("sensors shows nothing for it; a virtual function usually has none"),
+    }
+}
+
+pub fn diagnose(global: &GlobalArgs) -> Vec<Finding> {
+    let devices = Device::list();
+    let mut findings = vec![check_kernel(), check_devices(&devices)];
+    findings.extend(check_sources(global));
+    findings.extend(devices.into_iter().map(check_hwmon));
+    findings
+}
+
+fn write_table<W: Write>(out: &mut W, findings: &[Finding]) -> io::Result<()> {
+    for finding in findings {
+        writeln!(
+            out,
+            "{: <4} | {: <12} | {}",
+            finding.status.label(),
+            finding.check,
+            finding.detail
+        )?;
+        for advice in &finding.advice {
+            writeln!(out, "{: <4} | {: <12} | {}", "", "", advice)?;
+        }
+    }
+    let problems = findings
+        .iter()
+        .filter(|finding| matches!(finding.status, Status::Warning | Status::Failure))
+        .count();
+    match problems {
+        0 => writeln!(out, "Nothing's missing."),
+        1 => writeln!(out, "1 problem, see above."),
+        count => writeln!(out, "{} problems, see above.", count),
+    }
+}
+
+/// amdgpu's module version, which only out-of-tree builds have, or
+/// whether it's there at all.
+fn driver_status(devices: &[Device]) -> String {
+    let module = sysroot::path("/sys/module/amdgpu");
+    match std::fs::read_to_string(module.join("version")) {
+        Ok(version) => format!("amdgpu {}", version.trim()),
+        Err(_) if module.is_dir() || !devices.is_empty() => "amdgpu loaded".to_string(),
+        Err(_) => "amdgpu not loaded".to_string(),
+    }
+}
+
+/// `--version`: ours, and the environment bug reports always end up
+/// asking about.
+pub fn write_version<W: Write>(out: &mut W, global: &GlobalArgs) -> io::Result<()> {
+    writeln!(out, "amdtop {}", env!("CARGO_PKG_VERSION"))?;
+    let release = sysroot::kernel_release();
+    writeln!(out, "kernel: {}", release.as_deref().unwrap_or("unknown"))?;
+    let devices = Device::list();
+    let names = devices
+        .iter()
+        .map(|device| match device.pci_slot() {
+            Some(slot) => format!("{} ({})", device, slot),
+            None => device.to_string(),
+        })
+        .collect::<Vec<_>>();
+    let names = if names.is_empty() {
+        "no devices".to_string()
+    } else {
+        names.join(", ")
+    };
+    writeln!(out, "driver: {}, {}", driver_status(&devices), names)?;
+    let config = report_config(global);
+    let (working, failing): (Vec<_>, Vec<_>) = SourceKind::PER_PROCESS
+        .iter()
+        .chain(SourceKind::DEVICE)
+        .partition(|kind| source::probe(**kind, &config).is_ok());
+    let names = |kinds: &[&SourceKind]| {
+        kinds
+            .iter()
+            .map(|kind| kind.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if failing.is_empty() {
+        writeln!(out, "sources: {}", names(&working))
+    } else if working.is_empty() {
+        writeln!(
+            out,
+            "sources: none working ({} unavailable)",
+            names(&failing)
+        )
+    } else {
+        writeln!(
+            out,
+            "sources: {} ({} unavailable)",
+            names(&working),
+            names(&failing)
+        )
+    }
+}
+
+pub fn run(global: &GlobalArgs) -> error::Result<()> {
+    let findings = diagnose(global);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match global.output {
+        OutputFormat::Table => write_table(&mut out, &findings)?,
+        OutputFormat::Json => output::write_json(&mut out, &findings)?,
+        format => return Err(output::unsupported(format, "doctor")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_kernel_versions() {
+        assert_eq!(kernel_version("6.6.0-arch1-1"), Some((6, 6)));
+        assert_eq!(kernel_version("5.4.0-150-generic"), Some((5, 4)));
+        assert_eq!(kernel_version("6"), None);
+        assert!(kernel_version("5.15.0").unwrap() < FDINFO_ENGINES);
+    }
+}
@@ -0,0 +1,134 @@
+//! `--enrich`: a command of the user's own that adds fields to processes,
+//! like the scheduler job or the tenant they belong to, without amdtop
+//! knowing about either. It gets each refresh as JSON on stdin,
+//! `{"devices": [...]}` like `--output json`, and answers on stdout with an
+//! object of fields per pid: `{"3301": {"job": "slurm-4242"}}`.
+
+use crate::mem::DeviceView;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+/// How long the command may take before the refresh goes on without it.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Runs `command` with `sh -c` on `views`, and returns what it printed.
+fn ask(command: &str, views: &[DeviceView]) -> Result<Vec<u8>, String> {
+    let input = serde_json::to_vec(&serde_json::json!({ "devices": views }))
+        .map_err(|err| err.to_string())?;
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("can't run it: {}", err))?;
+
+    // In threads of their own, so a command that reads or writes more than
+    // a pipe holds can't keep us waiting on each other.
+    let mut stdin = child.stdin.take().expect("piped");
+    std::thread::spawn(move || {
+        let _ = stdin.write_all(&input);
+    });
+    let mut stdout = child.stdout.take().expect("piped");
+    let reader = std::thread::spawn(move || {
+        let mut output = Vec::new();
+        stdout.read_to_end(&mut output).map(|_| output)
+    });
+
+    let deadline = Instant::now() + TIMEOUT;
+    let status = loop {
+        if let Some(status) = child.try_wait().map_err(|err| err.to_string())? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(format!("still running after {}s", TIMEOUT.as_secs()));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+    if !status.success() {
+        return Err(format!("exited with {}", status));
+    }
+    reader
+        .join()
+        .map_err(|_| "reading its output failed".to_string())?
+        .map_err(|err| err.to_string())
+}
+
+/// The fields `output` gives each pid. Strings are taken as they are and
+/// numbers and booleans as JSON writes them; anything else is left out.
+fn parse(output: &[u8]) -> Result<BTreeMap<i32, BTreeMap<String, String>>, String> {
+    if output.trim_ascii().is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let fields = match serde_json::from_slice::<Value>(output) {
+        Ok(Value::Object(fields)) => fields,
+        Ok(_) => return Err("expected an object of fields per pid".to_string()),
+        Err(err) => return Err(format!("its output isn't JSON: {}", err)),
+    };
+    Ok(fields
+        .into_iter()
+        .filter_map(|(pid, fields)| {
+            let pid = pid.parse().ok()?;
+            let fields = match fields {
+                Value::Object(fields) => fields,
+                _ => return None,
+            };
+            let fields = fields
+                .into_iter()
+                .filter_map(|(name, value)| match value {
+                    Value::String(value) => Some((name, value)),
+                    Value::Number(_) | Value::Bool(_) => Some((name, value.to_string())),
+                    _ => None,
+                })
+                .collect();
+            Some((pid, fields))
+        })
+        .collect())
+}
+
+/// Adds what `command` says about each process to `views`.
+pub fn enrich(command: &str, views: &mut [DeviceView]) -> Result<(), String> {
+    let fields = parse(&ask(command, views)?)?;
+    for view in views {
+        let mut names = BTreeSet::new();
+        for process in view.processes.iter_mut().flatten() {
+            if let Some(extra) = fields.get(&process.pid) {
+                names.extend(extra.keys().cloned());
+                process.extra = extra.clone();
+            }
+        }
+        view.extra_columns = names.into_iter().collect();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_fields_per_pid() {
+        let fields = parse(
+            br#"{"3301": {"job": "slurm-4242", "gpus": 2, "nested": {}}, "self": {"job": "x"}}"#,
+        )
+        .unwrap();
+        assert_eq!(fields.len(), 1);
+        assert_eq!(
+            fields[&3301],
+            BTreeMap::from([
+                ("gpus".to_string(), "2".to_string()),
+                ("job".to_string(), "slurm-4242".to_string()),
+            ])
+        );
+        assert!(parse(b"\n").unwrap().is_empty());
+        assert!(parse(b"[]").is_err());
+        assert!(parse(b"job=4242").is_err());
+    }
+}
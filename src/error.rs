@@ -0,0 +1,72 @@
+//! Errors that end the program, each with its own exit status so scripts can
+//! tell them apart.
+
+use std::io;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// No amdgpu device to look at, or the driver isn't loaded.
+    #[error("{0}")]
+    NoDevice(String),
+    /// We're not allowed to read what we need.
+    #[error("{0}")]
+    PermissionDenied(String),
+    /// Something we read didn't look the way we expected.
+    #[error("{0}")]
+    Parse(String),
+    /// The kernel is too old, or built without what we need.
+    #[error("{0}")]
+    UnsupportedKernel(String),
+    /// `amdtop check` found something over its limit.
+    #[error("{0}")]
+    LimitExceeded(String),
+    /// An argument clap couldn't check by itself, like a value outside what
+    /// the device allows.
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Exit status to report this error with. 2 is shared with clap's usage
+    /// errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => 1,
+            Error::NoDevice(_) => 3,
+            Error::PermissionDenied(_) => 4,
+            Error::Parse(_) => 5,
+            Error::UnsupportedKernel(_) => 6,
+            Error::LimitExceeded(_) => 7,
+            Error::InvalidArgument(_) => 2,
+        }
+    }
+
+    /// Name used to pass the error across the helper pipe.
+    pub fn kind_name(&self) -> &'static str {
+        match self {
+            Error::Io(_) => "io",
+            Error::NoDevice(_) => "no-device",
+            Error::PermissionDenied(_) => "permission-denied",
+            Error::Parse(_) => "parse",
+            Error::UnsupportedKernel(_) => "unsupported-kernel",
+            Error::LimitExceeded(_) => "limit-exceeded",
+            Error::InvalidArgument(_) => "invalid-argument",
+        }
+    }
+
+    pub fn from_kind_name(kind: &str, message: String) -> Self {
+        match kind {
+            "no-device" => Error::NoDevice(message),
+            "permission-denied" => Error::PermissionDenied(message),
+            "parse" => Error::Parse(message),
+            "unsupported-kernel" => Error::UnsupportedKernel(message),
+            "limit-exceeded" => Error::LimitExceeded(message),
+            "invalid-argument" => Error::InvalidArgument(message),
+            _ => Error::Io(io::Error::other(message)),
+        }
+    }
+}
@@ -0,0 +1,178 @@
+//! What happened between refreshes of `top`, for its event log: processes
+//! appearing and exiting, VRAM filling past `VRAM_HIGH` and back, and GPU
+//! resets, each with the time it was noticed. So after looking away for a
+//! while, what went on can still be pieced together.
+
+use crate::{mem::DeviceView, output, FormatBytes};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How much of a device's VRAM in use makes an event.
+const VRAM_HIGH: f64 = 0.9;
+/// How many events are kept.
+const EVENTS: usize = 1000;
+
+/// What's compared between refreshes for one device.
+#[derive(Default)]
+struct DeviceState {
+    /// The name of every process still running.
+    processes: BTreeMap<i32, String>,
+    /// VRAM used and total, when the device says.
+    vram: Option<(u64, u64)>,
+    coredumps: BTreeSet<String>,
+    /// The kernel logged a reset since the last refresh.
+    reset_logged: bool,
+}
+
+impl DeviceState {
+    fn vram_high(&self) -> bool {
+        self.vram
+            .is_some_and(|(used, total)| used as f64 >= total as f64 * VRAM_HIGH)
+    }
+}
+
+#[derive(Default)]
+pub struct EventLog {
+    states: HashMap<String, DeviceState>,
+    events: VecDeque<String>,
+}
+
+impl EventLog {
+    /// Compares `views` with the last refresh. `kernel_lines` are what the
+    /// kernel log gained since, which is where resets are announced.
+    pub fn update(&mut self, views: &[DeviceView], kernel_lines: &[String]) {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let time = output::local_time(since_epoch);
+        for view in views {
+            let slot = view.device.pci_slot();
+            let state = DeviceState {
+                processes: view
+                    .processes
+                    .iter()
+                    .flatten()
+                    .filter(|process| !process.exited)
+                    .map(|process| {
+                        let name = process.name.as_deref().unwrap_or("unknown");
+                        (process.pid, name.to_string())
+                    })
+                    .collect(),
+                vram: view
+                    .usage
+                    .map(|usage| (usage.vram_used_bytes, usage.vram_total_bytes)),
+                coredumps: view
+                    .coredumps
+                    .iter()
+                    .map(|coredump| coredump.name.clone())
+                    .collect(),
+                reset_logged: kernel_lines.iter().any(|line| {
+                    line.contains("GPU reset begin")
+                        && slot.as_deref().is_none_or(|slot| line.contains(slot))
+                }),
+            };
+            self.observe(&time, &view.device.to_string(), state);
+        }
+    }
+
+    /// Logs how `state` differs from the device's last one. There's nothing
+    /// to compare the first one with.
+    fn observe(&mut self, time: &str, device: &str, state: DeviceState) {
+        let previous = match self.states.insert(device.to_string(), state) {
+            Some(previous) => previous,
+            None => return,
+        };
+        let state = &self.states[device];
+        let mut events = Vec::new();
+        for (pid, name) in &state.processes {
+            if !previous.processes.contains_key(pid) {
+                events.push(format!("{} ({}) appeared", pid, name));
+            }
+        }
+        for (pid, name) in &previous.processes {
+            if !state.processes.contains_key(pid) {
+                events.push(format!("{} ({}) exited", pid, name));
+            }
+        }
+        if let Some((used, total)) = state.vram {
+            if state.vram_high() && !previous.vram_high() {
+                events.push(format!(
+                    "VRAM over {:.0}%: {} of {}",
+                    VRAM_HIGH * 100.0,
+                    FormatBytes::new(used),
+                    FormatBytes::new(total)
+                ));
+            } else if !state.vram_high() && previous.vram_high() {
+                events.push(format!(
+                    "VRAM back under {:.0}%: {} of {}",
+                    VRAM_HIGH * 100.0,
+                    FormatBytes::new(used),
+                    FormatBytes::new(total)
+                ));
+            }
+        }
+        if state.reset_logged {
+            events.push("GPU reset, says the kernel log".to_string());
+        }
+        for name in state.coredumps.difference(&previous.coredumps) {
+            events.push(format!("GPU reset, leaving devcoredump {}", name));
+        }
+
+        for event in events {
+            if self.events.len() == EVENTS {
+                self.events.pop_front();
+            }
+            self.events
+                .push_back(format!("{} | {} | {}", time, device, event));
+        }
+    }
+
+    /// Every event kept, the oldest first.
+    pub fn events(&self) -> impl ExactSizeIterator<Item = &String> {
+        self.events.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn logs_what_changed() {
+        let mut log = EventLog::default();
+        let state = |processes: &[(i32, &str)], used: u64, coredumps: &[&str]| DeviceState {
+            processes: processes
+                .iter()
+                .map(|(pid, name)| (*pid, name.to_string()))
+                .collect(),
+            vram: Some((used << 20, 1000u64 << 20)),
+            coredumps: coredumps.iter().map(|name| name.to_string()).collect(),
+            reset_logged: false,
+        };
+        log.observe("10:00:00", "card0", state(&[(1523, "Xorg")], 100, &[]));
+        assert_eq!(log.events().len(), 0);
+        log.observe(
+            "10:00:01",
+            "card0",
+            state(&[(3301, "blender")], 950, &["devcd1"]),
+        );
+        log.observe(
+            "10:00:02",
+            "card0",
+            state(&[(3301, "blender")], 950, &["devcd1"]),
+        );
+        log.observe("10:00:03", "card0", state(&[(3301, "blender")], 500, &[]));
+        assert_eq!(
+            log.events().collect::<Vec<_>>(),
+            [
+                "10:00:01 | card0 | 3301 (blender) appeared",
+                "10:00:01 | card0 | 1523 (Xorg) exited",
+                "10:00:01 | card0 | VRAM over 90%: 950.00 MiB of 1000.00 MiB",
+                "10:00:01 | card0 | GPU reset, leaving devcoredump devcd1",
+                "10:00:03 | card0 | VRAM back under 90%: 500.00 MiB of 1000.00 MiB",
+            ]
+        );
+    }
+}
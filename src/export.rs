@@ -0,0 +1,605 @@
+//! `amdtop export`: a small HTTP server with Prometheus metrics on
+//! `/metrics` and a JSON snapshot of everything on `/snapshot`. Each request
+//! takes a fresh sample. `/stream` is a WebSocket that gets a new snapshot
+//! every `--delay`, for dashboards in a browser. With `--mqtt` samples are
+//! published to a broker as well.
+
+use crate::{
+    cli::{ExportArgs, GlobalArgs, MemArgs, ProcessLabel},
+    error,
+    mem::{self, DeviceView, Session},
+    mqtt::Publisher,
+    sensors::Sensors,
+    source::Sources,
+    systemd, websocket, INTERRUPTED,
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+/// What `/snapshot` serves.
+#[derive(Serialize)]
+pub struct Snapshot<'a> {
+    pub devices: &'a [DeviceView],
+    pub sensors: &'a [Sensors],
+}
+
+/// Escapes a Prometheus label value.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Which text format `/metrics` is written in. OpenMetrics is what
+/// Prometheus asks for in `Accept` these days; anything else gets the
+/// classic format.
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Format {
+    Prometheus,
+    OpenMetrics,
+}
+
+impl Format {
+    fn from_accept(accept: Option<&str>) -> Self {
+        match accept {
+            Some(accept) if accept.contains("application/openmetrics-text") => Format::OpenMetrics,
+            _ => Format::Prometheus,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Prometheus => "text/plain; version=0.0.4",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum MetricType {
+    Gauge,
+    /// Only ever goes up, though per-process ones start over when the
+    /// process does.
+    Counter,
+}
+
+/// A metric family. `name` ends in `_<unit>` when there is one, and never in
+/// `_total`, which counter samples get added.
+struct Family<'a> {
+    name: &'a str,
+    kind: MetricType,
+    unit: &'a str,
+    help: &'a str,
+}
+
+impl<'a> Family<'a> {
+    fn gauge(name: &'a str, unit: &'a str, help: &'a str) -> Self {
+        Family {
+            name,
+            kind: MetricType::Gauge,
+            unit,
+            help,
+        }
+    }
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+/// Writes one metric family.
+fn write_family<I>(out: &mut String, format: Format, family: &Family, samples: I)
+where
+    I: IntoIterator<Item = (Labels, f64)>,
+{
+    let (kind, suffix) = match family.kind {
+        MetricType::Gauge => ("gauge", ""),
+        MetricType::Counter => ("counter", "_total"),
+    };
+    // The classic format names counter families after their samples.
+    let name = match format {
+        Format::Prometheus => format!("{}{}", family.name, suffix),
+        Format::OpenMetrics => family.name.to_string(),
+    };
+    let _ = writeln!(out, "# HELP {} {}", name, family.help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    if format == Format::OpenMetrics && !family.unit.is_empty() {
+        let _ = writeln!(out, "# UNIT {} {}", name, family.unit);
+    }
+    for (labels, value) in samples {
+        let labels = labels
+            .iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let _ = writeln!(out, "{}{}{{{}}} {}", family.name, suffix, labels, value);
+    }
+}
+
+/// Per-process usage under one set of labels, after `--process-labels` and
+/// `--max-process-series` have had their way.
+#[derive(Default)]
+struct ProcessSeries {
+    labels: Labels,
+    vram_bytes: u64,
+    gtt_bytes: u64,
+    engine_ns: BTreeMap<String, u64>,
+    /// Whether this sums up the processes that didn't get a series.
+    other: bool,
+}
+
+fn process_series(view: &DeviceView, options: &ExportArgs) -> Vec<ProcessSeries> {
+    let mut series = Vec::<ProcessSeries>::new();
+    for process in view.processes.iter().flatten() {
+        let mut labels = vec![("device", view.device.to_string())];
+        for label in &options.process_labels {
+            labels.push(match label {
+                ProcessLabel::Pid => ("pid", process.pid.to_string()),
+                ProcessLabel::Name => (
+                    "name",
+                    process
+                        .name
+                        .clone()
+                        .unwrap_or_else(|| "unknown".to_string()),
+                ),
+                ProcessLabel::Unit => ("unit", process.unit.clone().unwrap_or_default()),
+            });
+        }
+        let index = match series.iter().position(|series| series.labels == labels) {
+            Some(index) => index,
+            None => {
+                series.push(ProcessSeries {
+                    labels,
+                    ..ProcessSeries::default()
+                });
+                series.len() - 1
+            }
+        };
+        let series = &mut series[index];
+        series.vram_bytes += process.vram_bytes;
+        series.gtt_bytes += process.gtt_bytes;
+        for (engine, ns) in &process.engine_ns {
+            *series.engine_ns.entry(engine.clone()).or_default() += ns;
+        }
+    }
+
+    series.sort_by_key(|series| std::cmp::Reverse(series.vram_bytes + series.gtt_bytes));
+    if series.len() > options.max_process_series {
+        let rest = series.split_off(options.max_process_series);
+        let mut other = ProcessSeries {
+            labels: rest[0]
+                .labels
+                .iter()
+                .map(|(key, value)| match *key {
+                    "device" => (*key, value.clone()),
+                    _ => (*key, "other".to_string()),
+                })
+                .collect(),
+            other: true,
+            ..ProcessSeries::default()
+        };
+        for rest in rest {
+            other.vram_bytes += rest.vram_bytes;
+            other.gtt_bytes += rest.gtt_bytes;
+        }
+        series.push(other);
+    }
+    series
+}
+
+/// A sensor reading's family name suffix in base units, and how to get
+/// there from the unit it's read in.
+fn base_unit(unit: &'static str) -> (&'static str, f64) {
+    match unit {
+        "%" => ("ratio", 0.01),
+        "MHz" => ("hertz", 1e6),
+        "mV" => ("volts", 1e-3),
+        "°C" => ("celsius", 1.0),
+        "W" => ("watts", 1.0),
+        "RPM" => ("rpm", 1.0),
+        unit => (unit, 1.0),
+    }
+}
+
+fn metrics(
+    views: &[DeviceView],
+    sensors: &[Sensors],
+    options: &ExportArgs,
+    format: Format,
+) -> String {
+    let mut out = String::new();
+    let device = |view: &DeviceView| vec![("device", view.device.to_string())];
+
+    write_family(
+        &mut out,
+        format,
+        &Family::gauge(
+            "amdtop_vram_used_bytes",
+            "bytes",
+            "VRAM in use on the device.",
+        ),
+        views
+            .iter()
+            .filter_map(|view| Some((device(view), view.usage?.vram_used_bytes as f64))),
+    );
+    write_family(
+        &mut out,
+        format,
+        &Family::gauge("amdtop_vram_total_bytes", "bytes", "VRAM on the device."),
+        views
+            .iter()
+            .filter_map(|view| Some((device(view), view.usage?.vram_total_bytes as f64))),
+    );
+    write_family(
+        &mut out,
+        format,
+        &Family::gauge(
+            "amdtop_gtt_used_bytes",
+            "bytes",
+            "GTT in use on the device.",
+        ),
+        views
+            .iter()
+            .filter_map(|view| Some((device(view), view.usage?.gtt_used_bytes? as f64))),
+    );
+
+    let processes = views
+        .iter()
+        .flat_map(|view| process_series(view, options))
+        .collect::<Vec<_>>();
+    write_family(
+        &mut out,
+        format,
+        &Family::gauge(
+            "amdtop_process_vram_bytes",
+            "bytes",
+            "VRAM used by a process.",
+        ),
+        processes
+            .iter()
+            .map(|series| (series.labels.clone(), series.vram_bytes as f64)),
+    );
+    write_family(
+        &mut out,
+        format,
+        &Family::gauge(
+            "amdtop_process_gtt_bytes",
+            "bytes",
+            "GTT used by a process.",
+        ),
+        processes
+            .iter()
+            .map(|series| (series.labels.clone(), series.gtt_bytes as f64)),
+    );
+    // Which processes "other" sums up changes between scrapes, so it has no
+    // business in a counter.
+    write_family(
+        &mut out,
+        format,
+        &Family {
+            name: "amdtop_process_engine_seconds",
+            kind: MetricType::Counter,
+            unit: "seconds",
+            help: "Time a process has kept an engine busy.",
+        },
+        processes
+            .iter()
+            .filter(|series| !series.other)
+            .flat_map(|series| {
+                series.engine_ns.iter().map(move |(engine, ns)| {
+                    let mut labels = series.labels.clone();
+                    labels.push(("engine", engine.clone()));
+                    (labels, *ns as f64 / 1e9)
+                })
+            }),
+    );
+    write_family(
+        &mut out,
+        format,
+        &Family::gauge(
+            "amdtop_unattributed_vram_bytes",
+            "bytes",
+            "VRAM in use that no process accounts for.",
+        ),
+        views
+            .iter()
+            .filter_map(|view| Some((device(view), view.unattributed?.vram_bytes as f64))),
+    );
+
+    let readings = sensors.iter().flat_map(|sensors| {
+        sensors
+            .readings()
+            .into_iter()
+            .map(move |reading| (sensors.device, reading))
+    });
+    let mut families = Vec::<(&str, Vec<_>)>::new();
+    for (device, reading) in readings {
+        match families.iter_mut().find(|(key, _)| *key == reading.key) {
+            Some((_, samples)) => samples.push((device, reading)),
+            None => families.push((reading.key, vec![(device, reading)])),
+        }
+    }
+    for (key, samples) in families {
+        let (unit, scale) = base_unit(samples[0].1.unit);
+        write_family(
+            &mut out,
+            format,
+            &Family::gauge(
+                &format!("amdtop_{}_{}", key, unit),
+                unit,
+                &format!("{}.", samples[0].1.label),
+            ),
+            samples.iter().map(|(device, reading)| {
+                (vec![("device", device.to_string())], reading.value * scale)
+            }),
+        );
+    }
+
+    if format == Format::OpenMetrics {
+        out.push_str("# EOF\n");
+    }
+    out
+}
+
+/// Samples on demand, keeping track of peaks between requests.
+pub struct Exporter {
+    sources: Sources,
+    session: Session,
+}
+
+impl Exporter {
+    pub fn new(global: &GlobalArgs) -> error::Result<Self> {
+        Ok(Exporter {
+            sources: mem::select_sources(global, &MemArgs::default())?,
+            session: Session::default(),
+        })
+    }
+
+    /// A fresh sample of everything, as the JSON `/snapshot` serves.
+    pub fn snapshot(&mut self, global: &GlobalArgs) -> error::Result<String> {
+        let (views, sensors) = self.sample(global)?;
+        let snapshot = Snapshot {
+            devices: &views,
+            sensors: &sensors,
+        };
+        Ok(serde_json::to_string(&snapshot).unwrap_or_default())
+    }
+
+    fn sample(&mut self, global: &GlobalArgs) -> error::Result<(Vec<DeviceView>, Vec<Sensors>)> {
+        let views = mem::refresh(
+            global,
+            &MemArgs::default(),
+            &mut self.sources,
+            &mut self.session,
+        )?;
+        let sensors = views
+            .iter()
+            .map(|view| Sensors::read(view.device))
+            .collect();
+        Ok((views, sensors))
+    }
+
+    fn respond(
+        &mut self,
+        global: &GlobalArgs,
+        options: &ExportArgs,
+        path: &str,
+        format: Format,
+    ) -> (&'static str, &'static str, String) {
+        let path = path.split('?').next().unwrap_or_default();
+        if path == "/stream" {
+            return (
+                "426 Upgrade Required",
+                "text/plain",
+                "/stream is a WebSocket\n".to_string(),
+            );
+        }
+        if path != "/metrics" && path != "/snapshot" {
+            return (
+                "404 Not Found",
+                "text/plain",
+                "try /metrics, /snapshot or /stream\n".to_string(),
+            );
+        }
+
+        let response = if path == "/metrics" {
+            self.sample(global).map(|(views, sensors)| {
+                (
+                    format.content_type(),
+                    metrics(&views, &sensors, options, format),
+                )
+            })
+        } else {
+            self.snapshot(global)
+                .map(|snapshot| ("application/json", snapshot))
+        };
+        match response {
+            Ok((content_type, body)) => ("200 OK", content_type, body),
+            Err(err) => (
+                "500 Internal Server Error",
+                "text/plain",
+                format!("{}\n", err),
+            ),
+        }
+    }
+
+    /// Answers one request, or hands the connection back if it became a
+    /// WebSocket.
+    fn handle(
+        &mut self,
+        global: &GlobalArgs,
+        options: &ExportArgs,
+        stream: TcpStream,
+    ) -> io::Result<Option<TcpStream>> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let mut reader = BufReader::new(&stream);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        let mut websocket_key = None;
+        let mut accept = None;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                let name = name.trim();
+                if name.eq_ignore_ascii_case("sec-websocket-key") {
+                    websocket_key = Some(value.trim().to_string());
+                } else if name.eq_ignore_ascii_case("accept") {
+                    accept = Some(value.trim().to_string());
+                }
+            }
+        }
+
+        let mut fields = request.split_whitespace();
+        let method_and_path = (fields.next(), fields.next());
+        if let ((Some("GET"), Some(path)), Some(key)) = (method_and_path, &websocket_key) {
+            if path.split('?').next() == Some("/stream") {
+                websocket::write_handshake(&mut &stream, key)?;
+                stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+                return Ok(Some(stream));
+            }
+        }
+        let (status, content_type, body) = match method_and_path {
+            (Some("GET"), Some(path)) => self.respond(
+                global,
+                options,
+                path,
+                Format::from_accept(accept.as_deref()),
+            ),
+            _ => (
+                "405 Method Not Allowed",
+                "text/plain",
+                "only GET is supported\n".to_string(),
+            ),
+        };
+
+        let mut stream = &stream;
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )?;
+        stream.flush()?;
+        Ok(None)
+    }
+
+    /// Sends a fresh snapshot down every stream, dropping those that closed.
+    fn push(&mut self, global: &GlobalArgs, streams: &mut Vec<TcpStream>) {
+        streams.retain(|stream| !closed(stream));
+        if streams.is_empty() {
+            return;
+        }
+        let snapshot = match self.snapshot(global) {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                eprintln!("amdtop: can't sample for /stream: {}", err);
+                return;
+            }
+        };
+        streams.retain(|mut stream| websocket::write_text(&mut stream, &snapshot).is_ok());
+    }
+}
+
+/// Whether the browser went away or asked to close its stream.
+fn closed(stream: &TcpStream) -> bool {
+    let mut bytes = [0; 256];
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+    let result = (&mut &*stream).read(&mut bytes);
+    let _ = stream.set_nonblocking(false);
+    match result {
+        Ok(0) => true,
+        Ok(length) => websocket::is_close(&bytes[..length]),
+        Err(err) => err.kind() != io::ErrorKind::WouldBlock,
+    }
+}
+
+pub fn run(global: &GlobalArgs, options: &ExportArgs) -> error::Result<()> {
+    let mut exporter = Exporter::new(global)?;
+    let delay = global.delay.unwrap_or(Duration::from_secs(1));
+    let mut publisher = options
+        .mqtt
+        .as_deref()
+        .map(|url| Publisher::new(url, &options.mqtt_topic, delay))
+        .transpose()?;
+
+    let listener = TcpListener::bind(&options.listen).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("can't listen on {}: {}", options.listen, err),
+        )
+    })?;
+    // Poll so Ctrl-C gets noticed between requests.
+    listener.set_nonblocking(true)?;
+    eprintln!(
+        "amdtop: serving http://{}/metrics, /snapshot and /stream",
+        listener.local_addr()?
+    );
+
+    let mut streams = Vec::new();
+    let mut next_push = Instant::now();
+    let mut next_publish = Instant::now();
+    crate::catch_interrupts();
+    systemd::notify("READY=1");
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        systemd::keep_alive();
+        match listener.accept() {
+            Ok((stream, _)) => match exporter.handle(global, options, stream) {
+                Ok(Some(stream)) => {
+                    // New viewers shouldn't wait a whole delay for data.
+                    streams.push(stream);
+                    next_push = Instant::now();
+                }
+                Ok(None) => {}
+                Err(err) => eprintln!("amdtop: request failed: {}", err),
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(err) => return Err(err.into()),
+        }
+        if !streams.is_empty() && Instant::now() >= next_push {
+            exporter.push(global, &mut streams);
+            next_push = Instant::now() + delay;
+        }
+        if let Some(publisher) = publisher
+            .as_mut()
+            .filter(|_| Instant::now() >= next_publish)
+        {
+            let published = exporter
+                .sample(global)
+                .and_then(|(views, sensors)| publisher.publish(&views, &sensors));
+            if let Err(err) = published {
+                eprintln!("amdtop: can't publish to MQTT: {}", err);
+            }
+            next_publish = Instant::now() + delay;
+        }
+    }
+    systemd::notify("STOPPING=1");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
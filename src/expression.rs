@@ -0,0 +1,224 @@
+//! Arithmetic over named fields, for the columns `--column` adds:
+//! numbers, names like `vram` or `device.vram_total`, `+ - * /` and
+//! parentheses, with the usual precedence.
+
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expression {
+    Number(f64),
+    Field(String),
+    Negate(Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Operator(Operator),
+    Open,
+    Close,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '+' => Token::Operator(Operator::Add),
+            '-' => Token::Operator(Operator::Subtract),
+            '*' => Token::Operator(Operator::Multiply),
+            '/' => Token::Operator(Operator::Divide),
+            '(' => Token::Open,
+            ')' => Token::Close,
+            c if c.is_ascii_digit() || c == '.' || c.is_ascii_alphabetic() || c == '_' => {
+                let mut end = start;
+                while let Some(&(at, c)) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_' || c == '.') {
+                        break;
+                    }
+                    end = at + c.len_utf8();
+                    chars.next();
+                }
+                let word = &source[start..end];
+                tokens.push(if c.is_ascii_digit() || c == '.' {
+                    Token::Number(
+                        word.parse()
+                            .map_err(|_| format!("{} isn't a number", word))?,
+                    )
+                } else {
+                    Token::Name(word.to_string())
+                });
+                continue;
+            }
+            c => return Err(format!("unexpected {:?}", c)),
+        };
+        tokens.push(token);
+        chars.next();
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent over the tokens, one function per precedence level.
+struct Parser {
+    tokens: Vec<Token>,
+    at: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.at).cloned();
+        self.at += 1;
+        token
+    }
+
+    fn peek_operator(&self, operators: &[Operator]) -> Option<Operator> {
+        match self.tokens.get(self.at) {
+            Some(Token::Operator(operator)) if operators.contains(operator) => Some(*operator),
+            _ => None,
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expression, String> {
+        let mut left = self.product()?;
+        while let Some(operator) = self.peek_operator(&[Operator::Add, Operator::Subtract]) {
+            self.at += 1;
+            left = Expression::Binary(operator, Box::new(left), Box::new(self.product()?));
+        }
+        Ok(left)
+    }
+
+    fn product(&mut self) -> Result<Expression, String> {
+        let mut left = self.unary()?;
+        while let Some(operator) = self.peek_operator(&[Operator::Multiply, Operator::Divide]) {
+            self.at += 1;
+            left = Expression::Binary(operator, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        match self.next() {
+            Some(Token::Operator(Operator::Subtract)) => {
+                Ok(Expression::Negate(Box::new(self.unary()?)))
+            }
+            Some(Token::Number(number)) => Ok(Expression::Number(number)),
+            Some(Token::Name(name)) => Ok(Expression::Field(name)),
+            Some(Token::Open) => {
+                let inner = self.sum()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(inner),
+                    _ => Err("missing )".to_string()),
+                }
+            }
+            _ => Err("expected a number, a field or (".to_string()),
+        }
+    }
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Expression, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            at: 0,
+        };
+        let expression = parser.sum()?;
+        if parser.at < parser.tokens.len() {
+            return Err("expected an operator".to_string());
+        }
+        Ok(expression)
+    }
+
+    /// Every field the expression reads.
+    pub fn fields(&self) -> Vec<&str> {
+        match self {
+            Expression::Number(_) => Vec::new(),
+            Expression::Field(name) => vec![name],
+            Expression::Negate(inner) => inner.fields(),
+            Expression::Binary(_, left, right) => {
+                let mut fields = left.fields();
+                fields.extend(right.fields());
+                fields
+            }
+        }
+    }
+
+    /// The value, with `field` looking fields up. `None` when a field has
+    /// no value or there's a division by zero.
+    pub fn eval(&self, field: &dyn Fn(&str) -> Option<f64>) -> Option<f64> {
+        match self {
+            Expression::Number(number) => Some(*number),
+            Expression::Field(name) => field(name),
+            Expression::Negate(inner) => Some(-inner.eval(field)?),
+            Expression::Binary(operator, left, right) => {
+                let (left, right) = (left.eval(field)?, right.eval(field)?);
+                match operator {
+                    Operator::Add => Some(left + right),
+                    Operator::Subtract => Some(left - right),
+                    Operator::Multiply => Some(left * right),
+                    Operator::Divide => Some(left / right).filter(|_| right != 0.0),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expression::Number(number) => write!(f, "{}", number),
+            Expression::Field(name) => write!(f, "{}", name),
+            Expression::Negate(inner) => write!(f, "-{}", inner),
+            Expression::Binary(operator, left, right) => {
+                let operator = match operator {
+                    Operator::Add => '+',
+                    Operator::Subtract => '-',
+                    Operator::Multiply => '*',
+                    Operator::Divide => '/',
+                };
+                write!(f, "({} {} {})", left, operator, right)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_with_precedence() {
+        let expression = Expression::parse("vram / device.vram_total * 100").unwrap();
+        assert_eq!(expression.to_string(), "((vram / device.vram_total) * 100)");
+        assert_eq!(expression.fields(), ["vram", "device.vram_total"]);
+        let fields = |name: &str| match name {
+            "vram" => Some(512.0),
+            "device.vram_total" => Some(2048.0),
+            _ => None,
+        };
+        assert_eq!(expression.eval(&fields), Some(25.0));
+        assert_eq!(
+            Expression::parse("-(1 + 2) * 3 - 4").unwrap().eval(&fields),
+            Some(-13.0)
+        );
+        assert_eq!(Expression::parse("vram / 0").unwrap().eval(&fields), None);
+        assert_eq!(Expression::parse("rss + 1").unwrap().eval(&fields), None);
+        assert!(Expression::parse("vram +").is_err());
+        assert!(Expression::parse("(vram").is_err());
+        assert!(Expression::parse("vram vram").is_err());
+        assert!(Expression::parse("vram % 2").is_err());
+    }
+}
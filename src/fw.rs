@@ -0,0 +1,107 @@
+//! `amdtop fw`: firmware versions from `device/fw_version` in sysfs, plus
+//! the VBIOS version.
+
+use crate::{
+    cli::{GlobalArgs, OutputFormat},
+    error, output,
+    sensors::selected_devices,
+    source::Device,
+};
+use serde::Serialize;
+use std::io::{self, Write};
+
+#[derive(Serialize)]
+pub struct Firmware {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Serialize)]
+pub struct DeviceFirmware {
+    pub device: Device,
+    pub vbios: Option<String>,
+    pub firmware: Vec<Firmware>,
+}
+
+impl DeviceFirmware {
+    pub fn read(device: Device) -> Self {
+        let device_dir = device.sysfs_dir();
+        let read = |path| {
+            std::fs::read_to_string(path)
+                .ok()
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+
+        let mut firmware = std::fs::read_dir(device_dir.join("fw_version"))
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter_map(|entry| {
+                        let file_name = entry.file_name();
+                        let name = file_name.to_str()?.strip_suffix("_fw_version")?;
+                        Some(Firmware {
+                            name: name.to_string(),
+                            version: read(entry.path())?,
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        firmware.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Self {
+            device,
+            vbios: read(device_dir.join("vbios_version")),
+            firmware,
+        }
+    }
+}
+
+fn write_table<W: Write>(out: &mut W, device: &DeviceFirmware) -> io::Result<()> {
+    match &device.vbios {
+        Some(vbios) => writeln!(out, "{} | VBIOS {}", device.device, vbios)?,
+        None => writeln!(out, "{}", device.device)?,
+    }
+    writeln!(out, "{: <10} | VERSION", "FIRMWARE")?;
+    writeln!(out, "{:-^1$}", "", 25)?;
+    for firmware in &device.firmware {
+        writeln!(out, "{0: <10} | {1}", firmware.name, firmware.version)?;
+    }
+    Ok(())
+}
+
+pub fn run(global: &GlobalArgs) -> error::Result<()> {
+    let devices = selected_devices(global)?
+        .into_iter()
+        .map(DeviceFirmware::read)
+        .collect::<Vec<_>>();
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match global.output {
+        OutputFormat::Table => {
+            for device in &devices {
+                write_table(&mut out, device)?;
+            }
+        }
+        OutputFormat::Json => output::write_json(&mut out, &devices)?,
+        OutputFormat::Csv => {
+            output::write_csv_row(&mut out, &["device", "firmware", "version"])?;
+            for device in &devices {
+                let name = device.device.to_string();
+                if let Some(vbios) = &device.vbios {
+                    output::write_csv_row(&mut out, &[name.as_str(), "vbios", vbios])?;
+                }
+                for firmware in &device.firmware {
+                    output::write_csv_row(
+                        &mut out,
+                        &[name.as_str(), &firmware.name, &firmware.version],
+                    )?;
+                }
+            }
+        }
+        format => return Err(output::unsupported(format, "fw")),
+    }
+    Ok(())
+}
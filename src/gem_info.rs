@@ -0,0 +1,550 @@
+//! Parsing for `/sys/kernel/debug/dri/N/amdgpu_gem_info`.
+//!
+//! The file lists every DRM client as `pid <pid> command <comm>:` followed by
+//! one line per buffer object it holds. The object lines have changed shape
+//! over the years, so we probe which layout a device uses and parse with the
+//! matching adapter.
+
+use crate::error::{self, Error};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::BufRead,
+    path::PathBuf,
+};
+
+#[derive(Default, Copy, Clone)]
+pub struct MemInfo {
+    pub pid: i32,
+    pub gtt_bytes: u64,
+    pub vram_bytes: u64,
+    pub unknown_bytes: u64,
+    /// What of it is pinned, where the source can tell.
+    pub pinned: Option<Pinned>,
+}
+
+/// Memory the kernel has pinned in place, like scanout surfaces, so it can
+/// never be evicted to make room for something else.
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Pinned {
+    pub vram_bytes: u64,
+    pub gtt_bytes: u64,
+}
+
+impl Pinned {
+    pub fn add(&mut self, other: Pinned) {
+        self.vram_bytes += other.vram_bytes;
+        self.gtt_bytes += other.gtt_bytes;
+    }
+}
+
+/// A single buffer object line from `amdgpu_gem_info`.
+pub struct GemObject {
+    /// The client's pid, which may be one of its process's threads.
+    pub pid: i32,
+    /// The client's command name.
+    pub command: String,
+    /// The client's GEM handle for it, where the layout has one.
+    pub handle: Option<u32>,
+    pub bytes: u64,
+    /// `VRAM`, `GTT` or `CPU`.
+    pub memory_type: String,
+    /// Inode of the dma-buf this object was exported as or imported from.
+    pub dma_buf: Option<u64>,
+    /// For VRAM, whether it's in the part the CPU can map. Old kernels don't
+    /// say.
+    pub visible: Option<bool>,
+    /// The creation flags the kernel prints, like `NO_CPU_ACCESS`.
+    pub flags: Vec<String>,
+    /// Whether something holds a pin on it, so it can't be moved.
+    pub pinned: bool,
+}
+
+/// What the flags `amdgpu_bo_print_info` prints mean for where a buffer can
+/// go, as `AMDGPU_GEM_CREATE_*` documents them.
+const FLAG_MEANINGS: &[(&str, &str)] = &[
+    (
+        "CPU_ACCESS_REQUIRED",
+        "the CPU maps it, so in VRAM it has to fit in the visible part",
+    ),
+    (
+        "NO_CPU_ACCESS",
+        "never mapped by the CPU, so it can stay out of visible VRAM",
+    ),
+    ("CPU_GTT_USWC", "write-combined when in system memory"),
+    ("VRAM_CLEARED", "cleared when it was allocated"),
+    (
+        "VRAM_CONTIGUOUS",
+        "physically contiguous, so harder to place and to move",
+    ),
+    (
+        "VM_ALWAYS_VALID",
+        "always mapped in its process's GPU address space",
+    ),
+    (
+        "EXPLICIT_SYNC",
+        "synchronized by the application instead of implicitly",
+    ),
+];
+
+/// What a flag means, if it's one we know.
+pub fn flag_meaning(flag: &str) -> Option<&'static str> {
+    FLAG_MEANINGS
+        .iter()
+        .find(|(name, _)| *name == flag)
+        .map(|(_, meaning)| *meaning)
+}
+
+/// The object line layouts we know about.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GemInfoFormat {
+    /// Kernels before 5.13: `\t0x<id>: <size> byte <placement>`, with an
+    /// `@ 0x<offset>` after pinned objects on the oldest ones and bare
+    /// `imported`/`exported` markers for shared buffers.
+    Legacy,
+    /// 5.13 and later, printed by `amdgpu_bo_print_info`: indented by two
+    /// tabs, placements like `VRAM VISIBLE`, and shared buffers identified by
+    /// dma-buf inode (`exported as ino:<n>`).
+    BoPrintInfo,
+    /// Neither of the above; find the size and placement around the `byte`
+    /// label wherever it ends up.
+    Unknown,
+}
+
+impl Display for GemInfoFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GemInfoFormat::Legacy => "legacy (before 5.13)",
+            GemInfoFormat::BoPrintInfo => "amdgpu_bo_print_info (5.13+)",
+            GemInfoFormat::Unknown => "unknown",
+        }
+        .fmt(f)
+    }
+}
+
+impl GemInfoFormat {
+    /// Works out the layout from the first object line. Returns `None` when
+    /// there aren't any objects to go by.
+    pub fn detect(contents: &[u8]) -> Option<Self> {
+        let line = contents
+            .lines()
+            .map_while(Result::ok)
+            .find(|line| line.starts_with(char::is_whitespace) && !line.trim().is_empty())?;
+
+        let segments = line.split_whitespace().collect::<Vec<_>>();
+        let handle_first = segments
+            .first()
+            .and_then(|handle| handle.strip_suffix(':'))
+            .is_some_and(|handle| handle.starts_with("0x"));
+        let labelled = segments.get(2) == Some(&"byte");
+
+        Some(match (handle_first && labelled, line.starts_with("\t\t")) {
+            (true, true) => GemInfoFormat::BoPrintInfo,
+            (true, false) => GemInfoFormat::Legacy,
+            (false, _) => GemInfoFormat::Unknown,
+        })
+    }
+
+    fn parse_object(self, pid: i32, command: &str, line: &str) -> Option<GemObject> {
+        let segments = line.split_whitespace().collect::<Vec<_>>();
+
+        let (bytes, memory_type, rest) = match self {
+            GemInfoFormat::Legacy | GemInfoFormat::BoPrintInfo => {
+                if segments.get(2) != Some(&"byte") {
+                    return None;
+                }
+                (segments.get(1)?, segments.get(3)?, segments.get(4..)?)
+            }
+            GemInfoFormat::Unknown => {
+                let label = segments
+                    .iter()
+                    .position(|segment| *segment == "byte" || *segment == "bytes")?;
+                (
+                    segments.get(label.checked_sub(1)?)?,
+                    segments.get(label + 1)?,
+                    segments.get(label + 2..)?,
+                )
+            }
+        };
+
+        // Only newer kernels say which dma-buf a shared object belongs to.
+        let dma_buf = match self {
+            GemInfoFormat::Legacy => None,
+            GemInfoFormat::BoPrintInfo | GemInfoFormat::Unknown => rest
+                .iter()
+                .find_map(|segment| segment.strip_prefix("ino:")?.parse().ok()),
+        };
+
+        let handle = segments
+            .first()
+            .and_then(|handle| handle.strip_suffix(':')?.strip_prefix("0x"))
+            .and_then(|handle| u32::from_str_radix(handle, 16).ok());
+        // 5.13 and later print `VISIBLE` after VRAM when it's CPU visible.
+        let visible = match (self, *memory_type) {
+            (GemInfoFormat::BoPrintInfo, "VRAM") => Some(rest.first() == Some(&"VISIBLE")),
+            (GemInfoFormat::Unknown, "VRAM") => Some(true).filter(|_| rest.contains(&"VISIBLE")),
+            _ => None,
+        };
+        let flags = rest
+            .iter()
+            .filter(|segment| {
+                **segment != "VISIBLE"
+                    && segment.starts_with(|c: char| c.is_ascii_uppercase())
+                    && segment
+                        .chars()
+                        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+            })
+            .map(|segment| segment.to_string())
+            .collect();
+        // `pin count N` since 4.11; before that only pinned objects had
+        // their offset printed after an `@`.
+        let pinned = rest.windows(3).any(|words| {
+            words[..2] == ["pin", "count"] && words[2].parse::<u32>().is_ok_and(|count| count > 0)
+        }) || rest
+            .windows(2)
+            .any(|words| words[0] == "@" && words[1].starts_with("0x"));
+
+        Some(GemObject {
+            pid,
+            command: command.to_string(),
+            handle,
+            bytes: bytes.parse().ok()?,
+            memory_type: memory_type.to_string(),
+            dma_buf,
+            visible,
+            flags,
+            pinned,
+        })
+    }
+}
+
+/// Resolves a thread id to the id of its thread group, since clients opened
+/// from a thread are listed under the thread's id.
+pub fn tgid(pid: i32) -> i32 {
+    crate::read_lines(crate::sysroot::path(format!("/proc/{}/status", pid)))
+        .ok()
+        .and_then(|lines| {
+            lines
+                .map_while(Result::ok)
+                .find_map(|line| line.strip_prefix("Tgid:")?.trim().parse().ok())
+        })
+        .unwrap_or(pid)
+}
+
+/// How many offending lines to keep as examples.
+const DIAGNOSTIC_SAMPLES: usize = 3;
+
+/// Lines of gem_info we couldn't make sense of.
+pub struct ParseDiagnostics {
+    format: GemInfoFormat,
+    /// Where the lines came from, when read directly.
+    pub file: Option<PathBuf>,
+    unparsed_lines: usize,
+    samples: Vec<String>,
+    /// Every line that couldn't be parsed, numbered from 1, for `--strict`.
+    offending: Vec<(usize, String)>,
+}
+
+impl ParseDiagnostics {
+    fn new(format: GemInfoFormat) -> Self {
+        Self {
+            format,
+            file: None,
+            unparsed_lines: 0,
+            samples: Vec::new(),
+            offending: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, number: usize, line: &str) {
+        self.unparsed_lines += 1;
+        if self.samples.len() < DIAGNOSTIC_SAMPLES {
+            self.samples.push(line.trim().to_string());
+        }
+        self.offending.push((number, line.to_string()));
+    }
+
+    /// The report `--strict` fails with, when any line couldn't be parsed.
+    pub fn strict_report<D: Display>(&self, device: D) -> Option<serde_json::Value> {
+        if self.unparsed_lines == 0 {
+            return None;
+        }
+        let lines = self
+            .offending
+            .iter()
+            .map(|(number, line)| serde_json::json!({ "line": number, "text": line }))
+            .collect::<Vec<_>>();
+        Some(serde_json::json!({
+            "device": device.to_string(),
+            "file": self.file,
+            "format": self.format.to_string(),
+            "unparsed_lines": self.unparsed_lines,
+            "lines": lines,
+        }))
+    }
+
+    pub fn report<D: Display>(&self, device: D) {
+        eprintln!("amdtop: {}: {} format", device, self.format);
+
+        if self.unparsed_lines == 0 {
+            return;
+        }
+
+        eprintln!(
+            "amdtop: {}: {} line(s) could not be parsed, totals may be wrong",
+            device, self.unparsed_lines
+        );
+        for sample in &self.samples {
+            eprintln!("    {}", sample);
+        }
+    }
+}
+
+/// `--strict`: fails, after writing `reports` to stderr as one JSON
+/// object, when there are any.
+pub fn fail_strictly(reports: Vec<serde_json::Value>) -> error::Result<()> {
+    if reports.is_empty() {
+        return Ok(());
+    }
+    eprintln!("{}", serde_json::json!({ "unparsed": reports }));
+    Err(Error::Parse(format!(
+        "--strict: {} gem_info file(s) had lines that couldn't be parsed",
+        reports.len()
+    )))
+}
+
+/// Every object line, with the lines that couldn't be parsed.
+pub fn objects(
+    contents: &[u8],
+    format: GemInfoFormat,
+) -> error::Result<(Vec<GemObject>, ParseDiagnostics)> {
+    let mut objects = Vec::new();
+    let mut diagnostics = ParseDiagnostics::new(format);
+    let mut cur_pid = -1;
+    let mut cur_command = String::new();
+
+    let mut process_line = |line: &str| -> Option<()> {
+        let mut segments = line.split_whitespace();
+        match segments.next()? {
+            "pid" => {
+                cur_pid = segments.next()?.parse().ok()?;
+                // `command <comm>:`, where the name may have spaces in it.
+                cur_command = line
+                    .split_once(" command ")
+                    .map(|(_, command)| command.trim().trim_end_matches(':').to_string())
+                    .unwrap_or_default();
+            }
+            _ => objects.push(format.parse_object(cur_pid, &cur_command, line)?),
+        }
+
+        Some(())
+    };
+
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.map_err(|err| Error::Parse(format!("unreadable gem_info: {}", err)))?;
+        if !line.trim().is_empty() && process_line(&line).is_none() {
+            diagnostics.record(index + 1, &line);
+        }
+    }
+    Ok((objects, diagnostics))
+}
+
+/// Sums up usage per process, merging clients by the thread group `tgid`
+/// resolves them to.
+pub fn parse<F>(
+    contents: &[u8],
+    format: GemInfoFormat,
+    mut tgid: F,
+) -> error::Result<(Vec<MemInfo>, ParseDiagnostics)>
+where
+    F: FnMut(i32) -> i32,
+{
+    let (objects, diagnostics) = objects(contents, format)?;
+    let mut tgids = HashMap::<i32, i32>::new();
+    let mut dma_bufs = HashSet::<(i32, u64)>::new();
+    let mut mem_infos = HashMap::<i32, MemInfo>::new();
+
+    for object in objects {
+        let pid = if object.pid > 0 {
+            *tgids.entry(object.pid).or_insert_with(|| tgid(object.pid))
+        } else {
+            object.pid
+        };
+
+        // A buffer shared between two clients of the same process shows up
+        // once as exported and once as imported; only count it once.
+        if let Some(dma_buf) = object.dma_buf {
+            if !dma_bufs.insert((pid, dma_buf)) {
+                continue;
+            }
+        }
+
+        let mem_info = mem_infos.entry(pid).or_default();
+        let pinned = mem_info.pinned.get_or_insert_with(Pinned::default);
+        match object.memory_type.as_str() {
+            "VRAM" => {
+                mem_info.vram_bytes += object.bytes;
+                if object.pinned {
+                    pinned.vram_bytes += object.bytes;
+                }
+            }
+            "GTT" => {
+                mem_info.gtt_bytes += object.bytes;
+                if object.pinned {
+                    pinned.gtt_bytes += object.bytes;
+                }
+            }
+            _ => mem_info.unknown_bytes += object.bytes,
+        }
+    }
+
+    let mut mem_infos_sorted = mem_infos
+        .iter()
+        .map(|(pid, mem_info)| MemInfo {
+            pid: *pid,
+            ..*mem_info
+        })
+        .collect::<Vec<_>>();
+
+    mem_infos_sorted
+        .sort_by_key(|mem_info| std::cmp::Reverse(mem_info.vram_bytes + mem_info.gtt_bytes));
+
+    Ok((mem_infos_sorted, diagnostics))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_with(contents: &str) -> (Vec<MemInfo>, ParseDiagnostics) {
+        let format = GemInfoFormat::detect(contents.as_bytes()).unwrap_or(GemInfoFormat::Unknown);
+        parse(contents.as_bytes(), format, |pid| pid).unwrap()
+    }
+
+    #[test]
+    fn detects_formats() {
+        let detect = |contents: &str| GemInfoFormat::detect(contents.as_bytes());
+
+        assert_eq!(
+            detect("pid 1 command a:\n\t0x00000001:   4096 byte VRAM\n"),
+            Some(GemInfoFormat::Legacy)
+        );
+        assert_eq!(
+            detect("pid 1 command a:\n\t\t0x00000001:   4096 byte VRAM VISIBLE\n"),
+            Some(GemInfoFormat::BoPrintInfo)
+        );
+        assert_eq!(
+            detect("pid 1 command a:\n  4096 bytes VRAM\n"),
+            Some(GemInfoFormat::Unknown)
+        );
+        assert_eq!(detect("pid 1 command a:\n"), None);
+    }
+
+    #[test]
+    fn sums_per_process() {
+        let (mem_infos, diagnostics) = parse_with(
+            "pid 1 command a:\n\
+             \t\t0x00000001:   4096 byte VRAM\n\
+             \t\t0x00000002:   8192 byte GTT\n\
+             \t\t0x00000003:   1024 byte CPU\n\
+             pid 2 command b:\n\
+             \t\t0x00000001:  65536 byte VRAM VISIBLE\n",
+        );
+
+        assert_eq!(diagnostics.unparsed_lines, 0);
+        assert_eq!(mem_infos.len(), 2);
+        assert_eq!(mem_infos[0].pid, 2);
+        assert_eq!(mem_infos[1].pid, 1);
+        assert_eq!(mem_infos[1].vram_bytes, 4096);
+        assert_eq!(mem_infos[1].gtt_bytes, 8192);
+        assert_eq!(mem_infos[1].unknown_bytes, 1024);
+    }
+
+    #[test]
+    fn counts_shared_dma_bufs_once_per_process() {
+        let (mem_infos, _) = parse_with(
+            "pid 1 command a:\n\
+             \t\t0x00000001:   4096 byte VRAM exported as ino:7\n\
+             pid 1 command a:\n\
+             \t\t0x00000001:   4096 byte VRAM imported from ino:7\n\
+             pid 2 command b:\n\
+             \t\t0x00000001:   4096 byte VRAM imported from ino:7\n",
+        );
+
+        let vram = |pid| {
+            mem_infos
+                .iter()
+                .find(|mem_info| mem_info.pid == pid)
+                .unwrap()
+                .vram_bytes
+        };
+        assert_eq!(vram(1), 4096);
+        assert_eq!(vram(2), 4096);
+    }
+
+    #[test]
+    fn merges_clients_by_tgid() {
+        let contents = "pid 10 command a:\n\
+                        \t\t0x00000001:   4096 byte VRAM\n\
+                        pid 11 command a:\n\
+                        \t\t0x00000001:   4096 byte VRAM\n";
+        let (mem_infos, _) =
+            parse(contents.as_bytes(), GemInfoFormat::BoPrintInfo, |_| 10).unwrap();
+
+        assert_eq!(mem_infos.len(), 1);
+        assert_eq!(mem_infos[0].pid, 10);
+        assert_eq!(mem_infos[0].vram_bytes, 8192);
+    }
+
+    #[test]
+    fn keeps_what_each_object_is() {
+        let contents = "pid 1523 command Xorg:\n\
+                        \t\t0x0000001a:   8388608 byte VRAM VISIBLE pin count 1 exported as ino:1234 CPU_ACCESS_REQUIRED\n";
+        let (objects, _) = objects(contents.as_bytes(), GemInfoFormat::BoPrintInfo).unwrap();
+        assert_eq!(objects[0].command, "Xorg");
+        assert_eq!(objects[0].handle, Some(0x1a));
+        assert_eq!(objects[0].dma_buf, Some(1234));
+        assert_eq!(objects[0].visible, Some(true));
+        assert_eq!(objects[0].flags, ["CPU_ACCESS_REQUIRED"]);
+        assert!(objects[0].pinned);
+    }
+
+    #[test]
+    fn sums_pinned_memory() {
+        let (mem_infos, _) = parse_with(
+            "pid 1 command a:\n\
+             \t0x00000001:   8192 byte VRAM @ 0x00f4000000\n\
+             \t0x00000002:   4096 byte VRAM pin count 0\n\
+             \t0x00000003:   2048 byte  GTT pin count 2\n",
+        );
+        assert_eq!(
+            mem_infos[0].pinned,
+            Some(Pinned {
+                vram_bytes: 8192,
+                gtt_bytes: 2048,
+            })
+        );
+    }
+
+    #[test]
+    fn reports_unparsed_lines() {
+        let (mem_infos, diagnostics) = parse_with(
+            "pid 1 command a:\n\
+             \t0x00000001:   4096 byte VRAM\n\
+             \tsomething new\n\
+             \n\
+             pid x command b:\n",
+        );
+
+        assert_eq!(mem_infos[0].vram_bytes, 4096);
+        assert_eq!(diagnostics.unparsed_lines, 2);
+        assert_eq!(diagnostics.samples, ["something new", "pid x command b:"]);
+    }
+
+    #[test]
+    fn objects_before_any_client_are_unattributed() {
+        let (mem_infos, _) = parse_with("\t0x00000001:   4096 byte VRAM\n");
+        assert_eq!(mem_infos[0].pid, -1);
+    }
+}
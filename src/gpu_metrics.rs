@@ -0,0 +1,265 @@
+//! Decoding for the binary `gpu_metrics` table in sysfs.
+//!
+//! The power management firmware fills in a versioned C struct with
+//! temperatures, activity, power, clocks and throttle status all sampled at
+//! once, so one read gives a consistent snapshot instead of racing across a
+//! dozen files. Every struct starts with a header saying how big it is and
+//! which revision it is. Format 1 is used by discrete GPUs and format 2 by
+//! APUs. Fields a device doesn't support are filled with all ones.
+//!
+//! Offsets below follow the structs in the kernel's `kgd_pp_interface.h`,
+//! which are laid out with natural alignment.
+
+use serde::Serialize;
+use std::convert::TryInto;
+
+/// Format 1 revisions 0 to 3 share everything up to the fan speed. 4 and
+/// later (MI300) are laid out differently.
+const V1_MAX_CONTENT_REVISION: u8 = 3;
+/// The APU layouts before format 3.
+const V2_MAX_CONTENT_REVISION: u8 = 4;
+
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct GpuMetrics {
+    pub format_revision: u8,
+    pub content_revision: u8,
+    /// Edge on discrete GPUs, GFX on APUs.
+    pub edge_temperature_celsius: Option<f64>,
+    pub junction_temperature_celsius: Option<f64>,
+    pub memory_temperature_celsius: Option<f64>,
+    /// APUs only.
+    pub soc_temperature_celsius: Option<f64>,
+    pub gfx_activity_percent: Option<u16>,
+    /// Memory controller.
+    pub memory_activity_percent: Option<u16>,
+    /// UVD or VCN.
+    pub media_activity_percent: Option<u16>,
+    pub socket_power_watts: Option<f64>,
+    pub average_gfx_clock_mhz: Option<u16>,
+    pub average_soc_clock_mhz: Option<u16>,
+    pub average_memory_clock_mhz: Option<u16>,
+    pub current_gfx_clock_mhz: Option<u16>,
+    pub current_memory_clock_mhz: Option<u16>,
+    /// Which throttlers are active; the bits are ASIC specific.
+    pub throttle_status: Option<u32>,
+    pub fan_rpm: Option<u16>,
+    pub pcie_link_width: Option<u16>,
+    /// In GT/s.
+    pub pcie_link_speed_gts: Option<f64>,
+    pub gfx_voltage_mv: Option<u16>,
+    pub soc_voltage_mv: Option<u16>,
+    pub memory_voltage_mv: Option<u16>,
+}
+
+/// Reads little endian fields, treating all ones as "not supported".
+struct Table<'a>(&'a [u8]);
+
+impl Table<'_> {
+    fn u8(&self, offset: usize) -> Option<u8> {
+        self.0
+            .get(offset)
+            .copied()
+            .filter(|value| *value != u8::MAX)
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.0.get(offset..offset + 2)?.try_into().ok()?;
+        Some(u16::from_le_bytes(bytes)).filter(|value| *value != u16::MAX)
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.0.get(offset..offset + 4)?.try_into().ok()?;
+        Some(u32::from_le_bytes(bytes)).filter(|value| *value != u32::MAX)
+    }
+}
+
+impl GpuMetrics {
+    /// Decodes the table, or `None` for revisions we don't know the layout
+    /// of.
+    pub fn parse(contents: &[u8]) -> Option<Self> {
+        let table = Table(contents);
+        let size = table.u16(0)? as usize;
+        let table = Table(contents.get(..size)?);
+        let format_revision = table.u8(2)?;
+        let content_revision = table.u8(3)?;
+
+        let mut metrics = GpuMetrics {
+            format_revision,
+            content_revision,
+            ..GpuMetrics::default()
+        };
+
+        match format_revision {
+            1 if content_revision <= V1_MAX_CONTENT_REVISION => {
+                let celsius = |offset| table.u16(offset).map(f64::from);
+                metrics.edge_temperature_celsius = celsius(4);
+                metrics.junction_temperature_celsius = celsius(6);
+                metrics.memory_temperature_celsius = celsius(8);
+                metrics.gfx_activity_percent = table.u16(16);
+                metrics.memory_activity_percent = table.u16(18);
+                metrics.media_activity_percent = table.u16(20);
+                metrics.socket_power_watts = table.u16(22).map(f64::from);
+                metrics.average_gfx_clock_mhz = table.u16(40);
+                metrics.average_soc_clock_mhz = table.u16(42);
+                metrics.average_memory_clock_mhz = table.u16(44);
+                metrics.current_gfx_clock_mhz = table.u16(54);
+                metrics.current_memory_clock_mhz = table.u16(58);
+                metrics.throttle_status = table.u32(68);
+                metrics.fan_rpm = table.u16(72);
+
+                // 1.0 squeezed the link into two bytes.
+                let (width, speed) = if content_revision == 0 {
+                    (table.u8(74).map(u16::from), table.u8(75).map(u16::from))
+                } else {
+                    (table.u16(74), table.u16(76))
+                };
+                metrics.pcie_link_width = width;
+                metrics.pcie_link_speed_gts = speed.map(|speed| f64::from(speed) / 10.0);
+
+                if content_revision >= 3 {
+                    metrics.soc_voltage_mv = table.u16(104);
+                    metrics.gfx_voltage_mv = table.u16(106);
+                    metrics.memory_voltage_mv = table.u16(108);
+                }
+            }
+            2 if content_revision <= V2_MAX_CONTENT_REVISION => {
+                // APUs report centi-degrees and milliwatts.
+                let celsius = |offset| table.u16(offset).map(|value| f64::from(value) / 100.0);
+                metrics.edge_temperature_celsius = celsius(16);
+                metrics.soc_temperature_celsius = celsius(18);
+                metrics.gfx_activity_percent = table.u16(40);
+                metrics.media_activity_percent = table.u16(42);
+                metrics.socket_power_watts = table
+                    .u16(44)
+                    .map(|milliwatts| f64::from(milliwatts) / 1000.0);
+                metrics.average_gfx_clock_mhz = table.u16(68);
+                metrics.average_soc_clock_mhz = table.u16(70);
+                metrics.average_memory_clock_mhz = table.u16(72);
+                metrics.current_gfx_clock_mhz = table.u16(80);
+                metrics.current_memory_clock_mhz = table.u16(84);
+                metrics.throttle_status = table.u32(112);
+            }
+            _ => return None,
+        }
+
+        Some(metrics)
+    }
+
+    /// Every value as `(key, label, value, unit)` for `sensors --detail`.
+    pub fn readings(&self) -> Vec<(&'static str, &'static str, f64, &'static str)> {
+        let u16_value = |value: Option<u16>| value.map(f64::from);
+        vec![
+            (
+                "media_activity",
+                "Media activity",
+                u16_value(self.media_activity_percent),
+                "%",
+            ),
+            (
+                "average_gfx_clock",
+                "Average GFX clock",
+                u16_value(self.average_gfx_clock_mhz),
+                "MHz",
+            ),
+            (
+                "average_soc_clock",
+                "Average SoC clock",
+                u16_value(self.average_soc_clock_mhz),
+                "MHz",
+            ),
+            (
+                "average_memory_clock",
+                "Average memory clock",
+                u16_value(self.average_memory_clock_mhz),
+                "MHz",
+            ),
+            (
+                "soc_temperature",
+                "SoC temperature",
+                self.soc_temperature_celsius,
+                "°C",
+            ),
+            (
+                "memory_voltage",
+                "Memory voltage",
+                u16_value(self.memory_voltage_mv),
+                "mV",
+            ),
+            (
+                "pcie_link_width",
+                "PCIe link width",
+                u16_value(self.pcie_link_width),
+                "lanes",
+            ),
+            (
+                "pcie_link_speed",
+                "PCIe link speed",
+                self.pcie_link_speed_gts,
+                "GT/s",
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(key, label, value, unit)| Some((key, label, value?, unit)))
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A table of `size` bytes, unsupported fields and all.
+    fn table(size: u16, format_revision: u8, content_revision: u8) -> Vec<u8> {
+        let mut table = vec![0xff; size as usize];
+        table[..2].copy_from_slice(&size.to_le_bytes());
+        table[2] = format_revision;
+        table[3] = content_revision;
+        table
+    }
+
+    fn put_u16(table: &mut [u8], offset: usize, value: u16) {
+        table[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    #[test]
+    fn decodes_v1_3() {
+        let mut contents = table(120, 1, 3);
+        put_u16(&mut contents, 6, 71);
+        put_u16(&mut contents, 16, 98);
+        put_u16(&mut contents, 22, 280);
+        put_u16(&mut contents, 54, 2450);
+        contents[68..72].copy_from_slice(&0x20u32.to_le_bytes());
+        put_u16(&mut contents, 74, 16);
+        put_u16(&mut contents, 76, 160);
+        put_u16(&mut contents, 106, 1050);
+
+        let metrics = GpuMetrics::parse(&contents).unwrap();
+        assert_eq!(metrics.junction_temperature_celsius, Some(71.0));
+        assert_eq!(metrics.edge_temperature_celsius, None);
+        assert_eq!(metrics.gfx_activity_percent, Some(98));
+        assert_eq!(metrics.socket_power_watts, Some(280.0));
+        assert_eq!(metrics.current_gfx_clock_mhz, Some(2450));
+        assert_eq!(metrics.throttle_status, Some(0x20));
+        assert_eq!(metrics.pcie_link_width, Some(16));
+        assert_eq!(metrics.pcie_link_speed_gts, Some(16.0));
+        assert_eq!(metrics.gfx_voltage_mv, Some(1050));
+    }
+
+    #[test]
+    fn decodes_v2_apus() {
+        let mut contents = table(120, 2, 1);
+        put_u16(&mut contents, 16, 4550);
+        put_u16(&mut contents, 44, 12500);
+
+        let metrics = GpuMetrics::parse(&contents).unwrap();
+        assert_eq!(metrics.edge_temperature_celsius, Some(45.5));
+        assert_eq!(metrics.socket_power_watts, Some(12.5));
+    }
+
+    #[test]
+    fn rejects_unknown_layouts() {
+        assert_eq!(GpuMetrics::parse(&table(120, 1, 4)), None);
+        assert_eq!(GpuMetrics::parse(&table(120, 3, 0)), None);
+        assert_eq!(GpuMetrics::parse(&[8, 0]), None);
+    }
+}
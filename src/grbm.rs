@@ -0,0 +1,163 @@
+//! Which graphics blocks are busy, from the GRBM status registers.
+//!
+//! Like radeontop, this reads `GRBM_STATUS` and the per shader engine
+//! `GRBM_STATUS_SE*` through debugfs `amdgpu_regs` a few hundred times a
+//! second and counts how often each busy bit was set. That gives a rough idea
+//! of how much of the shader array work keeps busy, beyond the one GPU busy
+//! percentage. Real wave occupancy would mean halting waves through
+//! `amdgpu_wave`, so the shader launch (SPI) figure is the closest we get.
+//!
+//! The registers have stayed at the same offsets from GCN 3 to RDNA 3.
+
+use crate::{
+    error,
+    source::{self, Device},
+};
+use serde::Serialize;
+use std::{
+    os::unix::fs::FileExt,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// Dword offsets into the register aperture.
+const GRBM_STATUS: u64 = 0x2004;
+const GRBM_STATUS_SE: [u64; 4] = [0x2005, 0x2006, 0x200e, 0x200f];
+
+/// How many times to read the registers, and how far apart.
+const SAMPLES: u32 = 200;
+const SAMPLE_INTERVAL: Duration = Duration::from_micros(500);
+
+/// `GRBM_STATUS` busy bits as `(key, label, bit)`.
+const BLOCKS: &[(&str, &str, u32)] = &[
+    ("gui_active", "Graphics pipe", 31),
+    ("cp", "Command processor", 29),
+    ("spi", "Shader launch (SPI)", 22),
+    ("ta", "Texture addresser", 14),
+    ("vgt", "Geometry (VGT)", 17),
+    ("pa", "Primitive assembly", 25),
+    ("sc", "Scan converter", 24),
+    ("sx", "Shader export", 20),
+    ("db", "Depth block", 26),
+    ("cb", "Color block", 30),
+];
+
+/// The busy bits of `GRBM_STATUS_SE*`, everything from BCI up but the
+/// reserved bit 28.
+const SE_BUSY_MASK: u32 = 0xefc0_0000;
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct BlockBusy {
+    pub key: &'static str,
+    pub label: &'static str,
+    pub percent: f64,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct GrbmBusy {
+    pub samples: u32,
+    pub blocks: Vec<BlockBusy>,
+    /// How often any block of each shader engine was busy. Engines the ASIC
+    /// doesn't have are left out.
+    pub shader_engines_percent: Vec<f64>,
+}
+
+/// One read of `GRBM_STATUS` and the shader engine registers.
+#[derive(Copy, Clone, Debug)]
+pub struct Sample {
+    pub status: u32,
+    pub shader_engines: [u32; 4],
+}
+
+impl GrbmBusy {
+    pub fn from_samples(samples: &[Sample]) -> Self {
+        let percent = |busy: usize| busy as f64 * 100.0 / samples.len().max(1) as f64;
+        let blocks = BLOCKS
+            .iter()
+            .map(|(key, label, bit)| BlockBusy {
+                key,
+                label,
+                percent: percent(
+                    samples
+                        .iter()
+                        .filter(|sample| sample.status & (1 << bit) != 0)
+                        .count(),
+                ),
+            })
+            .collect();
+
+        // Missing engines read back as all zeros or all ones, where a real
+        // one at least has its clean bits set while idle.
+        let shader_engines_percent = (0..GRBM_STATUS_SE.len())
+            .filter(|&engine| {
+                samples.iter().any(|sample| {
+                    let status = sample.shader_engines[engine];
+                    status != 0 && status != u32::MAX
+                })
+            })
+            .map(|engine| {
+                percent(
+                    samples
+                        .iter()
+                        .filter(|sample| sample.shader_engines[engine] & SE_BUSY_MASK != 0)
+                        .count(),
+                )
+            })
+            .collect();
+
+        GrbmBusy {
+            samples: samples.len() as u32,
+            blocks,
+            shader_engines_percent,
+        }
+    }
+
+    /// Samples `device`'s registers for about a tenth of a second.
+    pub fn read(debugfs_path: &Option<PathBuf>, device: Device) -> error::Result<Self> {
+        let regs = source::open_device_file(debugfs_path, device, "amdgpu_regs")?;
+        let read = |register: u64| -> error::Result<u32> {
+            let mut value = [0; 4];
+            regs.read_exact_at(&mut value, register * 4)?;
+            Ok(u32::from_le_bytes(value))
+        };
+
+        let mut samples = Vec::with_capacity(SAMPLES as usize);
+        for _ in 0..SAMPLES {
+            let started = Instant::now();
+            let mut shader_engines = [0; 4];
+            for (status, register) in shader_engines.iter_mut().zip(GRBM_STATUS_SE) {
+                *status = read(register)?;
+            }
+            samples.push(Sample {
+                status: read(GRBM_STATUS)?,
+                shader_engines,
+            });
+            std::thread::sleep(SAMPLE_INTERVAL.saturating_sub(started.elapsed()));
+        }
+        Ok(GrbmBusy::from_samples(&samples))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_busy_samples() {
+        let idle = Sample {
+            status: 0x0000_3000,
+            shader_engines: [0x6, 0x6, 0, 0],
+        };
+        let busy = Sample {
+            status: 1 << 31 | 1 << 22,
+            shader_engines: [1 << 27, 0x6, 0, 0],
+        };
+        let grbm = GrbmBusy::from_samples(&[idle, busy, busy, idle]);
+
+        assert_eq!(grbm.samples, 4);
+        assert_eq!(grbm.blocks[0].key, "gui_active");
+        assert_eq!(grbm.blocks[0].percent, 50.0);
+        assert_eq!(grbm.blocks[1].percent, 0.0);
+        assert_eq!(grbm.shader_engines_percent, [50.0, 0.0]);
+    }
+}
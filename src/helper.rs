@@ -0,0 +1,218 @@
+//! Privilege separation: a small helper runs with the rights needed to read
+//! debugfs and streams raw `amdgpu_gem_info` contents back over a pipe, so the
+//! process doing the parsing and printing never has to run as root.
+//!
+//! The protocol is line based. The parent writes `sample` to ask for a
+//! snapshot, and the helper answers with a `device <len> <path>` line per
+//! device, each followed by `len` bytes of gem_info, and finally `end`. If
+//! reading fails the helper answers `error <kind> <len>` followed by the
+//! message, where `kind` says which [`Error`] it was.
+
+use crate::error::{self, Error};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+};
+
+#[derive(Copy, Clone)]
+pub enum Elevate {
+    Pkexec,
+    Sudo,
+    /// Run the helper as is, for copies installed with file capabilities
+    /// (`setcap cap_dac_read_search+ep`).
+    Exec,
+}
+
+impl Elevate {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "pkexec" => Some(Elevate::Pkexec),
+            "sudo" => Some(Elevate::Sudo),
+            "exec" => Some(Elevate::Exec),
+            _ => None,
+        }
+    }
+}
+
+pub struct Helper {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: BufReader<ChildStdout>,
+}
+
+/// The program run as the helper.
+fn program() -> io::Result<PathBuf> {
+    // Let a separately installed, capability-enabled copy be used instead
+    // of ourselves.
+    match std::env::var_os("AMDTOP_HELPER") {
+        Some(program) => Ok(PathBuf::from(program)),
+        None => std::env::current_exe(),
+    }
+}
+
+/// Whether the helper could be started with `elevate`, without starting it:
+/// it and what elevates it are there to run.
+pub fn check(elevate: Elevate) -> io::Result<()> {
+    let launcher = match elevate {
+        Elevate::Pkexec => Some("pkexec"),
+        Elevate::Sudo => Some("sudo"),
+        Elevate::Exec => None,
+    };
+    if let Some(launcher) = launcher {
+        let found = std::env::var_os("PATH").is_some_and(|paths| {
+            std::env::split_paths(&paths).any(|dir| dir.join(launcher).is_file())
+        });
+        if !found {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} is not installed, so the helper can't be started",
+                    launcher
+                ),
+            ));
+        }
+    }
+    let program = program()?;
+    if !program.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("helper {} not found", program.display()),
+        ));
+    }
+    Ok(())
+}
+
+impl Helper {
+    pub fn spawn(elevate: Elevate) -> io::Result<Self> {
+        let program = program()?;
+
+        let mut command = match elevate {
+            Elevate::Pkexec => {
+                let mut command = Command::new("pkexec");
+                command.arg(&program);
+                command
+            }
+            Elevate::Sudo => {
+                let mut command = Command::new("sudo");
+                command.arg("--").arg(&program);
+                command
+            }
+            Elevate::Exec => Command::new(&program),
+        };
+
+        let mut child = command
+            .arg("--helper")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|err| {
+                io::Error::new(err.kind(), format!("failed to start helper: {}", err))
+            })?;
+
+        let stdin = child.stdin.take();
+        let stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+
+        Ok(Self {
+            child,
+            stdin,
+            stdout,
+        })
+    }
+
+    pub fn collect(&mut self) -> error::Result<Vec<(PathBuf, Vec<u8>)>> {
+        let stdin = self.stdin.as_mut().expect("stdin is open until drop");
+        writeln!(stdin, "sample")?;
+        stdin.flush()?;
+
+        let mut sample = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stdout.read_line(&mut line)? == 0 {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "helper exited unexpectedly",
+                )));
+            }
+
+            let mut fields = line.trim_end_matches('\n').splitn(3, ' ');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some("device"), Some(len), Some(path)) => {
+                    let contents = self.read_payload(len)?;
+                    sample.push((PathBuf::from(path), contents));
+                }
+                (Some("error"), Some(kind), Some(len)) => {
+                    let message = self.read_payload(len)?;
+                    return Err(Error::from_kind_name(
+                        kind,
+                        String::from_utf8_lossy(&message).into_owned(),
+                    ));
+                }
+                (Some("end"), None, None) => return Ok(sample),
+                _ => return Err(Error::Io(invalid_response())),
+            }
+        }
+    }
+
+    fn read_payload(&mut self, len: &str) -> io::Result<Vec<u8>> {
+        let len = len.parse::<usize>().map_err(|_| invalid_response())?;
+        let mut payload = vec![0; len];
+        self.stdout.read_exact(&mut payload)?;
+        Ok(payload)
+    }
+}
+
+impl Drop for Helper {
+    fn drop(&mut self) {
+        // Closing stdin tells the helper to exit. We may not be allowed to
+        // signal it, since it usually runs as root.
+        drop(self.stdin.take());
+        let _ = self.child.wait();
+    }
+}
+
+fn invalid_response() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "unexpected response from helper",
+    )
+}
+
+/// Runs the helper side of the protocol until stdin is closed.
+pub fn serve(debugfs_path: &Path) -> error::Result<()> {
+    // Ctrl-C in the terminal reaches us too; leave shutting down to the parent.
+    unsafe {
+        libc::signal(libc::SIGINT, libc::SIG_IGN);
+    }
+
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    for line in stdin.lock().lines() {
+        if line?.trim() != "sample" {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unexpected request",
+            )));
+        }
+
+        match crate::source::read_gem_infos(debugfs_path) {
+            Ok(sample) => {
+                for (path, contents) in sample {
+                    writeln!(out, "device {} {}", contents.len(), path.display())?;
+                    out.write_all(&contents)?;
+                }
+                writeln!(out, "end")?;
+            }
+            Err(err) => {
+                let message = err.to_string();
+                writeln!(out, "error {} {}", err.kind_name(), message.len())?;
+                out.write_all(message.as_bytes())?;
+            }
+        }
+        out.flush()?;
+    }
+
+    Ok(())
+}
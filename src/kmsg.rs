@@ -0,0 +1,425 @@
+//! The kernel log, from `/dev/kmsg`, for what amdgpu only ever says there.
+//!
+//! Reading it needs `CAP_SYSLOG` where `kernel.dmesg_restrict` is set, which
+//! most distributions do, so everything built on it is optional. Under sudo
+//! it's opened before we stop being root, and read from then on.
+
+use crate::{mem::DeviceView, privileges, sysroot, FormatBytes};
+use regex::Regex;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::OnceLock,
+    time::Duration,
+};
+
+/// One message of the kernel log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub sequence: u64,
+    /// Since boot.
+    pub timestamp: Duration,
+    pub message: String,
+}
+
+/// Parses `priority,sequence,microseconds,flags;message`. Continuation
+/// lines, which start with a space, aren't records.
+fn parse_record(line: &str) -> Option<Record> {
+    let (prefix, message) = line.split_once(';')?;
+    let mut fields = prefix.split(',');
+    let _priority = fields.next()?;
+    let sequence = fields.next()?.parse().ok()?;
+    let microseconds = fields.next()?.parse().ok()?;
+    Some(Record {
+        sequence,
+        timestamp: Duration::from_micros(microseconds),
+        message: message.to_string(),
+    })
+}
+
+/// Opens `path` as `/dev/kmsg` wants it read: it hands out one record per
+/// read and would then wait for the next, so it's non-blocking.
+pub fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+}
+
+/// `/dev/kmsg`, open for the whole session. Every open starts at the oldest
+/// record still in the kernel's buffer, and then each read goes on from the
+/// last.
+pub struct Kmsg {
+    reader: BufReader<File>,
+}
+
+impl Kmsg {
+    /// Takes the handle opened before dropping root if there is one.
+    pub fn open() -> io::Result<Self> {
+        let path = sysroot::path("/dev/kmsg");
+        let file = match privileges::kept(&path) {
+            Some(file) => file?,
+            None => open(&path)?,
+        };
+        Ok(Kmsg {
+            reader: BufReader::new(file),
+        })
+    }
+
+    /// The records logged since the last read; on the first, all there are.
+    pub fn read(&mut self) -> io::Result<Vec<Record>> {
+        let mut records = Vec::new();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => records.extend(parse_record(line.trim_end_matches('\n'))),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                // The kernel overwrote records before we got to them; the
+                // next read goes on with the oldest left.
+                Err(err) if err.raw_os_error() == Some(libc::EPIPE) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// How long the system has been up, to tell how recent a record is.
+pub fn uptime() -> Option<Duration> {
+    let contents = std::fs::read_to_string(sysroot::path("/proc/uptime")).ok()?;
+    let seconds = contents.split_whitespace().next()?.parse::<f64>().ok()?;
+    Some(Duration::from_secs_f64(seconds))
+}
+
+/// Whether `message` is from the driver bound to the device in PCI `slot`,
+/// which prefixes everything it logs with it: `amdgpu 0000:03:00.0: ...`.
+pub fn is_from(message: &str, slot: &str) -> bool {
+    message
+        .strip_prefix("amdgpu ")
+        .and_then(|message| message.strip_prefix(slot))
+        .is_some_and(|message| message.starts_with(": "))
+}
+
+/// Whether `message` is from amdgpu or the DRM core.
+fn is_gpu_related(message: &str) -> bool {
+    message.starts_with("amdgpu") || message.starts_with("[drm") || message.contains("[amdgpu]")
+}
+
+/// VRAM in use on each device at one refresh.
+struct Sample {
+    /// Since boot, like the records.
+    at: Duration,
+    vram_used_bytes: Vec<(Option<String>, u64)>,
+}
+
+/// How many refreshes to keep, to place log lines against.
+const SAMPLES: usize = 64;
+/// How many lines `recent` keeps.
+const RECENT_LINES: usize = 100;
+
+/// Follows amdgpu's and DRM's lines in the kernel log, each put next to the
+/// refresh before it: how long after it the line came, and how much VRAM
+/// its device had in use then. So a ring timeout right after VRAM filled up
+/// reads as such.
+pub struct KernelLog {
+    last_sequence: Option<u64>,
+    samples: VecDeque<Sample>,
+    recent: VecDeque<String>,
+    error: Option<String>,
+}
+
+impl Default for KernelLog {
+    fn default() -> Self {
+        KernelLog {
+            last_sequence: None,
+            samples: VecDeque::with_capacity(SAMPLES),
+            recent: VecDeque::with_capacity(RECENT_LINES),
+            error: None,
+        }
+    }
+}
+
+impl KernelLog {
+    /// Takes what was logged since the last refresh, as the session read
+    /// it, and this refresh's sample. Returns the new lines, which on the
+    /// first refresh exclude what was logged before we started; `recent`
+    /// has those.
+    pub fn update(
+        &mut self,
+        views: &[DeviceView],
+        records: Result<&[Record], &str>,
+    ) -> Vec<String> {
+        let records = match records {
+            Ok(records) => records,
+            Err(err) => {
+                self.error = Some(format!("can't read the kernel log: {}", err));
+                return Vec::new();
+            }
+        };
+        self.error = None;
+        let first = self.last_sequence.is_none();
+        let mut lines = Vec::new();
+        for record in records {
+            if self
+                .last_sequence
+                .is_some_and(|sequence| record.sequence <= sequence)
+            {
+                continue;
+            }
+            self.last_sequence = Some(record.sequence);
+            if !is_gpu_related(&record.message) {
+                continue;
+            }
+            let line = self.annotate(record);
+            if self.recent.len() == RECENT_LINES {
+                self.recent.pop_front();
+            }
+            self.recent.push_back(line.clone());
+            if !first {
+                lines.push(line);
+            }
+        }
+        // Nothing logged yet still counts, so later lines aren't reprinted.
+        self.last_sequence.get_or_insert(0);
+
+        if let Some(at) = uptime() {
+            if self.samples.len() == SAMPLES {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(Sample {
+                at,
+                vram_used_bytes: views
+                    .iter()
+                    .filter_map(|view| Some((view.device.pci_slot(), view.usage?.vram_used_bytes)))
+                    .collect(),
+            });
+        }
+        lines
+    }
+
+    /// `record` after the last sample taken before it.
+    fn annotate(&self, record: &Record) -> String {
+        let sample = self
+            .samples
+            .iter()
+            .rev()
+            .find(|sample| sample.at <= record.timestamp);
+        let (after, vram) = match sample {
+            Some(sample) => {
+                let after = format!("+{:.1}s", (record.timestamp - sample.at).as_secs_f64());
+                // Lines from the DRM core don't say which device; with only
+                // one it can't be another.
+                let vram = sample
+                    .vram_used_bytes
+                    .iter()
+                    .find(|(slot, _)| {
+                        slot.as_deref()
+                            .is_some_and(|slot| is_from(&record.message, slot))
+                    })
+                    .or_else(|| {
+                        Some(&sample.vram_used_bytes[0])
+                            .filter(|_| sample.vram_used_bytes.len() == 1)
+                    })
+                    .map_or_else(
+                        || "-".to_string(),
+                        |(_, bytes)| format!("VRAM {}", FormatBytes::new(*bytes)),
+                    );
+                (after, vram)
+            }
+            None => ("-".to_string(), "-".to_string()),
+        };
+        format!(
+            "[{:>12.6}] {: >7} | {: >15} | {}",
+            record.timestamp.as_secs_f64(),
+            after,
+            vram,
+            record.message
+        )
+    }
+
+    /// The last lines, from before we started too.
+    pub fn recent(&self) -> impl Iterator<Item = &String> {
+        self.recent.iter()
+    }
+
+    /// Why the log couldn't be read at the last refresh.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+}
+
+/// What counts as recent for `VmFaults`.
+const RECENT: Duration = Duration::from_secs(60);
+
+/// GPU page faults the kernel has logged for one device. Faults are what
+/// usually goes with corrupted rendering or a program crashing on the GPU.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct VmFaults {
+    /// Since we started, and before as far as the log buffer went back.
+    pub total: usize,
+    /// In the last minute.
+    pub recent: usize,
+    /// Who caused the last one, where the kernel says.
+    pub last_process: Option<String>,
+}
+
+struct FaultPatterns {
+    fault: Regex,
+    process: Regex,
+}
+
+static FAULT_PATTERNS: OnceLock<FaultPatterns> = OnceLock::new();
+
+/// One device's faults so far.
+#[derive(Default)]
+struct Tally {
+    total: usize,
+    /// When the ones that may still be recent came.
+    timestamps: VecDeque<Duration>,
+    last_process: Option<String>,
+}
+
+/// Counts each device's faults as records come in, since the log is only
+/// read once.
+#[derive(Default)]
+pub struct FaultLog {
+    /// By PCI slot.
+    tallies: HashMap<String, Tally>,
+}
+
+impl FaultLog {
+    /// Counts the faults among `records`.
+    pub fn add(&mut self, records: &[Record]) {
+        let patterns = FAULT_PATTERNS.get_or_init(|| FaultPatterns {
+            // `[gfxhub] page fault (src_id:0 ring:24 vmid:3 pasid:32781, ...)`
+            // since Vega, `GPU fault detected: 146 0x0c584801` before.
+            fault: Regex::new(r"\bpage fault \(|GPU fault detected:").expect("valid regex"),
+            process: Regex::new(r"for process (\S+) pid (\d+)").expect("valid regex"),
+        });
+        for record in records {
+            let slot = match record
+                .message
+                .strip_prefix("amdgpu ")
+                .and_then(|message| message.split_once(": "))
+            {
+                Some((slot, _)) => slot,
+                None => continue,
+            };
+            if !patterns.fault.is_match(&record.message) {
+                continue;
+            }
+            let tally = self.tallies.entry(slot.to_string()).or_default();
+            tally.total += 1;
+            tally.timestamps.push_back(record.timestamp);
+            if let Some(captures) = patterns.process.captures(&record.message) {
+                tally.last_process = Some(format!("{} ({})", &captures[1], &captures[2]));
+            }
+        }
+    }
+
+    /// The faults of the device in PCI `slot`, `uptime` into the boot.
+    pub fn vm_faults(&mut self, slot: &str, uptime: Option<Duration>) -> VmFaults {
+        let tally = match self.tallies.get_mut(slot) {
+            Some(tally) => tally,
+            None => return VmFaults::default(),
+        };
+        let recent = match uptime {
+            Some(uptime) => {
+                while tally
+                    .timestamps
+                    .front()
+                    .is_some_and(|timestamp| uptime.saturating_sub(*timestamp) > RECENT)
+                {
+                    tally.timestamps.pop_front();
+                }
+                tally.timestamps.len()
+            }
+            None => 0,
+        };
+        VmFaults {
+            total: tally.total,
+            recent,
+            last_process: tally.last_process.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_records() {
+        assert_eq!(
+            parse_record("3,4521,1234567890,-;amdgpu 0000:03:00.0: amdgpu: ring gfx_0.0.0 timeout"),
+            Some(Record {
+                sequence: 4521,
+                timestamp: Duration::from_micros(1234567890),
+                message: "amdgpu 0000:03:00.0: amdgpu: ring gfx_0.0.0 timeout".to_string(),
+            })
+        );
+        assert_eq!(parse_record(" SUBSYSTEM=pci"), None);
+    }
+
+    #[test]
+    fn counts_page_faults() {
+        let records = [
+            "3,1,100000000,-;amdgpu 0000:03:00.0: amdgpu: [gfxhub] page fault \
+             (src_id:0 ring:24 vmid:3 pasid:32781, for process blender pid 3301 thread blender pid 3305)",
+            "3,2,100000001,-;amdgpu 0000:03:00.0: amdgpu:   in page starting at address 0x0000800100200000",
+            "3,3,170000000,-;amdgpu 0000:03:00.0: GPU fault detected: 146 0x0c584801",
+            "3,4,170000001,-;amdgpu 0000:04:00.0: GPU fault detected: 146 0x0c584801",
+        ]
+        .iter()
+        .filter_map(|line| parse_record(line))
+        .collect::<Vec<_>>();
+        let mut log = FaultLog::default();
+        log.add(&records[..2]);
+        log.add(&records[2..]);
+        let faults = log.vm_faults("0000:03:00.0", Some(Duration::from_secs(200)));
+        assert_eq!(
+            faults,
+            VmFaults {
+                total: 2,
+                recent: 1,
+                last_process: Some("blender (3301)".to_string()),
+            }
+        );
+        // Older than a minute by now.
+        assert_eq!(
+            log.vm_faults("0000:03:00.0", Some(Duration::from_secs(300)))
+                .recent,
+            0
+        );
+        assert_eq!(log.vm_faults("0000:05:00.0", None), VmFaults::default());
+    }
+
+    #[test]
+    fn places_lines_after_the_sample_before_them() {
+        let mut log = KernelLog::default();
+        log.samples.push_back(Sample {
+            at: Duration::from_secs(100),
+            vram_used_bytes: vec![(Some("0000:03:00.0".to_string()), 4 << 30)],
+        });
+        let record = |microseconds, message: &str| Record {
+            sequence: 1,
+            timestamp: Duration::from_micros(microseconds),
+            message: message.to_string(),
+        };
+        assert_eq!(
+            log.annotate(&record(102_500_000, "amdgpu 0000:03:00.0: amdgpu: ring gfx timeout")),
+            "[  102.500000]   +2.5s |   VRAM 4.00 GiB | amdgpu 0000:03:00.0: amdgpu: ring gfx timeout"
+        );
+        assert_eq!(
+            log.annotate(&record(99_000_000, "[drm] Fence fallback timer expired")),
+            "[   99.000000]       - |               - | [drm] Fence fallback timer expired"
+        );
+        assert!(!is_gpu_related("usb 1-1: new high-speed USB device"));
+    }
+}
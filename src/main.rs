@@ -1,11 +1,82 @@
+mod adaptive;
+mod buffers;
+mod check;
+mod chrome_trace;
+mod cli;
+mod collectors;
+mod compare;
+mod dbus;
+mod devcoredump;
+mod doctor;
+mod enrich;
+mod error;
+mod events;
+mod export;
+mod expression;
+mod fw;
+mod gem_info;
+mod gpu_metrics;
+mod grbm;
+mod helper;
+mod kmsg;
+mod mangohud;
+mod mem;
+mod meminfo;
+mod meters;
+mod mqtt;
+mod oneline;
+mod output;
+mod overdrive;
+mod pm_info;
+mod power;
+mod priority;
+mod privileges;
+mod recording;
+mod remote;
+mod replay;
+mod rings;
+mod schema;
+mod sensors;
+mod smoothing;
+mod source;
+mod sysroot;
+mod systemd;
+mod trace;
+mod tui;
+mod verify;
+mod watch;
+mod websocket;
+mod xgmi;
+mod zabbix;
+
+use clap::CommandFactory;
+use cli::{Cli, Command, GlobalArgs, OutputFormat};
 use std::{
-    collections::HashMap,
     fmt::Display,
     fs::File,
     io::{self, BufRead},
     path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
 };
 
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Makes Ctrl-C and SIGTERM set `INTERRUPTED` instead of killing us, so
+/// long running commands get to clean up, and a service stopped by systemd
+/// still prints its summary.
+fn catch_interrupts() {
+    let handler = handle_interrupt as extern "C" fn(libc::c_int);
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+}
+
 fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
 where
     P: AsRef<Path>,
@@ -16,7 +87,7 @@ where
 
 #[inline]
 fn checked_log(x: u64, base: u64) -> Option<u64> {
-    if x <= 0 || base <= 1 {
+    if x == 0 || base <= 1 {
         None
     } else {
         let mut n = 0;
@@ -31,10 +102,7 @@ fn checked_log(x: u64, base: u64) -> Option<u64> {
 
 #[inline]
 fn log(x: u64, base: u64) -> u64 {
-    match checked_log(x, base) {
-        Some(n) => n,
-        None => 0,
-    }
+    checked_log(x, base).unwrap_or_default()
 }
 
 struct FormatBytes {
@@ -48,107 +116,262 @@ impl FormatBytes {
 impl Display for FormatBytes {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         const DIVISOR: u64 = 1024;
-        const SUFFIXES: &[&'static str] = &["", "KiB", "MiB", "GiB"];
+        const SUFFIXES: &[&str] = &["", "KiB", "MiB", "GiB"];
 
         if self.bytes == 0 {
             return self.bytes.fmt(f);
         }
 
-        let divisions = std::cmp::min(log(self.bytes, DIVISOR), SUFFIXES.len() as u64);
+        let divisions = std::cmp::min(log(self.bytes, DIVISOR), SUFFIXES.len() as u64 - 1);
         let result = self.bytes as f64 / DIVISOR.pow(divisions as u32) as f64;
         format!("{:.2} {}", result, SUFFIXES[divisions as usize]).fmt(f)
     }
 }
 
-#[derive(Default, Copy, Clone)]
-struct MemInfo {
-    pid: i32,
-    gtt_bytes: u64,
-    vram_bytes: u64,
-    unknown_bytes: u64,
-}
-
-fn main() -> Result<(), io::Error> {
-    for entry in glob::glob("/sys/kernel/debug/dri/*/amdgpu_gem_info").unwrap() {
-        if let Ok(gem_info_path) = entry {
-            let mut mem_infos = HashMap::<i32, MemInfo>::new();
-            let mut cur_pid = -1;
-
-            let mut process_line = |line: &str| -> Option<()> {
-                let mut segments = line.split_whitespace();
-                match segments.next()? {
-                    "pid" => {
-                        let pid = segments.next()?;
-                        if let Ok(pid) = pid.parse() {
-                            cur_pid = pid;
-                        }
-                    }
-                    _ => {
-                        let bytes = str::parse::<u64>(segments.next()?).ok()?;
-                        let _skip = segments.next()?;
-                        let memory_type = segments.next()?;
-                        let mem_info = mem_infos.entry(cur_pid).or_default();
-                        match memory_type {
-                            "VRAM" => mem_info.vram_bytes += bytes,
-                            "GTT" => mem_info.gtt_bytes += bytes,
-                            _ => mem_info.unknown_bytes += bytes,
-                        }
-                    }
-                }
-
-                Some(())
-            };
-
-            for line in read_lines(gem_info_path)? {
-                process_line(&line?);
-            }
+struct FormatDuration {
+    duration: Duration,
+}
+impl FormatDuration {
+    fn new(duration: Duration) -> Self {
+        Self { duration }
+    }
+}
+impl Display for FormatDuration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.duration.as_secs();
+        let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+        if hours > 0 {
+            format!("{}h {}m", hours, minutes).fmt(f)
+        } else if minutes > 0 {
+            format!("{}m {}s", minutes, seconds).fmt(f)
+        } else {
+            format!("{}s", seconds).fmt(f)
+        }
+    }
+}
+
+/// Parses sizes like `512`, `64K`, `16MiB` or `1.5G`, using binary units.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, suffix) = size.split_at(split);
+    let number = number.parse::<f64>().ok()?;
+
+    let multiplier: u64 = match suffix.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return None,
+    };
+
+    Some((number * multiplier as f64) as u64)
+}
+
+/// Sleeps for `duration`, waking early if we've been interrupted.
+fn sleep_interruptible(duration: Duration) {
+    const STEP: Duration = Duration::from_millis(100);
+
+    let deadline = Instant::now() + duration;
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        systemd::keep_alive();
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        std::thread::sleep(std::cmp::min(STEP, deadline - now));
+    }
+}
+
+/// Calls `refresh` until `--iterations` runs out or we're interrupted,
+/// waiting `--delay` in between. Just once unless running continuously.
+fn refresh_loop<F>(global: &GlobalArgs, mut refresh: F) -> error::Result<()>
+where
+    F: FnMut(u64) -> error::Result<()>,
+{
+    adaptive_refresh_loop(global, |iteration| refresh(iteration).map(|()| true))
+}
+
+/// `refresh_loop` for a `refresh` that says whether anything changed, so
+/// `--adaptive` can wait longer while nothing does.
+fn adaptive_refresh_loop<F>(global: &GlobalArgs, mut refresh: F) -> error::Result<()>
+where
+    F: FnMut(u64) -> error::Result<bool>,
+{
+    if global.continuous() {
+        catch_interrupts();
+    }
+
+    let delay = global.delay.unwrap_or(Duration::from_secs(1));
+    let mut pacing = global
+        .adaptive
+        .map(|idle_after| adaptive::Pacing::new(delay, idle_after));
+    let mut wait = delay;
+    let mut iteration = 0;
 
-            let mut mem_infos_sorted = mem_infos
-                .iter()
-                .map(|(pid, mem_info)| MemInfo {
-                    pid: *pid,
-                    ..*mem_info
-                })
-                .collect::<Vec<_>>();
-
-            mem_infos_sorted.sort_by_key(|mem_info| {
-                std::cmp::Reverse(mem_info.vram_bytes + mem_info.gtt_bytes)
-            });
-
-            println!(
-                "{0: <10} | {1: <20} | {2: <60} | {3: >15} | {4: >15} | {5: >15}",
-                "PID", "PROCESS", "PATH", "TOTAL", "VRAM", "GTT"
-            );
-
-            println!("{:-^1$}", "", 150);
-
-            for mem_info in mem_infos_sorted {
-                if mem_info.pid == -1 {
-                    continue;
-                }
-
-                let path = std::fs::read_link(format!("/proc/{}/exe", mem_info.pid))
-                    .map(|path| path.to_string_lossy().to_owned().to_string());
-                let name = std::fs::read_to_string(format!("/proc/{}/comm", mem_info.pid));
-
-                println!(
-                    "{0: <10} | {1: <20} | {2: <60} | {3: >15} | {4: >15} | {5: >15}",
-                    mem_info.pid,
-                    name.as_ref()
-                        .map(String::as_str)
-                        .map(str::trim)
-                        .unwrap_or("unknown"),
-                    path.as_ref()
-                        .map(String::as_str)
-                        .map(str::trim)
-                        .unwrap_or("unknown"),
-                    FormatBytes::new(mem_info.vram_bytes + mem_info.gtt_bytes),
-                    FormatBytes::new(mem_info.vram_bytes),
-                    FormatBytes::new(mem_info.gtt_bytes),
+    loop {
+        let changed = refresh(iteration)?;
+        if let Some(pacing) = &mut pacing {
+            let next = pacing.next(changed);
+            if global.diagnostics && next != wait {
+                eprintln!(
+                    "amdtop: --adaptive: refreshing every {}s",
+                    next.as_secs_f64()
                 );
             }
+            wait = next;
         }
+        if iteration == 0 && global.continuous() {
+            systemd::notify("READY=1");
+        }
+
+        iteration += 1;
+        if !global.continuous() || global.iterations.is_some_and(|count| iteration >= count) {
+            return Ok(());
+        }
+
+        sleep_interruptible(wait);
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            systemd::notify("STOPPING=1");
+            return Ok(());
+        }
+    }
+}
+
+fn main() {
+    let cli = Cli::parse_args();
+
+    if let Some(Command::Completions { shell }) = cli.command {
+        clap_complete::generate(shell, &mut Cli::command(), "amdtop", &mut io::stdout());
+        return;
     }
 
-    Ok(())
+    // A privileged helper must only ever look at the real system.
+    if let (Some(root), false) = (&cli.global.root, cli.helper) {
+        sysroot::set(root.clone());
+    }
+
+    if let Err(err) = run(cli) {
+        eprintln!("amdtop: {}", err);
+        std::process::exit(err.exit_code());
+    }
+}
+
+fn run(cli: Cli) -> error::Result<()> {
+    if cli.helper {
+        // Never let the caller choose what a privileged helper reads:
+        // --debugfs-path could point it at a tree of symlinks to anything.
+        let debugfs_path = source::find_debugfs()
+            .ok_or_else(|| error::Error::UnsupportedKernel("debugfs is not mounted".to_string()))?;
+        return helper::serve(&debugfs_path);
+    }
+
+    let global = &cli.global;
+    if cli.version {
+        return Ok(doctor::write_version(&mut io::stdout().lock(), global)?);
+    }
+    let command = cli.command.unwrap_or(Command::Mem(cli.mem));
+    if let Some(address) = &global.connect {
+        return match command {
+            Command::Mem(_) => remote::run_mem(global, address),
+            Command::Top(options) => tui::run(global, &options),
+            _ => Err(error::Error::InvalidArgument(
+                "--connect only works with top and mem".to_string(),
+            )),
+        };
+    }
+    if global.plain {
+        if global.output != OutputFormat::Table || global.watch {
+            return Err(error::Error::InvalidArgument(
+                "--plain only changes the table, printed line by line".to_string(),
+            ));
+        }
+        output::set_plain();
+    }
+    if global.nul {
+        if global.output != OutputFormat::Csv {
+            return Err(error::Error::InvalidArgument(
+                "-0 only works with --output csv".to_string(),
+            ));
+        }
+        output::set_nul_terminated();
+    }
+    if global.adaptive.is_some() && !matches!(command, Command::Mem(_)) {
+        return Err(error::Error::InvalidArgument(
+            "--adaptive only works with mem".to_string(),
+        ));
+    }
+    if global.watch
+        && (global.output != OutputFormat::Table
+            || !matches!(command, Command::Mem(_) | Command::Sensors(_)))
+    {
+        return Err(error::Error::InvalidArgument(
+            "--watch only works with the mem and sensors tables".to_string(),
+        ));
+    }
+    match command {
+        Command::Mem(options) => mem::run(global, &options),
+        Command::Sensors(options) => sensors::run(global, &options),
+        Command::Top(options) => tui::run(global, &options),
+        Command::Export(options) => export::run(global, &options),
+        Command::Agent(options) => remote::run_agent(global, &options),
+        Command::InstallService(options) => systemd::install(&options),
+        Command::Dbus(options) => dbus::run(global, &options),
+        Command::Check(options) => check::run(global, &options),
+        Command::Zabbix(options) => zabbix::run(global, &options),
+        Command::Histogram(options) => buffers::run_histogram(global, &options),
+        Command::Buffers(options) => buffers::run_buffers(global, &options),
+        Command::Fw => fw::run(global),
+        Command::Doctor => doctor::run(global),
+        Command::Schema => schema::run(),
+        Command::Xgmi => xgmi::run(global),
+        Command::Trace(options) => trace::run(global, &options),
+        Command::Rings => rings::run(global),
+        Command::Set(options) => power::run(global, &options),
+        Command::Replay(options) => replay::run(global, &options),
+        Command::Compare(options) => compare::run(global, &options),
+        Command::Completions { .. } => unreachable!("handled before running"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_bytes() {
+        assert_eq!(FormatBytes::new(0).to_string(), "0");
+        assert_eq!(FormatBytes::new(4096).to_string(), "4.00 KiB");
+        assert_eq!(FormatBytes::new(3 << 29).to_string(), "1.50 GiB");
+        assert_eq!(FormatBytes::new(2 << 40).to_string(), "2048.00 GiB");
+    }
+
+    #[test]
+    fn formats_durations() {
+        assert_eq!(
+            FormatDuration::new(Duration::from_secs(42)).to_string(),
+            "42s"
+        );
+        assert_eq!(
+            FormatDuration::new(Duration::from_secs(270)).to_string(),
+            "4m 30s"
+        );
+        assert_eq!(
+            FormatDuration::new(Duration::from_secs(3900)).to_string(),
+            "1h 5m"
+        );
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("64K"), Some(64 << 10));
+        assert_eq!(parse_size("16MiB"), Some(16 << 20));
+        assert_eq!(parse_size("1.5 GiB"), Some(3 << 29));
+        assert_eq!(parse_size("16 furlongs"), None);
+        assert_eq!(parse_size("MiB"), None);
+    }
 }
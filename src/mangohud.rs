@@ -0,0 +1,188 @@
+//! `--output mangohud` for `sensors`: the CSV MangoHud logs with
+//! `log_interval`, so the tools people already use to plot and compare
+//! those logs take amdtop's as well. amdtop doesn't see frames, so `fps`
+//! and `frametime` are always 0, and the CPU's power and temperature, which
+//! MangoHud reads from its own sensors, are too.
+
+use crate::{
+    meminfo::SystemMemory,
+    output,
+    sensors::Sensors,
+    source::{read_sysfs_u64, Device},
+    sysroot,
+};
+use std::{
+    io::{self, Write},
+    time::Instant,
+};
+
+const SYSTEM_HEADER: &[&str] = &[
+    "os",
+    "cpu",
+    "gpu",
+    "ram",
+    "kernel",
+    "driver",
+    "cpuscheduler",
+];
+
+const HEADER: &[&str] = &[
+    "fps",
+    "frametime",
+    "cpu_load",
+    "cpu_power",
+    "gpu_load",
+    "cpu_temp",
+    "gpu_temp",
+    "gpu_core_clock",
+    "gpu_mem_clock",
+    "gpu_vram_used",
+    "gpu_power",
+    "ram_used",
+    "swap_used",
+    "process_rss",
+    "elapsed",
+];
+
+const GIB: f64 = (1u64 << 30) as f64;
+
+fn read(path: &str) -> Option<String> {
+    std::fs::read_to_string(sysroot::path(path)).ok()
+}
+
+/// The value of the first `key: value` line with `key` in `contents`.
+fn field<'a>(contents: &'a str, key: &str) -> Option<&'a str> {
+    contents.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        Some(value.trim()).filter(|_| name.trim() == key)
+    })
+}
+
+/// Busy and total jiffies of every CPU, from the first line of /proc/stat.
+fn cpu_jiffies() -> Option<(u64, u64)> {
+    let stat = read("/proc/stat")?;
+    let times = stat
+        .lines()
+        .next()?
+        .strip_prefix("cpu ")?
+        .split_whitespace()
+        .map(|field| field.parse::<u64>().unwrap_or_default())
+        .collect::<Vec<_>>();
+    // idle and iowait.
+    let idle = times.get(3)? + times.get(4).copied().unwrap_or_default();
+    let total = times.iter().sum::<u64>();
+    Some((total - idle, total))
+}
+
+/// Swap in use, from /proc/meminfo.
+fn swap_used_bytes() -> Option<u64> {
+    let meminfo = read("/proc/meminfo")?;
+    let kib = |key| {
+        field(&meminfo, key)?
+            .strip_suffix("kB")?
+            .trim()
+            .parse::<u64>()
+            .ok()
+    };
+    Some(kib("SwapTotal")?.saturating_sub(kib("SwapFree")?) << 10)
+}
+
+/// One log, from its system line to the last row.
+pub struct Log {
+    device: Device,
+    started: Instant,
+    cpu: Option<(u64, u64)>,
+}
+
+impl Log {
+    pub fn new(device: Device) -> Self {
+        Log {
+            device,
+            started: Instant::now(),
+            cpu: cpu_jiffies(),
+        }
+    }
+
+    /// The system line, and the header of the rows under it.
+    pub fn write_header<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let os = read("/etc/os-release")
+            .and_then(|release| {
+                release.lines().find_map(|line| {
+                    Some(
+                        line.strip_prefix("PRETTY_NAME=")?
+                            .trim_matches('"')
+                            .to_string(),
+                    )
+                })
+            })
+            .unwrap_or_default();
+        let cpu = read("/proc/cpuinfo")
+            .and_then(|cpuinfo| field(&cpuinfo, "model name").map(str::to_string))
+            .unwrap_or_default();
+        let gpu = match self.device.pci_slot() {
+            Some(slot) => format!("AMD GPU {} ({})", self.device, slot),
+            None => format!("AMD GPU {}", self.device),
+        };
+        let ram = SystemMemory::read()
+            .map(|memory| format!("{:.0} GB", memory.total_bytes as f64 / GIB))
+            .unwrap_or_default();
+        let kernel = read("/proc/sys/kernel/osrelease").unwrap_or_default();
+        let scheduler =
+            read("/sys/devices/system/cpu/cpu0/cpufreq/scaling_governor").unwrap_or_default();
+        output::write_csv_row(out, SYSTEM_HEADER)?;
+        output::write_csv_row(
+            out,
+            &[
+                os.as_str(),
+                &cpu,
+                &gpu,
+                &ram,
+                kernel.trim(),
+                "amdgpu",
+                scheduler.trim(),
+            ],
+        )?;
+        output::write_csv_row(out, HEADER)
+    }
+
+    pub fn write_row<W: Write>(&mut self, out: &mut W, sensors: &Sensors) -> io::Result<()> {
+        let cpu = cpu_jiffies();
+        let cpu_load = match (self.cpu, cpu) {
+            (Some((busy_before, total_before)), Some((busy, total))) if total > total_before => {
+                (busy - busy_before) as f64 * 100.0 / (total - total_before) as f64
+            }
+            _ => 0.0,
+        };
+        self.cpu = cpu;
+        let vram_used =
+            read_sysfs_u64(&self.device.sysfs_dir().join("mem_info_vram_used")).unwrap_or_default();
+        let ram_used = SystemMemory::read()
+            .map(|memory| memory.total_bytes.saturating_sub(memory.available_bytes))
+            .unwrap_or_default();
+        let elapsed = self.started.elapsed().as_nanos();
+        output::write_csv_row(
+            out,
+            &[
+                "0".to_string(),
+                "0".to_string(),
+                format!("{:.0}", cpu_load),
+                "0".to_string(),
+                sensors.gpu_busy_percent.unwrap_or_default().to_string(),
+                "0".to_string(),
+                sensors
+                    .edge_temperature_celsius
+                    .map_or_else(|| "0".to_string(), |celsius| format!("{:.0}", celsius)),
+                sensors.shader_clock_mhz.unwrap_or_default().to_string(),
+                sensors.memory_clock_mhz.unwrap_or_default().to_string(),
+                format!("{:.6}", vram_used as f64 / GIB),
+                sensors
+                    .power_watts
+                    .map_or_else(|| "0".to_string(), |watts| format!("{:.0}", watts)),
+                format!("{:.6}", ram_used as f64 / GIB),
+                format!("{:.6}", swap_used_bytes().unwrap_or_default() as f64 / GIB),
+                "0".to_string(),
+                elapsed.to_string(),
+            ],
+        )
+    }
+}
@@ -0,0 +1,3117 @@
+//! The per-process memory view: `amdtop mem`, and the model `top` and
+//! `export` render from.
+
+use crate::{
+    cli::{GlobalArgs, GroupBy, MemArgs, OutputFormat, Record, SortKey},
+    collectors,
+    devcoredump::{self, Coredump},
+    enrich,
+    error::{self, Error},
+    expression::Expression,
+    gem_info::{self, MemInfo, Pinned},
+    kmsg::{self, FaultLog, KernelLog, Kmsg, VmFaults},
+    meminfo::{self, SystemMemory},
+    meters, oneline, output, power,
+    schema::SCHEMA_VERSION,
+    smoothing::{Smoother, Smoothing},
+    source::{self, ClientScan, Device, DeviceSample, DeviceUsage, SourceConfig, Sources},
+    sysroot, verify, watch, FormatBytes, FormatDuration,
+};
+use serde::Serialize;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// What `/proc` tells us about a process, if anything.
+#[derive(Default, Clone)]
+pub struct ProcessIdentity {
+    pub name: Option<String>,
+    pub path: Option<String>,
+    pub cmdline: Option<String>,
+    /// Graphics and compute APIs it has loaded, see [`detect_apis`].
+    pub apis: Vec<&'static str>,
+    /// The variables of [`ENVIRONMENT`] it was started with, if we may read
+    /// its environment.
+    pub environment: HashMap<&'static str, String>,
+    /// The Flatpak or Snap it runs in, see [`sandbox_app`].
+    pub app: Option<SandboxApp>,
+    /// The Steam game it is, see [`SteamApp::from_environment`].
+    pub steam: Option<SteamApp>,
+    /// The systemd unit it runs in, see [`systemd_unit`].
+    pub unit: Option<String>,
+    /// When it started, as a Unix time, when [`MemArgs::reads_usage`].
+    pub started: Option<u64>,
+    /// System memory it has resident, from `/proc/<pid>/statm`, likewise.
+    pub rss_bytes: Option<u64>,
+    /// Whether it has GPU buffers mapped, whose GTT pages its RSS then
+    /// counts too.
+    pub maps_gpu_memory: bool,
+}
+
+/// The path of a `/proc/<pid>/maps` or `smaps` mapping line, if any.
+fn map_path(line: &str) -> &str {
+    // Address, permissions, offset, device and inode come first.
+    line.splitn(6, char::is_whitespace)
+        .nth(5)
+        .unwrap_or_default()
+        .trim_start()
+}
+
+/// Whether a mapping of `path` is GPU memory: a buffer mapped through a
+/// DRM device file, or a DMA-BUF shared with the process.
+fn is_gpu_mapping(path: &str) -> bool {
+    path.starts_with("/dev/dri/") || path.starts_with("/dmabuf:")
+}
+
+/// What of a `/proc/<pid>/smaps`'s resident set is GPU buffers mapped
+/// into the process.
+fn mapped_gpu_rss(smaps: &str) -> u64 {
+    let mut mapped = false;
+    let mut bytes = 0;
+    for line in smaps.lines() {
+        let first = line.split_whitespace().next().unwrap_or_default();
+        if first.contains('-') && !first.ends_with(':') {
+            mapped = is_gpu_mapping(map_path(line));
+        } else if let (true, Some(rss)) = (mapped, line.strip_prefix("Rss:")) {
+            let kib = rss
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .unwrap_or(0);
+            bytes += kib << 10;
+        }
+    }
+    bytes
+}
+
+/// The resident set in a `/proc/<pid>/statm`, its second field, in pages.
+fn parse_statm(statm: &str) -> Option<u64> {
+    statm.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// The kernel's page size, which is what statm and tracepoints count in.
+pub fn page_size() -> u64 {
+    (unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).max(1) as u64
+}
+
+/// The fields of a `/proc/<pid>/stat` we use, in clock ticks.
+struct ProcStat {
+    /// On the CPU, in user and kernel mode.
+    cpu_ticks: u64,
+    /// Since boot.
+    start_ticks: u64,
+}
+
+fn parse_stat(stat: &str) -> Option<ProcStat> {
+    // The name in parentheses may hold spaces and parentheses of its own.
+    let fields = stat
+        .rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    // Numbered as in proc(5), which counts the pid and the name.
+    let field = |number: usize| fields.get(number - 3)?.parse::<u64>().ok();
+    Some(ProcStat {
+        cpu_ticks: field(14)? + field(15)?,
+        start_ticks: field(22)?,
+    })
+}
+
+/// When the system booted, as a Unix time, from `btime` in `/proc/stat`.
+fn boot_time() -> Option<u64> {
+    static BOOT_TIME: OnceLock<Option<u64>> = OnceLock::new();
+    *BOOT_TIME.get_or_init(|| {
+        let stat = std::fs::read_to_string(sysroot::path("/proc/stat")).ok()?;
+        stat.lines()
+            .find_map(|line| line.strip_prefix("btime "))?
+            .trim()
+            .parse()
+            .ok()
+    })
+}
+
+fn clock_ticks_per_second() -> u64 {
+    (unsafe { libc::sysconf(libc::_SC_CLK_TCK) }).max(1) as u64
+}
+
+/// The systemd service or scope in a `/proc/<pid>/cgroup`, like
+/// `ollama.service` in `0::/system.slice/ollama.service`. Units nest, as
+/// with a user's services below `user@1000.service`, and the innermost one
+/// is the most telling. Only the unified (v2) hierarchy is looked at.
+pub fn systemd_unit(cgroup: &str) -> Option<String> {
+    let path = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+    path.rsplit('/')
+        .find(|unit| unit.ends_with(".service") || unit.ends_with(".scope"))
+        .map(str::to_string)
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct SteamApp {
+    pub app_id: u64,
+    /// From the game's `appmanifest_<id>.acf`, if we found it.
+    pub name: Option<String>,
+}
+
+impl SteamApp {
+    /// Steam starts games with `SteamAppId` set, or only `SteamGameId` for
+    /// non-Steam games added to the library.
+    fn from_environment(environment: &HashMap<&'static str, String>) -> Option<Self> {
+        let app_id = ["SteamAppId", "SteamGameId"]
+            .iter()
+            .filter_map(|name| environment.get(name)?.parse().ok())
+            .find(|app_id| *app_id != 0)?;
+
+        // The library the game is installed in, as Proton sees it,
+        // otherwise the default library.
+        let mut libraries = Vec::new();
+        if let Some(install) = environment.get("STEAM_COMPAT_INSTALL_PATH") {
+            libraries.extend(Path::new(install).ancestors().nth(2).map(Path::to_path_buf));
+        }
+        if let Some(client) = environment.get("STEAM_COMPAT_CLIENT_INSTALL_PATH") {
+            libraries.push(Path::new(client).join("steamapps"));
+        }
+        if let Some(home) = environment.get("HOME") {
+            libraries.push(Path::new(home).join(".local/share/Steam/steamapps"));
+            libraries.push(Path::new(home).join(".steam/steam/steamapps"));
+        }
+        let name = libraries.iter().find_map(|library| {
+            let manifest = library.join(format!("appmanifest_{}.acf", app_id));
+            parse_app_manifest_name(&std::fs::read_to_string(sysroot::path(manifest)).ok()?)
+        });
+        Some(SteamApp { app_id, name })
+    }
+}
+
+impl std::fmt::Display for SteamApp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} (steam {})", name, self.app_id),
+            None => write!(f, "steam app {}", self.app_id),
+        }
+    }
+}
+
+/// The `"name"` of a Steam `appmanifest_<id>.acf`, whose lines look like
+/// `\t"name"\t\t"Cyberpunk 2077"`.
+fn parse_app_manifest_name(manifest: &str) -> Option<String> {
+    manifest.lines().find_map(|line| {
+        let value = line.trim().strip_prefix("\"name\"")?.trim();
+        Some(value.strip_prefix('"')?.strip_suffix('"')?.to_string())
+    })
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", content = "id", rename_all = "kebab-case")]
+pub enum SandboxApp {
+    /// The application ID, e.g. `com.valvesoftware.Steam`.
+    Flatpak(String),
+    /// The snap name, e.g. `firefox`.
+    Snap(String),
+}
+
+impl SandboxApp {
+    pub fn id(&self) -> &str {
+        match self {
+            SandboxApp::Flatpak(id) | SandboxApp::Snap(id) => id,
+        }
+    }
+}
+
+impl std::fmt::Display for SandboxApp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SandboxApp::Flatpak(id) => write!(f, "{} (flatpak)", id),
+            SandboxApp::Snap(name) => write!(f, "{} (snap)", name),
+        }
+    }
+}
+
+/// Which Flatpak or Snap a process belongs to. Snaps run from
+/// `/snap/<name>/<revision>`. Flatpak paths are inside the sandbox, so the
+/// ID comes from the `.flatpak-info` it mounts at the root, or from the
+/// `app-flatpak-<id>-<n>.scope` cgroup when we can't look inside.
+fn sandbox_app(proc_dir: &Path, path: Option<&str>, cgroup: Option<&str>) -> Option<SandboxApp> {
+    if let Some(name) = path
+        .and_then(|path| path.strip_prefix("/snap/"))
+        .and_then(|path| path.split('/').next())
+    {
+        return Some(SandboxApp::Snap(name.to_string()));
+    }
+
+    if let Ok(info) = std::fs::read_to_string(proc_dir.join("root/.flatpak-info")) {
+        let mut in_application = false;
+        for line in info.lines() {
+            if line.starts_with('[') {
+                in_application = line == "[Application]";
+            } else if let Some(id) = line.strip_prefix("name=").filter(|_| in_application) {
+                return Some(SandboxApp::Flatpak(id.trim().to_string()));
+            }
+        }
+    }
+
+    let scope = cgroup?.lines().last()?.rsplit('/').next()?;
+    let (id, _) = scope
+        .strip_prefix("app-flatpak-")?
+        .strip_suffix(".scope")?
+        .rsplit_once('-')?;
+    Some(SandboxApp::Flatpak(id.to_string()))
+}
+
+/// Environment variables worth knowing about.
+const ENVIRONMENT: &[&str] = &[
+    "DRI_PRIME",
+    "HOME",
+    "SteamAppId",
+    "SteamGameId",
+    "STEAM_COMPAT_INSTALL_PATH",
+    "STEAM_COMPAT_CLIENT_INSTALL_PATH",
+];
+
+/// Picks [`ENVIRONMENT`] out of `/proc/<pid>/environ`.
+fn parse_environment(environ: &[u8]) -> HashMap<&'static str, String> {
+    environ
+        .split(|byte| *byte == 0)
+        .filter_map(|variable| {
+            let variable = String::from_utf8_lossy(variable);
+            let (name, value) = variable.split_once('=')?;
+            let name = ENVIRONMENT.iter().find(|known| **known == name)?;
+            Some((*name, value.to_string()))
+        })
+        .collect()
+}
+
+/// Libraries that give away an API, by file name prefix. Mesa's GL driver
+/// has had a few names over the years.
+const API_LIBRARIES: &[(&str, &str)] = &[
+    ("libvulkan", "VK"),
+    ("libGLX", "GL"),
+    ("libEGL", "GL"),
+    ("radeonsi_dri", "GL"),
+    ("libgallium", "GL"),
+    ("libva.so", "VA"),
+    ("libamdhip64", "HIP"),
+    ("libOpenCL", "CL"),
+];
+
+/// Which APIs a process uses, going by the libraries in `/proc/<pid>/maps`.
+/// Loaded isn't the same as used, but it's a good guess.
+pub fn detect_apis(maps: &str) -> Vec<&'static str> {
+    let mut apis = Vec::new();
+    for line in maps.lines() {
+        let file_name = match line.rsplit('/').next() {
+            Some(file_name) if line.contains('/') => file_name,
+            _ => continue,
+        };
+        for (prefix, api) in API_LIBRARIES {
+            if file_name.starts_with(prefix) && !apis.contains(api) {
+                apis.push(*api);
+            }
+        }
+    }
+    // In the order of the table above, not of the mappings.
+    apis.sort_by_key(|api| API_LIBRARIES.iter().position(|(_, known)| known == api));
+    apis
+}
+
+/// What Wine's loader is called, which is what `/proc/<pid>/exe` points to
+/// for every Windows program.
+const WINE_LOADERS: &[&str] = &["wine-preloader", "wine64-preloader", "wine", "wine64"];
+
+/// The Windows program a Wine or Proton process runs, e.g.
+/// `Cyberpunk2077.exe`. Wine puts its path, like
+/// `C:\Games\bin\x64\Cyberpunk2077.exe`, in the command line.
+pub fn wine_exe(path: &str, args: &[String]) -> Option<String> {
+    let loader = path.rsplit('/').next()?;
+    if !WINE_LOADERS.contains(&loader) {
+        return None;
+    }
+    args.iter()
+        .find(|arg| arg.to_ascii_lowercase().ends_with(".exe"))
+        .and_then(|exe| exe.rsplit(['\\', '/']).next())
+        .map(str::to_string)
+}
+
+/// Display servers and compositors by process name, with the name people
+/// know them by.
+const COMPOSITORS: &[(&str, &str)] = &[
+    ("Xorg", "Xorg"),
+    ("Xwayland", "Xwayland"),
+    ("gnome-shell", "Mutter"),
+    ("mutter", "Mutter"),
+    ("kwin_wayland", "KWin"),
+    ("kwin_x11", "KWin"),
+    ("gamescope", "gamescope"),
+    ("gamescope-wl", "gamescope"),
+    ("sway", "Sway"),
+    ("Hyprland", "Hyprland"),
+    ("weston", "Weston"),
+    ("cosmic-comp", "COSMIC"),
+];
+
+/// Which compositor a process is, going by its name. They hold the window
+/// and scanout buffers of the whole session, which is why they always show
+/// up near the top.
+pub fn compositor(name: &str) -> Option<&'static str> {
+    COMPOSITORS
+        .iter()
+        .find(|(process, _)| *process == name)
+        .map(|(_, compositor)| *compositor)
+}
+
+/// How long to wait before retrying a `/proc` read that failed.
+const PROC_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Remembers `/proc` reads that recently failed, so we don't repeat them for
+/// inaccessible or exited processes every refresh.
+#[derive(Default)]
+struct NegativeCache {
+    failures: HashMap<PathBuf, Instant>,
+}
+
+impl NegativeCache {
+    fn read<T, F>(&mut self, path: PathBuf, read: F) -> Option<T>
+    where
+        F: FnOnce(&Path) -> io::Result<T>,
+    {
+        let now = Instant::now();
+        if let Some(failed_at) = self.failures.get(&path) {
+            if now.duration_since(*failed_at) < PROC_RETRY_AFTER {
+                return None;
+            }
+        }
+
+        match read(&path) {
+            Ok(value) => {
+                self.failures.remove(&path);
+                Some(value)
+            }
+            Err(_) => {
+                self.failures.insert(path, now);
+                None
+            }
+        }
+    }
+
+    fn prune(&mut self) {
+        let now = Instant::now();
+        self.failures
+            .retain(|_, failed_at| now.duration_since(*failed_at) < PROC_RETRY_AFTER);
+    }
+}
+
+/// What `/proc/<pid>` says of a process that stays the same while it runs,
+/// read once rather than every refresh.
+#[derive(Clone)]
+struct Invariants {
+    /// Its `comm` and, when read, start time, which give away a new
+    /// process that took over the pid.
+    comm: String,
+    start_ticks: Option<u64>,
+    path: Option<String>,
+    args: Option<Vec<String>>,
+    environment: HashMap<&'static str, String>,
+    app: Option<SandboxApp>,
+    steam: Option<SteamApp>,
+    unit: Option<String>,
+}
+
+impl Invariants {
+    fn read(
+        proc_dir: &Path,
+        comm: String,
+        start_ticks: Option<u64>,
+        failures: &mut NegativeCache,
+    ) -> Self {
+        let path = failures
+            .read(proc_dir.join("exe"), |path| std::fs::read_link(path))
+            .map(|path| path.to_string_lossy().trim().to_string());
+        let args = failures
+            .read(proc_dir.join("cmdline"), |path| std::fs::read(path))
+            .map(|cmdline| {
+                cmdline
+                    .split(|byte| *byte == 0)
+                    .filter(|arg| !arg.is_empty())
+                    .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                    .collect::<Vec<_>>()
+            });
+        let cgroup = failures.read(proc_dir.join("cgroup"), |path| {
+            std::fs::read_to_string(path)
+        });
+        let app = sandbox_app(proc_dir, path.as_deref(), cgroup.as_deref());
+        let unit = cgroup.as_deref().and_then(systemd_unit);
+        let environment = failures
+            .read(proc_dir.join("environ"), |path| std::fs::read(path))
+            .map(|environ| parse_environment(&environ))
+            .unwrap_or_default();
+        let steam = SteamApp::from_environment(&environment);
+
+        Self {
+            comm,
+            start_ticks,
+            path,
+            args,
+            environment,
+            app,
+            steam,
+            unit,
+        }
+    }
+}
+
+impl ProcessIdentity {
+    /// Reads what `/proc` tells of `pid`, taking what doesn't change from
+    /// `known` when it's the same process as before. Its start time and RSS
+    /// are only read with `usage`, since nothing else shows them.
+    fn read(
+        pid: i32,
+        failures: &mut NegativeCache,
+        known: &mut HashMap<i32, Invariants>,
+        usage: bool,
+    ) -> Self {
+        let proc_dir = sysroot::path(format!("/proc/{}", pid));
+        let comm = match failures.read(proc_dir.join("comm"), |path| std::fs::read_to_string(path))
+        {
+            Some(comm) => comm.trim().to_string(),
+            // Gone, most likely.
+            None => return Self::default(),
+        };
+        let stat = if usage {
+            failures
+                .read(proc_dir.join("stat"), |path| std::fs::read_to_string(path))
+                .and_then(|stat| parse_stat(&stat))
+        } else {
+            None
+        };
+        let start_ticks = stat.map(|stat| stat.start_ticks);
+        let invariants = match known.get(&pid) {
+            Some(known) if known.comm == comm && known.start_ticks == start_ticks => known.clone(),
+            _ => {
+                let invariants = Invariants::read(&proc_dir, comm.clone(), start_ticks, failures);
+                known.insert(pid, invariants.clone());
+                invariants
+            }
+        };
+
+        let name = match (invariants.path.as_deref(), &invariants.args) {
+            (Some(path), Some(args)) => wine_exe(path, args).or(Some(comm)),
+            _ => Some(comm),
+        };
+        let cmdline = invariants.args.map(|args| args.join(" "));
+        let maps = failures.read(proc_dir.join("maps"), |path| std::fs::read_to_string(path));
+        let apis = maps.as_deref().map(detect_apis).unwrap_or_default();
+        let maps_gpu_memory = maps
+            .as_deref()
+            .is_some_and(|maps| maps.lines().any(|line| is_gpu_mapping(map_path(line))));
+        let started =
+            start_ticks.and_then(|ticks| Some(boot_time()? + ticks / clock_ticks_per_second()));
+        let rss_bytes = if usage {
+            failures
+                .read(proc_dir.join("statm"), |path| std::fs::read_to_string(path))
+                .and_then(|statm| parse_statm(&statm))
+                .map(|pages| pages * page_size())
+        } else {
+            None
+        };
+
+        Self {
+            name,
+            path: invariants.path,
+            cmdline,
+            apis,
+            environment: invariants.environment,
+            app: invariants.app,
+            steam: invariants.steam,
+            unit: invariants.unit,
+            started,
+            rss_bytes,
+            maps_gpu_memory,
+        }
+    }
+}
+
+/// Highest usage observed for a single process over the session.
+#[derive(Default, Clone)]
+struct Peak {
+    name: String,
+    vram_bytes: u64,
+    gtt_bytes: u64,
+}
+
+impl Peak {
+    fn update(&mut self, mem_info: &MemInfo) {
+        self.vram_bytes = self.vram_bytes.max(mem_info.vram_bytes);
+        self.gtt_bytes = self.gtt_bytes.max(mem_info.gtt_bytes);
+    }
+}
+
+/// Number of samples used to estimate the VRAM growth rate.
+const VRAM_HISTORY_LEN: usize = 10;
+
+/// Don't bother warning about exhaustion further out than this.
+const VRAM_EXHAUSTION_HORIZON: Duration = Duration::from_secs(60 * 60);
+
+/// Recent device-wide VRAM usage, oldest first.
+#[derive(Default)]
+struct VramHistory {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl VramHistory {
+    fn push(&mut self, at: Instant, used_bytes: u64) {
+        if self.samples.len() == VRAM_HISTORY_LEN {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, used_bytes));
+    }
+
+    /// Least-squares slope of usage over time, in bytes per second.
+    fn growth_rate(&self) -> Option<f64> {
+        if self.samples.len() < 3 {
+            return None;
+        }
+
+        let (origin, _) = *self.samples.front()?;
+        let points = self
+            .samples
+            .iter()
+            .map(|(at, bytes)| (at.duration_since(origin).as_secs_f64(), *bytes as f64))
+            .collect::<Vec<_>>();
+        let n = points.len() as f64;
+        let mean_t = points.iter().map(|(t, _)| t).sum::<f64>() / n;
+        let mean_b = points.iter().map(|(_, b)| b).sum::<f64>() / n;
+        let covariance = points
+            .iter()
+            .map(|(t, b)| (t - mean_t) * (b - mean_b))
+            .sum::<f64>();
+        let variance = points
+            .iter()
+            .map(|(t, _)| (t - mean_t).powi(2))
+            .sum::<f64>();
+
+        if variance > 0.0 {
+            Some(covariance / variance)
+        } else {
+            None
+        }
+    }
+
+    /// Estimated time until VRAM is full, if usage is trending upward.
+    fn time_to_exhaustion(&self, usage: DeviceUsage) -> Option<Duration> {
+        let rate = self.growth_rate().filter(|rate| *rate > 0.0)?;
+        let remaining = usage.vram_total_bytes.saturating_sub(usage.vram_used_bytes) as f64;
+        let eta = Duration::from_secs_f64(remaining / rate);
+        if eta <= VRAM_EXHAUSTION_HORIZON {
+            Some(eta)
+        } else {
+            None
+        }
+    }
+}
+
+/// State carried across refreshes.
+#[derive(Default)]
+pub struct Session {
+    /// Peaks per device, then per pid.
+    peaks: BTreeMap<Device, HashMap<i32, Peak>>,
+    /// Device-wide VRAM usage per device.
+    vram_history: HashMap<Device, VramHistory>,
+    /// Last identity successfully read from `/proc`, per pid.
+    identities: HashMap<i32, ProcessIdentity>,
+    /// What of each pid's identity is read once, while it's the same
+    /// process.
+    invariants: HashMap<i32, Invariants>,
+    /// Pids listed in the previous refresh, per device.
+    present: HashMap<Device, HashSet<i32>>,
+    /// Pids that dropped out of a device's listing, with how many more
+    /// refreshes to keep showing them.
+    departed: HashMap<Device, HashMap<i32, u32>>,
+    /// Recently failed `/proc` reads.
+    proc_failures: NegativeCache,
+    /// When we first saw each pid on each device, and whether that was on
+    /// our first look at the device, when it may have been there for long.
+    first_seen: HashMap<Device, HashMap<i32, (Instant, bool)>>,
+    /// Each process's CPU time at the last refresh.
+    cpu_times: HashMap<i32, (Instant, u64)>,
+    /// Each client's busy time per engine at the last refresh, by device
+    /// and client id.
+    engine_times: HashMap<(Device, u64), (Instant, BTreeMap<String, u64>)>,
+    /// Each process's VRAM and GTT together at the last refresh, by
+    /// device and pid.
+    alloc_totals: HashMap<(Device, i32), (Instant, u64)>,
+    /// Averages engine busy and allocation rates over refreshes, as
+    /// `--smoothing` asks. Each process's series are keyed by its pid.
+    smoother: Option<Smoother>,
+    /// Where each devcoredump we copied went, or why it couldn't, by name.
+    saved_coredumps: HashMap<String, Result<PathBuf, String>>,
+    /// Whether VM faults and coredumps are shown, so worth looking for.
+    kernel_state: bool,
+    /// The kernel log, once opened, or why it couldn't be.
+    kmsg: Option<Result<Kmsg, String>>,
+    /// What the kernel logged since the last refresh.
+    kernel_records: Vec<kmsg::Record>,
+    /// Why the kernel log couldn't be read at the last refresh.
+    kernel_error: Option<String>,
+    /// Each device's GPU page faults in the kernel log so far.
+    fault_log: FaultLog,
+}
+
+impl Session {
+    /// A session whose views have VM faults and coredumps when
+    /// `kernel_state` is set; without, only coredumps to save are seen.
+    pub fn new(kernel_state: bool) -> Self {
+        Session {
+            kernel_state,
+            ..Session::default()
+        }
+    }
+
+    /// Reads a pid's identity, falling back to the last one we saw if the
+    /// process has exited. The flag is set when the process is gone.
+    fn identity(&mut self, pid: i32, usage: bool) -> (ProcessIdentity, bool) {
+        let identity =
+            ProcessIdentity::read(pid, &mut self.proc_failures, &mut self.invariants, usage);
+        if identity.name.is_some() {
+            self.identities.insert(pid, identity.clone());
+            (identity, false)
+        } else {
+            let last_known = self.identities.get(&pid).cloned().unwrap_or_default();
+            (last_known, true)
+        }
+    }
+
+    /// Notes which pids `device` lists this refresh, and returns the ones
+    /// that recently disappeared and should still be shown.
+    fn track_departures(&mut self, device: Device, pids: HashSet<i32>, keep: u32) -> Vec<i32> {
+        let departed = self.departed.entry(device).or_default();
+        let previous = self
+            .present
+            .insert(device, pids.clone())
+            .unwrap_or_default();
+
+        for pid in previous.difference(&pids) {
+            departed.insert(*pid, keep);
+        }
+        departed.retain(|pid, remaining| {
+            let keep = *remaining > 0 && !pids.contains(pid);
+            *remaining = remaining.saturating_sub(1);
+            keep
+        });
+
+        let mut pids = departed.keys().copied().collect::<Vec<_>>();
+        pids.sort_unstable();
+        pids
+    }
+
+    /// Fills in how busy each process kept the CPU since the last refresh.
+    /// Done once for all devices, since a process's CPU time isn't per
+    /// device.
+    fn measure_cpu(&mut self, views: &mut [DeviceView]) {
+        let now = Instant::now();
+        let mut cpu_times = HashMap::new();
+        let mut percents = HashMap::new();
+        for process in views
+            .iter()
+            .flat_map(|view| view.processes.iter().flatten())
+        {
+            if cpu_times.contains_key(&process.pid) {
+                continue;
+            }
+            let stat =
+                std::fs::read_to_string(sysroot::path(format!("/proc/{}/stat", process.pid)));
+            let ticks = match stat.ok().as_deref().and_then(parse_stat) {
+                Some(stat) => stat.cpu_ticks,
+                None => continue,
+            };
+            cpu_times.insert(process.pid, (now, ticks));
+            if let Some((at, last)) = self.cpu_times.get(&process.pid) {
+                let elapsed = now.duration_since(*at).as_secs_f64();
+                let busy = ticks.saturating_sub(*last) as f64 / clock_ticks_per_second() as f64;
+                if elapsed > 0.0 {
+                    percents.insert(process.pid, busy / elapsed * 100.0);
+                }
+            }
+        }
+        for view in views {
+            for process in view.processes.iter_mut().flatten() {
+                process.cpu_percent = percents.get(&process.pid).copied();
+            }
+        }
+        self.cpu_times = cpu_times;
+    }
+
+    /// Fills in how busy each process kept each engine since the last
+    /// refresh. Every client is compared with its own last read rather
+    /// than with the last scan, which takes a while with many processes and
+    /// would skew it; what's left of the skew is clamped off.
+    fn measure_busy(
+        &mut self,
+        views: &mut [DeviceView],
+        clients: &ClientScan,
+        smoothing: Smoothing,
+    ) {
+        let mut engine_times = HashMap::new();
+        let mut busy = HashMap::<(Device, i32), BTreeMap<String, f64>>::new();
+        for client in &clients.clients {
+            // Without an id, a client can't be told from the next one.
+            let key = match client.client_id {
+                Some(client_id) => (client.device, client_id),
+                None => continue,
+            };
+            if let Some((at, last)) = self.engine_times.get(&key) {
+                let elapsed = client.read_at.saturating_duration_since(*at);
+                // Like memory, a shared client counts against one holder.
+                let percents = busy.entry((client.device, client.pids[0])).or_default();
+                for (engine, ns) in &client.engine_ns {
+                    let last = match last.get(engine) {
+                        Some(last) => *last,
+                        None => continue,
+                    };
+                    let capacity = client.engine_capacity.get(engine).copied().unwrap_or(1);
+                    *percents.entry(engine.clone()).or_default() +=
+                        busy_percent(ns.saturating_sub(last), elapsed, capacity);
+                }
+            }
+            engine_times.insert(key, (client.read_at, client.engine_ns.clone()));
+        }
+        self.engine_times = engine_times;
+
+        let smoother = self
+            .smoother
+            .get_or_insert_with(|| Smoother::new(smoothing));
+        for view in views {
+            let device = view.device;
+            for process in view.processes.iter_mut().flatten() {
+                let percents = match busy.get(&(device, process.pid)) {
+                    Some(percents) => percents,
+                    None => continue,
+                };
+                process.engine_busy = percents
+                    .iter()
+                    .map(|(engine, percent)| {
+                        let key = format!("{}:{}", process.pid, engine);
+                        let percent = smoother.smooth(device, &key, percent.min(100.0));
+                        (engine.clone(), percent)
+                    })
+                    .collect();
+            }
+        }
+    }
+
+    /// Fills in how fast each process's VRAM and GTT together grew since
+    /// the last refresh; negative when it freed some.
+    fn measure_rate(&mut self, views: &mut [DeviceView], smoothing: Smoothing) {
+        let now = Instant::now();
+        let mut alloc_totals = HashMap::new();
+        let smoother = self
+            .smoother
+            .get_or_insert_with(|| Smoother::new(smoothing));
+        for view in views {
+            let device = view.device;
+            for process in view.processes.iter_mut().flatten() {
+                let total = process.vram_bytes + process.gtt_bytes;
+                alloc_totals.insert((device, process.pid), (now, total));
+                let (at, last) = match self.alloc_totals.get(&(device, process.pid)) {
+                    Some(last) => *last,
+                    None => continue,
+                };
+                let elapsed = now.duration_since(at).as_secs_f64();
+                if elapsed > 0.0 {
+                    let rate = (total as f64 - last as f64) / elapsed;
+                    let key = format!("{}:alloc", process.pid);
+                    process.alloc_bytes_per_second = Some(smoother.smooth(device, &key, rate));
+                }
+            }
+        }
+        self.alloc_totals = alloc_totals;
+    }
+
+    /// Reads what the kernel logged since the last refresh.
+    fn read_kernel_log(&mut self) {
+        let kmsg = self
+            .kmsg
+            .get_or_insert_with(|| Kmsg::open().map_err(|err| err.to_string()));
+        let records = match kmsg {
+            Ok(kmsg) => kmsg.read().map_err(|err| err.to_string()),
+            Err(err) => Err(err.clone()),
+        };
+        match records {
+            Ok(records) => {
+                self.fault_log.add(&records);
+                self.kernel_records = records;
+                self.kernel_error = None;
+            }
+            Err(err) => {
+                self.kernel_records.clear();
+                self.kernel_error = Some(err);
+            }
+        }
+    }
+
+    /// What the kernel logged since the last refresh, or why it couldn't
+    /// be read.
+    pub fn kernel_records(&self) -> Result<&[kmsg::Record], &str> {
+        match &self.kernel_error {
+            Some(err) => Err(err),
+            None => Ok(&self.kernel_records),
+        }
+    }
+
+    /// Copies dump `name` into `dir`, unless that was tried already.
+    pub fn save_coredump(&mut self, name: &str, slot: &str, dir: &Path) {
+        self.saved_coredumps
+            .entry(name.to_string())
+            .or_insert_with(|| devcoredump::save(name, slot, dir).map_err(|err| err.to_string()));
+    }
+
+    fn coredump(&self, name: &str) -> Coredump {
+        let saved = self.saved_coredumps.get(name);
+        Coredump {
+            name: name.to_string(),
+            saved_to: saved.and_then(|saved| saved.clone().ok()),
+            error: saved.and_then(|saved| saved.clone().err()),
+        }
+    }
+
+    /// Forgets identities and smoothed series of processes we no longer
+    /// list anywhere, and failures old enough to retry.
+    pub fn prune(&mut self) {
+        self.proc_failures.prune();
+
+        let present = &self.present;
+        let departed = &self.departed;
+        let listed = |device: &Device, pid: &i32| {
+            present.get(device).is_some_and(|pids| pids.contains(pid))
+                || departed
+                    .get(device)
+                    .is_some_and(|pids| pids.contains_key(pid))
+        };
+        let listed_anywhere = |pid: &i32| {
+            present.values().any(|pids| pids.contains(pid))
+                || departed.values().any(|pids| pids.contains_key(pid))
+        };
+        self.identities.retain(|pid, _| listed_anywhere(pid));
+        self.invariants.retain(|pid, _| listed_anywhere(pid));
+        for (device, first_seen) in &mut self.first_seen {
+            first_seen.retain(|pid, _| listed(device, pid));
+        }
+        // Or a new process given the same pid would carry on its history.
+        if let Some(smoother) = &mut self.smoother {
+            smoother.retain(|device, key| {
+                let pid = key.split_once(':').and_then(|(pid, _)| pid.parse().ok());
+                pid.is_some_and(|pid| listed(&device, &pid))
+            });
+        }
+    }
+}
+
+/// A rate of allocation like `+1.50 MiB/s`, signed `-` when freeing.
+fn format_rate(bytes_per_second: f64) -> String {
+    let bytes = bytes_per_second.abs().round() as u64;
+    let sign = match bytes {
+        0 => "",
+        _ if bytes_per_second < 0.0 => "-",
+        _ => "+",
+    };
+    // Bytes have no suffix, so no space before the `/s` either.
+    let bytes = FormatBytes::new(bytes).to_string();
+    format!("{}{}/s", sign, bytes.trim_end())
+}
+
+/// What share of `elapsed` a client kept `capacity` engines busy for
+/// `busy_ns`, at most all of it.
+fn busy_percent(busy_ns: u64, elapsed: Duration, capacity: u64) -> f64 {
+    let available_ns = elapsed.as_nanos() as f64 * capacity.max(1) as f64;
+    if available_ns == 0.0 {
+        return 0.0;
+    }
+    (busy_ns as f64 / available_ns * 100.0).min(100.0)
+}
+
+/// Memory that no process accounts for: allocations `amdgpu_gem_info` lists
+/// outside of any client, plus whatever device-level usage the per-process
+/// totals don't cover.
+fn unattributed(mem_infos: &[MemInfo], usage: Option<DeviceUsage>) -> MemInfo {
+    let mut unattributed = MemInfo::default();
+    let mut attributed = MemInfo::default();
+
+    for mem_info in mem_infos {
+        let bucket = if mem_info.pid <= 0 {
+            &mut unattributed
+        } else {
+            &mut attributed
+        };
+        bucket.vram_bytes += mem_info.vram_bytes;
+        bucket.gtt_bytes += mem_info.gtt_bytes;
+    }
+
+    if let Some(usage) = usage {
+        let listed_vram = attributed.vram_bytes + unattributed.vram_bytes;
+        unattributed.vram_bytes += usage.vram_used_bytes.saturating_sub(listed_vram);
+
+        if let Some(gtt_used_bytes) = usage.gtt_used_bytes {
+            let listed_gtt = attributed.gtt_bytes + unattributed.gtt_bytes;
+            unattributed.gtt_bytes += gtt_used_bytes.saturating_sub(listed_gtt);
+        }
+    }
+
+    unattributed
+}
+
+/// One process's row in the table.
+#[derive(Serialize, Clone)]
+pub struct ProcessRow {
+    pub pid: i32,
+    pub name: Option<String>,
+    pub path: Option<String>,
+    /// The Flatpak or Snap it runs in, whose paths say little.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app: Option<SandboxApp>,
+    /// The Steam game it is, when Steam started it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub steam: Option<SteamApp>,
+    /// The systemd service or scope it runs in.
+    pub unit: Option<String>,
+    /// When it started, as a Unix time, with the options needing it.
+    pub started: Option<u64>,
+    /// How long it has held memory on this device, as far as we've seen.
+    pub on_gpu_seconds: u64,
+    /// Set when it already held memory when we first looked, so it has
+    /// for longer than `on_gpu_seconds`.
+    pub on_gpu_before_us: bool,
+    /// System memory it has resident, with VRAM and GTT its whole
+    /// footprint, with the options needing it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rss_bytes: Option<u64>,
+    /// RSS, VRAM and GTT together, with `--show-footprint`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub footprint_bytes: Option<u64>,
+    /// Set when the footprint may count GTT twice, because the process
+    /// has some mapped and `--accurate` didn't take it out of the RSS.
+    pub footprint_may_double_count: bool,
+    /// How busy it kept the CPU since the last refresh, 100 for one core,
+    /// with `--show-cpu`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_percent: Option<f64>,
+    /// Set while we keep showing a process that has gone away.
+    pub exited: bool,
+    pub vram_bytes: u64,
+    pub gtt_bytes: u64,
+    pub peak_vram_bytes: u64,
+    pub peak_gtt_bytes: u64,
+    /// Memory it wants in VRAM that the kernel has moved out to GTT, from
+    /// fdinfo on 6.4 and later. Games stutter when this isn't zero.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub evicted_vram_bytes: Option<u64>,
+    /// What of its memory is pinned, from `amdgpu_gem_info`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned: Option<Pinned>,
+    /// DRM clients (open device files) the process holds on this device.
+    /// amdgpu doesn't say how many contexts each one has created, so this is
+    /// as close as we get to counting them.
+    pub drm_clients: Option<usize>,
+    /// Their `drm-client-id`s, where the kernel reports them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub client_ids: Vec<u64>,
+    /// Other processes holding one of the same clients, through a forked or
+    /// passed descriptor. The client's memory is only counted once, against
+    /// one of them.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub shared_with: Vec<i32>,
+    /// KFD compute queues it has created on this device.
+    pub kfd_queues: Option<usize>,
+    /// How long it has kept each engine busy, from fdinfo.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub engine_ns: BTreeMap<String, u64>,
+    /// The percentage of each engine it kept busy since the last refresh,
+    /// with `--show-busy`, smoothed as `--smoothing` asks.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub engine_busy: BTreeMap<String, f64>,
+    /// How fast its VRAM and GTT together grew since the last refresh, in
+    /// bytes per second, with `--show-rate`, smoothed as `--smoothing` asks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alloc_bytes_per_second: Option<f64>,
+    /// What each `--column` computed, `None` where a field it reads has no
+    /// value.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub columns: BTreeMap<String, Option<f64>>,
+    /// What the `--enrich` command added.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, String>,
+    /// `VK`, `GL`, `VA`, `HIP` or `CL`, from the libraries it has loaded.
+    pub apis: Vec<&'static str>,
+    /// Set for the display server or compositor, e.g. `Mutter`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compositor: Option<&'static str>,
+    /// Every device the process has memory on, this one included.
+    pub devices: Vec<Device>,
+    /// Which GPU it asked Mesa for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dri_prime: Option<String>,
+    /// On a hybrid laptop, the device holding most of its memory when that
+    /// isn't the GPU `DRI_PRIME` asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unexpected_gpu: Option<Device>,
+}
+
+/// Orders names ignoring case, so `blender` sorts with `Blender` rather than
+/// after every capital, and exactly only between those that differ in case
+/// alone. Missing names go first.
+fn compare_names(a: Option<&str>, b: Option<&str>) -> Ordering {
+    let folded = |name: Option<&str>| name.map(str::to_lowercase);
+    folded(a).cmp(&folded(b)).then_with(|| a.cmp(&b))
+}
+
+/// Orders `processes` by `keys`, each breaking the ties of the one before,
+/// and by pid after them.
+fn sort_processes(processes: &mut [ProcessRow], keys: &[SortKey]) {
+    let by = |key: SortKey, a: &ProcessRow, b: &ProcessRow| match key {
+        SortKey::Total => (b.vram_bytes + b.gtt_bytes).cmp(&(a.vram_bytes + a.gtt_bytes)),
+        SortKey::Vram => b.vram_bytes.cmp(&a.vram_bytes),
+        SortKey::Gtt => b.gtt_bytes.cmp(&a.gtt_bytes),
+        SortKey::PeakVram => b.peak_vram_bytes.cmp(&a.peak_vram_bytes),
+        SortKey::PeakGtt => b.peak_gtt_bytes.cmp(&a.peak_gtt_bytes),
+        SortKey::Pid => a.pid.cmp(&b.pid),
+        SortKey::Name => compare_names(a.name.as_deref(), b.name.as_deref()),
+    };
+    processes.sort_by(|a, b| {
+        keys.iter()
+            .fold(Ordering::Equal, |order, key| {
+                order.then_with(|| by(*key, a, b))
+            })
+            .then_with(|| a.pid.cmp(&b.pid))
+    });
+}
+
+/// `sort_processes` for `--group-by unit`, where only sizes and names
+/// mean anything, and the unit's name breaks ties.
+fn sort_units(units: &mut [UnitUsage], keys: &[SortKey]) {
+    let by = |key: SortKey, a: &UnitUsage, b: &UnitUsage| match key {
+        SortKey::Total => {
+            (b.usage.vram_bytes + b.usage.gtt_bytes).cmp(&(a.usage.vram_bytes + a.usage.gtt_bytes))
+        }
+        SortKey::Vram => b.usage.vram_bytes.cmp(&a.usage.vram_bytes),
+        SortKey::Gtt => b.usage.gtt_bytes.cmp(&a.usage.gtt_bytes),
+        SortKey::Name => compare_names(a.unit.as_deref(), b.unit.as_deref()),
+        SortKey::PeakVram | SortKey::PeakGtt | SortKey::Pid => Ordering::Equal,
+    };
+    units.sort_by(|a, b| {
+        keys.iter()
+            .fold(Ordering::Equal, |order, key| {
+                order.then_with(|| by(*key, a, b))
+            })
+            .then_with(|| compare_names(a.unit.as_deref(), b.unit.as_deref()))
+    });
+}
+
+/// What `--column` expressions can read, for each process and its device.
+pub const COLUMN_FIELDS: &[&str] = &[
+    "pid",
+    "vram",
+    "gtt",
+    "total",
+    "peak_vram",
+    "peak_gtt",
+    "evicted",
+    "rss",
+    "footprint",
+    "cpu",
+    "busy",
+    "clients",
+    "queues",
+    "age",
+    "on_gpu",
+    "device.vram_used",
+    "device.vram_total",
+    "device.gtt_used",
+];
+
+/// A column `--column` adds: its name, and what to compute for each row.
+#[derive(Clone)]
+pub struct ComputedColumn {
+    pub name: String,
+    pub expression: Expression,
+}
+
+impl ComputedColumn {
+    /// Parses `NAME=EXPR`, checking the fields `EXPR` reads.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (name, expression) = value
+            .split_once('=')
+            .ok_or_else(|| "expected NAME=EXPR".to_string())?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("the column needs a name".to_string());
+        }
+        let expression = Expression::parse(expression)?;
+        if let Some(unknown) = expression
+            .fields()
+            .into_iter()
+            .find(|field| !COLUMN_FIELDS.contains(field))
+        {
+            return Err(format!(
+                "there's no field {}; there are {}",
+                unknown,
+                COLUMN_FIELDS.join(", ")
+            ));
+        }
+        Ok(ComputedColumn {
+            name: name.to_string(),
+            expression,
+        })
+    }
+}
+
+/// Field `name` of `process` on `view`'s device, for `--column`. Sizes are
+/// in bytes and times in seconds.
+fn column_field(view: &DeviceView, process: &ProcessRow, name: &str) -> Option<f64> {
+    let bytes = |bytes: u64| Some(bytes as f64);
+    match name {
+        "pid" => Some(f64::from(process.pid)),
+        "vram" => bytes(process.vram_bytes),
+        "gtt" => bytes(process.gtt_bytes),
+        "total" => bytes(process.vram_bytes + process.gtt_bytes),
+        "peak_vram" => bytes(process.peak_vram_bytes),
+        "peak_gtt" => bytes(process.peak_gtt_bytes),
+        "evicted" => bytes(process.evicted_vram_bytes?),
+        "rss" => bytes(process.rss_bytes?),
+        "footprint" => bytes(process.footprint_bytes?),
+        "cpu" => process.cpu_percent,
+        "busy" => process.engine_busy.values().copied().reduce(f64::max),
+        "clients" => Some(process.drm_clients? as f64),
+        "queues" => Some(process.kfd_queues? as f64),
+        "age" => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            Some(now.saturating_sub(process.started?) as f64)
+        }
+        "on_gpu" => Some(process.on_gpu_seconds as f64),
+        "device.vram_used" => bytes(view.usage?.vram_used_bytes),
+        "device.vram_total" => bytes(view.usage?.vram_total_bytes),
+        "device.gtt_used" => bytes(view.usage?.gtt_used_bytes?),
+        _ => None,
+    }
+}
+
+/// Fills in what each `--column` computes for every process.
+fn compute_columns(views: &mut [DeviceView], columns: &[ComputedColumn]) {
+    for view in views {
+        let values = view
+            .processes
+            .iter()
+            .flatten()
+            .map(|process| {
+                columns
+                    .iter()
+                    .map(|column| {
+                        let value = column
+                            .expression
+                            .eval(&|name| column_field(view, process, name));
+                        (column.name.clone(), value)
+                    })
+                    .collect::<BTreeMap<_, _>>()
+            })
+            .collect::<Vec<_>>();
+        for (process, values) in view.processes.iter_mut().flatten().zip(values) {
+            process.columns = values;
+        }
+    }
+}
+
+/// Memory summed over several processes, or none in particular.
+#[derive(Serialize, Default, Copy, Clone)]
+pub struct Usage {
+    /// How many processes went into it, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub processes: Option<usize>,
+    pub vram_bytes: u64,
+    pub gtt_bytes: u64,
+}
+
+/// Everything shown for one device in a refresh.
+#[derive(Serialize)]
+pub struct DeviceView {
+    /// Of [`crate::schema::SCHEMA`], which the JSON follows.
+    pub schema_version: u32,
+    pub device: Device,
+    /// The driver of a device shown with `--generic-drm`, which only has
+    /// what fdinfo says: VRAM is the memory of its own, if any, and GTT
+    /// system memory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generic_drm: Option<String>,
+    pub usage: Option<DeviceUsage>,
+    /// Estimated seconds until VRAM runs out, if it's getting there.
+    pub vram_full_in_seconds: Option<u64>,
+    /// An SR-IOV virtual function.
+    pub virtual_function: bool,
+    /// `power_dpm_force_performance_level`, e.g. `auto`.
+    pub performance_level: Option<String>,
+    /// Pinned memory of every client on the device, where the source can
+    /// tell. The rest can be evicted under pressure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pinned: Option<Pinned>,
+    /// GPU page faults in the kernel log, when we may read it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vm_faults: Option<VmFaults>,
+    /// Coredumps of GPU hangs the kernel still holds.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub coredumps: Vec<Coredump>,
+    /// `None` when no source could attribute memory to processes.
+    pub processes: Option<Vec<ProcessRow>>,
+    /// Processes left out by `--top`.
+    pub rest: Option<Usage>,
+    /// Memory no process accounts for, unless `--no-kernel-row`.
+    pub unattributed: Option<Usage>,
+    /// The processes summed up per unit, with `--group-by unit`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub units: Option<Vec<UnitUsage>>,
+    /// Whether the table has a UNIT column, from `--show-unit`.
+    #[serde(skip)]
+    pub show_unit: bool,
+    /// Whether it has AGE and ON GPU columns, from `--show-age`.
+    #[serde(skip)]
+    pub show_age: bool,
+    /// Whether it has a CPU% column, from `--show-cpu`.
+    #[serde(skip)]
+    pub show_cpu: bool,
+    /// Whether it has an RSS column, from `--show-rss`.
+    #[serde(skip)]
+    pub show_rss: bool,
+    /// Whether it has a FOOTPRINT column, from `--show-footprint`.
+    #[serde(skip)]
+    pub show_footprint: bool,
+    /// Whether it has a BUSY column, from `--show-busy`.
+    #[serde(skip)]
+    pub show_busy: bool,
+    /// Whether it has a RATE column, from `--show-rate`.
+    #[serde(skip)]
+    pub show_rate: bool,
+    /// The names of the columns `--column` adds, in order.
+    #[serde(skip)]
+    pub columns: Vec<String>,
+    /// The fields `--enrich` added to any of the processes.
+    #[serde(skip)]
+    pub extra_columns: Vec<String>,
+}
+
+/// The processes of one systemd unit, summed up.
+#[derive(Serialize)]
+pub struct UnitUsage {
+    /// `None` for processes outside of any unit, or whose cgroup we
+    /// couldn't read.
+    pub unit: Option<String>,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+/// Sums processes up by unit, largest first.
+fn group_by_unit(processes: &[ProcessRow]) -> Vec<UnitUsage> {
+    let mut units: HashMap<Option<&str>, Usage> = HashMap::new();
+    for process in processes {
+        let usage = units.entry(process.unit.as_deref()).or_insert(Usage {
+            processes: Some(0),
+            ..Usage::default()
+        });
+        usage.processes = usage.processes.map(|count| count + 1);
+        usage.vram_bytes += process.vram_bytes;
+        usage.gtt_bytes += process.gtt_bytes;
+    }
+    let mut units = units
+        .into_iter()
+        .map(|(unit, usage)| UnitUsage {
+            unit: unit.map(str::to_string),
+            usage,
+        })
+        .collect::<Vec<_>>();
+    units.sort_by(|a, b| {
+        (b.usage.vram_bytes + b.usage.gtt_bytes)
+            .cmp(&(a.usage.vram_bytes + a.usage.gtt_bytes))
+            .then_with(|| compare_names(a.unit.as_deref(), b.unit.as_deref()))
+    });
+    units
+}
+
+impl Session {
+    /// Turns a sample into what we show for it, tracking peaks and departed
+    /// processes along the way.
+    pub fn view(
+        &mut self,
+        options: &MemArgs,
+        sample: DeviceSample,
+        clients: &ClientScan,
+    ) -> DeviceView {
+        let device = sample.device;
+        let usage = sample.usage;
+
+        let eta = usage.and_then(|usage| {
+            let history = self.vram_history.entry(device).or_default();
+            history.push(Instant::now(), usage.vram_used_bytes);
+            history.time_to_exhaustion(usage)
+        });
+
+        let mut view = DeviceView {
+            schema_version: SCHEMA_VERSION,
+            device,
+            generic_drm: None,
+            usage,
+            vram_full_in_seconds: eta.map(|eta| eta.as_secs()),
+            virtual_function: device.is_virtual_function(),
+            performance_level: power::performance_level(device),
+            pinned: None,
+            vm_faults: None,
+            coredumps: Vec::new(),
+            processes: None,
+            rest: None,
+            unattributed: None,
+            units: None,
+            show_unit: options.show_unit,
+            show_age: options.show_age,
+            show_cpu: options.show_cpu,
+            show_rss: options.show_rss,
+            show_footprint: options.show_footprint,
+            show_busy: options.show_busy,
+            show_rate: options.show_rate,
+            columns: options
+                .columns
+                .iter()
+                .map(|column| column.name.clone())
+                .collect(),
+            extra_columns: Vec::new(),
+        };
+
+        let mem_infos = match sample.mem_infos {
+            Some(mem_infos) => mem_infos,
+            None => return view,
+        };
+
+        view.pinned = mem_infos
+            .iter()
+            .filter_map(|mem_info| mem_info.pinned)
+            .reduce(|mut total, pinned| {
+                total.add(pinned);
+                total
+            });
+
+        let pids = mem_infos
+            .iter()
+            .map(|mem_info| mem_info.pid)
+            .filter(|pid| *pid > 0)
+            .collect::<HashSet<_>>();
+        let first_look = !self.present.contains_key(&device);
+        let departed = self.track_departures(device, pids, options.keep_exited);
+        let now = Instant::now();
+        let first_seen = self.first_seen.entry(device).or_default();
+        for mem_info in mem_infos.iter().filter(|mem_info| mem_info.pid > 0) {
+            first_seen.entry(mem_info.pid).or_insert((now, first_look));
+        }
+
+        let mut processes = Vec::new();
+        let mut rest = Usage {
+            processes: Some(0),
+            ..Usage::default()
+        };
+
+        let rows = mem_infos
+            .iter()
+            .copied()
+            .filter(|mem_info| mem_info.pid > 0)
+            .chain(departed.into_iter().map(|pid| MemInfo {
+                pid,
+                ..MemInfo::default()
+            }));
+
+        for mem_info in rows {
+            let (identity, exited) = self.identity(mem_info.pid, options.reads_usage());
+            if !options.matches(&identity) {
+                continue;
+            }
+
+            let peak = self
+                .peaks
+                .entry(device)
+                .or_default()
+                .entry(mem_info.pid)
+                .or_default();
+            peak.update(&mem_info);
+            if let Some(name) = &identity.name {
+                peak.name = name.clone();
+            } else if peak.name.is_empty() {
+                peak.name = "unknown".to_string();
+            }
+
+            if mem_info.vram_bytes + mem_info.gtt_bytes < options.min_size && !exited {
+                continue;
+            }
+
+            // With --sort, what's left out depends on the order.
+            let pinned = options.pin.contains(&mem_info.pid);
+            if !pinned
+                && options.sort.is_empty()
+                && options.top.is_some_and(|top| {
+                    let shown = processes
+                        .iter()
+                        .filter(|process: &&ProcessRow| !options.pin.contains(&process.pid))
+                        .count();
+                    shown >= top
+                })
+            {
+                rest.vram_bytes += mem_info.vram_bytes;
+                rest.gtt_bytes += mem_info.gtt_bytes;
+                rest.processes = rest.processes.map(|count| count + 1);
+                continue;
+            }
+
+            let (seen_at, on_gpu_before_us) = self.first_seen[&device]
+                .get(&mem_info.pid)
+                .copied()
+                .unwrap_or((now, false));
+            let rss_bytes = identity.rss_bytes.filter(|_| !exited);
+            let (footprint_bytes, footprint_may_double_count) = match rss_bytes {
+                Some(rss_bytes) if options.show_footprint => {
+                    let mapped = if options.accurate {
+                        let smaps = sysroot::path(format!("/proc/{}/smaps", mem_info.pid));
+                        self.proc_failures
+                            .read(smaps, |path| std::fs::read_to_string(path))
+                            .map(|smaps| mapped_gpu_rss(&smaps))
+                    } else {
+                        None
+                    };
+                    // Only GTT pages count in the RSS; VRAM seen through
+                    // the BAR doesn't.
+                    let counted_twice = mapped.unwrap_or(0).min(mem_info.gtt_bytes);
+                    let footprint =
+                        rss_bytes + mem_info.vram_bytes + mem_info.gtt_bytes - counted_twice;
+                    (
+                        Some(footprint),
+                        mapped.is_none() && identity.maps_gpu_memory,
+                    )
+                }
+                _ => (None, false),
+            };
+            let held = clients.clients_of(device, mem_info.pid).filter(|_| !exited);
+            let mut shared_with = held
+                .iter()
+                .flatten()
+                .flat_map(|client| client.pids.iter().copied())
+                .filter(|pid| *pid != mem_info.pid)
+                .collect::<Vec<_>>();
+            shared_with.sort_unstable();
+            shared_with.dedup();
+            // Like memory, a shared client's time counts against one holder.
+            let mut engine_ns = BTreeMap::<String, u64>::new();
+            let mut evicted_vram_bytes = None;
+            for client in held.iter().flatten() {
+                if client.pids[0] == mem_info.pid {
+                    for (engine, ns) in &client.engine_ns {
+                        *engine_ns.entry(engine.clone()).or_default() += ns;
+                    }
+                    if let Some(evicted) = client.evicted_vram_bytes {
+                        *evicted_vram_bytes.get_or_insert(0) += evicted;
+                    }
+                }
+            }
+            processes.push(ProcessRow {
+                drm_clients: held.as_ref().map(Vec::len),
+                client_ids: held
+                    .iter()
+                    .flatten()
+                    .filter_map(|client| client.client_id)
+                    .collect(),
+                shared_with,
+                engine_ns,
+                engine_busy: BTreeMap::new(),
+                alloc_bytes_per_second: None,
+                columns: BTreeMap::new(),
+                extra: BTreeMap::new(),
+                kfd_queues: source::kfd_queue_counts(mem_info.pid)
+                    .map(|counts| counts.get(&device).copied().unwrap_or_default())
+                    .filter(|_| !exited),
+                pid: mem_info.pid,
+                apis: identity.apis,
+                compositor: identity.name.as_deref().and_then(compositor),
+                devices: vec![device],
+                dri_prime: identity.environment.get("DRI_PRIME").cloned(),
+                unexpected_gpu: None,
+                name: identity.name,
+                path: identity.path,
+                app: identity.app,
+                steam: identity.steam,
+                unit: identity.unit,
+                started: identity.started,
+                on_gpu_seconds: now.duration_since(seen_at).as_secs(),
+                on_gpu_before_us,
+                cpu_percent: None,
+                rss_bytes,
+                footprint_bytes,
+                footprint_may_double_count,
+                exited,
+                vram_bytes: mem_info.vram_bytes,
+                gtt_bytes: mem_info.gtt_bytes,
+                peak_vram_bytes: peak.vram_bytes,
+                peak_gtt_bytes: peak.gtt_bytes,
+                pinned: mem_info.pinned,
+                evicted_vram_bytes,
+            });
+        }
+
+        if !options.sort.is_empty() {
+            sort_processes(&mut processes, &options.sort);
+        }
+        // Stable, so pinned rows keep their order among themselves.
+        processes.sort_by_key(|process| !options.pin.contains(&process.pid));
+        if !options.sort.is_empty() {
+            if let Some(top) = options.top {
+                let pinned = processes
+                    .iter()
+                    .filter(|process| options.pin.contains(&process.pid))
+                    .count();
+                let cut = (pinned + top).min(processes.len());
+                for process in processes.drain(cut..) {
+                    rest.vram_bytes += process.vram_bytes;
+                    rest.gtt_bytes += process.gtt_bytes;
+                    rest.processes = rest.processes.map(|count| count + 1);
+                }
+            }
+        }
+        if rest.processes != Some(0) {
+            view.rest = Some(rest);
+        }
+
+        if !options.no_kernel_row {
+            let kernel = unattributed(&mem_infos, usage);
+            if kernel.vram_bytes + kernel.gtt_bytes > 0 {
+                view.unattributed = Some(Usage {
+                    processes: None,
+                    vram_bytes: kernel.vram_bytes,
+                    gtt_bytes: kernel.gtt_bytes,
+                });
+            }
+        }
+
+        if options.group_by == Some(GroupBy::Unit) {
+            let mut units = group_by_unit(&processes);
+            if !options.sort.is_empty() {
+                sort_units(&mut units, &options.sort);
+            }
+            view.units = Some(units);
+        }
+        view.processes = Some(processes);
+        view
+    }
+}
+
+/// Which GPU a `DRI_PRIME` value asks for: `0` is the default one, which
+/// drives the display and on laptops is the integrated GPU, any other
+/// number the discrete one, and `pci-0000_03_00_0` a particular device.
+/// `None` when it means something else, like a `vendor:device` pair.
+fn dri_prime_device(dri_prime: &str, integrated: &HashSet<Device>) -> Option<Vec<Device>> {
+    if let Some(slot) = dri_prime.strip_prefix("pci-") {
+        // The last two underscores stand for `:` and `.`.
+        let mut slot = slot.to_string();
+        let dot = slot.rfind('_')?;
+        slot.replace_range(dot..=dot, ".");
+        let slot = slot.replace('_', ":");
+        return Some(Device::from_pci_slot(&slot).into_iter().collect());
+    }
+    let wants_discrete = dri_prime.parse::<u32>().ok()? > 0;
+    Some(
+        Device::list()
+            .into_iter()
+            .filter(|device| integrated.contains(device) != wants_discrete)
+            .collect(),
+    )
+}
+
+/// Fills in which devices each process has memory on, and flags those
+/// rendering on another GPU than the one they asked for. Only hybrid
+/// systems, with both an integrated and a discrete GPU, get flagged.
+fn attribute_gpus(views: &mut [DeviceView]) {
+    let mut footprints: HashMap<i32, Vec<(Device, u64)>> = HashMap::new();
+    for view in views.iter() {
+        for process in view.processes.iter().flatten() {
+            let bytes = process.vram_bytes + process.gtt_bytes;
+            if bytes > 0 && !process.exited {
+                footprints
+                    .entry(process.pid)
+                    .or_default()
+                    .push((view.device, bytes));
+            }
+        }
+    }
+
+    let all = Device::list();
+    let integrated = all
+        .iter()
+        .copied()
+        .filter(Device::is_integrated)
+        .collect::<HashSet<_>>();
+    let hybrid = !integrated.is_empty() && integrated.len() < all.len();
+
+    for view in views.iter_mut() {
+        for process in view.processes.iter_mut().flatten() {
+            let footprint = match footprints.get(&process.pid) {
+                Some(footprint) => footprint,
+                None => continue,
+            };
+            process.devices = footprint.iter().map(|(device, _)| *device).collect();
+            let rendering_on = footprint
+                .iter()
+                .max_by_key(|(_, bytes)| *bytes)
+                .map(|(device, _)| *device);
+            let expected = process
+                .dri_prime
+                .as_deref()
+                .filter(|_| hybrid)
+                .and_then(|dri_prime| dri_prime_device(dri_prime, &integrated));
+            if let (Some(expected), Some(rendering_on)) = (expected, rendering_on) {
+                process.unexpected_gpu = Some(rendering_on).filter(|on| !expected.contains(on));
+            }
+        }
+    }
+}
+
+impl ProcessRow {
+    /// The path column: the game for Steam games and the app for sandboxed
+    /// processes.
+    pub fn display_path(&self) -> String {
+        match (&self.steam, &self.app, &self.path) {
+            (Some(steam), _, _) => steam.to_string(),
+            (None, Some(app), _) => app.to_string(),
+            (None, None, Some(path)) => path.clone(),
+            (None, None, None) => "unknown".to_string(),
+        }
+    }
+
+    /// The name column, marking processes that have exited.
+    pub fn display_name(&self) -> String {
+        match (&self.name, self.exited) {
+            (Some(name), false) => name.clone(),
+            (Some(name), true) => format!("<exited> {}", name),
+            (None, _) => "<exited>".to_string(),
+        }
+    }
+}
+
+/// How much room the dashes under the table leave for unit names.
+const UNIT_COLUMN_WIDTH: usize = 40;
+
+/// The table for `--group-by unit`.
+fn write_units<W: Write>(
+    out: &mut W,
+    units: &[UnitUsage],
+    unattributed: Option<Usage>,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "{0: <1$} | {2: >9} | {3: >15} | {4: >15} | {5: >15}",
+        "UNIT", UNIT_COLUMN_WIDTH, "PROCESSES", "TOTAL", "VRAM", "GTT"
+    )?;
+    writeln!(out, "{:-^1$}", "", UNIT_COLUMN_WIDTH + 68)?;
+    let rows = units
+        .iter()
+        .map(|unit| (unit.unit.as_deref().unwrap_or("-"), unit.usage))
+        .chain(unattributed.map(|kernel| ("kernel/unattributed", kernel)));
+    for (unit, usage) in rows {
+        writeln!(
+            out,
+            "{0: <1$} | {2: >9} | {3: >15} | {4: >15} | {5: >15}",
+            unit,
+            UNIT_COLUMN_WIDTH,
+            usage
+                .processes
+                .map_or_else(String::new, |count| count.to_string()),
+            FormatBytes::new(usage.vram_bytes + usage.gtt_bytes),
+            FormatBytes::new(usage.vram_bytes),
+            FormatBytes::new(usage.gtt_bytes),
+        )?;
+    }
+    Ok(())
+}
+
+pub fn write_table<W: Write>(out: &mut W, view: &DeviceView) -> io::Result<()> {
+    let mut header = view.device.to_string();
+    if let Some(driver) = &view.generic_drm {
+        header += &format!(" | {}, generic DRM", driver);
+    }
+    if view.virtual_function {
+        header += " | VF";
+    }
+    if let Some(usage) = view.usage {
+        header += &format!(
+            " | VRAM {} / {}",
+            FormatBytes::new(usage.vram_used_bytes),
+            FormatBytes::new(usage.vram_total_bytes),
+        );
+        if let Some(eta) = view.vram_full_in_seconds {
+            header += &format!(
+                " | VRAM full in ~{}",
+                FormatDuration::new(Duration::from_secs(eta))
+            );
+        }
+    }
+    if let Some(level) = &view.performance_level {
+        header += &format!(" | perf {}", level);
+    }
+    if let Some(faults) = view.vm_faults.as_ref().filter(|faults| faults.total > 0) {
+        header += &format!(" | {} VM faults", faults.total);
+        if faults.recent > 0 {
+            header += &format!(", {} in the last minute!", faults.recent);
+        }
+        if let Some(process) = &faults.last_process {
+            header += &format!(", last by {}", process);
+        }
+    }
+    for coredump in &view.coredumps {
+        header += &match (&coredump.saved_to, &coredump.error) {
+            (Some(path), _) => format!(" | {} saved to {}", coredump.name, path.display()),
+            (None, Some(error)) => format!(" | {} not saved: {}", coredump.name, error),
+            (None, None) => format!(
+                " | {} waiting, gone 5 minutes after the hang!",
+                coredump.name
+            ),
+        };
+    }
+    writeln!(out, "{}", header)?;
+
+    let processes = match &view.processes {
+        Some(processes) => processes,
+        None => return Ok(()),
+    };
+    if let Some(units) = &view.units {
+        return write_units(out, units, view.unattributed);
+    }
+
+    let mut header = format!(
+        "{0: <10} | {1: <20} | {2: <60} | {3: >15} | {4: >15} | {5: >15} | {6: >15} | {7: >15} | {8: >7} | {9: >6} | {10: >10}",
+        "PID", "PROCESS", "PATH", "TOTAL", "VRAM", "GTT", "PEAK VRAM", "PEAK GTT", "CLIENTS", "QUEUES", "API"
+    );
+    let mut width = 218;
+    // The cells after GTT, left empty in the rows for the rest and the
+    // kernel.
+    let mut blanks = format!(
+        " | {: >15} | {: >15} | {: >7} | {: >6} | {: >10}",
+        "", "", "", "", ""
+    );
+    // Only when there's something to see, since it's rarely anything but 0.
+    let show_evicted = processes
+        .iter()
+        .any(|process| process.evicted_vram_bytes.unwrap_or_default() > 0);
+    if show_evicted {
+        header += &format!(" | {: >15}", "EVICTED");
+        blanks += &format!(" | {: >15}", "");
+        width += 18;
+    }
+    if view.show_age {
+        header += &format!(" | {: >10} | {: >10}", "AGE", "ON GPU");
+        blanks += &format!(" | {: >10} | {: >10}", "", "");
+        width += 26;
+    }
+    if view.show_cpu {
+        header += &format!(" | {: >6}", "CPU%");
+        blanks += &format!(" | {: >6}", "");
+        width += 9;
+    }
+    if view.show_rss {
+        header += &format!(" | {: >15}", "RSS");
+        blanks += &format!(" | {: >15}", "");
+        width += 18;
+    }
+    if view.show_footprint {
+        header += &format!(" | {: >16}", "FOOTPRINT");
+        blanks += &format!(" | {: >16}", "");
+        width += 19;
+    }
+    if view.show_busy {
+        header += &format!(" | {: >12}", "BUSY");
+        blanks += &format!(" | {: >12}", "");
+        width += 15;
+    }
+    if view.show_rate {
+        header += &format!(" | {: >15}", "RATE");
+        blanks += &format!(" | {: >15}", "");
+        width += 18;
+    }
+    for name in &view.columns {
+        header += &format!(" | {: >12}", name);
+        blanks += &format!(" | {: >12}", "");
+        width += 15;
+    }
+    for name in &view.extra_columns {
+        header += &format!(" | {: >16}", name);
+        blanks += &format!(" | {: >16}", "");
+        width += 19;
+    }
+    if view.show_unit {
+        header += " | UNIT";
+        blanks += " | ";
+        width += UNIT_COLUMN_WIDTH + 3;
+    }
+    writeln!(out, "{}", header)?;
+
+    writeln!(out, "{:-^1$}", "", width)?;
+
+    let count =
+        |count: Option<usize>| count.map_or_else(|| "-".to_string(), |count| count.to_string());
+
+    for process in processes {
+        // Marked, and explained below the table.
+        let mut clients = count(process.drm_clients);
+        if !process.shared_with.is_empty() {
+            clients.push('*');
+        }
+        let mut line = format!(
+            "{0: <10} | {1: <20} | {2: <60} | {3: >15} | {4: >15} | {5: >15} | {6: >15} | {7: >15} | {8: >7} | {9: >6} | {10: >10}",
+            process.pid,
+            process.display_name(),
+            process.display_path(),
+            FormatBytes::new(process.vram_bytes + process.gtt_bytes),
+            FormatBytes::new(process.vram_bytes),
+            FormatBytes::new(process.gtt_bytes),
+            FormatBytes::new(process.peak_vram_bytes),
+            FormatBytes::new(process.peak_gtt_bytes),
+            clients,
+            count(process.kfd_queues),
+            process.apis.join(","),
+        );
+        if show_evicted {
+            line += &format!(
+                " | {: >15}",
+                process.evicted_vram_bytes.map_or_else(
+                    || "-".to_string(),
+                    |bytes| FormatBytes::new(bytes).to_string()
+                )
+            );
+        }
+        if view.show_age {
+            let age = process.started.map_or_else(
+                || "-".to_string(),
+                |started| {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+                    FormatDuration::new(Duration::from_secs(now.saturating_sub(started)))
+                        .to_string()
+                },
+            );
+            let mut on_gpu =
+                FormatDuration::new(Duration::from_secs(process.on_gpu_seconds)).to_string();
+            if process.on_gpu_before_us {
+                on_gpu.insert(0, '>');
+            }
+            line += &format!(" | {: >10} | {: >10}", age, on_gpu);
+        }
+        if view.show_cpu {
+            // Nothing to compare with before the second refresh.
+            let cpu = process
+                .cpu_percent
+                .map_or_else(|| "-".to_string(), |percent| format!("{:.1}", percent));
+            line += &format!(" | {: >6}", cpu);
+        }
+        if view.show_rss {
+            let rss = process.rss_bytes.map_or_else(
+                || "-".to_string(),
+                |bytes| FormatBytes::new(bytes).to_string(),
+            );
+            line += &format!(" | {: >15}", rss);
+        }
+        if view.show_footprint {
+            // Marked, and explained below the table.
+            let footprint = process.footprint_bytes.map_or_else(
+                || "-".to_string(),
+                |bytes| {
+                    let mark = if process.footprint_may_double_count {
+                        "~"
+                    } else {
+                        ""
+                    };
+                    format!("{}{}", mark, FormatBytes::new(bytes))
+                },
+            );
+            line += &format!(" | {: >16}", footprint);
+        }
+        if view.show_busy {
+            // The engine it keeps busiest.
+            let busiest = process
+                .engine_busy
+                .iter()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map_or_else(
+                    || "-".to_string(),
+                    |(engine, percent)| format!("{} {:.0}%", engine, percent),
+                );
+            line += &format!(" | {: >12}", busiest);
+        }
+        if view.show_rate {
+            // Nothing to compare with before the second refresh.
+            let rate = process
+                .alloc_bytes_per_second
+                .map_or_else(|| "-".to_string(), format_rate);
+            line += &format!(" | {: >15}", rate);
+        }
+        for name in &view.columns {
+            let value = process.columns.get(name).copied().flatten().map_or_else(
+                || "-".to_string(),
+                |value| {
+                    if value.fract() == 0.0 {
+                        format!("{:.0}", value)
+                    } else {
+                        format!("{:.2}", value)
+                    }
+                },
+            );
+            line += &format!(" | {: >12}", value);
+        }
+        for name in &view.extra_columns {
+            line += &format!(
+                " | {: >16}",
+                process.extra.get(name).map_or("-", String::as_str)
+            );
+        }
+        if view.show_unit {
+            line += &format!(" | {}", process.unit.as_deref().unwrap_or("-"));
+        }
+        writeln!(out, "{}", line)?;
+    }
+
+    if let Some(rest) = view.rest {
+        let line = format!(
+            "{0: <10} | {1: <83} | {2: >15} | {3: >15} | {4: >15}{5}",
+            "",
+            format!(
+                "… and {} more using {}",
+                rest.processes.unwrap_or_default(),
+                FormatBytes::new(rest.vram_bytes + rest.gtt_bytes)
+            ),
+            FormatBytes::new(rest.vram_bytes + rest.gtt_bytes),
+            FormatBytes::new(rest.vram_bytes),
+            FormatBytes::new(rest.gtt_bytes),
+            blanks,
+        );
+        writeln!(out, "{}", line.trim_end())?;
+    }
+
+    if let Some(kernel) = view.unattributed {
+        let line = format!(
+            "{0: <10} | {1: <83} | {2: >15} | {3: >15} | {4: >15}{5}",
+            "-",
+            "kernel/unattributed",
+            FormatBytes::new(kernel.vram_bytes + kernel.gtt_bytes),
+            FormatBytes::new(kernel.vram_bytes),
+            FormatBytes::new(kernel.gtt_bytes),
+            blanks,
+        );
+        writeln!(out, "{}", line.trim_end())?;
+    }
+
+    if processes
+        .iter()
+        .any(|process| process.footprint_may_double_count)
+    {
+        writeln!(
+            out,
+            "~ counts GTT twice where the process has it mapped, in its RSS too; \
+             --accurate reads smaps to tell"
+        )?;
+    }
+
+    write_notes(out, view, processes)?;
+
+    for process in processes
+        .iter()
+        .filter(|process| !process.shared_with.is_empty())
+    {
+        let pids = process
+            .shared_with
+            .iter()
+            .map(i32::to_string)
+            .collect::<Vec<_>>();
+        writeln!(
+            out,
+            "* {} shares a DRM client with {}; its memory is counted once",
+            process.pid,
+            pids.join(", ")
+        )?;
+    }
+
+    Ok(())
+}
+
+/// What the table and `--plain` both say under a device's processes.
+fn write_notes<W: Write>(
+    out: &mut W,
+    view: &DeviceView,
+    processes: &[ProcessRow],
+) -> io::Result<()> {
+    if let Some(driver) = &view.generic_drm {
+        writeln!(
+            out,
+            "Generic DRM: only what {}'s fdinfo says, VRAM being memory of its own and GTT system memory",
+            driver
+        )?;
+    }
+
+    let compositors = processes
+        .iter()
+        .filter_map(|process| Some(format!("{} ({})", process.pid, process.compositor?)))
+        .collect::<Vec<_>>();
+    if !compositors.is_empty() {
+        writeln!(
+            out,
+            "Compositor: {} holds the window and scanout buffers of the whole session",
+            compositors.join(", ")
+        )?;
+    }
+
+    if let Some(pinned) = view.pinned.filter(|pinned| *pinned != Pinned::default()) {
+        let holders = processes
+            .iter()
+            .filter_map(|process| {
+                let pinned = process.pinned?;
+                let bytes = pinned.vram_bytes + pinned.gtt_bytes;
+                Some(format!("{} ({})", process.pid, FormatBytes::new(bytes))).filter(|_| bytes > 0)
+            })
+            .collect::<Vec<_>>();
+        let amounts = [("VRAM", pinned.vram_bytes), ("GTT", pinned.gtt_bytes)]
+            .iter()
+            .filter(|(_, bytes)| *bytes > 0)
+            .map(|(domain, bytes)| format!("{} of {}", FormatBytes::new(*bytes), domain))
+            .collect::<Vec<_>>();
+        let mut line = format!("Pinned, so never evicted: {}", amounts.join(" and "));
+        if !holders.is_empty() {
+            line += &format!(", held by {}", holders.join(", "));
+        }
+        writeln!(out, "{}", line)?;
+    }
+
+    for process in processes {
+        let others = process
+            .devices
+            .iter()
+            .filter(|device| **device != view.device)
+            .map(Device::to_string)
+            .collect::<Vec<_>>();
+        if let Some(rendering_on) = process.unexpected_gpu {
+            let kind = if rendering_on.is_integrated() {
+                "integrated"
+            } else {
+                "discrete"
+            };
+            writeln!(
+                out,
+                "! {} asked for DRI_PRIME={} but most of its memory is on {} ({})",
+                process.pid,
+                process.dri_prime.as_deref().unwrap_or_default(),
+                rendering_on,
+                kind
+            )?;
+        }
+        if !others.is_empty() {
+            writeln!(
+                out,
+                "{} also has buffers on {}",
+                process.pid,
+                others.join(", ")
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// `--plain`: a labeled line for each value instead of table cells, for
+/// screen readers and terminals that can't line columns up.
+fn write_plain<W: Write>(out: &mut W, view: &DeviceView) -> io::Result<()> {
+    writeln!(out, "device: {}", view.device)?;
+    if let Some(driver) = &view.generic_drm {
+        writeln!(out, "driver: {}, generic DRM", driver)?;
+    }
+    if view.virtual_function {
+        writeln!(out, "virtual function: yes")?;
+    }
+    if let Some(usage) = view.usage {
+        writeln!(
+            out,
+            "VRAM used: {}",
+            FormatBytes::new(usage.vram_used_bytes)
+        )?;
+        writeln!(
+            out,
+            "VRAM total: {}",
+            FormatBytes::new(usage.vram_total_bytes)
+        )?;
+        if let Some(gtt) = usage.gtt_used_bytes {
+            writeln!(out, "GTT used: {}", FormatBytes::new(gtt))?;
+        }
+        if let Some(eta) = view.vram_full_in_seconds {
+            writeln!(
+                out,
+                "VRAM full in: about {}",
+                FormatDuration::new(Duration::from_secs(eta))
+            )?;
+        }
+    }
+    if let Some(level) = &view.performance_level {
+        writeln!(out, "performance level: {}", level)?;
+    }
+    if let Some(faults) = view.vm_faults.as_ref().filter(|faults| faults.total > 0) {
+        writeln!(out, "VM faults: {}", faults.total)?;
+        writeln!(out, "VM faults in the last minute: {}", faults.recent)?;
+        if let Some(process) = &faults.last_process {
+            writeln!(out, "last VM fault by: {}", process)?;
+        }
+    }
+    for coredump in &view.coredumps {
+        match (&coredump.saved_to, &coredump.error) {
+            (Some(path), _) => writeln!(
+                out,
+                "coredump {}: saved to {}",
+                coredump.name,
+                path.display()
+            )?,
+            (None, Some(error)) => {
+                writeln!(out, "coredump {}: not saved, {}", coredump.name, error)?
+            }
+            (None, None) => writeln!(
+                out,
+                "coredump {}: waiting, gone 5 minutes after the hang",
+                coredump.name
+            )?,
+        }
+    }
+
+    let processes = match &view.processes {
+        Some(processes) => processes,
+        None => return Ok(()),
+    };
+    let write_usage = |out: &mut W, usage: Usage| {
+        writeln!(
+            out,
+            "  total: {}",
+            FormatBytes::new(usage.vram_bytes + usage.gtt_bytes)
+        )?;
+        writeln!(out, "  VRAM: {}", FormatBytes::new(usage.vram_bytes))?;
+        writeln!(out, "  GTT: {}", FormatBytes::new(usage.gtt_bytes))
+    };
+    if let Some(units) = &view.units {
+        for unit in units {
+            writeln!(out, "unit: {}", unit.unit.as_deref().unwrap_or("none"))?;
+            if let Some(count) = unit.usage.processes {
+                writeln!(out, "  processes: {}", count)?;
+            }
+            write_usage(out, unit.usage)?;
+        }
+        if let Some(kernel) = view.unattributed {
+            writeln!(out, "kernel, not attributed to a process:")?;
+            write_usage(out, kernel)?;
+        }
+        return Ok(());
+    }
+
+    for process in processes {
+        writeln!(out, "process: {}", process.pid)?;
+        writeln!(
+            out,
+            "  name: {}",
+            process.name.as_deref().unwrap_or("unknown")
+        )?;
+        if process.exited {
+            writeln!(out, "  exited: yes")?;
+        }
+        writeln!(out, "  path: {}", process.display_path())?;
+        write_usage(
+            out,
+            Usage {
+                vram_bytes: process.vram_bytes,
+                gtt_bytes: process.gtt_bytes,
+                ..Usage::default()
+            },
+        )?;
+        writeln!(
+            out,
+            "  peak VRAM: {}",
+            FormatBytes::new(process.peak_vram_bytes)
+        )?;
+        writeln!(
+            out,
+            "  peak GTT: {}",
+            FormatBytes::new(process.peak_gtt_bytes)
+        )?;
+        if let Some(clients) = process.drm_clients {
+            writeln!(out, "  DRM clients: {}", clients)?;
+        }
+        if !process.shared_with.is_empty() {
+            let pids = process
+                .shared_with
+                .iter()
+                .map(i32::to_string)
+                .collect::<Vec<_>>();
+            writeln!(
+                out,
+                "  shares a DRM client with: {}, its memory counted once",
+                pids.join(", ")
+            )?;
+        }
+        if let Some(queues) = process.kfd_queues {
+            writeln!(out, "  KFD queues: {}", queues)?;
+        }
+        if !process.apis.is_empty() {
+            writeln!(out, "  APIs: {}", process.apis.join(", "))?;
+        }
+        if let Some(evicted) = process.evicted_vram_bytes.filter(|bytes| *bytes > 0) {
+            writeln!(out, "  evicted VRAM: {}", FormatBytes::new(evicted))?;
+        }
+        if view.show_age {
+            if let Some(started) = process.started {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                writeln!(
+                    out,
+                    "  age: {}",
+                    FormatDuration::new(Duration::from_secs(now.saturating_sub(started)))
+                )?;
+            }
+            let before = if process.on_gpu_before_us {
+                "more than "
+            } else {
+                ""
+            };
+            writeln!(
+                out,
+                "  on the GPU for: {}{}",
+                before,
+                FormatDuration::new(Duration::from_secs(process.on_gpu_seconds))
+            )?;
+        }
+        if let (true, Some(percent)) = (view.show_cpu, process.cpu_percent) {
+            writeln!(out, "  CPU: {:.1}%", percent)?;
+        }
+        if let (true, Some(rss)) = (view.show_rss, process.rss_bytes) {
+            writeln!(out, "  RSS: {}", FormatBytes::new(rss))?;
+        }
+        if let (true, Some(footprint)) = (view.show_footprint, process.footprint_bytes) {
+            let note = if process.footprint_may_double_count {
+                ", may count GTT it has mapped twice"
+            } else {
+                ""
+            };
+            writeln!(out, "  footprint: {}{}", FormatBytes::new(footprint), note)?;
+        }
+        if view.show_busy {
+            for (engine, percent) in &process.engine_busy {
+                writeln!(out, "  {} busy: {:.0}%", engine, percent)?;
+            }
+        }
+        if let (true, Some(rate)) = (view.show_rate, process.alloc_bytes_per_second) {
+            writeln!(out, "  allocation rate: {}", format_rate(rate))?;
+        }
+        for name in &view.columns {
+            if let Some(value) = process.columns.get(name).copied().flatten() {
+                writeln!(out, "  {}: {}", name, value)?;
+            }
+        }
+        for name in &view.extra_columns {
+            if let Some(value) = process.extra.get(name) {
+                writeln!(out, "  {}: {}", name, value)?;
+            }
+        }
+        if view.show_unit {
+            writeln!(out, "  unit: {}", process.unit.as_deref().unwrap_or("none"))?;
+        }
+    }
+    if let Some(rest) = view.rest {
+        writeln!(
+            out,
+            "other processes: {}",
+            rest.processes.unwrap_or_default()
+        )?;
+        write_usage(out, rest)?;
+    }
+    if let Some(kernel) = view.unattributed {
+        writeln!(out, "kernel, not attributed to a process:")?;
+        write_usage(out, kernel)?;
+    }
+    write_notes(out, view, processes)
+}
+
+const CSV_HEADER: &[&str] = &[
+    "device",
+    "pid",
+    "name",
+    "path",
+    "vram_bytes",
+    "gtt_bytes",
+    "peak_vram_bytes",
+    "peak_gtt_bytes",
+    "drm_clients",
+    "kfd_queues",
+    "apis",
+    "compositor",
+    "devices",
+    "app",
+    "steam_app_id",
+    "steam_game",
+    "unit",
+];
+
+const UNITS_CSV_HEADER: &[&str] = &["device", "unit", "processes", "vram_bytes", "gtt_bytes"];
+
+/// What comes first in every row while sampling continuously.
+const STAMP_CSV_HEADER: &[&str] = &["time", "sequence"];
+
+fn write_stamped_row<W: Write>(out: &mut W, stamp: &[String], fields: &[String]) -> io::Result<()> {
+    output::write_csv_row(out, &[stamp, fields].concat())
+}
+
+fn write_units_csv<W: Write>(out: &mut W, stamp: &[String], view: &DeviceView) -> io::Result<()> {
+    let device = view.device.to_string();
+    let rows = view
+        .units
+        .iter()
+        .flatten()
+        .map(|unit| (unit.unit.clone().unwrap_or_default(), unit.usage))
+        .chain(
+            view.unattributed
+                .map(|kernel| ("kernel/unattributed".to_string(), kernel)),
+        );
+    for (unit, usage) in rows {
+        write_stamped_row(
+            out,
+            stamp,
+            &[
+                device.clone(),
+                unit,
+                usage
+                    .processes
+                    .map(|count| count.to_string())
+                    .unwrap_or_default(),
+                usage.vram_bytes.to_string(),
+                usage.gtt_bytes.to_string(),
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn write_csv<W: Write>(out: &mut W, stamp: &[String], view: &DeviceView) -> io::Result<()> {
+    let device = view.device.to_string();
+    let count = |count: Option<usize>| count.map(|count| count.to_string()).unwrap_or_default();
+    for process in view.processes.iter().flatten() {
+        write_stamped_row(
+            out,
+            stamp,
+            &[
+                device.clone(),
+                process.pid.to_string(),
+                process.display_name(),
+                process.path.clone().unwrap_or_default(),
+                process.vram_bytes.to_string(),
+                process.gtt_bytes.to_string(),
+                process.peak_vram_bytes.to_string(),
+                process.peak_gtt_bytes.to_string(),
+                count(process.drm_clients),
+                count(process.kfd_queues),
+                process.apis.join(","),
+                process.compositor.unwrap_or_default().to_string(),
+                process
+                    .devices
+                    .iter()
+                    .map(Device::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                process
+                    .app
+                    .as_ref()
+                    .map(SandboxApp::id)
+                    .unwrap_or_default()
+                    .to_string(),
+                process
+                    .steam
+                    .as_ref()
+                    .map(|steam| steam.app_id.to_string())
+                    .unwrap_or_default(),
+                process
+                    .steam
+                    .as_ref()
+                    .and_then(|steam| steam.name.clone())
+                    .unwrap_or_default(),
+                process.unit.clone().unwrap_or_default(),
+            ],
+        )?;
+    }
+
+    if let Some(kernel) = view.unattributed {
+        write_stamped_row(
+            out,
+            stamp,
+            &[
+                device,
+                String::new(),
+                "kernel/unattributed".to_string(),
+                String::new(),
+                kernel.vram_bytes.to_string(),
+                kernel.gtt_bytes.to_string(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+fn write_summary<W: Write>(out: &mut W, options: &MemArgs, session: &Session) -> io::Result<()> {
+    writeln!(out)?;
+    writeln!(out, "Peak usage this session")?;
+
+    for (device, peaks) in &session.peaks {
+        let mut peaks = peaks
+            .iter()
+            .filter(|(_, peak)| peak.vram_bytes + peak.gtt_bytes >= options.min_size)
+            .collect::<Vec<_>>();
+        peaks.sort_by_key(|(_, peak)| std::cmp::Reverse(peak.vram_bytes + peak.gtt_bytes));
+
+        writeln!(out)?;
+        writeln!(out, "{}", device)?;
+        writeln!(
+            out,
+            "{0: <10} | {1: <20} | {2: >15} | {3: >15}",
+            "PID", "PROCESS", "PEAK VRAM", "PEAK GTT"
+        )?;
+        writeln!(out, "{:-^1$}", "", 70)?;
+
+        for (pid, peak) in peaks {
+            writeln!(
+                out,
+                "{0: <10} | {1: <20} | {2: >15} | {3: >15}",
+                pid,
+                peak.name,
+                FormatBytes::new(peak.vram_bytes),
+                FormatBytes::new(peak.gtt_bytes),
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Selects sources for a per-process view, reporting which when asked.
+/// Saving coredumps keeps us root, as only root can read them.
+pub fn select_sources(global: &GlobalArgs, options: &MemArgs) -> error::Result<Sources> {
+    let sources = Sources::select(&SourceConfig {
+        keep_root: options.save_coredumps.is_some(),
+        ..global.source_config()
+    })?;
+    if global.diagnostics {
+        for kind in sources.kinds() {
+            eprintln!("amdtop: using {}", kind);
+        }
+    }
+    Ok(sources)
+}
+
+/// Samples every device `--gpu` selects and turns them into views.
+pub fn refresh(
+    global: &GlobalArgs,
+    options: &MemArgs,
+    sources: &mut Sources,
+    session: &mut Session,
+) -> error::Result<Vec<DeviceView>> {
+    // Whichever source counts memory, fdinfo tells which processes share a
+    // client.
+    let clients = match options.generic_drm {
+        true => ClientScan::read_with_generic(),
+        false => ClientScan::read(),
+    }
+    .unwrap_or_default();
+    let mut samples = sources.sample()?;
+    if options.generic_drm {
+        samples.extend(source::generic_samples(&clients));
+        samples.sort_by_key(|sample| sample.device);
+    }
+    let mut unparsed = Vec::new();
+    let mut views = samples
+        .into_iter()
+        .filter(|sample| global.selects(sample.device))
+        .map(|sample| {
+            if let (true, Some(diagnostics)) = (global.diagnostics, &sample.diagnostics) {
+                diagnostics.report(sample.device);
+            }
+            if let (true, Some(diagnostics)) = (global.strict, &sample.diagnostics) {
+                unparsed.extend(diagnostics.strict_report(sample.device));
+            }
+            session.view(options, sample, &clients)
+        })
+        .collect::<Vec<_>>();
+    gem_info::fail_strictly(unparsed)?;
+    if options.generic_drm {
+        for view in &mut views {
+            view.generic_drm = view.device.driver().filter(|driver| driver != "amdgpu");
+        }
+    }
+    attribute_gpus(&mut views);
+    if options.show_cpu {
+        session.measure_cpu(&mut views);
+    }
+    if options.show_busy {
+        session.measure_busy(&mut views, &clients, global.smoothing);
+    }
+    if options.show_rate {
+        session.measure_rate(&mut views, global.smoothing);
+    }
+    if !options.columns.is_empty() {
+        compute_columns(&mut views, &options.columns);
+    }
+    if let Some(command) = &options.enrich {
+        // Whatever the command does, the refresh goes on without it.
+        if let Err(err) = enrich::enrich(command, &mut views) {
+            eprintln!("amdtop: --enrich: {}", err);
+        }
+    }
+    if session.kernel_state {
+        session.read_kernel_log();
+    }
+    if session.kernel_state && session.kernel_error.is_none() {
+        let uptime = kmsg::uptime();
+        for view in &mut views {
+            if let Some(slot) = view.device.pci_slot() {
+                view.vm_faults = Some(session.fault_log.vm_faults(&slot, uptime));
+            }
+        }
+    }
+    let coredumps = match session.kernel_state || options.save_coredumps.is_some() {
+        true => devcoredump::list(),
+        false => Vec::new(),
+    };
+    for view in &mut views {
+        let slot = match view.device.pci_slot() {
+            Some(slot) => slot,
+            None => continue,
+        };
+        for (name, _) in coredumps.iter().filter(|(_, failing)| *failing == slot) {
+            if let Some(dir) = &options.save_coredumps {
+                session.save_coredump(name, &slot, dir);
+            }
+            view.coredumps.push(session.coredump(name));
+        }
+    }
+    session.prune();
+    Ok(views)
+}
+
+/// One refresh as `--record` asks, each line stamped with the local time in
+/// RFC 3339, like `--output json` and CSV, and the refresh's sequence number.
+fn write_ndjson<W: Write>(
+    out: &mut W,
+    options: &MemArgs,
+    sequence: u64,
+    views: &[DeviceView],
+) -> io::Result<()> {
+    let time = output::timestamp();
+    match options.record {
+        Record::Snapshot => {
+            let snapshot =
+                serde_json::json!({ "time": time, "sequence": sequence, "devices": views });
+            output::write_json_line(out, &snapshot)
+        }
+        Record::Process => {
+            for view in views {
+                for process in view.processes.iter().flatten() {
+                    let mut line = serde_json::to_value(process)?;
+                    line["schema_version"] = SCHEMA_VERSION.into();
+                    line["time"] = time.clone().into();
+                    line["sequence"] = sequence.into();
+                    line["device"] = serde_json::to_value(view.device)?;
+                    output::write_json_line(out, &line)?;
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// What `--adaptive` compares between refreshes: the memory of every
+/// process, and of every device.
+fn fingerprint(views: &[DeviceView]) -> Vec<(Device, Option<i32>, u64, u64)> {
+    let mut fingerprint = Vec::new();
+    for view in views {
+        if let Some(usage) = view.usage {
+            fingerprint.push((
+                view.device,
+                None,
+                usage.vram_used_bytes,
+                usage.gtt_used_bytes.unwrap_or_default(),
+            ));
+        }
+        for process in view.processes.iter().flatten() {
+            fingerprint.push((
+                view.device,
+                Some(process.pid),
+                process.vram_bytes,
+                process.gtt_bytes,
+            ));
+        }
+    }
+    fingerprint
+}
+
+/// `--changes-only`: what each process used when it was last printed. The
+/// kernel's row goes by no pid.
+#[derive(Default)]
+struct Changes {
+    printed: HashMap<(Device, Option<i32>), (u64, u64, bool)>,
+    /// Each process's last row, to say it exited once it's gone.
+    rows: HashMap<(Device, i32), ProcessRow>,
+}
+
+impl Changes {
+    /// Leaves only the processes of `views` that changed by more than
+    /// `epsilon` bytes since they were last printed, and adds a row for each
+    /// printed before that's no longer among the pids `listed`. The ones
+    /// merely hidden, by `--top` or `--min-size`, are listed still.
+    fn filter(
+        &mut self,
+        views: &mut [DeviceView],
+        listed: &HashMap<Device, HashSet<i32>>,
+        epsilon: u64,
+    ) {
+        let moved = |before: u64, now: u64| before.abs_diff(now) > epsilon;
+        let mut printed = HashMap::new();
+        let mut rows = HashMap::new();
+        for view in views {
+            let device = view.device;
+            if let Some(processes) = &mut view.processes {
+                let mut gone = self
+                    .rows
+                    .iter()
+                    .filter(|((row_device, pid), row)| {
+                        *row_device == device
+                            && !row.exited
+                            && !processes.iter().any(|process| process.pid == *pid)
+                            && !listed.get(&device).is_some_and(|pids| pids.contains(pid))
+                    })
+                    .map(|(_, row)| ProcessRow {
+                        exited: true,
+                        vram_bytes: 0,
+                        gtt_bytes: 0,
+                        evicted_vram_bytes: None,
+                        pinned: None,
+                        rss_bytes: None,
+                        footprint_bytes: None,
+                        cpu_percent: None,
+                        drm_clients: None,
+                        kfd_queues: None,
+                        client_ids: Vec::new(),
+                        shared_with: Vec::new(),
+                        engine_busy: BTreeMap::new(),
+                        alloc_bytes_per_second: None,
+                        ..row.clone()
+                    })
+                    .collect::<Vec<_>>();
+                gone.sort_by_key(|row| row.pid);
+                processes.extend(gone);
+                for process in processes.iter() {
+                    rows.insert((device, process.pid), process.clone());
+                }
+            }
+
+            let current = view
+                .processes
+                .iter()
+                .flatten()
+                .map(|process| {
+                    let now = (process.vram_bytes, process.gtt_bytes, process.exited);
+                    (Some(process.pid), now)
+                })
+                .chain(
+                    view.unattributed
+                        .map(|kernel| (None, (kernel.vram_bytes, kernel.gtt_bytes, false))),
+                );
+            for (pid, now) in current {
+                let key = (device, pid);
+                let kept = match self.printed.get(&key) {
+                    Some(&(vram, gtt, exited)) => {
+                        if moved(vram, now.0) || moved(gtt, now.1) || exited != now.2 {
+                            now
+                        } else {
+                            (vram, gtt, exited)
+                        }
+                    }
+                    None => now,
+                };
+                printed.insert(key, kept);
+            }
+            let changed = |pid| self.printed.get(&(device, pid)) != printed.get(&(device, pid));
+            if let Some(processes) = &mut view.processes {
+                processes.retain(|process| changed(Some(process.pid)));
+            }
+            view.unattributed = view.unattributed.filter(|_| changed(None));
+        }
+        self.printed = printed;
+        self.rows = rows;
+    }
+}
+
+/// What the table output prints for one refresh.
+fn write_tables<W: Write>(out: &mut W, options: &MemArgs, views: &[DeviceView]) -> io::Result<()> {
+    if options.oneline {
+        return oneline::write_oneline(out, views);
+    }
+    for (index, view) in views.iter().enumerate() {
+        if output::plain() {
+            if index > 0 {
+                writeln!(out)?;
+            }
+            write_plain(out, view)?;
+            continue;
+        }
+        if options.meters {
+            meters::write_meters(out, view)?;
+        }
+        write_table(out, view)?;
+    }
+    let warning =
+        SystemMemory::read().and_then(|memory| memory.gtt_warning(meminfo::gtt_in_use(views)));
+    if let Some(warning) = warning {
+        writeln!(out, "{}", warning)?;
+    }
+    Ok(())
+}
+
+/// Whether the output has each device's VM faults and coredumps: the table,
+/// unless in one line, and the JSON.
+fn shows_kernel_state(global: &GlobalArgs, options: &MemArgs) -> bool {
+    match global.output {
+        OutputFormat::Table => !options.oneline,
+        OutputFormat::Json => true,
+        OutputFormat::Ndjson => options.record == Record::Snapshot,
+        _ => false,
+    }
+}
+
+/// How many kernel log lines to show for context.
+const KERNEL_LOG_LINES: usize = 10;
+
+/// `lines` under a heading, or, when `first`, why there are none.
+fn write_kernel_log<W: Write>(
+    out: &mut W,
+    log: &KernelLog,
+    lines: &[String],
+    first: bool,
+) -> io::Result<()> {
+    if let (Some(error), true) = (log.error(), first) {
+        return writeln!(out, "Kernel log: {}", error);
+    }
+    if !lines.is_empty() {
+        writeln!(out, "Kernel log:")?;
+    }
+    for line in lines {
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+pub fn run(global: &GlobalArgs, options: &MemArgs) -> error::Result<()> {
+    if options.verify {
+        return verify::run(global);
+    }
+    if global.plain && (options.meters || options.oneline) {
+        return Err(Error::InvalidArgument(
+            "--plain doesn't draw --meters or --oneline".to_string(),
+        ));
+    }
+    if options.kernel_log && global.output != OutputFormat::Table {
+        return Err(Error::InvalidArgument(
+            "--kernel-log only works with the table".to_string(),
+        ));
+    }
+    let mut sources = select_sources(global, options)?;
+    let mut session = Session::new(shows_kernel_state(global, options));
+    let mut kernel_log = KernelLog::default();
+    let mut changes = Changes::default();
+    let mut previous = None;
+    let stdout = io::stdout();
+
+    crate::adaptive_refresh_loop(global, |iteration| {
+        let mut views = refresh(global, options, &mut sources, &mut session)?;
+        let latest = Some(fingerprint(&views));
+        let changed = latest != previous;
+        previous = latest;
+        if let Some(epsilon) = options.changes_only {
+            changes.filter(&mut views, &session.present, epsilon);
+        }
+        // The first refresh shows what was logged last, for context; later
+        // ones what came since.
+        let context = iteration == 0 || global.watch;
+        let log_lines = if !options.kernel_log {
+            Vec::new()
+        } else if context {
+            kernel_log.update(&views, session.kernel_records());
+            let recent = kernel_log.recent().cloned().collect::<Vec<_>>();
+            recent[recent.len().saturating_sub(KERNEL_LOG_LINES)..].to_vec()
+        } else {
+            kernel_log.update(&views, session.kernel_records())
+        };
+        // When sampling continuously, each snapshot says when it was taken,
+        // to line it up with other logs later.
+        let stamp = Some([output::timestamp(), iteration.to_string()])
+            .filter(|_| global.continuous() && !global.watch);
+        let mut out = stdout.lock();
+        match global.output {
+            OutputFormat::Table if global.watch => {
+                let mut frame = Vec::new();
+                write_tables(&mut frame, options, &views)?;
+                write_kernel_log(&mut frame, &kernel_log, &log_lines, context)?;
+                watch::redraw(&mut out, global, &frame)?;
+            }
+            OutputFormat::Table => {
+                if iteration > 0 && !options.oneline {
+                    writeln!(out)?;
+                }
+                if let (Some([time, sequence]), false) = (&stamp, options.oneline) {
+                    writeln!(out, "# {} | sample {}", time, sequence)?;
+                }
+                write_tables(&mut out, options, &views)?;
+                write_kernel_log(&mut out, &kernel_log, &log_lines, context)?;
+            }
+            OutputFormat::Json => match &stamp {
+                Some([time, _]) => output::write_json(
+                    &mut out,
+                    &serde_json::json!({ "time": time, "sequence": iteration, "devices": views }),
+                )?,
+                None => output::write_json(&mut out, &views)?,
+            },
+            OutputFormat::Csv => {
+                let grouped = options.group_by.is_some();
+                if iteration == 0 {
+                    let header = if grouped {
+                        UNITS_CSV_HEADER
+                    } else {
+                        CSV_HEADER
+                    };
+                    let stamp_header = if stamp.is_some() {
+                        STAMP_CSV_HEADER
+                    } else {
+                        &[]
+                    };
+                    output::write_csv_row(&mut out, &[stamp_header, header].concat())?;
+                }
+                let stamp = stamp.as_ref().map_or(&[][..], |stamp| &stamp[..]);
+                for view in &views {
+                    if grouped {
+                        write_units_csv(&mut out, stamp, view)?;
+                    } else {
+                        write_csv(&mut out, stamp, view)?;
+                    }
+                }
+            }
+            OutputFormat::Waybar => oneline::write_waybar(&mut out, &views)?,
+            OutputFormat::Ndjson => write_ndjson(&mut out, options, iteration, &views)?,
+            OutputFormat::Telegraf => collectors::write_influx_mem(&mut out, &views)?,
+            OutputFormat::Collectd => {
+                collectors::Putval::new(global.delay).write_mem(&mut out, &views)?
+            }
+            format => return Err(output::unsupported(format, "mem")),
+        }
+        Ok(changed)
+    })?;
+
+    if global.continuous() && global.output == OutputFormat::Table && !options.oneline {
+        write_summary(&mut stdout.lock(), options, &session)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_time_to_exhaustion() {
+        let start = Instant::now();
+        let mut history = VramHistory::default();
+        for second in 0..5 {
+            history.push(start + Duration::from_secs(second), (second + 1) << 20);
+        }
+
+        let usage = DeviceUsage {
+            vram_used_bytes: 5 << 20,
+            vram_total_bytes: 65 << 20,
+            gtt_used_bytes: None,
+        };
+        let eta = history.time_to_exhaustion(usage).unwrap();
+        assert_eq!(eta.as_secs(), 60);
+
+        let mut flat = VramHistory::default();
+        for second in 0..5 {
+            flat.push(start + Duration::from_secs(second), 5 << 20);
+        }
+        assert!(flat.time_to_exhaustion(usage).is_none());
+    }
+
+    #[test]
+    fn detects_apis_from_maps() {
+        let maps = "7f00-7f01 r-xp 00000000 08:02 12 /usr/lib/libvulkan_radeon.so\n\
+                    7f02-7f03 r-xp 00000000 08:02 13 /usr/lib/libEGL_mesa.so.0\n\
+                    7f04-7f05 r-xp 00000000 08:02 14 /usr/lib/libvulkan.so.1\n\
+                    7f06-7f07 rw-p 00000000 00:00 0 [heap]\n";
+        assert_eq!(detect_apis(maps), ["VK", "GL"]);
+        assert!(detect_apis("").is_empty());
+    }
+
+    #[test]
+    fn keeps_busy_within_the_engines_there_are() {
+        let second = Duration::from_secs(1);
+        assert_eq!(busy_percent(250_000_000, second, 1), 25.0);
+        assert_eq!(busy_percent(500_000_000, second, 2), 25.0);
+        // Read a little late compared with the last read.
+        assert_eq!(busy_percent(1_020_000_000, second, 1), 100.0);
+        assert_eq!(busy_percent(1, Duration::ZERO, 1), 0.0);
+    }
+
+    #[test]
+    fn sums_up_gpu_mappings_in_smaps() {
+        let smaps = "7f3a10000000-7f3a12000000 rw-s 00000000 00:0f 2048       /dev/dri/renderD128\n\
+                     Size:              32768 kB\n\
+                     Rss:               16384 kB\n\
+                     7f3a20000000-7f3a22400000 r-xp 00000000 08:02 1442001    /usr/lib/libvulkan.so.1\n\
+                     Rss:                1024 kB\n\
+                     7f3a30000000-7f3a30100000 rw-s 00000000 00:0e 9 /dmabuf:\n\
+                     Rss:                1024 kB\n\
+                     VmFlags: rd wr sh mr mw me ms\n";
+        assert_eq!(mapped_gpu_rss(smaps), 17 << 20);
+        assert_eq!(map_path("7f06-7f07 rw-p 00000000 00:00 0"), "");
+    }
+
+    #[test]
+    fn finds_the_innermost_systemd_unit() {
+        assert_eq!(
+            systemd_unit("0::/system.slice/ollama.service\n").as_deref(),
+            Some("ollama.service")
+        );
+        assert_eq!(
+            systemd_unit(
+                "12:cpu:/\n0::/user.slice/user-1000.slice/user@1000.service/app.slice/\
+                 app-org.kde.konsole-1234.scope\n"
+            )
+            .as_deref(),
+            Some("app-org.kde.konsole-1234.scope")
+        );
+        assert_eq!(systemd_unit("0::/\n"), None);
+    }
+
+    #[test]
+    fn finds_the_windows_exe_under_wine() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            wine_exe(
+                "/home/me/.steam/steam/steamapps/common/Proton 9.0/files/bin/wine64-preloader",
+                &args(&["C:\\Games\\bin\\x64\\Cyberpunk2077.exe", "--launcher-skip"])
+            )
+            .as_deref(),
+            Some("Cyberpunk2077.exe")
+        );
+        assert_eq!(
+            wine_exe("/usr/bin/wine", &args(&["/usr/bin/wine", "setup.EXE"])).as_deref(),
+            Some("setup.EXE")
+        );
+        assert_eq!(wine_exe("/usr/bin/blender", &args(&["game.exe"])), None);
+    }
+
+    #[test]
+    fn sorts_names_ignoring_case() {
+        let mut units = [
+            "zeta.service",
+            "Beta.service",
+            "alpha.service",
+            "beta.service",
+        ]
+        .iter()
+        .map(|unit| Some(unit.to_string()))
+        .chain(std::iter::once(None))
+        .map(|unit| UnitUsage {
+            unit,
+            usage: Usage::default(),
+        })
+        .collect::<Vec<_>>();
+        sort_units(&mut units, &[SortKey::Name]);
+        let units = units
+            .iter()
+            .map(|unit| unit.unit.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            units,
+            [
+                None,
+                Some("alpha.service"),
+                Some("Beta.service"),
+                Some("beta.service"),
+                Some("zeta.service")
+            ]
+        );
+        assert_eq!(
+            compare_names(Some("Xorg"), Some("blender")),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn reads_what_stays_the_same_once_per_process() {
+        let pid = std::process::id() as i32;
+        let comm = std::fs::read_to_string("/proc/self/comm").unwrap();
+        let stat = std::fs::read_to_string("/proc/self/stat").unwrap();
+        let start_ticks = parse_stat(&stat).map(|stat| stat.start_ticks);
+        let cached = |start_ticks| Invariants {
+            comm: comm.trim().to_string(),
+            start_ticks,
+            path: Some("/cached".to_string()),
+            args: None,
+            environment: HashMap::new(),
+            app: None,
+            steam: None,
+            unit: None,
+        };
+        let mut failures = NegativeCache::default();
+
+        let mut known = HashMap::from([(pid, cached(start_ticks))]);
+        let identity = ProcessIdentity::read(pid, &mut failures, &mut known, true);
+        assert_eq!(identity.path.as_deref(), Some("/cached"));
+        assert!(identity.rss_bytes.is_some());
+
+        // Another process that took over the pid.
+        let mut known = HashMap::from([(pid, cached(start_ticks.map(|ticks| ticks + 1)))]);
+        let identity = ProcessIdentity::read(pid, &mut failures, &mut known, true);
+        assert_ne!(identity.path.as_deref(), Some("/cached"));
+        assert!(identity.cmdline.is_some());
+        assert_eq!(known[&pid].start_ticks, start_ticks);
+
+        let identity = ProcessIdentity::read(pid, &mut failures, &mut HashMap::new(), false);
+        assert!(identity.started.is_none() && identity.rss_bytes.is_none());
+    }
+
+    #[test]
+    fn finds_the_start_time_after_odd_names() {
+        let stat = "3301 (Web Content (x)) S 1 3301 3301 0 -1 4194560 92113 0 0 0 4122 \
+                    911 0 0 20 0 31 0 1263145 5035012096 92672 18446744073709551615";
+        assert_eq!(parse_stat(stat).map(|stat| stat.start_ticks), Some(1263145));
+        assert!(parse_stat("3301 (blender").is_none());
+    }
+}
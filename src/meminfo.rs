@@ -0,0 +1,98 @@
+//! System memory from `/proc/meminfo`, to tell when GTT is what's eating it.
+//!
+//! GTT buffers live in ordinary system RAM, but neither `top` nor `free`
+//! says which of it the GPU holds, so a game or a model filling GTT looks
+//! like the machine running out of memory for no reason.
+
+use crate::{mem::DeviceView, sysroot, FormatBytes};
+use std::collections::HashMap;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SystemMemory {
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+}
+
+/// Below this share of RAM available, the kernel is about to start swapping.
+const LOW_AVAILABLE: f64 = 0.1;
+/// What share of the RAM in use GTT needs for us to blame it.
+const GTT_SHARE: f64 = 0.25;
+
+impl SystemMemory {
+    pub fn read() -> Option<Self> {
+        let contents = std::fs::read_to_string(sysroot::path("/proc/meminfo")).ok()?;
+        Self::parse(&contents)
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let fields = contents
+            .lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                let kib = value
+                    .trim()
+                    .strip_suffix("kB")?
+                    .trim()
+                    .parse::<u64>()
+                    .ok()?;
+                Some((key, kib << 10))
+            })
+            .collect::<HashMap<_, _>>();
+        Some(SystemMemory {
+            total_bytes: *fields.get("MemTotal")?,
+            available_bytes: *fields.get("MemAvailable")?,
+        })
+    }
+
+    /// A warning when little RAM is left and GTT holds a good part of what's
+    /// in use.
+    pub fn gtt_warning(&self, gtt_bytes: u64) -> Option<String> {
+        let used_bytes = self.total_bytes.saturating_sub(self.available_bytes);
+        let available = self.available_bytes as f64 / self.total_bytes.max(1) as f64;
+        if available >= LOW_AVAILABLE || (gtt_bytes as f64) < used_bytes as f64 * GTT_SHARE {
+            return None;
+        }
+        Some(format!(
+            "! GPU buffers hold {} of system RAM in GTT and only {} ({:.0}%) is available; \
+             the system is close to swapping",
+            FormatBytes::new(gtt_bytes),
+            FormatBytes::new(self.available_bytes),
+            available * 100.0
+        ))
+    }
+}
+
+/// GTT in use on every device shown.
+pub fn gtt_in_use(views: &[DeviceView]) -> u64 {
+    views
+        .iter()
+        .filter_map(|view| view.usage?.gtt_used_bytes)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blames_gtt_when_memory_runs_low() {
+        let memory = SystemMemory::parse(
+            "MemTotal:       16318412 kB\n\
+             MemFree:          210000 kB\n\
+             MemAvailable:     815920 kB\n",
+        )
+        .unwrap();
+        assert_eq!(memory.total_bytes, 16318412 << 10);
+        assert_eq!(memory.available_bytes, 815920 << 10);
+
+        assert!(memory.gtt_warning(8 << 30).is_some());
+        // Low, but something else is using it.
+        assert_eq!(memory.gtt_warning(1 << 30), None);
+
+        let plenty = SystemMemory {
+            available_bytes: 8 << 30,
+            ..memory
+        };
+        assert_eq!(plenty.gtt_warning(8 << 30), None);
+    }
+}
@@ -0,0 +1,119 @@
+//! `--meters`: two lines of htop-style bars above each device's table, so a
+//! glance at a log of batch output tells how full and how busy it was.
+
+use crate::{
+    mem::DeviceView,
+    sensors::Sensors,
+    source::{read_sysfs_u64, Device},
+    FormatBytes,
+};
+use std::io::{self, Write};
+
+/// How many characters are between a meter's brackets.
+const METER_WIDTH: usize = 30;
+
+/// A bar filled `fraction` of the way, with `text` over its right end.
+fn meter(label: &str, fraction: Option<f64>, text: &str) -> String {
+    let filled = fraction.map_or(0, |fraction| {
+        (fraction.clamp(0.0, 1.0) * METER_WIDTH as f64).round() as usize
+    });
+    let mut inside = std::iter::repeat_n('|', filled)
+        .chain(std::iter::repeat_n(' ', METER_WIDTH - filled))
+        .collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let start = METER_WIDTH.saturating_sub(text.len());
+    for (slot, c) in inside[start..].iter_mut().zip(text) {
+        *slot = c;
+    }
+    format!("{:<4} [{}]", label, inside.into_iter().collect::<String>())
+}
+
+fn bytes_meter(label: &str, used: Option<u64>, total: Option<u64>) -> String {
+    match (used, total) {
+        (Some(used), Some(total)) if total > 0 => {
+            let fraction = used as f64 / total as f64;
+            meter(label, Some(fraction), &format!("{:.1}%", fraction * 100.0))
+        }
+        (Some(used), _) => meter(label, None, &FormatBytes::new(used).to_string()),
+        _ => meter(label, None, "n/a"),
+    }
+}
+
+/// The hottest sensor's share of where it throttles.
+fn temperature_meter(sensors: &Sensors) -> String {
+    let temperature = [
+        (
+            sensors.junction_temperature_celsius,
+            sensors.junction_critical_celsius,
+        ),
+        (
+            sensors.edge_temperature_celsius,
+            sensors.edge_critical_celsius,
+        ),
+    ]
+    .iter()
+    .find_map(|(value, critical)| Some(((*value)?, critical.unwrap_or(100.0))));
+    match temperature {
+        Some((value, critical)) => meter(
+            "TEMP",
+            Some(value / critical),
+            &format!("{:.0}/{:.0}°C", value, critical),
+        ),
+        None => meter("TEMP", None, "n/a"),
+    }
+}
+
+fn gtt_total_bytes(device: Device) -> Option<u64> {
+    read_sysfs_u64(&device.sysfs_dir().join("mem_info_gtt_total"))
+}
+
+pub fn write_meters<W: Write>(out: &mut W, view: &DeviceView) -> io::Result<()> {
+    let sensors = Sensors::read(view.device);
+    let usage = view.usage;
+    let busy = sensors.gpu_busy_percent.map(|busy| busy as f64);
+    writeln!(
+        out,
+        "{}   {}",
+        bytes_meter(
+            "VRAM",
+            usage.map(|usage| usage.vram_used_bytes),
+            usage.map(|usage| usage.vram_total_bytes)
+        ),
+        bytes_meter(
+            "GTT",
+            usage.and_then(|usage| usage.gtt_used_bytes),
+            gtt_total_bytes(view.device)
+        ),
+    )?;
+    writeln!(
+        out,
+        "{}   {}",
+        meter(
+            "BUSY",
+            busy.map(|busy| busy / 100.0),
+            &busy.map_or_else(|| "n/a".to_string(), |busy| format!("{:.0}%", busy))
+        ),
+        temperature_meter(&sensors),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draws_meters() {
+        assert_eq!(
+            meter("BUSY", Some(0.5), "50%"),
+            "BUSY [|||||||||||||||            50%]"
+        );
+        assert_eq!(
+            meter("TEMP", Some(1.2), "120/100°C"),
+            "TEMP [|||||||||||||||||||||120/100°C]"
+        );
+        assert_eq!(
+            meter("GTT", None, "n/a"),
+            "GTT  [                           n/a]"
+        );
+    }
+}
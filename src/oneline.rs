@@ -0,0 +1,165 @@
+//! `--oneline`: everything worth knowing about the devices in one short
+//! line, for tmux status bars and i3blocks scripts; and `--output waybar`,
+//! the same line in the JSON a waybar custom module reads, with
+//! `return-type` set to `json`.
+
+use crate::{mem::DeviceView, sensors::Sensors, FormatBytes};
+use serde_json::json;
+use std::io::{self, Write};
+
+const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+/// The unit `bytes` is best written in, and its size.
+fn unit_for(bytes: u64) -> (&'static str, f64) {
+    let mut divisor = 1.0;
+    let mut unit = 0;
+    while unit + 1 < UNITS.len() && bytes as f64 >= divisor * 1024.0 {
+        divisor *= 1024.0;
+        unit += 1;
+    }
+    (UNITS[unit], divisor)
+}
+
+/// A number with one decimal at most, `16` rather than `16.0`.
+fn short(value: f64) -> String {
+    let text = format!("{:.1}", value);
+    match text.strip_suffix(".0") {
+        Some(whole) => whole.to_string(),
+        None => text,
+    }
+}
+
+fn bytes(bytes: u64) -> String {
+    let (unit, divisor) = unit_for(bytes);
+    format!("{}{}", short(bytes as f64 / divisor), unit)
+}
+
+/// `used/total` in the unit of the total.
+fn fraction(used: u64, total: u64) -> String {
+    let (unit, divisor) = unit_for(total);
+    format!(
+        "{}/{}{}",
+        short(used as f64 / divisor),
+        short(total as f64 / divisor),
+        unit
+    )
+}
+
+/// One device's part of the line, e.g.
+/// `card0 vram 7.2/16GiB gtt 1.1GiB busy 83% 74°C 212W top:blender 5.9GiB`.
+pub fn device_line(view: &DeviceView, sensors: &Sensors) -> String {
+    let mut parts = vec![view.device.to_string()];
+    if let Some(usage) = view.usage {
+        parts.push(format!(
+            "vram {}",
+            fraction(usage.vram_used_bytes, usage.vram_total_bytes)
+        ));
+        if let Some(gtt) = usage.gtt_used_bytes {
+            parts.push(format!("gtt {}", bytes(gtt)));
+        }
+    }
+    if let Some(busy) = sensors.gpu_busy_percent {
+        parts.push(format!("busy {}%", busy));
+    }
+    if let Some(temperature) = sensors
+        .junction_temperature_celsius
+        .or(sensors.edge_temperature_celsius)
+    {
+        parts.push(format!("{:.0}°C", temperature));
+    }
+    if let Some(power) = sensors.power_watts {
+        parts.push(format!("{:.0}W", power));
+    }
+    let top = view
+        .processes
+        .iter()
+        .flatten()
+        .filter(|process| process.vram_bytes > 0)
+        .max_by_key(|process| process.vram_bytes);
+    if let Some(process) = top {
+        parts.push(format!(
+            "top:{} {}",
+            process.name.as_deref().unwrap_or("unknown"),
+            bytes(process.vram_bytes)
+        ));
+    }
+    parts.join(" ")
+}
+
+/// Every device on one line, separated by ` | `.
+pub fn write_oneline<W: Write>(out: &mut W, views: &[DeviceView]) -> io::Result<()> {
+    let line = views
+        .iter()
+        .map(|view| device_line(view, &Sensors::read(view.device)))
+        .collect::<Vec<_>>()
+        .join(" | ");
+    writeln!(out, "{}", line)
+}
+
+/// How many processes the tooltip lists per device.
+const TOOLTIP_PROCESSES: usize = 5;
+
+/// VRAM use from which the module gets the `warning` and `critical`
+/// classes, for the bar's stylesheet to color.
+const WARNING_PERCENT: f64 = 75.0;
+const CRITICAL_PERCENT: f64 = 90.0;
+
+fn tooltip(view: &DeviceView) -> String {
+    let mut lines = vec![view.device.to_string()];
+    let mut processes = view.processes.iter().flatten().collect::<Vec<_>>();
+    processes.sort_by_key(|process| std::cmp::Reverse(process.vram_bytes + process.gtt_bytes));
+    for process in processes.into_iter().take(TOOLTIP_PROCESSES) {
+        lines.push(format!(
+            "{} ({})  VRAM {}  GTT {}",
+            process.name.as_deref().unwrap_or("unknown"),
+            process.pid,
+            FormatBytes::new(process.vram_bytes),
+            FormatBytes::new(process.gtt_bytes)
+        ));
+    }
+    lines.join("\n")
+}
+
+/// One line of `{"text", "tooltip", "class", "percentage"}` per refresh, as
+/// waybar wants them. The percentage is the fullest device's VRAM.
+pub fn write_waybar<W: Write>(out: &mut W, views: &[DeviceView]) -> io::Result<()> {
+    let text = views
+        .iter()
+        .map(|view| device_line(view, &Sensors::read(view.device)))
+        .collect::<Vec<_>>()
+        .join(" | ");
+    let percentage = views
+        .iter()
+        .filter_map(|view| view.usage)
+        .filter(|usage| usage.vram_total_bytes > 0)
+        .map(|usage| usage.vram_used_bytes as f64 * 100.0 / usage.vram_total_bytes as f64)
+        .fold(0.0, f64::max);
+    let class = if percentage >= CRITICAL_PERCENT {
+        "critical"
+    } else if percentage >= WARNING_PERCENT {
+        "warning"
+    } else {
+        "normal"
+    };
+    let tooltip = views.iter().map(tooltip).collect::<Vec<_>>().join("\n\n");
+    let module = json!({
+        "text": text,
+        "tooltip": tooltip,
+        "class": class,
+        "percentage": percentage.round() as u64,
+    });
+    writeln!(out, "{}", module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_short_sizes() {
+        assert_eq!(fraction(7_730_941_132, 17_179_869_184), "7.2/16GiB");
+        assert_eq!(bytes(1_181_116_006), "1.1GiB");
+        assert_eq!(bytes(73_400_320), "70MiB");
+        assert_eq!(bytes(0), "0B");
+    }
+}
@@ -0,0 +1,143 @@
+//! Helpers for the output formats.
+
+use crate::{cli::OutputFormat, error::Error};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    io::{self, IsTerminal, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+static PLAIN: AtomicBool = AtomicBool::new(false);
+
+pub fn set_plain() {
+    PLAIN.store(true, Ordering::Relaxed);
+}
+
+/// Whether `--plain` asked for labeled lines instead of tables.
+pub fn plain() -> bool {
+    PLAIN.load(Ordering::Relaxed)
+}
+
+/// Whether to color table output: only on a terminal, and never when
+/// `NO_COLOR` is set or with `--plain`.
+pub fn use_color() -> bool {
+    io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none() && !plain()
+}
+
+/// The local time in RFC 3339, to the millisecond, like
+/// `2026-10-14T09:30:00.250+02:00`, to line a capture up with other logs.
+pub fn timestamp() -> String {
+    local_time(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default(),
+    )
+}
+
+/// `since_epoch` like `timestamp`.
+pub fn local_time(since_epoch: Duration) -> String {
+    let seconds = since_epoch.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&seconds, &mut tm) }.is_null() {
+        return String::new();
+    }
+    let offset = tm.tm_gmtoff / 60;
+    let sign = if offset < 0 { '-' } else { '+' };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}{}{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        since_epoch.subsec_millis(),
+        sign,
+        offset.abs() / 60,
+        offset.abs() % 60
+    )
+}
+
+/// Quotes a CSV field if it needs it.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Set by `-0`: CSV rows get a NUL after every field instead of commas,
+/// quotes and newlines.
+static NUL_TERMINATED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_nul_terminated() {
+    NUL_TERMINATED.store(true, Ordering::Relaxed);
+}
+
+pub fn write_csv_row<W: Write, S: AsRef<str>>(out: &mut W, fields: &[S]) -> io::Result<()> {
+    if NUL_TERMINATED.load(Ordering::Relaxed) {
+        return write_nul_terminated(out, fields);
+    }
+    let fields = fields
+        .iter()
+        .map(|field| csv_field(field.as_ref()))
+        .collect::<Vec<_>>();
+    writeln!(out, "{}", fields.join(","))
+}
+
+/// Fields as they are, each followed by a NUL, so paths with commas, quotes
+/// or newlines in them come out unchanged. Records have as many fields as
+/// the header.
+fn write_nul_terminated<W: Write, S: AsRef<str>>(out: &mut W, fields: &[S]) -> io::Result<()> {
+    for field in fields {
+        out.write_all(field.as_ref().as_bytes())?;
+        out.write_all(b"\0")?;
+    }
+    Ok(())
+}
+
+/// The error for a command asked for an output format it doesn't have.
+pub fn unsupported(format: OutputFormat, command: &str) -> Error {
+    let name = format
+        .to_possible_value()
+        .map(|value| value.get_name().to_string())
+        .unwrap_or_default();
+    Error::InvalidArgument(format!("{} can't write --output {}", command, name))
+}
+
+pub fn write_json<W: Write, T: Serialize>(out: &mut W, value: &T) -> io::Result<()> {
+    serde_json::to_writer_pretty(&mut *out, value)?;
+    writeln!(out)
+}
+
+/// `value` on a line of its own, for newline-delimited JSON.
+pub fn write_json_line<W: Write, T: Serialize>(out: &mut W, value: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_csv_fields() {
+        let mut out = Vec::new();
+        write_csv_row(&mut out, &["plain", "a,b", "say \"hi\""]).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "plain,\"a,b\",\"say \"\"hi\"\"\"\n"
+        );
+    }
+
+    #[test]
+    fn terminates_fields_with_nul() {
+        let mut out = Vec::new();
+        write_nul_terminated(&mut out, &["3301", "/opt/my game,\n2/bin"]).unwrap();
+        assert_eq!(out, b"3301\0/opt/my game,\n2/bin\0");
+    }
+}
@@ -0,0 +1,232 @@
+//! Parsing for `pp_od_clk_voltage`, the overdrive table in sysfs.
+//!
+//! The file lists the clock and voltage points currently in effect under
+//! headers like `OD_SCLK:` or `OD_VDDC_CURVE:`, then the limits they can be
+//! moved within under `OD_RANGE:`. Which sections appear depends on the ASIC:
+//! Vega and older give a voltage per DPM level, Navi1x a voltage curve, and
+//! RDNA2 and later just min/max clocks and a voltage offset.
+
+use serde::Serialize;
+use std::convert::TryFrom;
+
+/// One line of a section, like `1: 1750MHz 800mV`, or `-50mV` for an offset.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Point {
+    pub index: Option<u32>,
+    pub clock_mhz: Option<u32>,
+    pub voltage_mv: Option<i32>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Section {
+    /// The header without `OD_`, e.g. `SCLK`.
+    pub name: String,
+    pub points: Vec<Point>,
+}
+
+/// How far a setting can be moved, e.g. `SCLK: 500Mhz 3150Mhz`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Range {
+    pub name: String,
+    pub min: i32,
+    pub max: i32,
+    pub unit: &'static str,
+}
+
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub struct Overdrive {
+    pub sections: Vec<Section>,
+    pub ranges: Vec<Range>,
+}
+
+/// Parses `2800Mhz`, `800mV` or `-50mV` into the number and a normalized unit.
+fn parse_value(value: &str) -> Option<(i32, &'static str)> {
+    let split = value
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let unit = match unit.to_ascii_lowercase().as_str() {
+        "mhz" => "MHz",
+        "mv" => "mV",
+        _ => return None,
+    };
+    Some((number.parse().ok()?, unit))
+}
+
+impl Overdrive {
+    pub fn parse(contents: &str) -> Self {
+        let mut overdrive = Overdrive::default();
+        let mut in_range = false;
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = line
+                .strip_prefix("OD_")
+                .and_then(|line| line.strip_suffix(':'))
+            {
+                in_range = name == "RANGE";
+                if !in_range {
+                    overdrive.sections.push(Section {
+                        name: name.to_string(),
+                        points: Vec::new(),
+                    });
+                }
+                continue;
+            }
+
+            let (label, values) = match line.split_once(':') {
+                Some((label, values)) => (Some(label.trim()), values),
+                None => (None, line),
+            };
+            let values = values
+                .split_whitespace()
+                .filter_map(parse_value)
+                .collect::<Vec<_>>();
+
+            if in_range {
+                if let (Some(name), [(min, unit), (max, _)]) = (label, values.as_slice()) {
+                    overdrive.ranges.push(Range {
+                        name: name.to_string(),
+                        min: *min,
+                        max: *max,
+                        unit,
+                    });
+                }
+                continue;
+            }
+
+            let section = match overdrive.sections.last_mut() {
+                Some(section) => section,
+                None => continue,
+            };
+            let value = |wanted| {
+                values
+                    .iter()
+                    .find(|(_, unit)| *unit == wanted)
+                    .map(|(value, _)| *value)
+            };
+            let point = Point {
+                index: label.and_then(|label| label.parse().ok()),
+                clock_mhz: value("MHz").and_then(|clock| u32::try_from(clock).ok()),
+                voltage_mv: value("mV"),
+            };
+            if point.clock_mhz.is_some() || point.voltage_mv.is_some() {
+                section.points.push(point);
+            }
+        }
+
+        overdrive
+    }
+
+    /// Every value as `(key, value, unit)`, e.g. `sclk_1_clock`, for CSV.
+    pub fn readings(&self) -> Vec<(String, f64, &'static str)> {
+        let mut readings = Vec::new();
+        for section in &self.sections {
+            for point in &section.points {
+                let key = match point.index {
+                    Some(index) => format!("{}_{}", section.name.to_ascii_lowercase(), index),
+                    None => section.name.to_ascii_lowercase(),
+                };
+                if let Some(clock) = point.clock_mhz {
+                    readings.push((format!("{}_clock", key), f64::from(clock), "MHz"));
+                }
+                if let Some(voltage) = point.voltage_mv {
+                    readings.push((format!("{}_voltage", key), f64::from(voltage), "mV"));
+                }
+            }
+        }
+        for range in &self.ranges {
+            let key = format!("range_{}", range.name.to_ascii_lowercase());
+            readings.push((format!("{}_min", key), f64::from(range.min), range.unit));
+            readings.push((format!("{}_max", key), f64::from(range.max), range.unit));
+        }
+        readings
+    }
+}
+
+impl Point {
+    /// `1: 1750 MHz 800 mV`, leaving out what isn't there.
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(index) = self.index {
+            parts.push(format!("{}:", index));
+        }
+        if let Some(clock) = self.clock_mhz {
+            parts.push(format!("{} MHz", clock));
+        }
+        if let Some(voltage) = self.voltage_mv {
+            parts.push(format!("{} mV", voltage));
+        }
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rdna2() {
+        let overdrive = Overdrive::parse(
+            "OD_SCLK:\n\
+             0: 500Mhz\n\
+             1: 2800Mhz\n\
+             OD_MCLK:\n\
+             0: 97Mhz\n\
+             1: 1000MHz\n\
+             OD_VDDGFX_OFFSET:\n\
+             -50mV\n\
+             OD_RANGE:\n\
+             SCLK:     500Mhz       3150Mhz\n\
+             MCLK:     674Mhz       1200Mhz\n",
+        );
+
+        let names = overdrive
+            .sections
+            .iter()
+            .map(|section| section.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["SCLK", "MCLK", "VDDGFX_OFFSET"]);
+        assert_eq!(overdrive.sections[0].points[1].describe(), "1: 2800 MHz");
+        assert_eq!(overdrive.sections[2].points[0].describe(), "-50 mV");
+        assert_eq!(
+            overdrive.ranges[0],
+            Range {
+                name: "SCLK".to_string(),
+                min: 500,
+                max: 3150,
+                unit: "MHz"
+            }
+        );
+    }
+
+    #[test]
+    fn parses_vega_voltages() {
+        let overdrive = Overdrive::parse(
+            "OD_SCLK:\n\
+             0:        852Mhz        800mV\n\
+             7:       1630Mhz       1200mV\n\
+             OD_RANGE:\n\
+             SCLK:     852MHz       2400MHz\n\
+             VDDC:     800mV        1200mV\n",
+        );
+
+        assert_eq!(
+            overdrive.sections[0].points[1],
+            Point {
+                index: Some(7),
+                clock_mhz: Some(1630),
+                voltage_mv: Some(1200)
+            }
+        );
+        assert_eq!(overdrive.ranges[1].unit, "mV");
+        assert_eq!(
+            overdrive.readings()[3],
+            ("sclk_7_voltage".to_string(), 1200.0, "mV")
+        );
+    }
+}
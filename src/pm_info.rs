@@ -0,0 +1,168 @@
+//! Parsing for `/sys/kernel/debug/dri/N/amdgpu_pm_info`.
+//!
+//! The file is the power management code's own summary of load, clocks,
+//! voltages, temperature and power, so it's a useful cross-check for what
+//! sysfs and hwmon report. Its layout is meant for people: a
+//! `GFX Clocks and Power:` block of `<value> <unit> (<label>)` lines and a few
+//! `Label: <value> <unit>` lines, varying a little between ASICs.
+
+use serde::Serialize;
+
+/// A value the file labels itself, like `1500 MHz (SCLK)`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Labelled {
+    pub label: String,
+    pub value: f64,
+}
+
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub struct PmInfo {
+    pub gpu_load_percent: Option<f64>,
+    pub memory_load_percent: Option<f64>,
+    pub temperature_celsius: Option<f64>,
+    /// `SCLK`, `MCLK` and whatever else the ASIC lists, in MHz.
+    pub clocks_mhz: Vec<Labelled>,
+    /// `VDDGFX`, `VDDNB` and the like, in mV.
+    pub voltages_mv: Vec<Labelled>,
+    /// Labelled by what was measured, e.g. `average SoC`, in W.
+    pub power_watts: Vec<Labelled>,
+}
+
+/// Splits `45 C` or `12 %` into the number and its unit.
+fn value_and_unit(text: &str) -> Option<(f64, &str)> {
+    let mut parts = text.split_whitespace();
+    let value = parts.next()?.parse().ok()?;
+    Some((value, parts.next().unwrap_or_default()))
+}
+
+impl PmInfo {
+    pub fn parse(contents: &str) -> Self {
+        let mut pm_info = PmInfo::default();
+
+        for line in contents.lines() {
+            let line = line.trim();
+
+            // `1500 MHz (SCLK)`
+            if let Some((reading, label)) = line
+                .strip_suffix(')')
+                .and_then(|line| line.split_once(" ("))
+            {
+                let (value, unit) = match value_and_unit(reading) {
+                    Some(value_and_unit) => value_and_unit,
+                    None => continue,
+                };
+                let labelled = Labelled {
+                    label: label.to_string(),
+                    value,
+                };
+                match unit {
+                    "MHz" => pm_info.clocks_mhz.push(labelled),
+                    "mV" => pm_info.voltages_mv.push(labelled),
+                    "W" => pm_info.power_watts.push(labelled),
+                    _ => {}
+                }
+                continue;
+            }
+
+            // `GPU Load: 12 %`
+            let (label, reading) = match line.split_once(':') {
+                Some(split) => split,
+                None => continue,
+            };
+            let value = value_and_unit(reading).map(|(value, _)| value);
+            match label {
+                "GPU Load" => pm_info.gpu_load_percent = value,
+                "MEM Load" => pm_info.memory_load_percent = value,
+                "GPU Temperature" => pm_info.temperature_celsius = value,
+                _ => {}
+            }
+        }
+
+        pm_info
+    }
+
+    /// Every value as `(key, label, value, unit)`, in display order.
+    pub fn readings(&self) -> Vec<(String, String, f64, &'static str)> {
+        let mut readings = Vec::new();
+        let mut single = |key: &str, label: &str, value: Option<f64>, unit| {
+            if let Some(value) = value {
+                readings.push((key.to_string(), label.to_string(), value, unit));
+            }
+        };
+        single("gpu_load", "GPU load", self.gpu_load_percent, "%");
+        single("memory_load", "Memory load", self.memory_load_percent, "%");
+        single("temperature", "Temperature", self.temperature_celsius, "°C");
+
+        let labelled = [
+            (&self.clocks_mhz, "clock", "MHz"),
+            (&self.voltages_mv, "voltage", "mV"),
+            (&self.power_watts, "power", "W"),
+        ];
+        for (values, kind, unit) in labelled {
+            for value in values {
+                let key = format!(
+                    "{}_{}",
+                    value.label.to_ascii_lowercase().replace(' ', "_"),
+                    kind
+                );
+                let label = match kind {
+                    "power" => format!("{} power", value.label),
+                    _ => value.label.clone(),
+                };
+                readings.push((key, label, value.value, unit));
+            }
+        }
+
+        readings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_navi21() {
+        let pm_info = PmInfo::parse(
+            "Clock Gating Flags Mask: 0x3c36c200\n\
+             \tGraphics Medium Grain Clock Gating: On\n\
+             \n\
+             GFX Clocks and Power:\n\
+             \t1000 MHz (MCLK)\n\
+             \t1500 MHz (SCLK)\n\
+             \t500 MHz (PSTATE_SCLK)\n\
+             \t96 MHz (PSTATE_MCLK)\n\
+             \t925 mV (VDDGFX)\n\
+             \t35.0 W (average SoC)\n\
+             \n\
+             GPU Temperature: 45 C\n\
+             GPU Load: 12 %\n\
+             MEM Load: 3 %\n\
+             \n\
+             SMC Feature Mask: 0x00003763a37f7dff\n\
+             VCN: Disabled\n",
+        );
+
+        assert_eq!(pm_info.gpu_load_percent, Some(12.0));
+        assert_eq!(pm_info.memory_load_percent, Some(3.0));
+        assert_eq!(pm_info.temperature_celsius, Some(45.0));
+        assert_eq!(pm_info.clocks_mhz.len(), 4);
+        assert_eq!(pm_info.clocks_mhz[1].label, "SCLK");
+        assert_eq!(pm_info.clocks_mhz[1].value, 1500.0);
+        assert_eq!(
+            pm_info.voltages_mv,
+            [Labelled {
+                label: "VDDGFX".to_string(),
+                value: 925.0
+            }]
+        );
+        assert_eq!(pm_info.power_watts[0].label, "average SoC");
+        assert_eq!(pm_info.readings()[8].0, "average_soc_power");
+    }
+
+    #[test]
+    fn ignores_what_it_does_not_know() {
+        let pm_info = PmInfo::parse("UVD: Disabled\n\tsomething (new)\nGPU Load: lots\n");
+        assert_eq!(pm_info, PmInfo::default());
+    }
+}
@@ -0,0 +1,307 @@
+//! Power management settings amdgpu exposes in sysfs, read for display and
+//! changed through `amdtop set`.
+
+use crate::{
+    cli::{GlobalArgs, SetArgs, Setting},
+    error::{self, Error},
+    priority,
+    sensors::{self, Sensors},
+    source::{read_sysfs_u64, Device},
+    sysroot,
+};
+use clap::ValueEnum;
+use std::{io, path::Path};
+
+/// What `power_dpm_force_performance_level` accepts.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum PerfLevel {
+    /// Let the driver pick clocks dynamically
+    Auto,
+    /// Pin clocks to their lowest level
+    Low,
+    /// Pin clocks to their highest level
+    High,
+    /// Allow choosing levels through pp_dpm_* and power profiles
+    Manual,
+}
+
+impl PerfLevel {
+    fn name(self) -> &'static str {
+        match self {
+            PerfLevel::Auto => "auto",
+            PerfLevel::Low => "low",
+            PerfLevel::High => "high",
+            PerfLevel::Manual => "manual",
+        }
+    }
+}
+
+/// What `amdtop set fan` was asked for.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum FanSpeed {
+    Auto,
+    Percent(f64),
+}
+
+/// The slowest manual fan speed we'll set. A stopped fan with the firmware's
+/// curve switched off is an easy way to cook a card.
+const MIN_MANUAL_FAN_PERCENT: f64 = 20.0;
+
+/// The device's `power_dpm_force_performance_level`, e.g. `auto`.
+pub fn performance_level(device: Device) -> Option<String> {
+    let level =
+        std::fs::read_to_string(device.sysfs_dir().join("power_dpm_force_performance_level"))
+            .ok()?;
+    Some(level.trim().to_string())
+}
+
+/// A row of `pp_power_profile_mode`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerProfile {
+    pub index: u32,
+    pub name: String,
+    pub active: bool,
+}
+
+/// Picks the profile rows out of `pp_power_profile_mode`. They start with
+/// the index and name, like ` 1 3D_FULL_SCREEN*:` or `  1 3D_FULL_SCREEN *:`,
+/// with a `*` marking the active one. Tuning parameters follow on the same
+/// line or the lines below, depending on the ASIC.
+fn parse_power_profiles(contents: &str) -> Vec<PowerProfile> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (head, _) = line.split_once(':')?;
+            let mut fields = head.split_whitespace();
+            let index = fields.next()?.parse().ok()?;
+            let name = fields.collect::<String>();
+            let active = name.ends_with('*');
+            let name = name.trim_end_matches('*');
+            if name.is_empty() {
+                return None;
+            }
+            Some(PowerProfile {
+                index,
+                name: name.to_string(),
+                active,
+            })
+        })
+        .collect()
+}
+
+/// The device's power profiles, empty if it doesn't have any.
+pub fn power_profiles(device: Device) -> Vec<PowerProfile> {
+    std::fs::read_to_string(device.sysfs_dir().join("pp_power_profile_mode"))
+        .map(|contents| parse_power_profiles(&contents))
+        .unwrap_or_default()
+}
+
+/// Changing settings needs root on a real system. Fixture trees under
+/// `--root` are just files, so anyone may write those.
+fn require_root(what: &str) -> error::Result<()> {
+    if unsafe { libc::geteuid() } == 0 || sysroot::is_set() {
+        return Ok(());
+    }
+    Err(Error::PermissionDenied(format!(
+        "changing the {} needs root, try `sudo amdtop set ...`",
+        what
+    )))
+}
+
+/// Writes `value` to one of `device`'s sysfs or hwmon files, explaining the
+/// usual failures.
+fn write_device_file(device: Device, path: &Path, value: &str) -> error::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    std::fs::write(path, value).map_err(|err| match err.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EPERM) => {
+            Error::PermissionDenied(format!("permission denied writing {}", path.display()))
+        }
+        Some(libc::ENOENT) => Error::UnsupportedKernel(format!(
+            "{} has no {}, so it can't be changed on this device",
+            device, name
+        )),
+        Some(libc::EINVAL) => {
+            Error::UnsupportedKernel(format!("{} rejected {:?} for {}", device, value, name))
+        }
+        _ => Error::Io(io::Error::new(
+            err.kind(),
+            format!("failed to write {}: {}", path.display(), err),
+        )),
+    })
+}
+
+pub fn run(global: &GlobalArgs, options: &SetArgs) -> error::Result<()> {
+    // Not power management, and meant for virtual functions as well.
+    if let Setting::Priority { pid, level } = options.setting {
+        return priority::run(global, pid, level);
+    }
+    let devices = sensors::selected_devices(global)?;
+    if let Some(device) = devices.iter().find(|device| device.is_virtual_function()) {
+        return Err(Error::UnsupportedKernel(format!(
+            "{} is a virtual function; its power management belongs to the host",
+            device
+        )));
+    }
+
+    match &options.setting {
+        Setting::PerfLevel { level } => {
+            require_root("performance level")?;
+            for device in devices {
+                let path = device.sysfs_dir().join("power_dpm_force_performance_level");
+                write_device_file(device, &path, level.name())?;
+                println!("{} | perf {}", device, level.name());
+            }
+        }
+        Setting::PowerCap { watts } => {
+            require_root("power cap")?;
+            for device in devices {
+                set_power_cap(device, *watts)?;
+                println!("{} | power cap {} W", device, watts);
+            }
+        }
+        Setting::Fan { speed } => {
+            require_root("fan speed")?;
+            for device in devices {
+                set_fan(device, *speed)?;
+            }
+        }
+        Setting::PowerProfile { profile } => {
+            require_root("power profile")?;
+            for device in devices {
+                set_power_profile(device, profile)?;
+            }
+        }
+        Setting::Priority { .. } => unreachable!("handled above"),
+    }
+    Ok(())
+}
+
+/// Switches to the profile named or numbered `wanted`.
+fn set_power_profile(device: Device, wanted: &str) -> error::Result<()> {
+    let profiles = power_profiles(device);
+    let profile = profiles
+        .iter()
+        .find(|profile| {
+            profile.name.eq_ignore_ascii_case(wanted) || profile.index.to_string() == wanted
+        })
+        .ok_or_else(|| {
+            let names = profiles
+                .iter()
+                .map(|profile| profile.name.as_str())
+                .collect::<Vec<_>>();
+            Error::InvalidArgument(if names.is_empty() {
+                format!("{} has no power profiles", device)
+            } else {
+                format!(
+                    "{} has no power profile {:?}, expected one of {}",
+                    device,
+                    wanted,
+                    names.join(", ")
+                )
+            })
+        })?;
+
+    let path = device.sysfs_dir().join("pp_power_profile_mode");
+    write_device_file(device, &path, &profile.index.to_string()).map_err(|err| {
+        match (err, performance_level(device)) {
+            // Older power management code ignores profiles unless asked to.
+            (Error::UnsupportedKernel(message), Some(level)) if level != "manual" => {
+                Error::UnsupportedKernel(format!(
+                    "{}\nsome devices only switch profiles with `amdtop set perf-level manual`",
+                    message
+                ))
+            }
+            (err, _) => err,
+        }
+    })?;
+    println!("{} | profile {}", device, profile.name);
+    Ok(())
+}
+
+/// Sets `power1_cap`, refusing values outside `power1_cap_min` and
+/// `power1_cap_max` rather than leaving it to the driver to clamp.
+fn set_power_cap(device: Device, watts: f64) -> error::Result<()> {
+    let hwmon_dir = sensors::hwmon_dir(device)
+        .ok_or_else(|| Error::UnsupportedKernel(format!("{} has no hwmon directory", device)))?;
+
+    let sensors = Sensors::read(device);
+    let min = sensors.power_cap_min_watts.unwrap_or(0.0);
+    if watts < min || sensors.power_cap_max_watts.is_some_and(|max| watts > max) {
+        return Err(Error::InvalidArgument(format!(
+            "{} only allows a power cap between {} W and {} W",
+            device,
+            min,
+            sensors
+                .power_cap_max_watts
+                .map_or_else(|| "?".to_string(), |max| max.to_string()),
+        )));
+    }
+
+    let microwatts = (watts * 1e6).round() as u64;
+    write_device_file(
+        device,
+        &hwmon_dir.join("power1_cap"),
+        &microwatts.to_string(),
+    )
+}
+
+/// Switches `pwm1_enable` between the firmware's curve and a fixed duty
+/// cycle, clamped to at least `MIN_MANUAL_FAN_PERCENT`.
+fn set_fan(device: Device, speed: FanSpeed) -> error::Result<()> {
+    let hwmon_dir = sensors::hwmon_dir(device)
+        .ok_or_else(|| Error::UnsupportedKernel(format!("{} has no hwmon directory", device)))?;
+
+    let percent = match speed {
+        FanSpeed::Auto => {
+            write_device_file(device, &hwmon_dir.join("pwm1_enable"), "2")?;
+            println!("{} | fan auto", device);
+            return Ok(());
+        }
+        FanSpeed::Percent(percent) if percent < MIN_MANUAL_FAN_PERCENT => {
+            eprintln!(
+                "amdtop: {}: fan speeds below {} % aren't allowed, using {} %",
+                device, MIN_MANUAL_FAN_PERCENT, MIN_MANUAL_FAN_PERCENT
+            );
+            MIN_MANUAL_FAN_PERCENT
+        }
+        FanSpeed::Percent(percent) => percent,
+    };
+
+    let min = read_sysfs_u64(&hwmon_dir.join("pwm1_min")).unwrap_or(0);
+    let max = read_sysfs_u64(&hwmon_dir.join("pwm1_max")).unwrap_or(255);
+    let pwm = min + ((max.saturating_sub(min)) as f64 * percent / 100.0).round() as u64;
+
+    // The driver only accepts pwm1 once it's in manual mode.
+    write_device_file(device, &hwmon_dir.join("pwm1_enable"), "1")?;
+    write_device_file(device, &hwmon_dir.join("pwm1"), &pwm.to_string())?;
+    println!("{} | fan manual {} %", device, percent);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_power_profiles() {
+        let navi = parse_power_profiles(
+            "PROFILE_INDEX(NAME) CLOCK_TYPE(NAME) FPS MinActiveFreqType MinActiveFreq\n              0 BOOTUP_DEFAULT :\n                    0(       GFXCLK)       0       5       0\n              1 3D_FULL_SCREEN*:\n                    0(       GFXCLK)       1       5       1\n              5 COMPUTE :\n",
+        );
+        assert_eq!(navi.len(), 3);
+        assert_eq!(
+            navi[1],
+            PowerProfile {
+                index: 1,
+                name: "3D_FULL_SCREEN".to_string(),
+                active: true
+            }
+        );
+        assert!(!navi[2].active);
+
+        let vega = parse_power_profiles(
+            "NUM        MODE_NAME     SCLK_UP_HYST   SCLK_DOWN_HYST\n               0   BOOTUP_DEFAULT:        -                -\n               3          VIDEO *:        10               0\n",
+        );
+        assert_eq!(vega[1].name, "VIDEO");
+        assert!(vega[1].active);
+    }
+}
@@ -0,0 +1,196 @@
+//! `amdtop set priority`: moves a process's GPU work up or down in amdgpu's
+//! schedulers, so a background transcode can make way for a game without
+//! being killed.
+//!
+//! The `AMDGPU_SCHED` ioctl overrides the priority of every context created
+//! through one DRM file, which it takes as a descriptor of our own; we copy
+//! the target's with `pidfd_getfd(2)`. amdgpu only accepts the ioctl from
+//! the DRM master, and never says what priority a context has, so there's
+//! nothing for `mem` to show either.
+
+use crate::{
+    cli::GlobalArgs,
+    error::{self, Error},
+    source::Device,
+    sysroot,
+};
+use clap::ValueEnum;
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+};
+
+/// `DRM_IOW(DRM_COMMAND_BASE + DRM_AMDGPU_SCHED, struct drm_amdgpu_sched)`.
+const DRM_IOCTL_AMDGPU_SCHED: libc::c_ulong = 0x4010_6455;
+
+const AMDGPU_SCHED_OP_PROCESS_PRIORITY_OVERRIDE: u32 = 1;
+
+/// `struct drm_amdgpu_sched`.
+#[repr(C)]
+struct DrmAmdgpuSched {
+    op: u32,
+    fd: u32,
+    priority: i32,
+    ctx_id: u32,
+}
+
+/// The `AMDGPU_CTX_PRIORITY_*` levels.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Priority {
+    /// Behind everything else
+    VeryLow,
+    /// Behind other clients, for background work
+    Low,
+    /// Where every client starts
+    Normal,
+    /// Above other clients; only the compositor usually asks for it
+    High,
+    /// Ahead of everything, including the compositor
+    VeryHigh,
+}
+
+impl Priority {
+    fn name(self) -> &'static str {
+        match self {
+            Priority::VeryLow => "very-low",
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+            Priority::VeryHigh => "very-high",
+        }
+    }
+
+    fn value(self) -> i32 {
+        match self {
+            Priority::VeryLow => -1023,
+            Priority::Low => -512,
+            Priority::Normal => 0,
+            Priority::High => 512,
+            Priority::VeryHigh => 1023,
+        }
+    }
+}
+
+/// The device a `/dev/dri` node is for.
+fn node_device(node: &str) -> Option<Device> {
+    if let Some(minor) = node.strip_prefix("renderD") {
+        return Device::from_render_minor(minor.parse().ok()?);
+    }
+    let minor = node.strip_prefix("card")?.parse::<u32>().ok()?;
+    Device::list()
+        .into_iter()
+        .find(|device| device.minor == minor)
+}
+
+/// The descriptors `pid` has open on amdgpu devices.
+fn drm_descriptors(pid: i32) -> error::Result<Vec<(Device, RawFd)>> {
+    let fd_dir = sysroot::path(format!("/proc/{}/fd", pid));
+    let entries = std::fs::read_dir(&fd_dir).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound => Error::InvalidArgument(format!("there's no process {}", pid)),
+        io::ErrorKind::PermissionDenied => Error::PermissionDenied(format!(
+            "can't look at the descriptors of {}, try `sudo amdtop set ...`",
+            pid
+        )),
+        _ => Error::Io(err),
+    })?;
+    let mut descriptors = entries
+        .flatten()
+        .filter_map(|entry| {
+            let fd = entry.file_name().to_str()?.parse().ok()?;
+            let target = std::fs::read_link(entry.path()).ok()?;
+            let node = target.strip_prefix("/dev/dri").ok()?.to_str()?.to_string();
+            Some((node_device(&node)?, fd))
+        })
+        .collect::<Vec<_>>();
+    descriptors.sort_unstable();
+    Ok(descriptors)
+}
+
+fn syscall_fd(result: libc::c_long) -> io::Result<OwnedFd> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(result as RawFd) })
+}
+
+/// A copy of descriptor `fd` of `pid`, referring to the same open file.
+fn copy_descriptor(pid: i32, fd: RawFd) -> io::Result<OwnedFd> {
+    let pidfd = syscall_fd(unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) })?;
+    syscall_fd(unsafe { libc::syscall(libc::SYS_pidfd_getfd, pidfd.as_raw_fd(), fd, 0) })
+}
+
+fn override_priority(master: &File, client: &OwnedFd, priority: Priority) -> io::Result<()> {
+    let mut request = DrmAmdgpuSched {
+        op: AMDGPU_SCHED_OP_PROCESS_PRIORITY_OVERRIDE,
+        fd: client.as_raw_fd() as u32,
+        priority: priority.value(),
+        ctx_id: 0,
+    };
+    let result = unsafe {
+        libc::ioctl(
+            master.as_raw_fd(),
+            DRM_IOCTL_AMDGPU_SCHED as _,
+            &mut request as *mut DrmAmdgpuSched,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn run(global: &GlobalArgs, pid: i32, priority: Priority) -> error::Result<()> {
+    let descriptors = drm_descriptors(pid)?
+        .into_iter()
+        .filter(|(device, _)| global.selects(*device))
+        .collect::<Vec<_>>();
+    if descriptors.is_empty() {
+        return Err(Error::NoDevice(format!(
+            "{} has no amdgpu device open that matches --gpu",
+            pid
+        )));
+    }
+    if sysroot::is_set() {
+        return Err(Error::UnsupportedKernel(
+            "priorities live in the kernel, not in the files under --root".to_string(),
+        ));
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(Error::PermissionDenied(
+            "changing priorities needs root, try `sudo amdtop set ...`".to_string(),
+        ));
+    }
+
+    let mut devices = descriptors
+        .iter()
+        .map(|(device, _)| *device)
+        .collect::<Vec<_>>();
+    devices.dedup();
+    for device in devices {
+        let master = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(format!("/dev/dri/card{}", device.minor))?;
+        for (_, fd) in descriptors.iter().filter(|(on, _)| *on == device) {
+            let client = copy_descriptor(pid, *fd)?;
+            override_priority(&master, &client, priority).map_err(|err| {
+                match err.raw_os_error() {
+                    Some(libc::EACCES) => Error::PermissionDenied(format!(
+                        "{} only takes priorities from its DRM master; while a compositor \
+                         holds it, run this from a console without one",
+                        device
+                    )),
+                    Some(libc::EINVAL) => Error::UnsupportedKernel(format!(
+                        "{} rejected priority {}",
+                        device,
+                        priority.name()
+                    )),
+                    _ => Error::Io(err),
+                }
+            })?;
+        }
+        println!("{} | {} priority {}", device, pid, priority.name());
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! Running as root on someone's behalf, under sudo or pkexec: what only root
+//! may read is opened first, then we switch to the user who ran us for the
+//! rest of the session. Later reads of those files go through the handles
+//! kept here, since opening them again would fail.
+
+use crate::{kmsg, sysroot};
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+/// Files of each device in debugfs' `dri/N` that are read after dropping
+/// root, besides `amdgpu_gem_info`: the fence pane and GFXOFF in `top`.
+const DEVICE_FILES: &[&str] = &["amdgpu_fence_info", "amdgpu_gfxoff_status"];
+
+/// Handles opened while still root, by path.
+static KEPT: Mutex<Vec<(PathBuf, File)>> = Mutex::new(Vec::new());
+
+static DROPPED: AtomicBool = AtomicBool::new(false);
+
+fn env_id(name: &str) -> Option<u32> {
+    std::env::var(name).ok()?.parse().ok()
+}
+
+/// The user who started us through sudo or pkexec, if we're running as root
+/// on their behalf.
+pub fn invoking_user() -> Option<(libc::uid_t, libc::gid_t)> {
+    if unsafe { libc::geteuid() } != 0 {
+        return None;
+    }
+
+    if let Some(uid) = env_id("SUDO_UID") {
+        return Some((uid, env_id("SUDO_GID").unwrap_or(uid)));
+    }
+
+    // pkexec only tells us the uid, so look up the primary group.
+    let uid = env_id("PKEXEC_UID")?;
+    let passwd = unsafe { libc::getpwuid(uid) };
+    if passwd.is_null() {
+        return None;
+    }
+    Some((uid, unsafe { (*passwd).pw_gid }))
+}
+
+/// Whether we switched away from root, so what only root may open fails.
+pub fn dropped() -> bool {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Keeps `file`, opened from `path`, for reads after dropping root.
+pub fn keep(path: PathBuf, file: File) {
+    KEPT.lock().expect("not poisoned").push((path, file));
+}
+
+/// Another handle to `path`, if one was opened before dropping root. It
+/// shares the offset of the one kept.
+pub fn kept(path: &Path) -> Option<io::Result<File>> {
+    let kept = KEPT.lock().expect("not poisoned");
+    kept.iter()
+        .find(|(kept_path, _)| kept_path == path)
+        .map(|(_, file)| file.try_clone())
+}
+
+/// Reads all of `path`, through the handle kept for it if there is one.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    match kept(path) {
+        Some(file) => {
+            let mut file = file?;
+            let mut contents = Vec::new();
+            file.seek(SeekFrom::Start(0))?;
+            file.read_to_end(&mut contents)?;
+            Ok(contents)
+        }
+        None => std::fs::read(path),
+    }
+}
+
+/// Opens what's read later and only root may, then switches to `uid`,
+/// `gid` and the groups the user is in. Files that appear later, like those of a device plugged in after,
+/// can't be read.
+pub fn drop_to(uid: libc::uid_t, gid: libc::gid_t, debugfs_path: &Path) -> io::Result<()> {
+    // Where dmesg is restricted, and it's optional anyway.
+    let kmsg_path = sysroot::path("/dev/kmsg");
+    if let Ok(file) = kmsg::open(&kmsg_path) {
+        keep(kmsg_path, file);
+    }
+
+    for name in DEVICE_FILES {
+        let pattern = debugfs_path.join("dri").join("*").join(name);
+        let paths = glob::glob(&pattern.to_string_lossy())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        // Not every device or kernel has them.
+        for path in paths.flatten() {
+            if let Ok(file) = File::open(&path) {
+                keep(path, file);
+            }
+        }
+    }
+
+    unsafe {
+        // The user's own groups, like render and video for the device
+        // files; a uid without a name has none besides its group.
+        let passwd = libc::getpwuid(uid);
+        let groups = if passwd.is_null() {
+            libc::setgroups(1, &gid)
+        } else {
+            libc::initgroups((*passwd).pw_name, gid)
+        };
+        if groups != 0 || libc::setgid(gid) != 0 || libc::setuid(uid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::setuid(0) == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "could still become root after switching users",
+            ));
+        }
+    }
+    DROPPED.store(true, Ordering::Relaxed);
+    Ok(())
+}
@@ -0,0 +1,268 @@
+//! Sessions recorded with `--output ndjson`, a snapshot a line, read back.
+//! Only what's needed to show them again is parsed; a recording made by a
+//! newer amdtop, with fields we don't know, still reads.
+
+use crate::{
+    error::{self, Error},
+    source::DeviceUsage,
+};
+use serde::{de, Deserialize, Deserializer};
+use std::{collections::BTreeMap, io, path::Path};
+
+/// One refresh of a recording.
+#[derive(Deserialize)]
+pub struct Snapshot {
+    /// Seconds since the Unix epoch.
+    #[serde(deserialize_with = "deserialize_time")]
+    pub time: f64,
+    /// Missing from recordings made before snapshots were numbered.
+    #[serde(default)]
+    pub sequence: u64,
+    pub devices: Vec<RecordedDevice>,
+    /// The line as it was recorded.
+    #[serde(skip)]
+    pub line: String,
+}
+
+#[derive(Deserialize)]
+pub struct RecordedDevice {
+    /// `card0` and up, as the recording machine named it.
+    pub device: String,
+    pub usage: Option<DeviceUsage>,
+    pub processes: Option<Vec<RecordedProcess>>,
+}
+
+#[derive(Deserialize)]
+pub struct RecordedProcess {
+    pub pid: i32,
+    pub name: Option<String>,
+    pub path: Option<String>,
+    #[serde(default)]
+    pub exited: bool,
+    pub vram_bytes: u64,
+    pub gtt_bytes: u64,
+    /// With `--show-busy`.
+    #[serde(default)]
+    pub engine_busy: BTreeMap<String, f64>,
+}
+
+/// When a snapshot was taken, as recorded: in RFC 3339, or in seconds since
+/// the Unix epoch before schema version 2.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RecordedTime {
+    Unix(f64),
+    Rfc3339(String),
+}
+
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    match RecordedTime::deserialize(deserializer)? {
+        RecordedTime::Unix(seconds) => Ok(seconds),
+        RecordedTime::Rfc3339(time) => match TimeSpec::from_name(&time) {
+            Some(TimeSpec::Unix(seconds)) => Ok(seconds),
+            _ => Err(de::Error::custom(format!(
+                "not an RFC 3339 time: {:?}",
+                time
+            ))),
+        },
+    }
+}
+
+/// Every snapshot in the recording at `path`. A last line cut short, by a
+/// recording that was killed, is left out.
+pub fn read(path: &Path) -> error::Result<Vec<Snapshot>> {
+    let contents = std::fs::read_to_string(path).map_err(|err| match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => {
+            Error::InvalidArgument(format!("can't read {}: {}", path.display(), err))
+        }
+        _ => Error::Io(err),
+    })?;
+    let lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .collect::<Vec<_>>();
+    let mut snapshots = Vec::with_capacity(lines.len());
+    for (index, &(number, line)) in lines.iter().enumerate() {
+        let record = match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(record) => record,
+            Err(_) if index + 1 == lines.len() && index > 0 => break,
+            Err(err) => {
+                return Err(Error::Parse(format!(
+                    "{}:{}: {}",
+                    path.display(),
+                    number + 1,
+                    err
+                )))
+            }
+        };
+        if record.get("pid").is_some() {
+            return Err(Error::InvalidArgument(format!(
+                "{} has a line per process; record snapshots, without --record process",
+                path.display()
+            )));
+        }
+        let mut snapshot = serde_json::from_value::<Snapshot>(record)
+            .map_err(|err| Error::Parse(format!("{}:{}: {}", path.display(), number + 1, err)))?;
+        snapshot.line = line.to_string();
+        snapshots.push(snapshot);
+    }
+    Ok(snapshots)
+}
+
+/// A point in a recording, for `--from` and `--to`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TimeSpec {
+    /// Seconds since the Unix epoch.
+    Unix(f64),
+    /// Seconds since local midnight, on the day the recording starts.
+    TimeOfDay(f64),
+}
+
+/// `HH:MM`, `HH:MM:SS` or `HH:MM:SS.fff`, in seconds.
+fn parse_clock(value: &str) -> Option<f64> {
+    let mut fields = value.split(':');
+    let hours = fields
+        .next()?
+        .parse::<u32>()
+        .ok()
+        .filter(|hours| *hours < 24)?;
+    let minutes = fields
+        .next()?
+        .parse::<u32>()
+        .ok()
+        .filter(|minutes| *minutes < 60)?;
+    let seconds = match fields.next() {
+        Some(seconds) => seconds
+            .parse::<f64>()
+            .ok()
+            .filter(|seconds| (0.0..61.0).contains(seconds))?,
+        None => 0.0,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some(f64::from(hours * 3600 + minutes * 60) + seconds)
+}
+
+/// Seconds since the epoch of `day` at local midnight, or at UTC midnight
+/// with `utc`.
+fn midnight(mut day: libc::tm, utc: bool) -> Option<f64> {
+    day.tm_hour = 0;
+    day.tm_min = 0;
+    day.tm_sec = 0;
+    day.tm_isdst = -1;
+    let seconds = unsafe {
+        if utc {
+            libc::timegm(&mut day)
+        } else {
+            libc::mktime(&mut day)
+        }
+    };
+    Some(seconds as f64).filter(|_| seconds != -1)
+}
+
+impl TimeSpec {
+    /// Parses Unix seconds like `1791963000.5`, RFC 3339 like
+    /// `2026-10-14T09:30:00+02:00` (local time without an offset, and a
+    /// space works as well as the `T`), or a time of day like `09:30`.
+    pub fn from_name(value: &str) -> Option<TimeSpec> {
+        if let Ok(seconds) = value.parse::<f64>() {
+            return Some(TimeSpec::Unix(seconds)).filter(|_| seconds.is_finite());
+        }
+        let (date, time) = match value.split_once(['T', ' ']) {
+            Some(split) => split,
+            None => return parse_clock(value).map(TimeSpec::TimeOfDay),
+        };
+        let mut fields = date.split('-');
+        let mut day: libc::tm = unsafe { std::mem::zeroed() };
+        day.tm_year = fields.next()?.parse::<i32>().ok()? - 1900;
+        day.tm_mon = fields
+            .next()?
+            .parse::<i32>()
+            .ok()
+            .filter(|month| (1..=12).contains(month))?
+            - 1;
+        day.tm_mday = fields
+            .next()?
+            .parse::<i32>()
+            .ok()
+            .filter(|day| (1..=31).contains(day))?;
+        if fields.next().is_some() {
+            return None;
+        }
+
+        let (clock, offset) = if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+            (clock, Some(0.0))
+        } else if let Some(split) = time.rfind(['+', '-']) {
+            let (clock, offset) = time.split_at(split);
+            let sign = if offset.starts_with('-') { -1.0 } else { 1.0 };
+            (clock, Some(sign * parse_clock(&offset[1..])?))
+        } else {
+            (time, None)
+        };
+        let clock = parse_clock(clock)?;
+        let seconds = match offset {
+            Some(offset) => midnight(day, true)? + clock - offset,
+            None => midnight(day, false)? + clock,
+        };
+        Some(TimeSpec::Unix(seconds))
+    }
+
+    /// Seconds since the epoch, for a recording that starts at `start`.
+    pub fn resolve(self, start: f64) -> f64 {
+        match self {
+            TimeSpec::Unix(seconds) => seconds,
+            TimeSpec::TimeOfDay(clock) => {
+                let seconds = start as libc::time_t;
+                let mut day: libc::tm = unsafe { std::mem::zeroed() };
+                if unsafe { libc::localtime_r(&seconds, &mut day) }.is_null() {
+                    return start;
+                }
+                midnight(day, false).map_or(start, |midnight| midnight + clock)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(
+            TimeSpec::from_name("1791963000.5"),
+            Some(TimeSpec::Unix(1791963000.5))
+        );
+        assert_eq!(
+            TimeSpec::from_name("2026-10-14T09:30:00.5+02:00"),
+            Some(TimeSpec::Unix(1791963000.5))
+        );
+        assert_eq!(
+            TimeSpec::from_name("2026-10-14 07:30Z"),
+            Some(TimeSpec::Unix(1791963000.0))
+        );
+        assert_eq!(
+            TimeSpec::from_name("09:30:15"),
+            Some(TimeSpec::TimeOfDay(34215.0))
+        );
+        assert_eq!(TimeSpec::from_name("25:00"), None);
+        assert_eq!(TimeSpec::from_name("2026-13-14T09:30"), None);
+        assert_eq!(TimeSpec::from_name("yesterday"), None);
+    }
+
+    #[test]
+    fn reads_times_as_recorded() {
+        let snapshot = |line| serde_json::from_str::<Snapshot>(line).map(|snapshot| snapshot.time);
+        assert_eq!(
+            snapshot(r#"{"time": "2026-10-14T09:30:00.250+02:00", "devices": []}"#).unwrap(),
+            1791963000.25
+        );
+        assert_eq!(
+            snapshot(r#"{"time": 1791963000.25, "devices": []}"#).unwrap(),
+            1791963000.25
+        );
+        assert!(snapshot(r#"{"time": "yesterday", "devices": []}"#).is_err());
+    }
+}
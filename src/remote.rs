@@ -0,0 +1,457 @@
+//! `amdtop agent` and `--connect`: sampling on a headless GPU box and
+//! watching from somewhere else.
+//!
+//! The agent listens on TCP. A viewer sends a request and gets a frame back,
+//! each as a 4 byte big endian length followed by that much JSON. A frame
+//! carries what `top` would draw for each device along with the JSON
+//! snapshot `export` serves, so a viewer needs nothing but the frame to show
+//! either. Viewers ask at their own pace; every request takes a fresh
+//! sample.
+//!
+//! Built with the `tls` feature, `agent --tls-cert --tls-key` encrypts
+//! connections and `--connect ... --tls` checks the agent's certificate.
+//! Without it, across an untrusted network, keep the agent on localhost
+//! and tunnel to it, e.g. `ssh -L 9859:localhost:9859 gpu-box`.
+
+#[cfg(feature = "tls")]
+mod tls;
+
+use crate::{
+    cli::{AgentArgs, GlobalArgs, MemArgs, OutputFormat},
+    error::{self, Error},
+    export::Snapshot,
+    mem::{self, Session},
+    output,
+    sensors::Sensors,
+    smoothing::Smoother,
+    source::Sources,
+    sysroot, systemd,
+    tui::{self, DeviceScreen},
+    INTERRUPTED,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Frames bigger than this are refused rather than allocated.
+const MAX_FRAME_BYTES: usize = 64 << 20;
+
+/// How long a viewer may take none of its frame before it's dropped.
+const STALLED_AFTER: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize)]
+pub struct Request {
+    pub version: u32,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Frame {
+    /// The agent's host name.
+    pub host: String,
+    pub devices: Vec<DeviceScreen>,
+    /// Everything, as `export`'s `/snapshot` has it.
+    pub snapshot: serde_json::Value,
+    /// Why the agent couldn't sample, in place of the rest.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub fn write_frame<W: Write, T: Serialize>(out: &mut W, message: &T) -> io::Result<()> {
+    let json = serde_json::to_vec(message)?;
+    out.write_all(&(json.len() as u32).to_be_bytes())?;
+    out.write_all(&json)?;
+    out.flush()
+}
+
+/// Splits a whole frame off the front of `buffer`, if one has arrived.
+fn take_frame(buffer: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    if buffer.len() < 4 {
+        return Ok(None);
+    }
+    let length = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    if length > MAX_FRAME_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too big", length),
+        ));
+    }
+    if buffer.len() < 4 + length {
+        return Ok(None);
+    }
+    let frame = buffer[4..4 + length].to_vec();
+    buffer.drain(..4 + length);
+    Ok(Some(frame))
+}
+
+pub fn read_frame<R: Read, T: for<'de> Deserialize<'de>>(input: &mut R) -> io::Result<T> {
+    let mut length = [0; 4];
+    input.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_FRAME_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too big", length),
+        ));
+    }
+    let mut json = vec![0; length];
+    input.read_exact(&mut json)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+struct Agent {
+    host: String,
+    sources: Sources,
+    session: Session,
+    smoother: Smoother,
+}
+
+impl Agent {
+    fn frame(&mut self, global: &GlobalArgs, options: &MemArgs) -> error::Result<Frame> {
+        let views = mem::refresh(global, options, &mut self.sources, &mut self.session)?;
+        let devices = tui::device_screens(global, &views, &mut self.smoother)?;
+        let sensors = views
+            .iter()
+            .map(|view| Sensors::read(view.device))
+            .collect::<Vec<_>>();
+        let snapshot = serde_json::to_value(Snapshot {
+            devices: &views,
+            sensors: &sensors,
+        })
+        .map_err(|err| Error::Io(err.into()))?;
+        Ok(Frame {
+            host: self.host.clone(),
+            devices,
+            snapshot,
+            error: None,
+        })
+    }
+
+    /// Answers one request.
+    fn answer(&mut self, global: &GlobalArgs, options: &MemArgs, request: &[u8]) -> Frame {
+        let request = serde_json::from_slice::<Request>(request);
+        let result = match request {
+            Ok(request) if request.version == PROTOCOL_VERSION => self.frame(global, options),
+            Ok(request) => Err(Error::InvalidArgument(format!(
+                "this agent speaks version {}, not {}",
+                PROTOCOL_VERSION, request.version
+            ))),
+            Err(err) => Err(Error::Parse(format!("bad request: {}", err))),
+        };
+        result.unwrap_or_else(|err| Frame {
+            host: self.host.clone(),
+            error: Some(err.to_string()),
+            ..Frame::default()
+        })
+    }
+}
+
+/// What a viewer's bytes go through on their way to and from its socket.
+enum Link {
+    Plain,
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::ServerConnection>),
+}
+
+/// A viewer connection, with what's arrived of its next request and
+/// what's left to send of its last frame.
+struct Client {
+    stream: TcpStream,
+    link: Link,
+    buffer: Vec<u8>,
+    outgoing: Vec<u8>,
+    /// When the viewer last took some of `outgoing`, or it was queued.
+    progressed_at: Instant,
+}
+
+impl Client {
+    /// Reads what's there without waiting. `false` once the viewer is gone.
+    fn read_available(&mut self) -> bool {
+        #[cfg(feature = "tls")]
+        if let Link::Tls(connection) = &mut self.link {
+            return tls::read_available(connection, &mut self.stream, &mut self.buffer)
+                .unwrap_or(false);
+        }
+        let mut chunk = [0; 4096];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return false,
+                Ok(length) => self.buffer.extend_from_slice(&chunk[..length]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    fn new(stream: TcpStream, link: Link) -> Self {
+        Client {
+            stream,
+            link,
+            buffer: Vec::new(),
+            outgoing: Vec::new(),
+            progressed_at: Instant::now(),
+        }
+    }
+
+    /// Queues a frame, sent as the viewer takes it.
+    fn queue(&mut self, frame: &Frame) -> io::Result<()> {
+        write_frame(&mut self.outgoing, frame)?;
+        self.progressed_at = Instant::now();
+        Ok(())
+    }
+
+    /// Sends what the socket takes without waiting. `false` once the viewer
+    /// is gone, or took none of it for `STALLED_AFTER`, so one that stopped
+    /// reading can't hold up sampling and every other viewer.
+    fn send_available(&mut self) -> bool {
+        #[cfg(feature = "tls")]
+        if let Link::Tls(connection) = &mut self.link {
+            return match tls::send_available(connection, &mut self.stream, &mut self.outgoing) {
+                Ok(0) => !self.pending() || self.progressed_at.elapsed() < STALLED_AFTER,
+                Ok(_) => {
+                    self.progressed_at = Instant::now();
+                    true
+                }
+                Err(_) => false,
+            };
+        }
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return false,
+                Ok(length) => {
+                    self.outgoing.drain(..length);
+                    self.progressed_at = Instant::now();
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    return self.progressed_at.elapsed() < STALLED_AFTER
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return false,
+            }
+        }
+        true
+    }
+
+    /// Whether there's more to send, of a frame or of the TLS handshake.
+    fn pending(&self) -> bool {
+        match &self.link {
+            Link::Plain => !self.outgoing.is_empty(),
+            #[cfg(feature = "tls")]
+            Link::Tls(connection) => !self.outgoing.is_empty() || connection.wants_write(),
+        }
+    }
+}
+
+pub fn run_agent(global: &GlobalArgs, options: &AgentArgs) -> error::Result<()> {
+    let mut agent = Agent {
+        host: sysroot::host_name(),
+        sources: mem::select_sources(global, &options.mem)?,
+        session: Session::new(true),
+        smoother: Smoother::new(global.smoothing),
+    };
+
+    #[cfg(feature = "tls")]
+    let tls_config = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(cert, key)?),
+        _ => None,
+    };
+    #[cfg(feature = "tls")]
+    let (secured, connect) = match &tls_config {
+        Some(_) => (" with TLS", "--connect --tls"),
+        None => ("", "--connect"),
+    };
+    #[cfg(not(feature = "tls"))]
+    let (secured, connect) = ("", "--connect");
+
+    let listener = TcpListener::bind(&options.listen).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("can't listen on {}: {}", options.listen, err),
+        )
+    })?;
+    listener.set_nonblocking(true)?;
+    eprintln!(
+        "amdtop: agent listening on {}{}; view with amdtop top {}",
+        listener.local_addr()?,
+        secured,
+        connect
+    );
+
+    let mut clients = Vec::<Client>::new();
+    crate::catch_interrupts();
+    systemd::notify("READY=1");
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        systemd::keep_alive();
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(true)?;
+                let _ = stream.set_nodelay(true);
+                #[cfg(feature = "tls")]
+                let link = match &tls_config {
+                    Some(config) => Link::Tls(Box::new(tls::accept(config)?)),
+                    None => Link::Plain,
+                };
+                #[cfg(not(feature = "tls"))]
+                let link = Link::Plain;
+                clients.push(Client::new(stream, link));
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        clients.retain_mut(|client| {
+            if !client.read_available() {
+                return false;
+            }
+            // A request is only answered once the last frame is sent, so a
+            // slow viewer's frames don't pile up.
+            loop {
+                if !client.send_available() {
+                    return false;
+                }
+                if client.pending() {
+                    return true;
+                }
+                match take_frame(&mut client.buffer) {
+                    Ok(Some(request)) => {
+                        let frame = agent.answer(global, &options.mem, &request);
+                        if client.queue(&frame).is_err() {
+                            return false;
+                        }
+                    }
+                    Ok(None) => return true,
+                    Err(_) => return false,
+                }
+            }
+        });
+
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    systemd::notify("STOPPING=1");
+
+    Ok(())
+}
+
+/// Either way a viewer talks to an agent.
+trait Duplex: Read + Write {}
+
+impl<T: Read + Write> Duplex for T {}
+
+/// A connection to an agent.
+pub struct Viewer {
+    stream: Box<dyn Duplex>,
+}
+
+impl Viewer {
+    /// Connects to the agent at `address`, with TLS under `--tls`.
+    pub fn connect(global: &GlobalArgs, address: &str) -> error::Result<Self> {
+        let stream = TcpStream::connect(address).map_err(|err| {
+            io::Error::new(err.kind(), format!("can't connect to {}: {}", address, err))
+        })?;
+        let _ = stream.set_nodelay(true);
+        #[cfg(feature = "tls")]
+        if global.tls {
+            let stream = tls::connect(stream, address, global.tls_ca.as_deref())?;
+            return Ok(Viewer {
+                stream: Box::new(stream),
+            });
+        }
+        #[cfg(not(feature = "tls"))]
+        let _ = global;
+        Ok(Viewer {
+            stream: Box::new(stream),
+        })
+    }
+
+    /// Asks the agent for a fresh frame.
+    pub fn fetch(&mut self) -> error::Result<Frame> {
+        write_frame(
+            &mut self.stream,
+            &Request {
+                version: PROTOCOL_VERSION,
+            },
+        )?;
+        let frame: Frame = read_frame(&mut self.stream)?;
+        match frame.error {
+            Some(error) => Err(Error::Io(io::Error::other(format!(
+                "the agent on {} failed: {}",
+                frame.host, error
+            )))),
+            None => Ok(frame),
+        }
+    }
+}
+
+/// `amdtop mem --connect`: the agent's tables, or its devices as `mem` has
+/// them in JSON.
+pub fn run_mem(global: &GlobalArgs, address: &str) -> error::Result<()> {
+    if !matches!(global.output, OutputFormat::Table | OutputFormat::Json) {
+        return Err(output::unsupported(global.output, "mem --connect"));
+    }
+    let mut viewer = Viewer::connect(global, address)?;
+    let stdout = io::stdout();
+
+    crate::refresh_loop(global, |iteration| {
+        let frame = viewer.fetch()?;
+        let mut out = stdout.lock();
+        match global.output {
+            OutputFormat::Json => output::write_json(&mut out, &frame.snapshot["devices"])?,
+            _ => {
+                if iteration > 0 {
+                    writeln!(out)?;
+                }
+                for device in &frame.devices {
+                    out.write_all(device.table.as_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_frames() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, &Request { version: 1 }).unwrap();
+        let length = buffer.len();
+        write_frame(&mut buffer, &Request { version: 2 }).unwrap();
+        buffer.truncate(length + 3);
+
+        let first = take_frame(&mut buffer).unwrap().unwrap();
+        assert_eq!(first, br#"{"version":1}"#);
+        assert_eq!(take_frame(&mut buffer).unwrap(), None);
+        assert_eq!(buffer.len(), 3);
+
+        let mut huge = u32::MAX.to_be_bytes().to_vec();
+        assert!(take_frame(&mut huge).is_err());
+    }
+
+    #[test]
+    fn drops_viewers_that_stop_reading() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _viewer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let mut client = Client::new(stream, Link::Plain);
+
+        // More than the socket buffers hold, never read.
+        client.outgoing = vec![0; 64 << 20];
+        let started = Instant::now();
+        assert!(client.send_available());
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(!client.outgoing.is_empty());
+
+        client.progressed_at = Instant::now() - STALLED_AFTER;
+        assert!(!client.send_available());
+    }
+}
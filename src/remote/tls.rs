@@ -0,0 +1,148 @@
+//! TLS for the agent and its viewers, with rustls: `agent --tls-cert` and
+//! `--tls-key`, and `--connect --tls`. The agent drives its side by hand
+//! on its non-blocking sockets; viewers wait on theirs.
+
+use crate::error::{self, Error};
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection, StreamOwned,
+};
+use std::{
+    convert::TryFrom,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::Arc,
+};
+
+fn read_certificates(path: &Path) -> error::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| {
+            Error::InvalidArgument(format!(
+                "can't read certificates from {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+    if certificates.is_empty() {
+        return Err(Error::InvalidArgument(format!(
+            "{} holds no certificates",
+            path.display()
+        )));
+    }
+    Ok(certificates)
+}
+
+/// What the agent serves viewers with: the certificate chain in PEM file
+/// `cert`, leaf first, and its private key in `key`.
+pub fn server_config(cert: &Path, key: &Path) -> error::Result<Arc<ServerConfig>> {
+    let certificates = read_certificates(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|err| {
+        Error::InvalidArgument(format!("can't read a key from {}: {}", key.display(), err))
+    })?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|err| Error::InvalidArgument(format!("bad --tls-cert or --tls-key: {}", err)))?;
+    Ok(Arc::new(config))
+}
+
+/// The TLS side of a viewer that just connected.
+pub fn accept(config: &Arc<ServerConfig>) -> error::Result<ServerConnection> {
+    ServerConnection::new(config.clone()).map_err(|err| Error::Io(io::Error::other(err)))
+}
+
+/// Reads what's arrived on `stream` without waiting, and what of it
+/// decrypts into `buffer`. `Ok(false)` once the viewer is gone.
+pub fn read_available(
+    connection: &mut ServerConnection,
+    stream: &mut TcpStream,
+    buffer: &mut Vec<u8>,
+) -> io::Result<bool> {
+    loop {
+        match connection.read_tls(stream) {
+            Ok(0) => return Ok(false),
+            Ok(_) => {
+                let state = connection
+                    .process_new_packets()
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                let mut plaintext = vec![0; state.plaintext_bytes_to_read()];
+                connection.reader().read_exact(&mut plaintext)?;
+                buffer.extend_from_slice(&plaintext);
+                if state.peer_has_closed() {
+                    return Ok(false);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Encrypts what of `outgoing` rustls takes, and sends what `stream` takes
+/// of that, and of the handshake, without waiting. Returns the bytes sent.
+pub fn send_available(
+    connection: &mut ServerConnection,
+    stream: &mut TcpStream,
+    outgoing: &mut Vec<u8>,
+) -> io::Result<usize> {
+    let mut sent = 0;
+    loop {
+        if !outgoing.is_empty() {
+            let taken = connection.writer().write(outgoing)?;
+            outgoing.drain(..taken);
+        }
+        if !connection.wants_write() {
+            return Ok(sent);
+        }
+        match connection.write_tls(stream) {
+            Ok(length) => sent += length,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(sent),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Starts TLS with the agent at `address` over `stream`, checking its
+/// certificate against the CAs in PEM file `ca`, or else the system's.
+pub fn connect(
+    stream: TcpStream,
+    address: &str,
+    ca: Option<&Path>,
+) -> error::Result<StreamOwned<ClientConnection, TcpStream>> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            roots.add_parsable_certificates(read_certificates(ca)?);
+        }
+        None => {
+            roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+        }
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    // The host, without the port or an IPv6 address's brackets.
+    let host = address
+        .rsplit_once(':')
+        .map_or(address, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|err| Error::InvalidArgument(format!("bad host {:?}: {}", host, err)))?;
+    let connection = ClientConnection::new(Arc::new(config), name)
+        .map_err(|err| Error::Io(io::Error::other(err)))?;
+
+    let mut stream = StreamOwned::new(connection, stream);
+    // Now, so a certificate that doesn't check out fails here, saying so.
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock).map_err(|err| {
+            io::Error::new(err.kind(), format!("TLS with {} failed: {}", address, err))
+        })?;
+    }
+    Ok(stream)
+}
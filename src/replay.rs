@@ -0,0 +1,191 @@
+//! `amdtop replay`: a recorded session, a snapshot at a time. On a terminal
+//! it's full screen, and the arrow keys step between snapshots, so a long
+//! recording can be walked through to the minute something went wrong;
+//! otherwise every snapshot is printed. `--from` and `--to` cut it down
+//! first, and with `--output ndjson` what's left is written out as it was
+//! recorded, to keep just the part that matters; `--output chrome-trace`
+//! turns it into counter tracks for ui.perfetto.dev.
+
+use crate::{
+    chrome_trace,
+    cli::{GlobalArgs, OutputFormat, ReplayArgs},
+    error::{self, Error},
+    output,
+    recording::{self, RecordedDevice, Snapshot},
+    tui::{self, Terminal},
+    FormatBytes,
+};
+use crossterm::event::{self, Event, KeyCode};
+use std::{
+    io::{self, IsTerminal, Write},
+    time::Duration,
+};
+
+/// How far Page Up and Page Down go.
+const PAGE: usize = 10;
+
+fn write_device<W: Write>(out: &mut W, device: &RecordedDevice) -> io::Result<()> {
+    let mut header = device.device.clone();
+    if let Some(usage) = device.usage {
+        header += &format!(
+            " | VRAM {} / {}",
+            FormatBytes::new(usage.vram_used_bytes),
+            FormatBytes::new(usage.vram_total_bytes),
+        );
+        if let Some(gtt) = usage.gtt_used_bytes {
+            header += &format!(" | GTT {}", FormatBytes::new(gtt));
+        }
+    }
+    writeln!(out, "{}", header)?;
+
+    let processes = match &device.processes {
+        Some(processes) => processes,
+        None => return Ok(()),
+    };
+    let show_busy = processes
+        .iter()
+        .any(|process| !process.engine_busy.is_empty());
+    let mut header = format!(
+        "{0: <10} | {1: <20} | {2: <60} | {3: >15} | {4: >15} | {5: >15}",
+        "PID", "PROCESS", "PATH", "TOTAL", "VRAM", "GTT"
+    );
+    let mut width = 150;
+    if show_busy {
+        header += &format!(" | {: >12}", "BUSY");
+        width += 15;
+    }
+    writeln!(out, "{}", header)?;
+    writeln!(out, "{:-^1$}", "", width)?;
+    for process in processes {
+        let name = match (&process.name, process.exited) {
+            (Some(name), false) => name.clone(),
+            (Some(name), true) => format!("<exited> {}", name),
+            (None, _) => "<exited>".to_string(),
+        };
+        let mut line = format!(
+            "{0: <10} | {1: <20} | {2: <60} | {3: >15} | {4: >15} | {5: >15}",
+            process.pid,
+            name,
+            process.path.as_deref().unwrap_or("unknown"),
+            FormatBytes::new(process.vram_bytes + process.gtt_bytes),
+            FormatBytes::new(process.vram_bytes),
+            FormatBytes::new(process.gtt_bytes),
+        );
+        if show_busy {
+            let busiest = process
+                .engine_busy
+                .iter()
+                .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                .map_or_else(
+                    || "-".to_string(),
+                    |(engine, percent)| format!("{} {:.0}%", engine, percent),
+                );
+            line += &format!(" | {: >12}", busiest);
+        }
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// When `snapshot` was taken, in local time.
+fn time(snapshot: &Snapshot) -> String {
+    output::local_time(Duration::try_from_secs_f64(snapshot.time).unwrap_or_default())
+}
+
+/// A snapshot the way `mem` stamps one while sampling continuously.
+fn write_snapshot<W: Write>(out: &mut W, snapshot: &Snapshot) -> io::Result<()> {
+    writeln!(out, "# {} | sample {}", time(snapshot), snapshot.sequence)?;
+    for device in &snapshot.devices {
+        write_device(out, device)?;
+    }
+    Ok(())
+}
+
+/// Full screen, one snapshot at a time, until `q`.
+fn seek(snapshots: &[Snapshot]) -> error::Result<()> {
+    let _terminal = Terminal::enter()?;
+    let last = snapshots.len() - 1;
+    let mut at = 0;
+    loop {
+        let mut screen = Vec::new();
+        write_snapshot(&mut screen, &snapshots[at])?;
+        let lines = String::from_utf8_lossy(&screen)
+            .lines()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        tui::draw(
+            &lines,
+            &format!(
+                " amdtop replay | {} of {} | left/right step | PgUp/PgDn {} | Home/End | q to quit",
+                at + 1,
+                snapshots.len(),
+                PAGE
+            ),
+        )?;
+
+        if let Event::Key(key) = event::read()? {
+            if tui::quits(key) {
+                return Ok(());
+            }
+            at = match key.code {
+                KeyCode::Left => at.saturating_sub(1),
+                KeyCode::Right => (at + 1).min(last),
+                KeyCode::PageUp => at.saturating_sub(PAGE),
+                KeyCode::PageDown => (at + PAGE).min(last),
+                KeyCode::Home => 0,
+                KeyCode::End => last,
+                _ => at,
+            };
+        }
+    }
+}
+
+pub fn run(global: &GlobalArgs, options: &ReplayArgs) -> error::Result<()> {
+    let snapshots = recording::read(&options.path)?;
+    let start = match snapshots.first() {
+        Some(snapshot) => snapshot.time,
+        None => {
+            return Err(Error::InvalidArgument(format!(
+                "{} has no snapshots",
+                options.path.display()
+            )))
+        }
+    };
+    let from = options.from.map(|from| from.resolve(start));
+    let to = options.to.map(|to| to.resolve(start));
+    let snapshots = snapshots
+        .into_iter()
+        .filter(|snapshot| from.is_none_or(|from| snapshot.time >= from))
+        .filter(|snapshot| to.is_none_or(|to| snapshot.time <= to))
+        .collect::<Vec<_>>();
+    if snapshots.is_empty() {
+        return Err(Error::InvalidArgument(format!(
+            "{} has no snapshots between --from and --to",
+            options.path.display()
+        )));
+    }
+
+    let stdout = io::stdout();
+    match global.output {
+        OutputFormat::Table if stdout.is_terminal() => seek(&snapshots),
+        OutputFormat::Table => {
+            let mut out = stdout.lock();
+            for (index, snapshot) in snapshots.iter().enumerate() {
+                if index > 0 {
+                    writeln!(out)?;
+                }
+                write_snapshot(&mut out, snapshot)?;
+            }
+            Ok(())
+        }
+        OutputFormat::Ndjson => {
+            let mut out = stdout.lock();
+            for snapshot in &snapshots {
+                writeln!(out, "{}", snapshot.line)?;
+            }
+            Ok(())
+        }
+        OutputFormat::ChromeTrace => Ok(chrome_trace::write(&mut stdout.lock(), &snapshots)?),
+        format => Err(output::unsupported(format, "replay")),
+    }
+}
@@ -0,0 +1,375 @@
+//! `amdtop rings`: what each ring (gfx, compute, sdma, vcn and so on) has
+//! queued, from the `amdgpu_ring_*` files and `amdgpu_fence_info` in debugfs.
+//!
+//! Each ring file starts with the read pointer, the write pointer and the
+//! driver's copy of the write pointer, followed by the ring itself. The
+//! command processor consuming the ring moves the read pointer; jobs that
+//! were submitted but haven't signaled their fence yet are still pending. A
+//! ring with pending jobs whose fence doesn't move between refreshes is
+//! most likely stuck.
+
+use crate::{
+    cli::{GlobalArgs, OutputFormat},
+    error, output,
+    sensors::selected_devices,
+    source::{self, Device},
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::{self, Write},
+    os::unix::fs::FileExt,
+    path::PathBuf,
+};
+
+/// The last fences `amdgpu_fence_info` lists for a ring.
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RingFences {
+    pub name: String,
+    pub last_signaled: u32,
+    pub last_emitted: u32,
+}
+
+impl RingFences {
+    /// Jobs submitted but not finished yet. Fence sequence numbers wrap.
+    pub fn pending(&self) -> u32 {
+        self.last_emitted.wrapping_sub(self.last_signaled)
+    }
+}
+
+/// Parses `amdgpu_fence_info`, which has a block like this per ring:
+///
+/// ```text
+/// --- ring 0 (gfx_0.0.0) ---
+/// Last signaled fence          0x00001234
+/// Last emitted                 0x00001236
+/// ```
+///
+/// The gfx rings go on with trailing fence and preemption lines, which only
+/// matter for mid command buffer preemption and are skipped.
+pub fn parse_fence_info(contents: &str) -> Vec<RingFences> {
+    let mut rings = Vec::new();
+    let mut current: Option<RingFences> = None;
+    let mut finished_ring = false;
+
+    for line in contents.lines() {
+        if let Some(name) = line
+            .strip_prefix("--- ring ")
+            .and_then(|line| line.split_once(" ("))
+            .and_then(|(_, name)| name.strip_suffix(") ---"))
+        {
+            rings.extend(current.take());
+            current = Some(RingFences {
+                name: name.to_string(),
+                ..RingFences::default()
+            });
+            finished_ring = false;
+            continue;
+        }
+
+        let ring = match current.as_mut() {
+            Some(ring) if !finished_ring => ring,
+            _ => continue,
+        };
+        let (label, value) = match line.rsplit_once(' ') {
+            Some(split) => split,
+            None => continue,
+        };
+        let value = match u32::from_str_radix(value.trim_start_matches("0x"), 16) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        match label.trim() {
+            "Last signaled fence" => ring.last_signaled = value,
+            "Last emitted" => {
+                ring.last_emitted = value;
+                finished_ring = true;
+            }
+            _ => {}
+        }
+    }
+    rings.extend(current);
+    rings
+}
+
+/// The fences of every ring as a small table, for the fence pane in `top`.
+pub fn write_fences<W: Write>(out: &mut W, fences: &[RingFences]) -> io::Result<()> {
+    writeln!(
+        out,
+        "  {: <14} {: >10} {: >10} {: >8}",
+        "FENCES", "SIGNALED", "EMITTED", "PENDING"
+    )?;
+    for ring in fences {
+        writeln!(
+            out,
+            "  {: <14} {:#010x} {:#010x} {: >8}",
+            ring.name,
+            ring.last_signaled,
+            ring.last_emitted,
+            ring.pending()
+        )?;
+    }
+    Ok(())
+}
+
+/// Whether a ring is keeping up.
+#[derive(Serialize, Copy, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum RingStatus {
+    Idle,
+    Busy,
+    /// Jobs are pending but no fence signaled since the last refresh.
+    Stalled,
+}
+
+impl Display for RingStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RingStatus::Idle => "idle",
+            RingStatus::Busy => "busy",
+            RingStatus::Stalled => "stalled",
+        }
+        .fmt(f)
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Ring {
+    /// As amdgpu names it, e.g. `gfx_0.0.0` or `sdma0`.
+    pub name: String,
+    pub read_pointer: Option<u32>,
+    pub write_pointer: Option<u32>,
+    /// Dwords written to the ring that haven't been fetched yet.
+    pub queued_dwords: Option<u32>,
+    pub pending_jobs: Option<u32>,
+    pub status: Option<RingStatus>,
+    #[serde(skip)]
+    last_signaled: Option<u32>,
+}
+
+/// The pointers at the start of an `amdgpu_ring_*` file and how far apart
+/// they are, given the ring's size.
+fn read_pointers(file: &std::fs::File) -> Option<(u32, u32, u32)> {
+    let mut header = [0; 12];
+    file.read_exact_at(&mut header, 0).ok()?;
+    let [read, write] = [0, 4].map(|offset| {
+        u32::from_le_bytes([
+            header[offset],
+            header[offset + 1],
+            header[offset + 2],
+            header[offset + 3],
+        ])
+    });
+    let ring_dwords = (file.metadata().ok()?.len().checked_sub(12)? / 4) as u32;
+    if !ring_dwords.is_power_of_two() {
+        return None;
+    }
+    let mask = ring_dwords - 1;
+    Some((read, write, write.wrapping_sub(read) & mask))
+}
+
+#[derive(Serialize)]
+pub struct DeviceRings {
+    pub device: Device,
+    pub rings: Vec<Ring>,
+}
+
+impl DeviceRings {
+    /// Reads every ring of `device`, judging stalls against `previous`.
+    pub fn read(
+        debugfs_path: &Option<PathBuf>,
+        device: Device,
+        previous: Option<&DeviceRings>,
+    ) -> error::Result<Self> {
+        let fence_info = source::read_device_file(debugfs_path, device, "amdgpu_fence_info")?;
+        let fences = parse_fence_info(&String::from_utf8_lossy(&fence_info))
+            .into_iter()
+            .map(|fences| (fences.name.clone(), fences))
+            .collect::<HashMap<_, _>>();
+        let previous = previous
+            .map(|previous| {
+                previous
+                    .rings
+                    .iter()
+                    .map(|ring| (ring.name.as_str(), ring.last_signaled))
+                    .collect::<HashMap<_, _>>()
+            })
+            .unwrap_or_default();
+
+        let rings = source::device_files(debugfs_path, device)?
+            .into_iter()
+            .filter_map(|file| Some(file.strip_prefix("amdgpu_ring_")?.to_string()))
+            .map(|name| {
+                let file = source::open_device_file(
+                    debugfs_path,
+                    device,
+                    &format!("amdgpu_ring_{}", name),
+                )
+                .ok();
+                let pointers = file.as_ref().and_then(read_pointers);
+                let fences = fences.get(&name);
+                let pending_jobs = fences.map(RingFences::pending);
+                let last_signaled = fences.map(|fences| fences.last_signaled);
+                let status = pending_jobs.map(|pending| match previous.get(name.as_str()) {
+                    _ if pending == 0 => RingStatus::Idle,
+                    Some(previous) if *previous == last_signaled => RingStatus::Stalled,
+                    _ => RingStatus::Busy,
+                });
+                Ring {
+                    read_pointer: pointers.map(|(read, _, _)| read),
+                    write_pointer: pointers.map(|(_, write, _)| write),
+                    queued_dwords: pointers.map(|(_, _, queued)| queued),
+                    pending_jobs,
+                    status,
+                    last_signaled,
+                    name,
+                }
+            })
+            .collect();
+
+        Ok(DeviceRings { device, rings })
+    }
+}
+
+fn write_table<W: Write>(out: &mut W, device: &DeviceRings) -> io::Result<()> {
+    let field =
+        |value: Option<u32>| value.map_or_else(|| "-".to_string(), |value| value.to_string());
+    writeln!(out, "{}", device.device)?;
+    writeln!(
+        out,
+        "{: <14} | {: >8} | {: >8} | {: >6} | {: >7} | STATUS",
+        "RING", "RPTR", "WPTR", "QUEUED", "PENDING"
+    )?;
+    writeln!(out, "{:-^1$}", "", 66)?;
+    for ring in &device.rings {
+        writeln!(
+            out,
+            "{: <14} | {: >8} | {: >8} | {: >6} | {: >7} | {}",
+            ring.name,
+            ring.read_pointer
+                .map_or_else(|| "-".to_string(), |pointer| format!("{:#x}", pointer)),
+            ring.write_pointer
+                .map_or_else(|| "-".to_string(), |pointer| format!("{:#x}", pointer)),
+            field(ring.queued_dwords),
+            field(ring.pending_jobs),
+            ring.status
+                .map_or_else(|| "-".to_string(), |status| status.to_string())
+        )?;
+    }
+    Ok(())
+}
+
+pub fn run(global: &GlobalArgs) -> error::Result<()> {
+    let devices = selected_devices(global)?;
+    let stdout = io::stdout();
+    let mut previous: Vec<DeviceRings> = Vec::new();
+
+    crate::refresh_loop(global, |iteration| {
+        let all_rings = devices
+            .iter()
+            .map(|device| {
+                let previous = previous.iter().find(|rings| rings.device == *device);
+                DeviceRings::read(&global.debugfs_path, *device, previous)
+            })
+            .collect::<error::Result<Vec<_>>>()?;
+        let mut out = stdout.lock();
+
+        match global.output {
+            OutputFormat::Table => {
+                if iteration > 0 {
+                    writeln!(out)?;
+                }
+                for rings in &all_rings {
+                    write_table(&mut out, rings)?;
+                }
+            }
+            OutputFormat::Json => output::write_json(&mut out, &all_rings)?,
+            OutputFormat::Csv => {
+                if iteration == 0 {
+                    output::write_csv_row(
+                        &mut out,
+                        &[
+                            "device", "ring", "rptr", "wptr", "queued", "pending", "status",
+                        ],
+                    )?;
+                }
+                let field =
+                    |value: Option<u32>| value.map(|value| value.to_string()).unwrap_or_default();
+                for rings in &all_rings {
+                    let device = rings.device.to_string();
+                    for ring in &rings.rings {
+                        let status = ring
+                            .status
+                            .map(|status| status.to_string())
+                            .unwrap_or_default();
+                        output::write_csv_row(
+                            &mut out,
+                            &[
+                                &device,
+                                &ring.name,
+                                &field(ring.read_pointer),
+                                &field(ring.write_pointer),
+                                &field(ring.queued_dwords),
+                                &field(ring.pending_jobs),
+                                &status,
+                            ],
+                        )?;
+                    }
+                }
+            }
+            format => return Err(output::unsupported(format, "rings")),
+        }
+        drop(out);
+        previous = all_rings;
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_fence_info() {
+        let rings = parse_fence_info(
+            "--- ring 0 (gfx_0.0.0) ---\n\
+             Last signaled fence          0x00001234\n\
+             Last emitted                 0x00001236\n\
+             Last signaled trailing fence 0x00000000\n\
+             Last emitted                 0x00000000\n\
+             Last preempted               0x00000000\n\
+             --- ring 9 (sdma0) ---\n\
+             Last signaled fence          0xffffffff\n\
+             Last emitted                 0x00000001\n",
+        );
+
+        assert_eq!(
+            rings[0],
+            RingFences {
+                name: "gfx_0.0.0".to_string(),
+                last_signaled: 0x1234,
+                last_emitted: 0x1236
+            }
+        );
+        assert_eq!(rings[0].pending(), 2);
+        assert_eq!(rings[1].name, "sdma0");
+        assert_eq!(rings[1].pending(), 2);
+    }
+
+    #[test]
+    fn writes_fence_pane() {
+        let mut out = Vec::new();
+        let fences = [RingFences {
+            name: "sdma0".to_string(),
+            last_signaled: 0x4d2,
+            last_emitted: 0x4d4,
+        }];
+        write_fences(&mut out, &fences).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "  FENCES           SIGNALED    EMITTED  PENDING\n  \
+             sdma0          0x000004d2 0x000004d4        2\n"
+        );
+    }
+}
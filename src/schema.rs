@@ -0,0 +1,34 @@
+//! The JSON Schema of what `mem` writes with `--output json` and `--output
+//! ndjson`, for `amdtop schema`. Every device and every process line says
+//! which version of it they follow in `schema_version`, so whoever reads
+//! them notices when the format changes under them.
+
+use crate::error;
+use std::io::{self, Write};
+
+/// Goes up when a field is removed, renamed or changes meaning. Adding one
+/// doesn't need it to.
+pub const SCHEMA_VERSION: u32 = 2;
+
+pub const SCHEMA: &str = include_str!("schema.json");
+
+pub fn run() -> error::Result<()> {
+    io::stdout().lock().write_all(SCHEMA.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn schema_is_json_of_this_version() {
+        let schema = serde_json::from_str::<Value>(SCHEMA).unwrap();
+        assert_eq!(schema["$defs"]["schema_version"]["const"], SCHEMA_VERSION);
+        assert!(schema["description"]
+            .as_str()
+            .unwrap()
+            .contains(&format!("schema_version {}", SCHEMA_VERSION)));
+    }
+}
@@ -0,0 +1,895 @@
+//! `amdtop sensors`: load, clocks, temperatures, power and fans, from the
+//! files amdgpu exposes in sysfs and hwmon, and optionally `amdgpu_pm_info`
+//! and the overdrive table.
+
+use crate::{
+    cli::{GlobalArgs, OutputFormat, SensorsArgs},
+    collectors,
+    error::{self, Error},
+    gpu_metrics::GpuMetrics,
+    grbm::GrbmBusy,
+    mangohud, output,
+    overdrive::Overdrive,
+    pm_info::PmInfo,
+    power,
+    smoothing::Smoother,
+    source::{self, read_sysfs_u64, Device},
+    watch,
+};
+use crossterm::style::{Color, Stylize};
+use serde::Serialize;
+use std::{
+    convert::TryInto,
+    fmt::Display,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// The device's hwmon directory, `device/hwmon/hwmonN`.
+pub fn hwmon_dir(device: Device) -> Option<PathBuf> {
+    std::fs::read_dir(device.sysfs_dir().join("hwmon"))
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| path.join("name").exists())
+}
+
+/// The DPM tables `sensors --detail` shows, by file suffix.
+const DPM_CLOCKS: &[&str] = &["sclk", "mclk", "fclk", "socclk"];
+
+/// A level of a `pp_dpm_*` table, like `1: 1500Mhz *`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DpmLevel {
+    pub index: u32,
+    pub clock_mhz: u32,
+    /// Marked with a `*`.
+    pub active: bool,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DpmTable {
+    /// `sclk`, `mclk`, `fclk` or `socclk`.
+    pub clock: &'static str,
+    pub levels: Vec<DpmLevel>,
+}
+
+fn parse_dpm_table(table: &str) -> Vec<DpmLevel> {
+    table
+        .lines()
+        .filter_map(|line| {
+            let (index, level) = line.split_once(':')?;
+            Some(DpmLevel {
+                index: index.trim().parse().ok()?,
+                clock_mhz: parse_dpm_clock(level)?,
+                active: line.trim_end().ends_with('*'),
+            })
+        })
+        .collect()
+}
+
+fn read_dpm_table(device: Device, clock: &'static str) -> Option<DpmTable> {
+    let table = std::fs::read_to_string(device.sysfs_dir().join(format!("pp_dpm_{}", clock)));
+    Some(DpmTable {
+        clock,
+        levels: parse_dpm_table(&table.ok()?),
+    })
+}
+
+/// The clock of the active level in a `pp_dpm_*` table.
+fn active_dpm_clock(device: Device, clock: &'static str) -> Option<u32> {
+    read_dpm_table(device, clock)?
+        .levels
+        .iter()
+        .find(|level| level.active)
+        .map(|level| level.clock_mhz)
+}
+
+/// Parses the `2500Mhz` in a DPM level.
+fn parse_dpm_clock(level: &str) -> Option<u32> {
+    let clock = level.split_whitespace().next()?;
+    clock
+        .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+        .parse()
+        .ok()
+}
+
+/// `tempN_<file>` for the sensor labelled `label`, in °C. `file` is `input`
+/// for the current temperature or `crit` for the critical threshold.
+fn temperature(hwmon_dir: &Path, label: &str, file: &str) -> Option<f64> {
+    (1..=8).find_map(|index| {
+        let sensor_label = std::fs::read_to_string(hwmon_dir.join(format!("temp{}_label", index)));
+        if sensor_label.ok()?.trim() != label {
+            return None;
+        }
+        let millidegrees = read_sysfs_u64(&hwmon_dir.join(format!("temp{}_{}", index, file)))?;
+        Some(millidegrees as f64 / 1000.0)
+    })
+}
+
+/// Who drives the fan, from hwmon's `pwm1_enable`.
+#[derive(Serialize, Copy, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum FanMode {
+    /// No control at all, the fan runs flat out.
+    FullSpeed,
+    /// Held at the duty cycle written to `pwm1`.
+    Manual,
+    /// Following the firmware's fan curve.
+    Auto,
+}
+
+impl FanMode {
+    fn from_pwm_enable(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(FanMode::FullSpeed),
+            1 => Some(FanMode::Manual),
+            2 => Some(FanMode::Auto),
+            _ => None,
+        }
+    }
+}
+
+impl Display for FanMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FanMode::FullSpeed => "full speed",
+            FanMode::Manual => "manual",
+            FanMode::Auto => "auto",
+        }
+        .fmt(f)
+    }
+}
+
+/// `pwm1` as a percentage of the `pwm1_min`..`pwm1_max` range.
+fn fan_pwm_percent(hwmon_dir: &Path) -> Option<f64> {
+    let pwm = read_sysfs_u64(&hwmon_dir.join("pwm1"))?;
+    let min = read_sysfs_u64(&hwmon_dir.join("pwm1_min")).unwrap_or(0);
+    let max = read_sysfs_u64(&hwmon_dir.join("pwm1_max")).unwrap_or(255);
+    if max <= min {
+        return None;
+    }
+    Some((pwm.saturating_sub(min) as f64 * 100.0 / (max - min) as f64).round())
+}
+
+/// What `amdgpu_gfxoff_status` says GFX is up to. RDNA cards power the
+/// graphics block off entirely when idle, which is why their shader clock
+/// often reads 0 MHz.
+#[derive(Serialize, Copy, Clone, PartialEq, Eq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum GfxoffState {
+    Off,
+    LeavingOff,
+    On,
+    EnteringOff,
+}
+
+impl GfxoffState {
+    /// The file holds a binary `u32`, not text.
+    fn parse(contents: &[u8]) -> Option<Self> {
+        let status = u32::from_le_bytes(contents.get(..4)?.try_into().ok()?);
+        match status {
+            0 => Some(GfxoffState::Off),
+            1 => Some(GfxoffState::LeavingOff),
+            2 => Some(GfxoffState::On),
+            3 => Some(GfxoffState::EnteringOff),
+            _ => None,
+        }
+    }
+}
+
+impl Display for GfxoffState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GfxoffState::Off => "off (GFXOFF)",
+            GfxoffState::LeavingOff => "leaving GFXOFF",
+            GfxoffState::On => "on",
+            GfxoffState::EnteringOff => "entering GFXOFF",
+        }
+        .fmt(f)
+    }
+}
+
+/// A SmartShift share like `12.34`, the percentage the kernel prints with
+/// two decimals.
+fn smartshift_percent(device_dir: &Path, name: &str) -> Option<f64> {
+    std::fs::read_to_string(device_dir.join(name))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// `inN_input` for the voltage labelled `label`, in mV.
+fn voltage(hwmon_dir: &Path, label: &str) -> Option<f64> {
+    (0..=3).find_map(|index| {
+        let sensor_label = std::fs::read_to_string(hwmon_dir.join(format!("in{}_label", index)));
+        if sensor_label.ok()?.trim() != label {
+            return None;
+        }
+        read_sysfs_u64(&hwmon_dir.join(format!("in{}_input", index))).map(|mv| mv as f64)
+    })
+}
+
+#[derive(Serialize)]
+pub struct Sensors {
+    pub device: Device,
+    /// An SR-IOV virtual function, which leaves power management to the host.
+    pub virtual_function: bool,
+    pub gpu_busy_percent: Option<u64>,
+    pub memory_busy_percent: Option<u64>,
+    pub shader_clock_mhz: Option<u32>,
+    pub memory_clock_mhz: Option<u32>,
+    pub gfx_voltage_mv: Option<f64>,
+    /// Only APUs report this, as `vddnb`.
+    pub soc_voltage_mv: Option<f64>,
+    pub edge_temperature_celsius: Option<f64>,
+    pub junction_temperature_celsius: Option<f64>,
+    pub memory_temperature_celsius: Option<f64>,
+    /// Where each sensor starts throttling, from `tempN_crit`.
+    pub edge_critical_celsius: Option<f64>,
+    pub junction_critical_celsius: Option<f64>,
+    pub memory_critical_celsius: Option<f64>,
+    pub power_watts: Option<f64>,
+    pub power_cap_watts: Option<f64>,
+    pub power_cap_min_watts: Option<f64>,
+    pub power_cap_max_watts: Option<f64>,
+    /// How SmartShift laptops are splitting the power budget between the APU
+    /// and the dGPU, as a percentage of each one's limit.
+    pub smartshift_apu_power_percent: Option<f64>,
+    pub smartshift_dgpu_power_percent: Option<f64>,
+    /// The active `pp_power_profile_mode`, e.g. `3D_FULL_SCREEN`.
+    pub power_profile: Option<String>,
+    /// Whether GFX is power gated. Read from debugfs when we can.
+    pub gfxoff: Option<GfxoffState>,
+    pub fan_rpm: Option<u64>,
+    pub fan_mode: Option<FanMode>,
+    pub fan_pwm_percent: Option<f64>,
+    /// What the firmware is aiming the fan at.
+    pub fan_target_rpm: Option<u64>,
+    /// Only read when asked for, since it needs debugfs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pm_info: Option<PmInfo>,
+    /// Only read for `--detail`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub overdrive: Option<Overdrive>,
+    /// Every DPM level, only read for `--detail`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dpm_tables: Option<Vec<DpmTable>>,
+    /// The decoded `gpu_metrics` table most of the above came from, if the
+    /// device has one we understand.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gpu_metrics: Option<GpuMetrics>,
+    /// Only read for `--grbm`, since it needs debugfs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grbm: Option<GrbmBusy>,
+}
+
+/// One reading, for the table and CSV output.
+pub struct Reading {
+    pub key: &'static str,
+    pub label: &'static str,
+    pub value: f64,
+    pub unit: &'static str,
+    /// The threshold the value shouldn't reach, for temperatures.
+    pub critical: Option<f64>,
+}
+
+impl Sensors {
+    pub fn read(device: Device) -> Self {
+        let device_dir = device.sysfs_dir();
+        let hwmon_dir = hwmon_dir(device);
+        let hwmon = |read: &dyn Fn(&Path) -> Option<f64>| hwmon_dir.as_deref().and_then(read);
+        let watts = |name: &str| {
+            let microwatts = read_sysfs_u64(&hwmon_dir.as_deref()?.join(name))?;
+            Some(microwatts as f64 / 1e6)
+        };
+        // Prefer gpu_metrics, sampled all at once, to the individual files.
+        let metrics = std::fs::read(device_dir.join("gpu_metrics"))
+            .ok()
+            .and_then(|contents| GpuMetrics::parse(&contents));
+        let metric = |field: fn(&GpuMetrics) -> Option<u16>| metrics.as_ref().and_then(field);
+        let metric_f64 = |field: fn(&GpuMetrics) -> Option<f64>| metrics.as_ref().and_then(field);
+
+        let mut sensors = Sensors {
+            device,
+            virtual_function: device.is_virtual_function(),
+            gpu_busy_percent: metric(|metrics| metrics.gfx_activity_percent)
+                .map(u64::from)
+                .or_else(|| read_sysfs_u64(&device_dir.join("gpu_busy_percent"))),
+            memory_busy_percent: metric(|metrics| metrics.memory_activity_percent)
+                .map(u64::from)
+                .or_else(|| read_sysfs_u64(&device_dir.join("mem_busy_percent"))),
+            shader_clock_mhz: metric(|metrics| metrics.current_gfx_clock_mhz)
+                .map(u32::from)
+                .or_else(|| active_dpm_clock(device, "sclk")),
+            memory_clock_mhz: metric(|metrics| metrics.current_memory_clock_mhz)
+                .map(u32::from)
+                .or_else(|| active_dpm_clock(device, "mclk")),
+            gfx_voltage_mv: metric(|metrics| metrics.gfx_voltage_mv)
+                .map(f64::from)
+                .or_else(|| hwmon(&|dir| voltage(dir, "vddgfx"))),
+            soc_voltage_mv: metric(|metrics| metrics.soc_voltage_mv)
+                .map(f64::from)
+                .or_else(|| hwmon(&|dir| voltage(dir, "vddnb"))),
+            edge_temperature_celsius: metric_f64(|metrics| metrics.edge_temperature_celsius)
+                .or_else(|| hwmon(&|dir| temperature(dir, "edge", "input"))),
+            junction_temperature_celsius: metric_f64(|metrics| {
+                metrics.junction_temperature_celsius
+            })
+            .or_else(|| hwmon(&|dir| temperature(dir, "junction", "input"))),
+            memory_temperature_celsius: metric_f64(|metrics| metrics.memory_temperature_celsius)
+                .or_else(|| hwmon(&|dir| temperature(dir, "mem", "input"))),
+            edge_critical_celsius: hwmon(&|dir| temperature(dir, "edge", "crit")),
+            junction_critical_celsius: hwmon(&|dir| temperature(dir, "junction", "crit")),
+            memory_critical_celsius: hwmon(&|dir| temperature(dir, "mem", "crit")),
+            // Newer kernels only provide the instantaneous reading.
+            power_watts: metric_f64(|metrics| metrics.socket_power_watts)
+                .or_else(|| watts("power1_average"))
+                .or_else(|| watts("power1_input")),
+            power_cap_watts: watts("power1_cap"),
+            power_cap_min_watts: watts("power1_cap_min"),
+            power_cap_max_watts: watts("power1_cap_max"),
+            smartshift_apu_power_percent: smartshift_percent(&device_dir, "smartshift_apu_power"),
+            smartshift_dgpu_power_percent: smartshift_percent(&device_dir, "smartshift_dgpu_power"),
+            power_profile: power::power_profiles(device)
+                .into_iter()
+                .find(|profile| profile.active)
+                .map(|profile| profile.name),
+            gfxoff: None,
+            fan_rpm: metric(|metrics| metrics.fan_rpm)
+                .map(u64::from)
+                .or_else(|| {
+                    hwmon_dir
+                        .as_deref()
+                        .and_then(|dir| read_sysfs_u64(&dir.join("fan1_input")))
+                }),
+            fan_mode: hwmon_dir
+                .as_deref()
+                .and_then(|dir| read_sysfs_u64(&dir.join("pwm1_enable")))
+                .and_then(FanMode::from_pwm_enable),
+            fan_pwm_percent: hwmon(&fan_pwm_percent),
+            fan_target_rpm: hwmon_dir
+                .as_deref()
+                .and_then(|dir| read_sysfs_u64(&dir.join("fan1_target"))),
+            pm_info: None,
+            overdrive: None,
+            dpm_tables: None,
+            gpu_metrics: metrics,
+            grbm: None,
+        };
+        // What a VF reads back for these is the host's business at best and
+        // garbage at worst.
+        if sensors.virtual_function {
+            sensors.power_cap_watts = None;
+            sensors.power_cap_min_watts = None;
+            sensors.power_cap_max_watts = None;
+            sensors.power_profile = None;
+            sensors.fan_rpm = None;
+            sensors.fan_mode = None;
+            sensors.fan_pwm_percent = None;
+            sensors.fan_target_rpm = None;
+        }
+        sensors
+    }
+
+    /// Adds every `pp_dpm_*` table the device has.
+    pub fn read_dpm_tables(&mut self) {
+        self.dpm_tables = Some(
+            DPM_CLOCKS
+                .iter()
+                .filter_map(|clock| read_dpm_table(self.device, clock))
+                .collect(),
+        );
+    }
+
+    /// Adds the overdrive table, if the device has one. It's only there
+    /// when overdrive is enabled through `amdgpu.ppfeaturemask`.
+    pub fn read_overdrive(&mut self) {
+        if self.virtual_function {
+            return;
+        }
+        let path = self.device.sysfs_dir().join("pp_od_clk_voltage");
+        self.overdrive = std::fs::read_to_string(path)
+            .ok()
+            .map(|contents| Overdrive::parse(&contents));
+    }
+
+    /// Adds the GFXOFF state, if debugfs is readable. It's not worth failing
+    /// over, so this is quietly skipped otherwise.
+    pub fn read_gfxoff(&mut self, debugfs_path: &Option<PathBuf>) {
+        if self.virtual_function {
+            return;
+        }
+        self.gfxoff = source::read_device_file(debugfs_path, self.device, "amdgpu_gfxoff_status")
+            .ok()
+            .and_then(|contents| GfxoffState::parse(&contents));
+    }
+
+    /// Adds what `amdgpu_pm_info` says.
+    pub fn read_pm_info(&mut self, debugfs_path: &Option<PathBuf>) -> error::Result<()> {
+        let contents = source::read_device_file(debugfs_path, self.device, "amdgpu_pm_info")?;
+        self.pm_info = Some(PmInfo::parse(&String::from_utf8_lossy(&contents)));
+        Ok(())
+    }
+
+    /// Adds how busy the GRBM registers say each block is.
+    pub fn read_grbm(&mut self, debugfs_path: &Option<PathBuf>) -> error::Result<()> {
+        self.grbm = Some(GrbmBusy::read(debugfs_path, self.device)?);
+        Ok(())
+    }
+
+    /// Every reading the device provided, in display order.
+    pub fn readings(&self) -> Vec<Reading> {
+        let reading = |key, label, value: Option<f64>, unit| {
+            value.map(|value| Reading {
+                key,
+                label,
+                value,
+                unit,
+                critical: None,
+            })
+        };
+        let temperature = |key, label, value: Option<f64>, critical| {
+            value.map(|value| Reading {
+                key,
+                label,
+                value,
+                unit: "°C",
+                critical,
+            })
+        };
+
+        vec![
+            reading(
+                "gpu_busy",
+                "GPU busy",
+                self.gpu_busy_percent.map(|value| value as f64),
+                "%",
+            ),
+            reading(
+                "memory_busy",
+                "Memory busy",
+                self.memory_busy_percent.map(|value| value as f64),
+                "%",
+            ),
+            reading(
+                "shader_clock",
+                "Shader clock",
+                self.shader_clock_mhz.map(f64::from),
+                "MHz",
+            ),
+            reading(
+                "memory_clock",
+                "Memory clock",
+                self.memory_clock_mhz.map(f64::from),
+                "MHz",
+            ),
+            reading("gfx_voltage", "GFX voltage", self.gfx_voltage_mv, "mV"),
+            reading("soc_voltage", "SoC voltage", self.soc_voltage_mv, "mV"),
+            temperature(
+                "edge_temperature",
+                "Edge temperature",
+                self.edge_temperature_celsius,
+                self.edge_critical_celsius,
+            ),
+            temperature(
+                "junction_temperature",
+                "Junction temperature",
+                self.junction_temperature_celsius,
+                self.junction_critical_celsius,
+            ),
+            temperature(
+                "memory_temperature",
+                "Memory temperature",
+                self.memory_temperature_celsius,
+                self.memory_critical_celsius,
+            ),
+            reading("power", "Power", self.power_watts, "W"),
+            reading("power_cap", "Power cap", self.power_cap_watts, "W"),
+            reading(
+                "power_cap_min",
+                "Power cap min",
+                self.power_cap_min_watts,
+                "W",
+            ),
+            reading(
+                "power_cap_max",
+                "Power cap max",
+                self.power_cap_max_watts,
+                "W",
+            ),
+            reading(
+                "smartshift_apu_power",
+                "APU power share",
+                self.smartshift_apu_power_percent,
+                "%",
+            ),
+            reading(
+                "smartshift_dgpu_power",
+                "dGPU power share",
+                self.smartshift_dgpu_power_percent,
+                "%",
+            ),
+            reading(
+                "fan_speed",
+                "Fan speed",
+                self.fan_rpm.map(|value| value as f64),
+                "RPM",
+            ),
+            reading(
+                "fan_target",
+                "Fan target",
+                self.fan_target_rpm.map(|value| value as f64),
+                "RPM",
+            ),
+            reading("fan_pwm", "Fan PWM", self.fan_pwm_percent, "%"),
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    /// `(sensor, value, unit)` for every reading, including whatever extra
+    /// tables were read.
+    fn csv_rows(&self) -> Vec<(String, String, &'static str)> {
+        let mut rows = Vec::new();
+        for reading in self.readings() {
+            rows.push((
+                reading.key.to_string(),
+                reading.value.to_string(),
+                reading.unit,
+            ));
+            if let Some(critical) = reading.critical {
+                rows.push((
+                    format!("{}_critical", reading.key),
+                    critical.to_string(),
+                    reading.unit,
+                ));
+            }
+        }
+        for (key, _, value, unit) in self.pm_info.iter().flat_map(PmInfo::readings) {
+            rows.push((format!("pm_info_{}", key), value.to_string(), unit));
+        }
+        for table in self.dpm_tables.iter().flatten() {
+            for level in &table.levels {
+                rows.push((
+                    format!("dpm_{}_{}", table.clock, level.index),
+                    level.clock_mhz.to_string(),
+                    "MHz",
+                ));
+            }
+            if let Some(active) = table.levels.iter().find(|level| level.active) {
+                rows.push((
+                    format!("dpm_{}_active", table.clock),
+                    active.index.to_string(),
+                    "",
+                ));
+            }
+        }
+        if let Some(metrics) = &self.gpu_metrics {
+            for (key, _, value, unit) in metrics.readings() {
+                rows.push((format!("gpu_metrics_{}", key), value.to_string(), unit));
+            }
+            if let Some(status) = metrics.throttle_status {
+                rows.push((
+                    "gpu_metrics_throttle_status".to_string(),
+                    status.to_string(),
+                    "",
+                ));
+            }
+        }
+        if let Some(grbm) = &self.grbm {
+            for block in &grbm.blocks {
+                rows.push((
+                    format!("grbm_{}", block.key),
+                    block.percent.to_string(),
+                    "%",
+                ));
+            }
+            for (engine, percent) in grbm.shader_engines_percent.iter().enumerate() {
+                rows.push((format!("grbm_se{}", engine), percent.to_string(), "%"));
+            }
+        }
+        for (key, value, unit) in self.overdrive.iter().flat_map(Overdrive::readings) {
+            rows.push((format!("od_{}", key), value.to_string(), unit));
+        }
+        rows
+    }
+
+    /// The hottest temperature reported, whichever sensor it's from.
+    pub fn hottest_celsius(&self) -> Option<f64> {
+        [
+            self.edge_temperature_celsius,
+            self.junction_temperature_celsius,
+            self.memory_temperature_celsius,
+        ]
+        .iter()
+        .flatten()
+        .copied()
+        .reduce(f64::max)
+    }
+}
+
+fn format_value(value: f64, unit: &str) -> String {
+    if value.fract() == 0.0 {
+        format!("{} {}", value, unit)
+    } else {
+        format!("{:.1} {}", value, unit)
+    }
+}
+
+/// How worrying a temperature is: red at the critical threshold, yellow
+/// within `WARM_MARGIN` of it.
+fn heat_color(celsius: f64, critical: f64) -> Color {
+    const WARM_MARGIN: f64 = 15.0;
+    if celsius >= critical {
+        Color::Red
+    } else if celsius >= critical - WARM_MARGIN {
+        Color::Yellow
+    } else {
+        Color::Green
+    }
+}
+
+fn write_table<W: Write>(out: &mut W, sensors: &Sensors, color: bool) -> io::Result<()> {
+    let mut header = sensors.device.to_string();
+    if sensors.virtual_function {
+        header += " | VF";
+    }
+    if let Some(profile) = &sensors.power_profile {
+        header += &format!(" | profile {}", profile);
+    }
+    if let Some(mode) = sensors.fan_mode {
+        header += &format!(" | fan {}", mode);
+    }
+    if let Some(gfxoff) = sensors.gfxoff {
+        header += &format!(" | gfx {}", gfxoff);
+    }
+    writeln!(out, "{}", header)?;
+    for reading in sensors.readings() {
+        let mut value = format_value(reading.value, reading.unit);
+        if let (Some(critical), true) = (reading.critical, color) {
+            value = value.with(heat_color(reading.value, critical)).to_string();
+        }
+
+        let note = match (reading.key, sensors.gfxoff, reading.critical) {
+            // Explain the 0 MHz people otherwise report as a bug.
+            ("shader_clock", Some(GfxoffState::Off), _) => " (GFX powered off)".to_string(),
+            (_, _, Some(critical)) => {
+                format!(" (critical {})", format_value(critical, reading.unit))
+            }
+            _ => String::new(),
+        };
+        writeln!(out, "  {: <22} {}{}", reading.label, value, note)?;
+    }
+    if let Some(pm_info) = &sensors.pm_info {
+        writeln!(out, "  amdgpu_pm_info")?;
+        for (_, label, value, unit) in pm_info.readings() {
+            writeln!(out, "    {: <20} {}", label, format_value(value, unit))?;
+        }
+    }
+    if let Some(tables) = sensors
+        .dpm_tables
+        .as_ref()
+        .filter(|tables| !tables.is_empty())
+    {
+        writeln!(out, "  DPM levels")?;
+        for table in tables {
+            // Bracket the active level, as amdgpu marks it with a `*`.
+            let levels = table
+                .levels
+                .iter()
+                .map(|level| {
+                    if level.active {
+                        format!("[{}]", level.clock_mhz)
+                    } else {
+                        level.clock_mhz.to_string()
+                    }
+                })
+                .collect::<Vec<_>>();
+            writeln!(
+                out,
+                "    {: <20} {} MHz",
+                table.clock.to_ascii_uppercase(),
+                levels.join(" ")
+            )?;
+        }
+    }
+    if let Some(metrics) = &sensors.gpu_metrics {
+        writeln!(
+            out,
+            "  gpu_metrics v{}.{}",
+            metrics.format_revision, metrics.content_revision
+        )?;
+        for (_, label, value, unit) in metrics.readings() {
+            writeln!(out, "    {: <20} {}", label, format_value(value, unit))?;
+        }
+        if let Some(status) = metrics.throttle_status {
+            writeln!(out, "    {: <20} {:#010x}", "Throttle status", status)?;
+        }
+    }
+    if let Some(grbm) = &sensors.grbm {
+        writeln!(out, "  GRBM busy, {} samples", grbm.samples)?;
+        for block in &grbm.blocks {
+            writeln!(
+                out,
+                "    {: <20} {}",
+                block.label,
+                format_value(block.percent, "%")
+            )?;
+        }
+        for (engine, percent) in grbm.shader_engines_percent.iter().enumerate() {
+            let label = format!("SE{}", engine);
+            writeln!(out, "    {: <20} {}", label, format_value(*percent, "%"))?;
+        }
+    }
+    if let Some(overdrive) = &sensors.overdrive {
+        writeln!(out, "  Overdrive")?;
+        for section in &overdrive.sections {
+            let points = section
+                .points
+                .iter()
+                .map(|point| point.describe())
+                .collect::<Vec<_>>();
+            writeln!(out, "    {: <20} {}", section.name, points.join(", "))?;
+        }
+        for range in &overdrive.ranges {
+            writeln!(
+                out,
+                "    {: <20} {} {} – {} {}",
+                format!("{} range", range.name),
+                range.min,
+                range.unit,
+                range.max,
+                range.unit
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The devices `--gpu` selects, or an error if there aren't any.
+pub fn selected_devices(global: &GlobalArgs) -> error::Result<Vec<Device>> {
+    let devices = Device::list()
+        .into_iter()
+        .filter(|device| global.selects(*device))
+        .collect::<Vec<_>>();
+    if devices.is_empty() {
+        return Err(Error::NoDevice(
+            "no amdgpu devices found in /sys/class/drm".to_string(),
+        ));
+    }
+    Ok(devices)
+}
+
+pub fn run(global: &GlobalArgs, options: &SensorsArgs) -> error::Result<()> {
+    let devices = selected_devices(global)?;
+    let stdout = io::stdout();
+    let color = output::use_color();
+    let mut smoother = Smoother::new(global.smoothing);
+    let mut mangohud = match (global.output, devices.as_slice()) {
+        (OutputFormat::Mangohud, [device]) => Some(mangohud::Log::new(*device)),
+        (OutputFormat::Mangohud, _) => {
+            return Err(Error::InvalidArgument(
+                "MangoHud logs have one GPU; pick it with --gpu".to_string(),
+            ))
+        }
+        _ => None,
+    };
+
+    crate::refresh_loop(global, |iteration| {
+        let mut all_sensors = devices
+            .iter()
+            .map(|device| Sensors::read(*device))
+            .collect::<Vec<_>>();
+        for sensors in &mut all_sensors {
+            sensors.read_gfxoff(&global.debugfs_path);
+        }
+        if options.pm_info {
+            for sensors in &mut all_sensors {
+                sensors.read_pm_info(&global.debugfs_path)?;
+            }
+        }
+        if options.grbm {
+            for sensors in &mut all_sensors {
+                sensors.read_grbm(&global.debugfs_path)?;
+            }
+        }
+        for sensors in &mut all_sensors {
+            smoother.sensors(sensors);
+            if options.detail {
+                sensors.read_dpm_tables();
+                sensors.read_overdrive();
+            } else {
+                // Only shown with --detail, like the other tables.
+                sensors.gpu_metrics = None;
+            }
+        }
+        let mut out = stdout.lock();
+
+        match global.output {
+            OutputFormat::Table if global.watch => {
+                let mut frame = Vec::new();
+                for sensors in &all_sensors {
+                    write_table(&mut frame, sensors, color)?;
+                }
+                watch::redraw(&mut out, global, &frame)?;
+            }
+            OutputFormat::Table => {
+                if iteration > 0 {
+                    writeln!(out)?;
+                }
+                for sensors in &all_sensors {
+                    write_table(&mut out, sensors, color)?;
+                }
+            }
+            OutputFormat::Json => output::write_json(&mut out, &all_sensors)?,
+            OutputFormat::Csv => {
+                if iteration == 0 {
+                    output::write_csv_row(&mut out, &["device", "sensor", "value", "unit"])?;
+                }
+                for sensors in &all_sensors {
+                    let device = sensors.device.to_string();
+                    for (key, value, unit) in sensors.csv_rows() {
+                        output::write_csv_row(&mut out, &[device.as_str(), &key, &value, unit])?;
+                    }
+                }
+            }
+            OutputFormat::Telegraf => collectors::write_influx_sensors(&mut out, &all_sensors)?,
+            OutputFormat::Collectd => {
+                collectors::Putval::new(global.delay).write_sensors(&mut out, &all_sensors)?
+            }
+            OutputFormat::Mangohud => {
+                let log = mangohud.as_mut().expect("created for --output mangohud");
+                if iteration == 0 {
+                    log.write_header(&mut out)?;
+                }
+                log.write_row(&mut out, &all_sensors[0])?;
+            }
+            format => return Err(output::unsupported(format, "sensors")),
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gfxoff_status() {
+        assert_eq!(GfxoffState::parse(&[0, 0, 0, 0]), Some(GfxoffState::Off));
+        assert_eq!(GfxoffState::parse(&[2, 0, 0, 0]), Some(GfxoffState::On));
+        assert_eq!(GfxoffState::parse(&[9, 0, 0, 0]), None);
+        assert_eq!(GfxoffState::parse(&[0]), None);
+    }
+
+    #[test]
+    fn parses_dpm_clocks() {
+        assert_eq!(parse_dpm_clock(" 2500Mhz *"), Some(2500));
+        assert_eq!(parse_dpm_clock(" 96MHz"), Some(96));
+        assert_eq!(parse_dpm_clock(""), None);
+    }
+
+    #[test]
+    fn colors_by_distance_to_critical() {
+        assert_eq!(heat_color(50.0, 100.0), Color::Green);
+        assert_eq!(heat_color(90.0, 100.0), Color::Yellow);
+        assert_eq!(heat_color(100.0, 100.0), Color::Red);
+    }
+
+    #[test]
+    fn parses_dpm_tables() {
+        let levels = parse_dpm_table("0: 500Mhz\n1: 1500Mhz *\n2: 2660Mhz\n");
+        assert_eq!(levels.len(), 3);
+        assert_eq!(
+            levels[1],
+            DpmLevel {
+                index: 1,
+                clock_mhz: 1500,
+                active: true
+            }
+        );
+        assert!(!levels[2].active);
+    }
+}
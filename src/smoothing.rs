@@ -0,0 +1,156 @@
+//! `--smoothing`: busy percentages and allocation rates averaged over
+//! refreshes, since a single interval's share of busy samples, or what was
+//! allocated in it, jumps around too much to read at short delays.
+
+use crate::{sensors::Sensors, source::Device};
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Smoothing {
+    /// Each refresh's value as it is.
+    #[default]
+    None,
+    /// The mean of the last N values.
+    Average(usize),
+    /// An exponential moving average giving the latest value this weight.
+    Ema(f64),
+}
+
+impl Smoothing {
+    /// Parses `none`, `avg:N` or `ema:ALPHA`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.split_once(':') {
+            None if name == "none" => Some(Smoothing::None),
+            Some(("avg", count)) => count
+                .parse()
+                .ok()
+                .filter(|count| *count > 0)
+                .map(Smoothing::Average),
+            Some(("ema", alpha)) => alpha
+                .parse()
+                .ok()
+                .filter(|alpha| *alpha > 0.0 && *alpha <= 1.0)
+                .map(Smoothing::Ema),
+            _ => None,
+        }
+    }
+}
+
+/// What a series remembers of its past values.
+enum History {
+    Window(VecDeque<f64>),
+    Ema(f64),
+}
+
+/// Smooths every series it's given, telling them apart by device and key.
+pub struct Smoother {
+    smoothing: Smoothing,
+    series: HashMap<(Device, String), History>,
+}
+
+impl Smoother {
+    pub fn new(smoothing: Smoothing) -> Self {
+        Smoother {
+            smoothing,
+            series: HashMap::new(),
+        }
+    }
+
+    /// Adds `value` to a series and returns the series' smoothed value.
+    pub fn smooth(&mut self, device: Device, key: &str, value: f64) -> f64 {
+        let smoothing = self.smoothing;
+        if smoothing == Smoothing::None {
+            return value;
+        }
+        let history =
+            self.series
+                .entry((device, key.to_string()))
+                .or_insert_with(|| match smoothing {
+                    Smoothing::Ema(_) => History::Ema(value),
+                    _ => History::Window(VecDeque::new()),
+                });
+        match (history, smoothing) {
+            (History::Window(values), Smoothing::Average(count)) => {
+                if values.len() == count {
+                    values.pop_front();
+                }
+                values.push_back(value);
+                values.iter().sum::<f64>() / values.len() as f64
+            }
+            (History::Ema(average), Smoothing::Ema(alpha)) => {
+                *average += alpha * (value - *average);
+                *average
+            }
+            _ => value,
+        }
+    }
+
+    /// Forgets the series `keep` returns false for, given each one's
+    /// device and key.
+    pub fn retain<F: FnMut(Device, &str) -> bool>(&mut self, mut keep: F) {
+        self.series.retain(|(device, key), _| keep(*device, key));
+    }
+
+    fn smooth_percent(&mut self, device: Device, key: &str, percent: &mut Option<u64>) {
+        if let Some(value) = percent {
+            *value = self.smooth(device, key, *value as f64).round() as u64;
+        }
+    }
+
+    /// Smooths a device's busy percentages: the GPU's, the memory
+    /// controller's and, with `--grbm`, each block's and shader engine's.
+    pub fn sensors(&mut self, sensors: &mut Sensors) {
+        let device = sensors.device;
+        self.smooth_percent(device, "gpu_busy", &mut sensors.gpu_busy_percent);
+        self.smooth_percent(device, "memory_busy", &mut sensors.memory_busy_percent);
+        if let Some(grbm) = &mut sensors.grbm {
+            for block in &mut grbm.blocks {
+                block.percent = self.smooth(device, block.key, block.percent);
+            }
+            for (engine, percent) in grbm.shader_engines_percent.iter_mut().enumerate() {
+                *percent = self.smooth(device, &format!("se{}", engine), *percent);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_smoothing() {
+        assert_eq!(Smoothing::from_name("none"), Some(Smoothing::None));
+        assert_eq!(Smoothing::from_name("avg:4"), Some(Smoothing::Average(4)));
+        assert_eq!(Smoothing::from_name("ema:0.3"), Some(Smoothing::Ema(0.3)));
+        assert_eq!(Smoothing::from_name("avg:0"), None);
+        assert_eq!(Smoothing::from_name("ema:2"), None);
+        assert_eq!(Smoothing::from_name("median:3"), None);
+    }
+
+    #[test]
+    fn smooths_series() {
+        let device = Device { minor: 0 };
+        let mut average = Smoother::new(Smoothing::Average(2));
+        assert_eq!(average.smooth(device, "gpu_busy", 100.0), 100.0);
+        assert_eq!(average.smooth(device, "gpu_busy", 0.0), 50.0);
+        assert_eq!(average.smooth(device, "gpu_busy", 20.0), 10.0);
+        assert_eq!(average.smooth(device, "memory_busy", 7.0), 7.0);
+
+        let mut ema = Smoother::new(Smoothing::Ema(0.25));
+        assert_eq!(ema.smooth(device, "gpu_busy", 100.0), 100.0);
+        assert_eq!(ema.smooth(device, "gpu_busy", 0.0), 75.0);
+        assert_eq!(ema.smooth(device, "gpu_busy", 0.0), 56.25);
+    }
+
+    #[test]
+    fn forgets_series() {
+        let device = Device { minor: 0 };
+        let mut average = Smoother::new(Smoothing::Average(2));
+        average.smooth(device, "3301:gfx", 100.0);
+        average.smooth(device, "1523:gfx", 100.0);
+        average.retain(|_, key| key.starts_with("1523:"));
+        assert_eq!(average.smooth(device, "3301:gfx", 0.0), 0.0);
+        assert_eq!(average.smooth(device, "1523:gfx", 0.0), 50.0);
+    }
+}
@@ -0,0 +1,456 @@
+//! Where the numbers come from. Each backend checks whether it works on this
+//! system, and we use the first one that does in priority order, so amdtop
+//! still shows something useful without root or without debugfs.
+
+mod debugfs;
+mod fdinfo;
+mod ioctl;
+mod kfd;
+mod sysfs;
+
+pub use fdinfo::{generic_samples, ClientScan};
+pub use kfd::queue_counts as kfd_queue_counts;
+
+pub use debugfs::{
+    device_files, find_debugfs, find_tracefs, open_device_file, read_device_file,
+    read_device_gem_infos, read_gem_infos,
+};
+
+use crate::{
+    error::{self, Error},
+    gem_info::MemInfo,
+    gem_info::ParseDiagnostics,
+    helper::Elevate,
+    sysroot,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+/// PCI device IDs amdgpu only uses for virtual functions: Tonga, Fiji,
+/// Vega 10, Navi 12 and Sienna Cichlid.
+const VF_DEVICE_IDS: &[u16] = &[0x692f, 0x730f, 0x686c, 0x7362, 0x73ae];
+
+/// A DRM device, identified by the minor of its primary node (`cardN`).
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Device {
+    pub minor: u32,
+}
+
+impl Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "card{}", self.minor)
+    }
+}
+
+impl Serialize for Device {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Device {
+    pub fn sysfs_dir(&self) -> PathBuf {
+        sysroot::path("/sys/class/drm")
+            .join(self.to_string())
+            .join("device")
+    }
+
+    /// PCI address of the device, like `0000:03:00.0`.
+    pub fn pci_slot(&self) -> Option<String> {
+        pci_slot(&self.sysfs_dir())
+    }
+
+    /// Whether this is an SR-IOV virtual function, as in cloud VMs. The host
+    /// sees a `physfn` link; a guest only has the PCI device ID to go by.
+    pub fn is_virtual_function(&self) -> bool {
+        let device_dir = self.sysfs_dir();
+        if device_dir.join("physfn").exists() {
+            return true;
+        }
+        std::fs::read_to_string(device_dir.join("device"))
+            .ok()
+            .and_then(|id| u16::from_str_radix(id.trim().trim_start_matches("0x"), 16).ok())
+            .is_some_and(|id| VF_DEVICE_IDS.contains(&id))
+    }
+
+    /// Whether this is the GPU of an APU rather than a discrete card. APUs
+    /// fill in `gpu_metrics` format 2 or later, or report a north bridge
+    /// voltage on older kernels.
+    pub fn is_integrated(&self) -> bool {
+        let device_dir = self.sysfs_dir();
+        let mut header = [0; 4];
+        if let Ok(file) = std::fs::File::open(device_dir.join("gpu_metrics")) {
+            use std::os::unix::fs::FileExt;
+            if file.read_exact_at(&mut header, 0).is_ok() {
+                return header[2] >= 2;
+            }
+        }
+        std::fs::read_dir(device_dir.join("hwmon"))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .any(|hwmon| {
+                std::fs::read_to_string(hwmon.path().join("in1_label"))
+                    .is_ok_and(|label| label.trim() == "vddnb")
+            })
+    }
+
+    /// Every DRM device bound to amdgpu, in minor order.
+    pub fn list() -> Vec<Device> {
+        Device::list_all()
+            .into_iter()
+            .filter(Device::is_amdgpu)
+            .collect()
+    }
+
+    /// Every device that renders but isn't bound to amdgpu, like an Intel
+    /// iGPU next to an AMD card, in minor order. Leaves out display-only
+    /// drivers like simpledrm, which have no render node.
+    pub fn list_generic() -> Vec<Device> {
+        Device::list_all()
+            .into_iter()
+            .filter(|device| {
+                !device.is_amdgpu() && device.driver().is_some() && device.render_minor().is_some()
+            })
+            .collect()
+    }
+
+    fn list_all() -> Vec<Device> {
+        let pattern = sysroot::path("/sys/class/drm").join("card*");
+        let mut devices = glob::glob(&pattern.to_string_lossy())
+            .map(|paths| {
+                paths
+                    .flatten()
+                    .filter_map(|path| {
+                        let name = path.file_name()?.to_str()?;
+                        let minor = name.strip_prefix("card")?.parse().ok()?;
+                        Some(Device { minor })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        devices.sort();
+        devices
+    }
+
+    /// The kernel driver bound to the device, e.g. `amdgpu` or `i915`.
+    pub fn driver(&self) -> Option<String> {
+        let driver = std::fs::read_link(self.sysfs_dir().join("driver")).ok()?;
+        Some(driver.file_name()?.to_string_lossy().into_owned())
+    }
+
+    fn is_amdgpu(&self) -> bool {
+        self.driver().as_deref() == Some("amdgpu")
+    }
+
+    pub fn from_pci_slot(slot: &str) -> Option<Device> {
+        Device::list()
+            .into_iter()
+            .find(|device| device.pci_slot().as_deref() == Some(slot))
+    }
+
+    /// Finds the device a render node (`renderD128` and up) belongs to.
+    pub fn from_render_minor(minor: u32) -> Option<Device> {
+        let render_dir = sysroot::path("/sys/class/drm")
+            .join(format!("renderD{}", minor))
+            .join("device");
+        Device::from_pci_slot(&pci_slot(&render_dir)?)
+    }
+
+    /// Minor of the render node that shares this device's PCI function.
+    pub fn render_minor(&self) -> Option<u32> {
+        std::fs::read_dir(self.sysfs_dir().join("drm"))
+            .ok()?
+            .flatten()
+            .find_map(|entry| {
+                entry
+                    .file_name()
+                    .to_str()?
+                    .strip_prefix("renderD")?
+                    .parse()
+                    .ok()
+            })
+    }
+}
+
+fn pci_slot(device_dir: &Path) -> Option<String> {
+    let target = std::fs::read_link(device_dir).ok()?;
+    Some(target.file_name()?.to_string_lossy().into_owned())
+}
+
+pub fn read_sysfs_u64(path: &Path) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Device-wide memory usage.
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub struct DeviceUsage {
+    pub vram_used_bytes: u64,
+    pub vram_total_bytes: u64,
+    pub gtt_used_bytes: Option<u64>,
+}
+
+/// What a source saw on one device in a refresh.
+pub struct DeviceSample {
+    pub device: Device,
+    /// Usage per process, if the source can attribute memory at all.
+    pub mem_infos: Option<Vec<MemInfo>>,
+    pub usage: Option<DeviceUsage>,
+    pub diagnostics: Option<ParseDiagnostics>,
+}
+
+impl DeviceSample {
+    fn new(device: Device) -> Self {
+        Self {
+            device,
+            mem_infos: None,
+            usage: None,
+            diagnostics: None,
+        }
+    }
+}
+
+pub trait DataSource {
+    fn kind(&self) -> SourceKind;
+
+    /// Checks that the source works here, explaining why if it doesn't.
+    fn probe(&mut self) -> error::Result<()>;
+
+    fn sample(&mut self) -> error::Result<Vec<DeviceSample>>;
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SourceKind {
+    /// `amdgpu_gem_info` in debugfs: every client, but needs root.
+    Debugfs,
+    /// DRM fdinfo in `/proc`: only processes we're allowed to inspect.
+    Fdinfo,
+    /// Per-process counters from the KFD compute driver, the ones
+    /// `rocm-smi --showpids` reports. Compute clients and VRAM only.
+    Kfd,
+    /// `mem_info_*` attributes in sysfs: device totals only.
+    Sysfs,
+    /// `AMDGPU_INFO` queries on the render node: device totals only.
+    Ioctl,
+}
+
+impl Display for SourceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceKind::Debugfs => "debugfs",
+            SourceKind::Fdinfo => "fdinfo",
+            SourceKind::Kfd => "kfd",
+            SourceKind::Sysfs => "sysfs",
+            SourceKind::Ioctl => "ioctl",
+        }
+        .fmt(f)
+    }
+}
+
+impl SourceKind {
+    /// Sources that attribute memory to processes, best first.
+    pub const PER_PROCESS: &'static [SourceKind] =
+        &[SourceKind::Debugfs, SourceKind::Fdinfo, SourceKind::Kfd];
+
+    /// Sources of device totals, best first.
+    pub const DEVICE: &'static [SourceKind] = &[SourceKind::Sysfs, SourceKind::Ioctl];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            SourceKind::Debugfs,
+            SourceKind::Fdinfo,
+            SourceKind::Kfd,
+            SourceKind::Sysfs,
+            SourceKind::Ioctl,
+        ]
+        .iter()
+        .copied()
+        .find(|kind| kind.to_string() == name)
+    }
+
+    fn per_process(self) -> bool {
+        SourceKind::PER_PROCESS.contains(&self)
+    }
+}
+
+/// What the sources need to know from the command line.
+#[derive(Clone)]
+pub struct SourceConfig {
+    /// Use only this source instead of picking one.
+    pub forced: Option<SourceKind>,
+    pub debugfs_path: Option<PathBuf>,
+    pub elevate: Option<Elevate>,
+    /// Stay root under sudo or pkexec instead of switching to the user, for
+    /// files that only appear later, like coredumps.
+    pub keep_root: bool,
+    /// Only probe, without side effects, for `doctor` and `--version`:
+    /// never switch to the user or start a helper, whose rights the report
+    /// would then describe instead.
+    pub report_only: bool,
+}
+
+fn create(kind: SourceKind, config: &SourceConfig) -> Box<dyn DataSource> {
+    match kind {
+        SourceKind::Debugfs => Box::new(debugfs::DebugfsGemInfo::new(
+            config.debugfs_path.clone(),
+            config.elevate,
+            config.keep_root,
+            config.report_only,
+        )),
+        SourceKind::Fdinfo => Box::new(fdinfo::FdInfo),
+        SourceKind::Kfd => Box::new(kfd::Kfd),
+        SourceKind::Sysfs => Box::new(sysfs::Sysfs),
+        SourceKind::Ioctl => Box::new(ioctl::Ioctl),
+    }
+}
+
+/// Whether a source of `kind` works here, and why not; for `doctor`.
+pub fn probe(kind: SourceKind, config: &SourceConfig) -> error::Result<()> {
+    create(kind, config).probe()
+}
+
+/// Sources that didn't work, and why.
+type Failures = Vec<(SourceKind, Error)>;
+
+/// Probes `kinds` in order and returns the first source that works, along
+/// with why the ones before it didn't.
+fn first_working(
+    kinds: &[SourceKind],
+    config: &SourceConfig,
+) -> (Option<Box<dyn DataSource>>, Failures) {
+    let mut failures = Vec::new();
+    for kind in kinds {
+        let mut source = create(*kind, config);
+        match source.probe() {
+            Ok(()) => return (Some(source), failures),
+            Err(err) => failures.push((*kind, err)),
+        }
+    }
+    (None, failures)
+}
+
+/// The sources used for a session: the best one that can attribute memory
+/// to processes, and the best one for device totals.
+pub struct Sources {
+    per_process: Option<Box<dyn DataSource>>,
+    device: Option<Box<dyn DataSource>>,
+}
+
+impl Sources {
+    pub fn select(config: &SourceConfig) -> error::Result<Self> {
+        // Forcing a source only replaces the choice for its own kind of data.
+        let (per_process_kinds, device_kinds) = match config.forced {
+            Some(kind) if kind.per_process() => (vec![kind], SourceKind::DEVICE.to_vec()),
+            Some(kind) => (Vec::new(), vec![kind]),
+            None => (
+                SourceKind::PER_PROCESS.to_vec(),
+                SourceKind::DEVICE.to_vec(),
+            ),
+        };
+
+        let (per_process, mut failures) = first_working(&per_process_kinds, config);
+        let (device, device_failures) = first_working(&device_kinds, config);
+
+        if config.forced.is_some() {
+            if let Some((_, err)) = failures
+                .into_iter()
+                .chain(device_failures)
+                .find(|(kind, _)| Some(*kind) == config.forced)
+            {
+                return Err(err);
+            }
+            return Ok(Sources {
+                per_process,
+                device,
+            });
+        }
+
+        if per_process.is_none() && device.is_none() {
+            failures.extend(device_failures);
+            let (_, err) = failures.remove(0);
+            return Err(err);
+        }
+
+        if per_process.is_none() {
+            for (kind, err) in &failures {
+                eprintln!("amdtop: {}: {}", kind, err);
+            }
+            eprintln!("amdtop: no per-process data available, showing device totals only");
+        }
+        // Only worth mentioning when we ended up with something worse.
+        if let Some(kind) = per_process.as_ref().map(|source| source.kind()) {
+            if kind != SourceKind::Debugfs {
+                for (kind, err) in &failures {
+                    eprintln!("amdtop: {}: {}", kind, err);
+                }
+                eprintln!(
+                    "amdtop: falling back to {}, some processes may be missing",
+                    kind
+                );
+            }
+        }
+
+        Ok(Sources {
+            per_process,
+            device,
+        })
+    }
+
+    /// Just a source of device totals, for when per-process data isn't
+    /// needed.
+    pub fn select_device_totals(config: &SourceConfig) -> error::Result<Self> {
+        let kinds = match config.forced {
+            Some(kind) if !kind.per_process() => vec![kind],
+            _ => SourceKind::DEVICE.to_vec(),
+        };
+        let (device, mut failures) = first_working(&kinds, config);
+        match device {
+            Some(device) => Ok(Sources {
+                per_process: None,
+                device: Some(device),
+            }),
+            None => Err(failures.remove(0).1),
+        }
+    }
+
+    pub fn kinds(&self) -> Vec<SourceKind> {
+        self.per_process
+            .iter()
+            .chain(self.device.iter())
+            .map(|source| source.kind())
+            .collect()
+    }
+
+    /// Samples every source, combining what they saw per device.
+    pub fn sample(&mut self) -> error::Result<Vec<DeviceSample>> {
+        let mut samples = match &mut self.per_process {
+            Some(source) => source.sample()?,
+            None => Vec::new(),
+        };
+
+        if let Some(source) = &mut self.device {
+            for device_sample in source.sample()? {
+                match samples
+                    .iter_mut()
+                    .find(|sample| sample.device == device_sample.device)
+                {
+                    Some(sample) => {
+                        if sample.usage.is_none() {
+                            sample.usage = device_sample.usage;
+                        }
+                    }
+                    None => samples.push(device_sample),
+                }
+            }
+        }
+
+        samples.sort_by_key(|sample| sample.device);
+        Ok(samples)
+    }
+}
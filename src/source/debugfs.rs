@@ -0,0 +1,398 @@
+//! `amdgpu_gem_info` in debugfs, which lists every buffer object of every
+//! client. The most complete source, but debugfs is only readable by root.
+
+use super::{DataSource, Device, DeviceSample, SourceKind};
+use crate::{
+    error::{self, Error},
+    gem_info::{self, GemInfoFormat},
+    helper::{self, Elevate},
+    privileges, sysroot,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_DEBUGFS_PATH: &str = "/sys/kernel/debug";
+
+/// Undoes the octal escaping `/proc/mounts` applies to spaces and the like.
+fn unescape_mount_path(path: &str) -> String {
+    let mut unescaped = Vec::with_capacity(path.len());
+    let mut bytes = path.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'\\' {
+            let digits = bytes.clone().take(3).collect::<Vec<_>>();
+            let digits = std::str::from_utf8(&digits).unwrap_or_default();
+            if let Ok(escaped) = u8::from_str_radix(digits, 8) {
+                unescaped.push(escaped);
+                bytes.nth(2);
+                continue;
+            }
+        }
+        unescaped.push(byte);
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+/// Where debugfs is mounted, according to `/proc/mounts`.
+pub fn find_debugfs() -> Option<PathBuf> {
+    let lines = crate::read_lines(sysroot::path("/proc/mounts")).ok()?;
+    lines.map_while(Result::ok).find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let mount_point = fields.nth(1)?;
+        if fields.next()? == "debugfs" {
+            Some(sysroot::path(unescape_mount_path(mount_point)))
+        } else {
+            None
+        }
+    })
+}
+
+/// Where tracefs is: its own mount on newer systems, or `tracing` under
+/// debugfs.
+pub fn find_tracefs(debugfs_path: &Option<PathBuf>) -> error::Result<PathBuf> {
+    let lines = crate::read_lines(sysroot::path("/proc/mounts"))?;
+    let mounted = lines.map_while(Result::ok).find_map(|line| {
+        let mut fields = line.split_whitespace();
+        let mount_point = fields.nth(1)?;
+        if fields.next()? == "tracefs" {
+            Some(sysroot::path(unescape_mount_path(mount_point)))
+        } else {
+            None
+        }
+    });
+    if let Some(path) = mounted {
+        return Ok(path);
+    }
+    let path = self::debugfs_path(debugfs_path)?.join("tracing");
+    if path.is_dir() {
+        Ok(path)
+    } else {
+        Err(Error::UnsupportedKernel(format!(
+            "tracefs is not mounted\n\
+             mount it with `sudo mount -t tracefs none {}`",
+            path.display()
+        )))
+    }
+}
+
+fn debugfs_path(configured: &Option<PathBuf>) -> error::Result<PathBuf> {
+    if let Some(path) = configured {
+        return Ok(path.clone());
+    }
+
+    find_debugfs().ok_or_else(|| {
+        Error::UnsupportedKernel(format!(
+            "debugfs is not mounted\n\
+             mount it with `sudo mount -t debugfs none {}`",
+            DEFAULT_DEBUGFS_PATH
+        ))
+    })
+}
+
+/// Replaces the errors people commonly hit reading debugfs with advice on
+/// how to fix them.
+fn explain_debugfs_error(err: io::Error, path: &Path) -> Error {
+    match err.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EPERM) => Error::PermissionDenied(format!(
+            "permission denied reading {}\n\
+             debugfs is only accessible to root, try running `sudo amdtop`",
+            path.display()
+        )),
+        Some(libc::ENOENT) => Error::NoDevice(format!(
+            "{} does not exist\n\
+             is the amdgpu driver loaded? check with `lsmod | grep amdgpu`",
+            path.display()
+        )),
+        _ => Error::Io(err),
+    }
+}
+
+fn gem_info_paths(debugfs_path: &Path) -> error::Result<Vec<PathBuf>> {
+    // glob quietly skips directories it can't read, so check access first.
+    let dri_path = debugfs_path.join("dri");
+    std::fs::read_dir(&dri_path).map_err(|err| explain_debugfs_error(err, &dri_path))?;
+
+    let pattern = dri_path.join("*").join("amdgpu_gem_info");
+    let paths = glob::glob(&pattern.to_string_lossy())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?
+        .flatten()
+        .collect::<Vec<_>>();
+
+    if paths.is_empty() {
+        // The driver is there, it just doesn't tell debugfs about buffers.
+        if !Device::list().is_empty() {
+            return Err(Error::UnsupportedKernel(format!(
+                "no amdgpu_gem_info in {}\n\
+                 this kernel may be too old, try `--source fdinfo`",
+                dri_path.display()
+            )));
+        }
+        return Err(Error::NoDevice(format!(
+            "no amdgpu devices found in {}\n\
+             is the amdgpu driver loaded? check with `lsmod | grep amdgpu`",
+            dri_path.display()
+        )));
+    }
+
+    Ok(paths)
+}
+
+/// Reads every device's `amdgpu_gem_info`.
+pub fn read_gem_infos(debugfs_path: &Path) -> error::Result<Vec<(PathBuf, Vec<u8>)>> {
+    gem_info_paths(debugfs_path)?
+        .into_iter()
+        .map(|gem_info_path| {
+            let contents = std::fs::read(&gem_info_path)
+                .map_err(|err| explain_debugfs_error(err, &gem_info_path))?;
+            Ok((gem_info_path, contents))
+        })
+        .collect()
+}
+
+/// Every device's `amdgpu_gem_info` and the layout it's in. Only works when
+/// we can read debugfs ourselves.
+pub fn read_device_gem_infos(
+    debugfs_path: &Option<PathBuf>,
+) -> error::Result<Vec<(Device, GemInfoFormat, Vec<u8>)>> {
+    Ok(read_gem_infos(&self::debugfs_path(debugfs_path)?)?
+        .into_iter()
+        .filter_map(|(gem_info_path, contents)| {
+            let device = gem_info_device(&gem_info_path)?;
+            let format = GemInfoFormat::detect(&contents).unwrap_or(GemInfoFormat::Unknown);
+            Some((device, format, contents))
+        })
+        .collect())
+}
+
+/// Reads one of `device`'s files in `dri/N`, e.g. `amdgpu_pm_info`. Only
+/// works when we can read debugfs ourselves, or kept it open from before
+/// dropping root.
+pub fn read_device_file(
+    debugfs_path: &Option<PathBuf>,
+    device: Device,
+    name: &str,
+) -> error::Result<Vec<u8>> {
+    let path = device_file_path(debugfs_path, device, name)?;
+    privileges::read(&path).map_err(|err| explain_debugfs_error(err, &path))
+}
+
+/// Opens one of `device`'s files in `dri/N`, for those like `amdgpu_regs`
+/// that are read at an offset rather than from start to end.
+pub fn open_device_file(
+    debugfs_path: &Option<PathBuf>,
+    device: Device,
+    name: &str,
+) -> error::Result<File> {
+    let path = device_file_path(debugfs_path, device, name)?;
+    File::open(&path).map_err(|err| explain_debugfs_error(err, &path))
+}
+
+/// The names of `device`'s files in `dri/N`, sorted.
+pub fn device_files(debugfs_path: &Option<PathBuf>, device: Device) -> error::Result<Vec<String>> {
+    let path = device_file_path(debugfs_path, device, "")?;
+    let mut names = std::fs::read_dir(&path)
+        .map_err(|err| explain_debugfs_error(err, &path))?
+        .flatten()
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+    names.sort();
+    Ok(names)
+}
+
+fn device_file_path(
+    debugfs_path: &Option<PathBuf>,
+    device: Device,
+    name: &str,
+) -> error::Result<PathBuf> {
+    Ok(self::debugfs_path(debugfs_path)?
+        .join("dri")
+        .join(device.minor.to_string())
+        .join(name))
+}
+
+/// Maps `/sys/kernel/debug/dri/N/amdgpu_gem_info` to the device `cardN`.
+fn gem_info_device(gem_info_path: &Path) -> Option<Device> {
+    let minor = gem_info_path.parent()?.file_name()?.to_str()?;
+    Some(Device {
+        minor: minor.parse().ok()?,
+    })
+}
+
+/// How `amdgpu_gem_info` contents are fetched.
+enum Transport {
+    /// Read debugfs ourselves.
+    Direct(PathBuf),
+    /// Have a privileged helper process read debugfs for us.
+    Helper(helper::Helper),
+    /// Re-read files opened before dropping root.
+    Handles(Vec<(PathBuf, File)>),
+}
+
+impl Transport {
+    fn collect(&mut self) -> error::Result<Vec<(PathBuf, Vec<u8>)>> {
+        match self {
+            Transport::Direct(debugfs_path) => read_gem_infos(debugfs_path),
+            Transport::Helper(helper) => helper.collect(),
+            Transport::Handles(handles) => handles
+                .iter_mut()
+                .map(|(gem_info_path, file)| {
+                    let mut contents = Vec::new();
+                    file.seek(SeekFrom::Start(0))?;
+                    file.read_to_end(&mut contents)?;
+                    Ok((gem_info_path.clone(), contents))
+                })
+                .collect::<io::Result<_>>()
+                .map_err(Error::Io),
+        }
+    }
+}
+
+/// Opens everything that needs root, then switches to `uid` and `gid` so the
+/// rest of the session runs unprivileged. Devices that appear later won't be
+/// picked up.
+fn open_and_drop_privileges(
+    debugfs_path: &Path,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+) -> error::Result<Transport> {
+    let handles = gem_info_paths(debugfs_path)?
+        .into_iter()
+        .map(|gem_info_path| {
+            let file = File::open(&gem_info_path)
+                .map_err(|err| explain_debugfs_error(err, &gem_info_path))?;
+            Ok((gem_info_path, file))
+        })
+        .collect::<error::Result<Vec<_>>>()?;
+
+    privileges::drop_to(uid, gid, debugfs_path)
+        .map_err(|err| io::Error::new(err.kind(), format!("failed to drop privileges: {}", err)))?;
+
+    Ok(Transport::Handles(handles))
+}
+
+pub struct DebugfsGemInfo {
+    debugfs_path: Option<PathBuf>,
+    elevate: Option<Elevate>,
+    keep_root: bool,
+    report_only: bool,
+    transport: Option<Transport>,
+    /// Layout of each device's gem_info, probed from its first sample.
+    formats: HashMap<Device, GemInfoFormat>,
+}
+
+impl DebugfsGemInfo {
+    pub fn new(
+        debugfs_path: Option<PathBuf>,
+        elevate: Option<Elevate>,
+        keep_root: bool,
+        report_only: bool,
+    ) -> Self {
+        Self {
+            debugfs_path,
+            elevate,
+            keep_root,
+            report_only,
+            transport: None,
+            formats: HashMap::new(),
+        }
+    }
+}
+
+impl DebugfsGemInfo {
+    /// `probe` as we are: reading directly what root, or the user dropped
+    /// to, could read, or else seeing that the helper could be started.
+    fn probe_in_place(&mut self) -> error::Result<()> {
+        let mut transport = Transport::Direct(debugfs_path(&self.debugfs_path)?);
+        match (transport.collect(), self.elevate) {
+            (Ok(_), _) => {
+                self.transport = Some(transport);
+                Ok(())
+            }
+            (Err(_), Some(elevate)) => Ok(helper::check(elevate)?),
+            (Err(err), None) => Err(err),
+        }
+    }
+}
+
+impl DataSource for DebugfsGemInfo {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Debugfs
+    }
+
+    fn probe(&mut self) -> error::Result<()> {
+        if self.report_only {
+            return self.probe_in_place();
+        }
+
+        let mut transport = match (self.elevate, privileges::invoking_user()) {
+            (Some(elevate), _) => Transport::Helper(helper::Helper::spawn(elevate)?),
+            (None, Some((uid, gid))) if !self.keep_root => {
+                open_and_drop_privileges(&debugfs_path(&self.debugfs_path)?, uid, gid)?
+            }
+            (None, _) => Transport::Direct(debugfs_path(&self.debugfs_path)?),
+        };
+
+        // Make sure the files are readable now rather than on the first refresh.
+        if let Transport::Direct(_) = transport {
+            transport.collect()?;
+        }
+
+        self.transport = Some(transport);
+        Ok(())
+    }
+
+    fn sample(&mut self) -> error::Result<Vec<DeviceSample>> {
+        let transport = self.transport.as_mut().expect("probed before sampling");
+        // The helper's paths are its own, on the real system.
+        let direct = matches!(transport, Transport::Direct(_));
+
+        transport
+            .collect()?
+            .into_iter()
+            .filter_map(|(gem_info_path, contents)| {
+                let device = gem_info_device(&gem_info_path)?;
+                Some((device, gem_info_path, contents))
+            })
+            .map(|(device, gem_info_path, contents)| {
+                let format = match self.formats.get(&device) {
+                    Some(format) => *format,
+                    None => match GemInfoFormat::detect(&contents) {
+                        Some(format) => {
+                            self.formats.insert(device, format);
+                            format
+                        }
+                        None => GemInfoFormat::Unknown,
+                    },
+                };
+
+                let (mem_infos, mut diagnostics) =
+                    gem_info::parse(&contents, format, gem_info::tgid)?;
+                if direct {
+                    diagnostics.file = Some(gem_info_path);
+                }
+                Ok(DeviceSample {
+                    mem_infos: Some(mem_infos),
+                    diagnostics: Some(diagnostics),
+                    ..DeviceSample::new(device)
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unescapes_mount_paths() {
+        assert_eq!(unescape_mount_path("/mnt/debug\\040fs"), "/mnt/debug fs");
+        assert_eq!(
+            unescape_mount_path("/sys/kernel/debug"),
+            "/sys/kernel/debug"
+        );
+    }
+}
@@ -0,0 +1,473 @@
+//! DRM fdinfo: `/proc/<pid>/fdinfo/<fd>` of a DRM file descriptor carries
+//! that client's memory usage. Only processes we're allowed to inspect
+//! are visible, so without root this is usually just our own.
+//!
+//! Other drivers' fdinfo has the same standard keys, which is all
+//! `--generic-drm` goes by for their devices.
+
+use super::{DataSource, Device, DeviceSample, SourceKind};
+use crate::{
+    error::{self, Error},
+    gem_info::MemInfo,
+    sysroot,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    path::Path,
+    time::Instant,
+};
+
+pub struct FdInfo;
+
+/// What one DRM client's fdinfo says.
+#[derive(Default, Debug, PartialEq, Eq)]
+struct Client {
+    pdev: Option<String>,
+    client_id: Option<u64>,
+    vram_bytes: u64,
+    gtt_bytes: u64,
+    evicted_vram_bytes: Option<u64>,
+    engine_ns: BTreeMap<String, u64>,
+    engine_capacity: BTreeMap<String, u64>,
+}
+
+/// Parses amounts like `1234 KiB`, or `1234 kB` on older kernels, which
+/// also means KiB.
+fn parse_amount(value: &str) -> Option<u64> {
+    let mut fields = value.split_whitespace();
+    let number = fields.next()?.parse::<u64>().ok()?;
+    let multiplier = match fields.next() {
+        None => 1,
+        Some("KiB") | Some("kB") => 1 << 10,
+        Some("MiB") => 1 << 20,
+        Some("GiB") => 1 << 30,
+        Some(_) => return None,
+    };
+    Some(number * multiplier)
+}
+
+fn parse_fields(contents: &str) -> HashMap<&str, &str> {
+    let mut fields = HashMap::new();
+    for line in contents.lines() {
+        if let Some((key, value)) = line.split_once(':') {
+            fields.insert(key.trim(), value.trim());
+        }
+    }
+    fields
+}
+
+/// `drm-engine-gfx: 1234 ns` is how long the client has kept the gfx
+/// engine busy since it was opened; `drm-engine-capacity-*` isn't a time.
+fn engine_ns(fields: &HashMap<&str, &str>) -> BTreeMap<String, u64> {
+    fields
+        .iter()
+        .filter_map(|(key, value)| {
+            let engine = key.strip_prefix("drm-engine-")?;
+            let ns = value.strip_suffix("ns")?.trim().parse().ok()?;
+            Some((engine.to_string(), ns))
+        })
+        .filter(|(engine, _)| !engine.starts_with("capacity-"))
+        .collect()
+}
+
+/// How many of each engine there are, when more than one.
+fn engine_capacity(fields: &HashMap<&str, &str>) -> BTreeMap<String, u64> {
+    fields
+        .iter()
+        .filter_map(|(key, value)| {
+            let engine = key.strip_prefix("drm-engine-capacity-")?;
+            Some((engine.to_string(), value.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Parses an fdinfo file, returning `None` unless it belongs to an amdgpu
+/// client.
+fn parse_client(contents: &str) -> Option<Client> {
+    let fields = parse_fields(contents);
+    if fields.get("drm-driver") != Some(&"amdgpu") {
+        return None;
+    }
+
+    // Kernels have renamed these a few times; prefer the newest.
+    let amount = |keys: &[&str]| {
+        keys.iter()
+            .find_map(|key| parse_amount(fields.get(key)?))
+            .unwrap_or_default()
+    };
+
+    Some(Client {
+        pdev: fields.get("drm-pdev").map(|pdev| pdev.to_string()),
+        client_id: fields
+            .get("drm-client-id")
+            .and_then(|client_id| client_id.parse().ok()),
+        vram_bytes: amount(&["drm-resident-vram", "drm-memory-vram", "vram mem"]),
+        gtt_bytes: amount(&["drm-resident-gtt", "drm-memory-gtt", "gtt mem"]),
+        // Since 6.4: what wants to be in VRAM but was moved out of it.
+        evicted_vram_bytes: fields
+            .get("amd-evicted-vram")
+            .and_then(|value| parse_amount(value)),
+        engine_ns: engine_ns(&fields),
+        engine_capacity: engine_capacity(&fields),
+    })
+}
+
+/// Parses the fdinfo of another driver's client, from the keys every
+/// driver shares: `drm-resident-<region>`, or only `drm-total-<region>`
+/// where the driver doesn't say what's resident, and `drm-engine-*`.
+/// Regions like `vram0` or `local0` count as VRAM, and the rest, like
+/// `system0` or `gtt`, as GTT. Without `drm-pdev` there's no telling the
+/// device, so such clients are left out.
+fn parse_generic_client(contents: &str) -> Option<Client> {
+    let fields = parse_fields(contents);
+    match fields.get("drm-driver") {
+        Some(&"amdgpu") | None => return None,
+        Some(_) => {}
+    }
+
+    let resident = fields.keys().any(|key| key.starts_with("drm-resident-"));
+    let prefix = if resident {
+        "drm-resident-"
+    } else {
+        "drm-total-"
+    };
+    let (mut vram_bytes, mut gtt_bytes) = (0, 0);
+    for (key, value) in &fields {
+        let (region, bytes) = match (key.strip_prefix(prefix), parse_amount(value)) {
+            (Some(region), Some(bytes)) => (region, bytes),
+            _ => continue,
+        };
+        if region.starts_with("vram") || region.starts_with("local") {
+            vram_bytes += bytes;
+        } else {
+            gtt_bytes += bytes;
+        }
+    }
+
+    Some(Client {
+        pdev: Some(fields.get("drm-pdev")?.to_string()),
+        client_id: fields
+            .get("drm-client-id")
+            .and_then(|client_id| client_id.parse().ok()),
+        vram_bytes,
+        gtt_bytes,
+        evicted_vram_bytes: None,
+        engine_ns: engine_ns(&fields),
+        engine_capacity: engine_capacity(&fields),
+    })
+}
+
+/// The amdgpu clients `pid` holds open, and with `generic` those of other
+/// drivers too, each with when it was read, or `None` if we can't look.
+fn read_clients(pid: i32, generic: bool) -> Option<Vec<(Client, Instant)>> {
+    let proc_dir = sysroot::path(format!("/proc/{}", pid));
+    let entries = std::fs::read_dir(proc_dir.join("fd")).ok()?;
+
+    let clients = entries
+        .flatten()
+        .filter(|entry| {
+            // Skip reading fdinfo for everything that isn't a DRM node.
+            std::fs::read_link(entry.path())
+                .ok()
+                .is_some_and(|target| target.starts_with("/dev/dri"))
+        })
+        .filter_map(|entry| {
+            let fdinfo = proc_dir.join("fdinfo").join(entry.file_name());
+            let contents = std::fs::read_to_string(fdinfo).ok()?;
+            let client = match generic {
+                true => parse_client(&contents).or_else(|| parse_generic_client(&contents)),
+                false => parse_client(&contents),
+            };
+            Some((client?, Instant::now()))
+        })
+        .collect();
+    Some(clients)
+}
+
+/// Which device a client is for.
+fn client_device(
+    client: &Client,
+    slots: &HashMap<String, Device>,
+    devices: &[Device],
+) -> Option<Device> {
+    match &client.pdev {
+        Some(pdev) => slots.get(pdev).copied(),
+        // Old kernels don't say; fine as long as there's only one.
+        None if devices.len() == 1 => Some(devices[0]),
+        None => None,
+    }
+}
+
+fn device_slots(devices: &[Device]) -> HashMap<String, Device> {
+    devices
+        .iter()
+        .filter_map(|device| Some((device.pci_slot()?, *device)))
+        .collect()
+}
+
+/// One DRM client on one device. Processes that inherited or were passed its
+/// descriptor all hold the same client, which `drm-client-id` tells apart
+/// from a second open of the device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DrmClient {
+    pub device: Device,
+    pub client_id: Option<u64>,
+    /// Every process holding it, lowest first.
+    pub pids: Vec<i32>,
+    pub vram_bytes: u64,
+    pub gtt_bytes: u64,
+    /// Memory that prefers VRAM but currently sits in GTT, where the kernel
+    /// says.
+    pub evicted_vram_bytes: Option<u64>,
+    /// Busy time per engine, e.g. `gfx`, since the client was opened.
+    pub engine_ns: BTreeMap<String, u64>,
+    /// How many there are of each engine that there's more than one of.
+    pub engine_capacity: BTreeMap<String, u64>,
+    /// When its fdinfo was read. Busy times are only comparable to the
+    /// time that passed between two reads of the same client, not between
+    /// two scans, which take a while with many processes.
+    pub read_at: Instant,
+}
+
+/// Every amdgpu client in `/proc` we were allowed to look at.
+#[derive(Default)]
+pub struct ClientScan {
+    pub clients: Vec<DrmClient>,
+    /// Processes whose descriptors we could inspect.
+    pub inspected: HashSet<i32>,
+}
+
+impl ClientScan {
+    pub fn read() -> io::Result<Self> {
+        ClientScan::scan(false)
+    }
+
+    /// The clients of [`Device::list_generic`] too, for `--generic-drm`.
+    pub fn read_with_generic() -> io::Result<Self> {
+        ClientScan::scan(true)
+    }
+
+    fn scan(generic: bool) -> io::Result<Self> {
+        let devices = Device::list();
+        let mut slots = device_slots(&devices);
+        if generic {
+            slots.extend(device_slots(&Device::list_generic()));
+        }
+        let mut scan = ClientScan::default();
+        let mut by_id = HashMap::new();
+
+        for pid in pids(&sysroot::path("/proc"))? {
+            let clients = match read_clients(pid, generic) {
+                Some(clients) => clients,
+                None => continue,
+            };
+            scan.inspected.insert(pid);
+            for (client, read_at) in clients {
+                let device = match client_device(&client, &slots, &devices) {
+                    Some(device) => device,
+                    None => continue,
+                };
+                if let Some(index) = client
+                    .client_id
+                    .and_then(|client_id| by_id.get(&(device, client_id)))
+                {
+                    let shared: &mut DrmClient = &mut scan.clients[*index];
+                    if !shared.pids.contains(&pid) {
+                        shared.pids.push(pid);
+                    }
+                    continue;
+                }
+                if let Some(client_id) = client.client_id {
+                    by_id.insert((device, client_id), scan.clients.len());
+                }
+                scan.clients.push(DrmClient {
+                    device,
+                    client_id: client.client_id,
+                    pids: vec![pid],
+                    vram_bytes: client.vram_bytes,
+                    gtt_bytes: client.gtt_bytes,
+                    evicted_vram_bytes: client.evicted_vram_bytes,
+                    engine_ns: client.engine_ns,
+                    engine_capacity: client.engine_capacity,
+                    read_at,
+                });
+            }
+        }
+        Ok(scan)
+    }
+
+    /// The clients `pid` holds on `device`, or `None` if we couldn't look.
+    pub fn clients_of(&self, device: Device, pid: i32) -> Option<Vec<&DrmClient>> {
+        if !self.inspected.contains(&pid) {
+            return None;
+        }
+        Some(
+            self.clients
+                .iter()
+                .filter(|client| client.device == device && client.pids.contains(&pid))
+                .collect(),
+        )
+    }
+}
+
+fn pids(proc_path: &Path) -> io::Result<Vec<i32>> {
+    let mut pids = std::fs::read_dir(proc_path)?
+        .flatten()
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .collect::<Vec<i32>>();
+    pids.sort_unstable();
+    Ok(pids)
+}
+
+impl DataSource for FdInfo {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Fdinfo
+    }
+
+    fn probe(&mut self) -> error::Result<()> {
+        pids(&sysroot::path("/proc"))?;
+        if Device::list().is_empty() {
+            return Err(Error::NoDevice(
+                "no amdgpu devices found in /sys/class/drm".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn sample(&mut self) -> error::Result<Vec<DeviceSample>> {
+        Ok(samples(Device::list(), &ClientScan::read()?))
+    }
+}
+
+/// What `scan` saw of the devices of other drivers, for `--generic-drm`.
+/// There's neither debugfs nor sysfs to go by for them, so no totals.
+pub fn generic_samples(scan: &ClientScan) -> Vec<DeviceSample> {
+    samples(Device::list_generic(), scan)
+}
+
+/// Memory per process on each of `devices`.
+fn samples(devices: Vec<Device>, scan: &ClientScan) -> Vec<DeviceSample> {
+    let mut usage = devices
+        .into_iter()
+        .map(|device| (device, HashMap::<i32, MemInfo>::new()))
+        .collect::<HashMap<_, _>>();
+
+    // Forked children inherit their parent's descriptors; count a shared
+    // client once, against the lowest pid holding it.
+    for client in &scan.clients {
+        let pid = client.pids[0];
+        let mem_info = match usage.get_mut(&client.device) {
+            Some(mem_infos) => mem_infos.entry(pid).or_insert(MemInfo {
+                pid,
+                ..MemInfo::default()
+            }),
+            None => continue,
+        };
+        mem_info.vram_bytes += client.vram_bytes;
+        mem_info.gtt_bytes += client.gtt_bytes;
+    }
+
+    usage
+        .into_iter()
+        .map(|(device, mem_infos)| {
+            let mut mem_infos = mem_infos.into_values().collect::<Vec<_>>();
+            mem_infos.sort_by_key(|mem_info| {
+                std::cmp::Reverse(mem_info.vram_bytes + mem_info.gtt_bytes)
+            });
+            DeviceSample {
+                mem_infos: Some(mem_infos),
+                ..DeviceSample::new(device)
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_amdgpu_fdinfo() {
+        let client = parse_client(
+            "pos:\t0\n\
+             flags:\t02100002\n\
+             drm-driver:\tamdgpu\n\
+             drm-pdev:\t0000:03:00.0\n\
+             drm-client-id:\t42\n\
+             drm-memory-vram:\t2048 KiB\n\
+             drm-memory-gtt:\t512 KiB\n\
+             drm-resident-vram:\t4 MiB\n\
+             amd-evicted-vram:\t1024 KiB\n\
+             drm-engine-gfx:\t123456 ns\n\
+             drm-engine-capacity-gfx:\t2\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            client,
+            Client {
+                pdev: Some("0000:03:00.0".to_string()),
+                client_id: Some(42),
+                vram_bytes: 4 << 20,
+                gtt_bytes: 512 << 10,
+                evicted_vram_bytes: Some(1 << 20),
+                engine_ns: std::iter::once(("gfx".to_string(), 123456)).collect(),
+                engine_capacity: std::iter::once(("gfx".to_string(), 2)).collect(),
+            }
+        );
+    }
+
+    #[test]
+    fn parses_pre_5_19_fdinfo() {
+        let client = parse_client(
+            "drm-driver:\tamdgpu\n\
+             pasid:\t32770\n\
+             vram mem:\t1024 kB\n\
+             gtt mem:\t8 kB\n",
+        )
+        .unwrap();
+        assert_eq!(client.vram_bytes, 1 << 20);
+        assert_eq!(client.gtt_bytes, 8 << 10);
+        assert_eq!(client.pdev, None);
+        assert_eq!(client.evicted_vram_bytes, None);
+    }
+
+    #[test]
+    fn parses_other_drivers_generically() {
+        let i915 = "drm-driver:\ti915\n\
+                    drm-pdev:\t0000:00:02.0\n\
+                    drm-client-id:\t7\n\
+                    drm-total-system0:\t40960 KiB\n\
+                    drm-resident-system0:\t32768 KiB\n\
+                    drm-resident-stolen-system0:\t0\n\
+                    drm-engine-render:\t25662044 ns\n\
+                    drm-engine-capacity-video:\t2\n";
+        assert_eq!(parse_client(i915), None);
+        let client = parse_generic_client(i915).unwrap();
+        assert_eq!(client.pdev.as_deref(), Some("0000:00:02.0"));
+        assert_eq!((client.vram_bytes, client.gtt_bytes), (0, 32 << 20));
+        assert_eq!(client.engine_ns["render"], 25662044);
+        assert_eq!(client.engine_capacity["video"], 2);
+
+        let xe = parse_generic_client(
+            "drm-driver:\txe\n\
+             drm-pdev:\t0000:03:00.0\n\
+             drm-total-vram0:\t8 MiB\n\
+             drm-total-gtt:\t2 MiB\n",
+        )
+        .unwrap();
+        assert_eq!((xe.vram_bytes, xe.gtt_bytes), (8 << 20, 2 << 20));
+        assert_eq!(parse_generic_client("drm-driver:\tnouveau\n"), None);
+        assert_eq!(
+            parse_generic_client("drm-driver:\tamdgpu\ndrm-pdev:\tx\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn ignores_other_drivers() {
+        assert_eq!(parse_client("drm-driver:\ti915\n"), None);
+        assert_eq!(parse_client("pos:\t0\nflags:\t02\n"), None);
+    }
+}
@@ -0,0 +1,103 @@
+//! `AMDGPU_INFO` queries on the render node. Anyone allowed to open the
+//! render node may ask, so this still works where sysfs is missing or
+//! restricted, but only gives device totals.
+
+use super::{DataSource, Device, DeviceSample, DeviceUsage, SourceKind};
+use crate::{
+    error::{self, Error},
+    sysroot,
+};
+use std::{fs::File, io, os::unix::io::AsRawFd};
+
+/// `DRM_IOW(DRM_COMMAND_BASE + DRM_AMDGPU_INFO, struct drm_amdgpu_info)`.
+const DRM_IOCTL_AMDGPU_INFO: libc::c_ulong = 0x4020_6445;
+
+const AMDGPU_INFO_VRAM_USAGE: u32 = 0x10;
+const AMDGPU_INFO_GTT_USAGE: u32 = 0x11;
+const AMDGPU_INFO_VRAM_GTT: u32 = 0x14;
+
+/// `struct drm_amdgpu_info`, leaving the query-specific union unused.
+#[repr(C)]
+struct DrmAmdgpuInfo {
+    return_pointer: u64,
+    return_size: u32,
+    query: u32,
+    _query_args: [u64; 2],
+}
+
+/// `struct drm_amdgpu_info_vram_gtt`.
+#[repr(C)]
+#[derive(Default)]
+struct DrmAmdgpuInfoVramGtt {
+    vram_size: u64,
+    vram_cpu_accessible_size: u64,
+    gtt_size: u64,
+}
+
+fn query<T: Default>(file: &File, query: u32) -> io::Result<T> {
+    let mut value = T::default();
+    let mut request = DrmAmdgpuInfo {
+        return_pointer: &mut value as *mut T as u64,
+        return_size: std::mem::size_of::<T>() as u32,
+        query,
+        _query_args: [0; 2],
+    };
+
+    let result = unsafe {
+        libc::ioctl(
+            file.as_raw_fd(),
+            DRM_IOCTL_AMDGPU_INFO as _,
+            &mut request as *mut DrmAmdgpuInfo,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+fn read_usage(device: Device) -> io::Result<DeviceUsage> {
+    let render_minor = device.render_minor().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no render node", device),
+        )
+    })?;
+    let file = File::open(sysroot::path(format!("/dev/dri/renderD{}", render_minor)))?;
+
+    let vram_gtt = query::<DrmAmdgpuInfoVramGtt>(&file, AMDGPU_INFO_VRAM_GTT)?;
+    Ok(DeviceUsage {
+        vram_used_bytes: query::<u64>(&file, AMDGPU_INFO_VRAM_USAGE)?,
+        vram_total_bytes: vram_gtt.vram_size,
+        gtt_used_bytes: query::<u64>(&file, AMDGPU_INFO_GTT_USAGE).ok(),
+    })
+}
+
+pub struct Ioctl;
+
+impl DataSource for Ioctl {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Ioctl
+    }
+
+    fn probe(&mut self) -> error::Result<()> {
+        let mut last_err = Error::NoDevice("no amdgpu devices found in /sys/class/drm".to_string());
+        for device in Device::list() {
+            match read_usage(device) {
+                Ok(_) => return Ok(()),
+                Err(err) => last_err = Error::Io(err),
+            }
+        }
+        Err(last_err)
+    }
+
+    fn sample(&mut self) -> error::Result<Vec<DeviceSample>> {
+        Ok(Device::list()
+            .into_iter()
+            .map(|device| DeviceSample {
+                usage: read_usage(device).ok(),
+                ..DeviceSample::new(device)
+            })
+            .collect())
+    }
+}
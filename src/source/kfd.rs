@@ -0,0 +1,139 @@
+//! The KFD compute driver's per-process counters in
+//! `/sys/class/kfd/kfd/proc/<pid>/vram_<gpu_id>`, which is where
+//! `rocm-smi --showpids` gets its numbers. World readable, but only covers
+//! ROCm/HIP clients and only their VRAM.
+
+use super::{DataSource, Device, DeviceSample, SourceKind};
+use crate::{
+    error::{self, Error},
+    gem_info::MemInfo,
+    sysroot,
+};
+use std::{collections::HashMap, convert::TryFrom};
+
+pub struct Kfd;
+
+/// Maps KFD gpu ids to devices through the topology nodes.
+fn gpu_devices() -> HashMap<u32, Device> {
+    let nodes = match std::fs::read_dir(sysroot::path("/sys/class/kfd/kfd/topology/nodes")) {
+        Ok(nodes) => nodes,
+        Err(_) => return HashMap::new(),
+    };
+
+    nodes
+        .flatten()
+        .filter_map(|node| {
+            let node = node.path();
+            let gpu_id = std::fs::read_to_string(node.join("gpu_id"))
+                .ok()?
+                .trim()
+                .parse::<u32>()
+                .ok()
+                .filter(|gpu_id| *gpu_id != 0)?;
+            let properties = std::fs::read_to_string(node.join("properties")).ok()?;
+            let render_minor = properties
+                .lines()
+                .find_map(|line| line.strip_prefix("drm_render_minor ")?.trim().parse().ok())?;
+            Some((gpu_id, Device::from_render_minor(render_minor)?))
+        })
+        .collect()
+}
+
+/// How many compute queues `pid` has created per device, or `None` without
+/// KFD.
+pub fn queue_counts(pid: i32) -> Option<HashMap<Device, usize>> {
+    let procs = sysroot::path("/sys/class/kfd/kfd/proc");
+    if !procs.is_dir() {
+        return None;
+    }
+    let queues = match std::fs::read_dir(procs.join(pid.to_string()).join("queues")) {
+        Ok(queues) => queues,
+        // Not a compute client.
+        Err(_) => return Some(HashMap::new()),
+    };
+
+    let devices = gpu_devices();
+    let mut counts = HashMap::new();
+    for queue in queues.flatten() {
+        let gpu_id = match super::read_sysfs_u64(&queue.path().join("gpuid")) {
+            Some(gpu_id) => gpu_id,
+            None => continue,
+        };
+        if let Some(device) = u32::try_from(gpu_id)
+            .ok()
+            .and_then(|gpu_id| devices.get(&gpu_id))
+        {
+            *counts.entry(*device).or_default() += 1;
+        }
+    }
+    Some(counts)
+}
+
+impl DataSource for Kfd {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Kfd
+    }
+
+    fn probe(&mut self) -> error::Result<()> {
+        std::fs::read_dir(sysroot::path("/sys/class/kfd/kfd/proc"))?;
+        if gpu_devices().is_empty() {
+            return Err(Error::NoDevice(
+                "no amdgpu devices in the KFD topology".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn sample(&mut self) -> error::Result<Vec<DeviceSample>> {
+        let devices = gpu_devices();
+        let mut usage = devices
+            .values()
+            .map(|device| (*device, Vec::new()))
+            .collect::<HashMap<_, _>>();
+
+        for process in std::fs::read_dir(sysroot::path("/sys/class/kfd/kfd/proc"))?.flatten() {
+            let pid = match process
+                .file_name()
+                .to_str()
+                .and_then(|pid| pid.parse().ok())
+            {
+                Some(pid) => pid,
+                None => continue,
+            };
+
+            for counter in std::fs::read_dir(process.path())?.flatten() {
+                let file_name = counter.file_name();
+                let gpu_id = match file_name
+                    .to_str()
+                    .and_then(|name| name.strip_prefix("vram_"))
+                    .and_then(|gpu_id| gpu_id.parse::<u32>().ok())
+                {
+                    Some(gpu_id) => gpu_id,
+                    None => continue,
+                };
+                let device = match devices.get(&gpu_id) {
+                    Some(device) => *device,
+                    None => continue,
+                };
+                if let Some(vram_bytes) = super::read_sysfs_u64(&counter.path()) {
+                    usage.entry(device).or_default().push(MemInfo {
+                        pid,
+                        vram_bytes,
+                        ..MemInfo::default()
+                    });
+                }
+            }
+        }
+
+        Ok(usage
+            .into_iter()
+            .map(|(device, mut mem_infos)| {
+                mem_infos.sort_by_key(|mem_info| std::cmp::Reverse(mem_info.vram_bytes));
+                DeviceSample {
+                    mem_infos: Some(mem_infos),
+                    ..DeviceSample::new(device)
+                }
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,45 @@
+//! The `mem_info_*` attributes amdgpu exposes in sysfs. World readable, but
+//! only device totals.
+
+use super::{read_sysfs_u64, DataSource, Device, DeviceSample, DeviceUsage, SourceKind};
+use crate::error::{self, Error};
+
+pub struct Sysfs;
+
+fn read_usage(device: Device) -> Option<DeviceUsage> {
+    let device_dir = device.sysfs_dir();
+    Some(DeviceUsage {
+        vram_used_bytes: read_sysfs_u64(&device_dir.join("mem_info_vram_used"))?,
+        vram_total_bytes: read_sysfs_u64(&device_dir.join("mem_info_vram_total"))?,
+        gtt_used_bytes: read_sysfs_u64(&device_dir.join("mem_info_gtt_used")),
+    })
+}
+
+impl DataSource for Sysfs {
+    fn kind(&self) -> SourceKind {
+        SourceKind::Sysfs
+    }
+
+    fn probe(&mut self) -> error::Result<()> {
+        if Device::list()
+            .into_iter()
+            .any(|device| read_usage(device).is_some())
+        {
+            Ok(())
+        } else {
+            Err(Error::NoDevice(
+                "no amdgpu device exposes mem_info_vram_used".to_string(),
+            ))
+        }
+    }
+
+    fn sample(&mut self) -> error::Result<Vec<DeviceSample>> {
+        Ok(Device::list()
+            .into_iter()
+            .map(|device| DeviceSample {
+                usage: read_usage(device),
+                ..DeviceSample::new(device)
+            })
+            .collect())
+    }
+}
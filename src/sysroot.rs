@@ -0,0 +1,44 @@
+//! Lets every read of `/proc`, `/sys` and debugfs be redirected into another
+//! directory with `--root`, so we can run against recorded fixture trees.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+static ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Sets the directory absolute paths are resolved under. Only the first call
+/// has any effect.
+pub fn set(root: PathBuf) {
+    let _ = ROOT.set(root);
+}
+
+/// Resolves an absolute system path like `/proc/1/comm` under the root.
+pub fn path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let path = path.as_ref();
+    match ROOT.get() {
+        Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Whether `--root` redirected us away from the real system.
+pub fn is_set() -> bool {
+    ROOT.get().is_some()
+}
+
+/// The running kernel's release, like `6.6.0-arch1-1`.
+pub fn kernel_release() -> Option<String> {
+    std::fs::read_to_string(path("/proc/sys/kernel/osrelease"))
+        .ok()
+        .map(|release| release.trim().to_string())
+        .filter(|release| !release.is_empty())
+}
+
+/// The machine's name, from under the root like everything else.
+pub fn host_name() -> String {
+    std::fs::read_to_string(path("/proc/sys/kernel/hostname"))
+        .map(|name| name.trim().to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,142 @@
+//! Running as a systemd service: readiness and watchdog notifications over
+//! `$NOTIFY_SOCKET`, and `amdtop install-service`, which writes a unit for
+//! `amdtop export`.
+//!
+//! The notification protocol is a datagram of `KEY=value` lines, so there's
+//! no need for libsystemd. Outside of a `Type=notify` service nothing is
+//! sent.
+
+use crate::{
+    cli::InstallServiceArgs,
+    error::{self, Error},
+    sysroot,
+};
+use std::{
+    io::{self, Write},
+    os::unix::net::{SocketAddr, UnixDatagram},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// Sends `state`, e.g. `READY=1`, to the service manager if it's listening.
+/// Failures are ignored: a missed notification isn't worth stopping for.
+pub fn notify(state: &str) {
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+    let path = path.to_string_lossy();
+    // A leading `@` stands for the abstract namespace.
+    let address = match path.strip_prefix('@') {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            SocketAddr::from_abstract_name(name.as_bytes())
+        }
+        None => SocketAddr::from_pathname(path.as_ref()),
+    };
+    if let (Ok(socket), Ok(address)) = (UnixDatagram::unbound(), address) {
+        let _ = socket.send_to_addr(state.as_bytes(), &address);
+    }
+}
+
+/// How often systemd wants to hear from us, from `WatchdogSec=`. `None` if
+/// the watchdog is off or meant for another process.
+fn watchdog_interval() -> Option<Duration> {
+    static INTERVAL: OnceLock<Option<Duration>> = OnceLock::new();
+    *INTERVAL.get_or_init(|| {
+        if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+            if pid.parse::<u32>().ok() != Some(std::process::id()) {
+                return None;
+            }
+        }
+        let usec = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        Some(Duration::from_micros(usec)).filter(|interval| !interval.is_zero())
+    })
+}
+
+/// Pings the watchdog once half its interval has passed since the last
+/// ping. Cheap enough to call from every wait loop.
+pub fn keep_alive() {
+    static LAST_PING: Mutex<Option<Instant>> = Mutex::new(None);
+
+    let interval = match watchdog_interval() {
+        Some(interval) => interval,
+        None => return,
+    };
+    let mut last_ping = match LAST_PING.lock() {
+        Ok(last_ping) => last_ping,
+        Err(_) => return,
+    };
+    let now = Instant::now();
+    if last_ping.is_none_or(|last_ping| now.duration_since(last_ping) >= interval / 2) {
+        notify("WATCHDOG=1");
+        *last_ping = Some(now);
+    }
+}
+
+/// A unit running `amdtop export` on `listen` from `executable`.
+pub fn unit_file(executable: &str, listen: &str) -> String {
+    format!(
+        "[Unit]
+Description=amdtop GPU metrics exporter
+After=network.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={} export --listen {}
+WatchdogSec=30
+Restart=on-failure
+# Root is only needed to read debugfs; nothing is ever written.
+ProtectSystem=strict
+ProtectHome=true
+PrivateTmp=true
+NoNewPrivileges=true
+
+[Install]
+WantedBy=multi-user.target
+",
+        executable, listen
+    )
+}
+
+pub fn install(options: &InstallServiceArgs) -> error::Result<()> {
+    let executable = std::env::current_exe()?;
+    let unit = unit_file(&executable.to_string_lossy(), &options.listen);
+    if options.print {
+        io::stdout().write_all(unit.as_bytes())?;
+        return Ok(());
+    }
+
+    let path = sysroot::path(&options.path);
+    std::fs::write(&path, unit).map_err(|err| match err.kind() {
+        io::ErrorKind::PermissionDenied => Error::PermissionDenied(format!(
+            "can't write {}; install-service needs root",
+            path.display()
+        )),
+        _ => Error::Io(err),
+    })?;
+    eprintln!(
+        "amdtop: installed {}; start it with systemctl daemon-reload && systemctl enable --now {}",
+        path.display(),
+        options
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_a_notify_unit() {
+        let unit = unit_file("/usr/bin/amdtop", "0.0.0.0:9858");
+        assert!(unit.contains("\nType=notify\n"));
+        assert!(unit.contains("\nExecStart=/usr/bin/amdtop export --listen 0.0.0.0:9858\n"));
+        assert!(unit.ends_with("WantedBy=multi-user.target\n"));
+    }
+}
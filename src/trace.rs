@@ -0,0 +1,457 @@
+//! `amdtop trace`: records amdgpu's buffer object tracepoints through
+//! tracefs for a while, then sums them up per process.
+//!
+//! Sampling `amdgpu_gem_info` only sees what's allocated at that moment;
+//! the tracepoints also catch buffers that come and go in between, and
+//! every move the kernel makes, which is where eviction churn shows up.
+//! Events are attributed to the task that was running when they fired, so
+//! a move caused by memory pressure can land on whoever allocated last.
+
+#[cfg(feature = "ebpf")]
+mod ebpf;
+
+use crate::{
+    cli::{GlobalArgs, OutputFormat, TraceArgs},
+    error::{self, Error},
+    mem, output, source, FormatBytes, INTERRUPTED,
+};
+use clap::ValueEnum;
+use regex::Regex;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, mpsc},
+    time::{Duration, Instant},
+};
+
+/// The tracepoints `--events` knows, under `events/amdgpu/`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, ValueEnum)]
+pub enum TraceEvent {
+    /// A buffer object was created
+    #[value(name = "bo_create")]
+    BoCreate,
+    /// A buffer object moved between VRAM, GTT and system memory
+    #[value(name = "bo_move")]
+    BoMove,
+}
+
+impl TraceEvent {
+    fn tracepoint(self) -> &'static str {
+        match self {
+            TraceEvent::BoCreate => "amdgpu_bo_create",
+            TraceEvent::BoMove => "amdgpu_bo_move",
+        }
+    }
+}
+
+/// TTM's placements, which `amdgpu_bo_move` reports as numbers.
+fn placement(mem_type: u32) -> &'static str {
+    match mem_type {
+        0 => "CPU",
+        1 => "GTT",
+        2 => "VRAM",
+        _ => "other",
+    }
+}
+
+/// What one line of `trace_pipe` says, of the events we know.
+#[derive(Debug, PartialEq, Eq)]
+enum Event {
+    Create { bytes: u64 },
+    Move { from: u32, to: u32, bytes: u64 },
+}
+
+/// A line of `trace_pipe`, which looks like
+/// `  blender-3301  [003] ..... 1234.567890: amdgpu_bo_move: bo=..., from=2, to=1, size=4096`.
+struct Line {
+    name: String,
+    pid: i32,
+    event: Event,
+}
+
+fn line_pattern() -> Regex {
+    Regex::new(r"^\s*(.+)-(\d+)\s+(?:\(\s*\S+\)\s+)?\[\d+\].*?\s[\d.]+: (\w+): (.*)$")
+        .expect("valid regex")
+}
+
+fn parse_line(pattern: &Regex, line: &str) -> Option<Line> {
+    let captures = pattern.captures(line)?;
+    let fields = captures[4]
+        .split(", ")
+        .filter_map(|field| field.split_once('='))
+        .collect::<HashMap<_, _>>();
+    let number = |key: &str| fields.get(key)?.parse::<u64>().ok();
+    let event = match &captures[3] {
+        "amdgpu_bo_create" => Event::Create {
+            bytes: number("pages")? * mem::page_size(),
+        },
+        "amdgpu_bo_move" => Event::Move {
+            from: number("from")? as u32,
+            to: number("to")? as u32,
+            bytes: number("size")?,
+        },
+        _ => return None,
+    };
+    Some(Line {
+        name: captures[1].trim().to_string(),
+        pid: captures[2].parse().ok()?,
+        event,
+    })
+}
+
+/// The events of one process, summed up.
+#[derive(Default, Serialize)]
+pub struct Churn {
+    pub pid: i32,
+    pub name: String,
+    pub creates: u64,
+    pub created_bytes: u64,
+    pub moves: u64,
+    pub moved_bytes: u64,
+    /// Moved out of VRAM, to GTT or system memory.
+    pub evicted_bytes: u64,
+    /// Bytes moved per `from->to`, e.g. `VRAM->GTT`.
+    pub moved: BTreeMap<String, u64>,
+}
+
+impl Churn {
+    fn add(&mut self, event: &Event) {
+        match *event {
+            Event::Create { bytes } => {
+                self.creates += 1;
+                self.created_bytes += bytes;
+            }
+            Event::Move { from, to, bytes } => {
+                self.moves += 1;
+                self.moved_bytes += bytes;
+                if placement(from) == "VRAM" && placement(to) != "VRAM" {
+                    self.evicted_bytes += bytes;
+                }
+                *self
+                    .moved
+                    .entry(format!("{}->{}", placement(from), placement(to)))
+                    .or_default() += bytes;
+            }
+        }
+    }
+}
+
+/// Busiest first.
+fn summarize<I: IntoIterator<Item = Line>>(lines: I) -> (u64, Vec<Churn>) {
+    let mut events = 0;
+    let mut churn = HashMap::<i32, Churn>::new();
+    for line in lines {
+        events += 1;
+        let process = churn.entry(line.pid).or_insert_with(|| Churn {
+            pid: line.pid,
+            name: line.name.clone(),
+            ..Churn::default()
+        });
+        process.add(&line.event);
+    }
+    let mut churn = churn.into_values().collect::<Vec<_>>();
+    churn.sort_by_key(|process| {
+        (
+            std::cmp::Reverse(process.created_bytes + process.moved_bytes),
+            process.pid,
+        )
+    });
+    (events, churn)
+}
+
+fn tracefs_error(err: io::Error, path: &Path) -> Error {
+    match err.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EPERM) => Error::PermissionDenied(format!(
+            "permission denied writing {}\n\
+             tracing is only accessible to root, try running `sudo amdtop trace`",
+            path.display()
+        )),
+        _ => Error::Io(err),
+    }
+}
+
+fn write_tracefs(path: &Path, value: &str) -> error::Result<()> {
+    std::fs::write(path, value).map_err(|err| tracefs_error(err, path))
+}
+
+/// Turns tracepoints on, and back to what they were when dropped.
+struct Enabled {
+    previous: Vec<(PathBuf, String)>,
+}
+
+impl Enabled {
+    fn new(tracing: &Path, events: &[TraceEvent]) -> error::Result<Self> {
+        let mut enabled = Enabled {
+            previous: Vec::new(),
+        };
+        for event in events {
+            let path = tracing
+                .join("events/amdgpu")
+                .join(event.tracepoint())
+                .join("enable");
+            let previous = std::fs::read_to_string(&path).map_err(|err| match err.kind() {
+                io::ErrorKind::NotFound => Error::UnsupportedKernel(format!(
+                    "{} doesn't exist; is the amdgpu driver loaded?",
+                    path.display()
+                )),
+                _ => tracefs_error(err, &path),
+            })?;
+            write_tracefs(&path, "1")?;
+            enabled.previous.push((path, previous.trim().to_string()));
+        }
+        Ok(enabled)
+    }
+}
+
+impl Drop for Enabled {
+    fn drop(&mut self) {
+        for (path, previous) in &self.previous {
+            let _ = std::fs::write(path, previous);
+        }
+    }
+}
+
+/// Turns `events` on and reads them from `trace_pipe` until `duration` is
+/// up, Ctrl-C is pressed or it ends.
+fn record_tracefs(tracing: &Path, options: &TraceArgs) -> error::Result<Vec<Line>> {
+    let enabled = Enabled::new(tracing, &options.events)?;
+    // Start from an empty buffer, not whatever was left in it.
+    write_tracefs(&tracing.join("trace"), "")?;
+    let lines = record(tracing, options.duration)?;
+    drop(enabled);
+    Ok(lines)
+}
+
+/// Reads `trace_pipe` until `duration` is up, Ctrl-C is pressed or it ends.
+fn record(tracing: &Path, duration: Duration) -> error::Result<Vec<Line>> {
+    let path = tracing.join("trace_pipe");
+    let pipe = std::fs::File::open(&path).map_err(|err| tracefs_error(err, &path))?;
+    let (sender, receiver) = mpsc::channel();
+    // Reads block until there's an event, so the reader is left behind
+    // when we stop.
+    std::thread::spawn(move || {
+        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    crate::catch_interrupts();
+    let pattern = line_pattern();
+    let deadline = Instant::now() + duration;
+    let mut lines = Vec::new();
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        match receiver.recv_timeout(left.min(Duration::from_millis(200))) {
+            Ok(line) => lines.extend(parse_line(&pattern, &line)),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(lines)
+}
+
+/// One event as `--ebpf` prints it when it comes.
+#[cfg(feature = "ebpf")]
+fn write_event<W: Write>(out: &mut W, at: Duration, line: &Line) -> io::Result<()> {
+    let what = match line.event {
+        Event::Create { bytes } => format!("create {}", FormatBytes::new(bytes)),
+        Event::Move { from, to, bytes } => format!(
+            "move {}->{} {}",
+            placement(from),
+            placement(to),
+            FormatBytes::new(bytes)
+        ),
+    };
+    writeln!(
+        out,
+        "+{:>10.6}s | {: <10} | {: <20} | {}",
+        at.as_secs_f64(),
+        line.pid,
+        line.name,
+        what
+    )
+}
+
+fn write_table<W: Write>(
+    out: &mut W,
+    options: &TraceArgs,
+    elapsed: Duration,
+    events: u64,
+    churn: &[Churn],
+) -> io::Result<()> {
+    let tracepoints = options
+        .events
+        .iter()
+        .map(|event| event.tracepoint())
+        .collect::<Vec<_>>();
+    writeln!(
+        out,
+        "{} for {:.1}s: {} events",
+        tracepoints.join(", "),
+        elapsed.as_secs_f64(),
+        events
+    )?;
+    writeln!(
+        out,
+        "{: <10} | {: <20} | {: >9} | {: >15} | {: >9} | {: >15} | {: >15}",
+        "PID", "PROCESS", "CREATES", "CREATED", "MOVES", "MOVED", "EVICTED"
+    )?;
+    writeln!(out, "{:-^1$}", "", 114)?;
+    for process in churn {
+        writeln!(
+            out,
+            "{: <10} | {: <20} | {: >9} | {: >15} | {: >9} | {: >15} | {: >15}",
+            process.pid,
+            process.name,
+            process.creates,
+            FormatBytes::new(process.created_bytes),
+            process.moves,
+            FormatBytes::new(process.moved_bytes),
+            FormatBytes::new(process.evicted_bytes),
+        )?;
+    }
+    Ok(())
+}
+
+pub fn run(global: &GlobalArgs, options: &TraceArgs) -> error::Result<()> {
+    if !matches!(
+        global.output,
+        OutputFormat::Table | OutputFormat::Json | OutputFormat::Csv
+    ) {
+        return Err(output::unsupported(global.output, "trace"));
+    }
+    let tracing = source::find_tracefs(&global.debugfs_path)?;
+
+    let started = Instant::now();
+    #[cfg(feature = "ebpf")]
+    let lines = match options.ebpf {
+        true => ebpf::record(&tracing, &options.events, options.duration, |at, line| {
+            if global.output == OutputFormat::Table {
+                let _ = write_event(&mut io::stdout().lock(), at, line);
+            }
+        })?,
+        false => record_tracefs(&tracing, options)?,
+    };
+    #[cfg(not(feature = "ebpf"))]
+    let lines = record_tracefs(&tracing, options)?;
+    let elapsed = started.elapsed();
+
+    let (events, churn) = summarize(lines);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match global.output {
+        OutputFormat::Table => write_table(&mut out, options, elapsed, events, &churn)?,
+        OutputFormat::Json => output::write_json(&mut out, &churn)?,
+        OutputFormat::Csv => {
+            output::write_csv_row(
+                &mut out,
+                &[
+                    "pid",
+                    "name",
+                    "creates",
+                    "created_bytes",
+                    "moves",
+                    "moved_bytes",
+                    "evicted_bytes",
+                ],
+            )?;
+            for process in &churn {
+                output::write_csv_row(
+                    &mut out,
+                    &[
+                        process.pid.to_string(),
+                        process.name.clone(),
+                        process.creates.to_string(),
+                        process.created_bytes.to_string(),
+                        process.moves.to_string(),
+                        process.moved_bytes.to_string(),
+                        process.evicted_bytes.to_string(),
+                    ],
+                )?;
+            }
+        }
+        _ => unreachable!("checked above"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_trace_pipe_lines() {
+        let pattern = line_pattern();
+        let line = parse_line(
+            &pattern,
+            "  Web Content-4242    [003] ..... 12345.678901: amdgpu_bo_create: \
+             bo=00000000a1b2c3d4, pages=256, type=2, preferred=4, allowed=6, visible=1",
+        )
+        .unwrap();
+        assert_eq!(line.name, "Web Content");
+        assert_eq!(line.pid, 4242);
+        assert_eq!(
+            line.event,
+            Event::Create {
+                bytes: 256 * mem::page_size()
+            }
+        );
+
+        let line = parse_line(
+            &pattern,
+            "blender-3301 (   3301) [000] d..1. 2.5: amdgpu_bo_move: bo=0000, from=2, to=1, size=8192",
+        )
+        .unwrap();
+        assert_eq!(
+            line.event,
+            Event::Move {
+                from: 2,
+                to: 1,
+                bytes: 8192
+            }
+        );
+
+        assert!(parse_line(&pattern, "# tracer: nop").is_none());
+    }
+
+    #[test]
+    fn sums_churn_per_process() {
+        let line = |pid, event| Line {
+            name: "blender".to_string(),
+            pid,
+            event,
+        };
+        let (events, churn) = summarize(vec![
+            line(1, Event::Create { bytes: 4096 }),
+            line(
+                2,
+                Event::Move {
+                    from: 2,
+                    to: 1,
+                    bytes: 8192,
+                },
+            ),
+            line(
+                2,
+                Event::Move {
+                    from: 1,
+                    to: 2,
+                    bytes: 8192,
+                },
+            ),
+        ]);
+        assert_eq!(events, 3);
+        assert_eq!(churn[0].pid, 2);
+        assert_eq!(churn[0].moves, 2);
+        assert_eq!(churn[0].evicted_bytes, 8192);
+        assert_eq!(churn[0].moved["GTT->VRAM"], 8192);
+        assert_eq!(churn[1].created_bytes, 4096);
+    }
+}
@@ -0,0 +1,676 @@
+//! `amdtop trace --ebpf`: the same tracepoints, attached to with a small
+//! eBPF program rather than read from `trace_pipe`. Each event comes with
+//! the process it fired in, not the thread, as it happens, and whatever
+//! else is tracing doesn't share the buffer. Built with the `ebpf` feature.
+//!
+//! The program is a handful of instructions assembled here, so there's no
+//! BPF toolchain or library to build with. It copies the start of the
+//! tracepoint's record, along with the time and the process, into a perf
+//! buffer per CPU, which we map and read.
+
+use super::{Event, Line, TraceEvent};
+use crate::{
+    error::{self, Error},
+    mem, INTERRUPTED,
+};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    io,
+    os::unix::io::{AsRawFd, FromRawFd, OwnedFd},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+const BPF_MAP_CREATE: libc::c_long = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_PROG_LOAD: libc::c_long = 5;
+const BPF_MAP_TYPE_PERF_EVENT_ARRAY: u32 = 4;
+const BPF_PROG_TYPE_TRACEPOINT: u32 = 5;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+
+const HELPER_KTIME_GET_NS: i32 = 5;
+const HELPER_GET_CURRENT_PID_TGID: i32 = 14;
+const HELPER_GET_CURRENT_COMM: i32 = 16;
+const HELPER_PERF_EVENT_OUTPUT: i32 = 25;
+
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_TYPE_TRACEPOINT: u32 = 2;
+const PERF_COUNT_SW_BPF_OUTPUT: u64 = 10;
+const PERF_SAMPLE_RAW: u64 = 1 << 10;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 8;
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_SET_BPF: libc::c_ulong = 0x4004_2408;
+const PERF_RECORD_LOST: u32 = 2;
+const PERF_RECORD_SAMPLE: u32 = 9;
+
+/// How much room the start of each tracepoint's record gets, amdgpu's fields
+/// all fitting. The program copies only the fields we need, as reading past
+/// the record's end isn't allowed. It can't read the first word, the common
+/// fields, either, so it puts which tracepoint it's attached to there.
+const RAW_BYTES: usize = 48;
+/// What the program sends: the time since boot, `pid_tgid`, the thread's
+/// name, then the record.
+const SAMPLE_BYTES: usize = 32 + RAW_BYTES;
+/// Pages of each CPU's buffer, a power of two.
+const BUFFER_PAGES: usize = 64;
+
+/// One eBPF instruction.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Instruction {
+    code: u8,
+    /// The destination register in the low nibble, the source in the high.
+    registers: u8,
+    offset: i16,
+    immediate: i32,
+}
+
+fn instruction(code: u8, dst: u8, src: u8, offset: i16, immediate: i32) -> Instruction {
+    Instruction {
+        code,
+        registers: src << 4 | dst,
+        offset,
+        immediate,
+    }
+}
+
+const MOV64_REG: u8 = 0xbf;
+const MOV64_IMM: u8 = 0xb7;
+const MOV32_IMM: u8 = 0xb4;
+const ADD64_IMM: u8 = 0x07;
+const STORE_DW: u8 = 0x7b;
+const STORE_DW_IMM: u8 = 0x7a;
+/// Loads and stores of each size ORed in, for 1, 2, 4 and 8 bytes.
+const LOAD: u8 = 0x61;
+const STORE: u8 = 0x63;
+const SIZES: [(usize, u8); 4] = [(1, 0x10), (2, 0x08), (4, 0x00), (8, 0x18)];
+const LOAD_IMM64: u8 = 0x18;
+const CALL: u8 = 0x85;
+const EXIT: u8 = 0x95;
+
+/// The program for the tracepoint of layout `index`, which has `fields`:
+/// builds the sample on the stack, where register 10 points past its end,
+/// and hands it to the perf buffer of the CPU it runs on.
+fn program(map_fd: i32, index: i32, fields: &[(usize, usize)]) -> Vec<Instruction> {
+    let sample = -(SAMPLE_BYTES as i16);
+    let mut program = vec![
+        // The record, which the helpers' calls clobber register 1 for.
+        instruction(MOV64_REG, 6, 1, 0, 0),
+        instruction(CALL, 0, 0, 0, HELPER_KTIME_GET_NS),
+        instruction(STORE_DW, 10, 0, sample, 0),
+        instruction(CALL, 0, 0, 0, HELPER_GET_CURRENT_PID_TGID),
+        instruction(STORE_DW, 10, 0, sample + 8, 0),
+        instruction(MOV64_REG, 1, 10, 0, 0),
+        instruction(ADD64_IMM, 1, 0, 0, (sample + 16).into()),
+        instruction(MOV64_IMM, 2, 0, 0, 16),
+        instruction(CALL, 0, 0, 0, HELPER_GET_CURRENT_COMM),
+        instruction(STORE_DW_IMM, 10, 0, sample + 32, index),
+    ];
+    // What it sends has to be initialized, fields or not.
+    for word in 1..(RAW_BYTES / 8) as i16 {
+        program.push(instruction(STORE_DW_IMM, 10, 0, sample + 32 + word * 8, 0));
+    }
+    for &(offset, size) in fields {
+        let width = SIZES
+            .iter()
+            .find(|(bytes, _)| *bytes == size)
+            .map_or(0, |(_, width)| *width);
+        program.push(instruction(LOAD | width, 0, 6, offset as i16, 0));
+        program.push(instruction(
+            STORE | width,
+            10,
+            0,
+            sample + 32 + offset as i16,
+            0,
+        ));
+    }
+    program.extend([
+        instruction(MOV64_REG, 1, 6, 0, 0),
+        instruction(LOAD_IMM64, 2, BPF_PSEUDO_MAP_FD, 0, map_fd),
+        instruction(0, 0, 0, 0, 0),
+        // BPF_F_CURRENT_CPU, all of the low 32 bits.
+        instruction(MOV32_IMM, 3, 0, 0, -1),
+        instruction(MOV64_REG, 4, 10, 0, 0),
+        instruction(ADD64_IMM, 4, 0, 0, sample.into()),
+        instruction(MOV64_IMM, 5, 0, 0, SAMPLE_BYTES as i32),
+        instruction(CALL, 0, 0, 0, HELPER_PERF_EVENT_OUTPUT),
+        instruction(MOV64_IMM, 0, 0, 0, 0),
+        instruction(EXIT, 0, 0, 0, 0),
+    ]);
+    program
+}
+
+#[repr(C)]
+struct MapAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+}
+
+#[repr(C)]
+struct UpdateAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+struct ProgramAttr {
+    program_type: u32,
+    instructions: u32,
+    instructions_ptr: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+}
+
+/// `perf_event_attr` as far as its first version went.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+fn permission_error(err: io::Error, what: &str) -> Error {
+    match err.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EPERM) => Error::PermissionDenied(format!(
+            "permission denied {}\n\
+             eBPF needs CAP_BPF and CAP_PERFMON, try running `sudo amdtop trace --ebpf`",
+            what
+        )),
+        _ => Error::Io(err),
+    }
+}
+
+fn bpf<T>(command: libc::c_long, attr: &T) -> io::Result<libc::c_long> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            command,
+            attr as *const T,
+            std::mem::size_of::<T>(),
+        )
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+fn bpf_fd<T>(command: libc::c_long, attr: &T) -> io::Result<OwnedFd> {
+    bpf(command, attr).map(|fd| unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+fn perf_event_open(attr: &mut PerfEventAttr, cpu: i32) -> io::Result<OwnedFd> {
+    attr.size = std::mem::size_of::<PerfEventAttr>() as u32;
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            attr as *const PerfEventAttr,
+            -1,
+            cpu,
+            -1,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+fn ioctl(fd: &OwnedFd, request: libc::c_ulong, argument: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::ioctl(fd.as_raw_fd(), request as _, argument) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn load(map: &OwnedFd, index: usize, layout: &Layout) -> error::Result<OwnedFd> {
+    let program = program(map.as_raw_fd(), index as i32, &layout.fields);
+    let license = b"Dual MIT/GPL\0";
+    let mut attr = ProgramAttr {
+        program_type: BPF_PROG_TYPE_TRACEPOINT,
+        instructions: program.len() as u32,
+        instructions_ptr: program.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        log_level: 0,
+        log_size: 0,
+        log_buf: 0,
+    };
+    if let Ok(fd) = bpf_fd(BPF_PROG_LOAD, &attr) {
+        return Ok(fd);
+    }
+    // Again, to hear from the verifier why not.
+    let mut log = vec![0u8; 1 << 16];
+    attr.log_level = 1;
+    attr.log_size = log.len() as u32;
+    attr.log_buf = log.as_mut_ptr() as u64;
+    bpf_fd(BPF_PROG_LOAD, &attr).map_err(|err| match err.raw_os_error() {
+        Some(libc::EINVAL) | Some(libc::EACCES) if log[0] != 0 => {
+            let end = log.iter().position(|&byte| byte == 0).unwrap_or(log.len());
+            Error::UnsupportedKernel(format!(
+                "the kernel rejected amdtop's eBPF program:\n{}",
+                String::from_utf8_lossy(&log[..end]).trim_end()
+            ))
+        }
+        _ => permission_error(err, "loading an eBPF program"),
+    })
+}
+
+/// Where each field of a tracepoint's record is, as offset and size, from
+/// its `format` file, which has `field:unsigned int pages; offset:16; size:4;`
+/// and so on, separated by tabs.
+fn parse_format(format: &str) -> HashMap<String, (usize, usize)> {
+    format
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split(';').map(str::trim);
+            let field = parts.next()?.strip_prefix("field:")?;
+            let name = field.rsplit(' ').next()?;
+            let name = name.split('[').next()?;
+            let mut number = |key: &str| parts.next()?.strip_prefix(key)?.parse().ok();
+            Some((name.to_string(), (number("offset:")?, number("size:")?)))
+        })
+        .collect()
+}
+
+/// The fields of one tracepoint that make up an `Event`.
+struct Layout {
+    event: TraceEvent,
+    id: u64,
+    fields: Vec<(usize, usize)>,
+}
+
+impl Layout {
+    fn read(tracing: &Path, event: TraceEvent) -> error::Result<Self> {
+        let directory = tracing.join("events/amdgpu").join(event.tracepoint());
+        let read = |name| {
+            let path = directory.join(name);
+            std::fs::read_to_string(&path).map_err(|err| match err.kind() {
+                io::ErrorKind::NotFound => Error::UnsupportedKernel(format!(
+                    "{} doesn't exist; is the amdgpu driver loaded?",
+                    path.display()
+                )),
+                _ => permission_error(err, &format!("reading {}", path.display())),
+            })
+        };
+        let id = read("id")?;
+        let id = id.trim().parse().map_err(|_| {
+            Error::Parse(format!(
+                "{} isn't a tracepoint id: {:?}",
+                event.tracepoint(),
+                id
+            ))
+        })?;
+        let offsets = parse_format(&read("format")?);
+        let names: &[&str] = match event {
+            TraceEvent::BoCreate => &["pages"],
+            TraceEvent::BoMove => &["old_placement", "new_placement", "size"],
+        };
+        let fields = names
+            .iter()
+            .map(|name| {
+                offsets
+                    .get(*name)
+                    .copied()
+                    .filter(|(offset, size)| {
+                        *offset >= 8
+                            && offset + size <= RAW_BYTES
+                            && SIZES.iter().any(|(bytes, _)| bytes == size)
+                            && offset % size == 0
+                    })
+                    .ok_or_else(|| {
+                        Error::UnsupportedKernel(format!(
+                            "{} has no field {} where amdtop --ebpf looks",
+                            event.tracepoint(),
+                            name
+                        ))
+                    })
+            })
+            .collect::<error::Result<_>>()?;
+        Ok(Layout { event, id, fields })
+    }
+
+    fn decode(&self, raw: &[u8]) -> Event {
+        let field = |index: usize| {
+            let (offset, size) = self.fields[index];
+            let mut bytes = [0; 8];
+            bytes[..size].copy_from_slice(&raw[offset..offset + size]);
+            u64::from_le_bytes(bytes)
+        };
+        match self.event {
+            TraceEvent::BoCreate => Event::Create {
+                bytes: field(0) * mem::page_size(),
+            },
+            TraceEvent::BoMove => Event::Move {
+                from: field(0) as u32,
+                to: field(1) as u32,
+                bytes: field(2),
+            },
+        }
+    }
+}
+
+/// A sample the program sent, as the time since boot and the event.
+fn decode_sample(layouts: &[Layout], sample: &[u8]) -> Option<(Duration, Line)> {
+    if sample.len() < SAMPLE_BYTES {
+        return None;
+    }
+    let word = |at: usize| u64::from_le_bytes(sample[at..at + 8].try_into().expect("8 bytes"));
+    let raw = &sample[32..SAMPLE_BYTES];
+    let layout = layouts.get(word(32) as usize)?;
+    let name = &sample[16..32];
+    let end = name
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(name.len());
+    Some((
+        Duration::from_nanos(word(0)),
+        Line {
+            name: String::from_utf8_lossy(&name[..end]).into_owned(),
+            pid: (word(8) >> 32) as i32,
+            event: layout.decode(raw),
+        },
+    ))
+}
+
+/// One CPU's perf buffer, mapped.
+struct Buffer {
+    fd: OwnedFd,
+    base: *mut u8,
+    page: usize,
+}
+
+impl Buffer {
+    fn open(cpu: i32, page: usize) -> io::Result<Self> {
+        let fd = perf_event_open(
+            &mut PerfEventAttr {
+                kind: PERF_TYPE_SOFTWARE,
+                config: PERF_COUNT_SW_BPF_OUTPUT,
+                sample_type: PERF_SAMPLE_RAW,
+                sample_period: 1,
+                wakeup_events: 1,
+                ..PerfEventAttr::default()
+            },
+            cpu,
+        )?;
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                (BUFFER_PAGES + 1) * page,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        ioctl(&fd, PERF_EVENT_IOC_ENABLE, 0)?;
+        Ok(Buffer {
+            fd,
+            base: base as *mut u8,
+            page,
+        })
+    }
+
+    /// `data_head` and `data_tail` of the first page.
+    fn position(&self, offset: usize) -> &AtomicU64 {
+        unsafe { &*(self.base.add(offset) as *const AtomicU64) }
+    }
+
+    /// Takes the samples written since the last call, and how many were
+    /// lost to the buffer filling up.
+    fn drain(&mut self, samples: &mut Vec<Vec<u8>>) -> u64 {
+        let size = BUFFER_PAGES * self.page;
+        let data = unsafe { std::slice::from_raw_parts(self.base.add(self.page), size) };
+        let copy = |from: u64, length: usize| {
+            (0..length as u64)
+                .map(|index| data[((from + index) % size as u64) as usize])
+                .collect::<Vec<_>>()
+        };
+        let head = self.position(1024).load(Ordering::Acquire);
+        let mut tail = self.position(1032).load(Ordering::Relaxed);
+        let mut lost = 0;
+        while tail < head {
+            let header = copy(tail, 8);
+            let kind = u32::from_le_bytes(header[..4].try_into().expect("4 bytes"));
+            let length = u16::from_le_bytes([header[6], header[7]]) as usize;
+            if length < 8 {
+                break;
+            }
+            let record = copy(tail + 8, length - 8);
+            match kind {
+                // The raw sample's size, then the sample.
+                PERF_RECORD_SAMPLE if record.len() >= 4 => {
+                    let raw = u32::from_le_bytes(record[..4].try_into().expect("4 bytes"));
+                    let end = (4 + raw as usize).min(record.len());
+                    samples.push(record[4..end].to_vec());
+                }
+                // The id of the event, then how many were lost.
+                PERF_RECORD_LOST if record.len() >= 16 => {
+                    lost += u64::from_le_bytes(record[8..16].try_into().expect("8 bytes"));
+                }
+                _ => {}
+            }
+            tail += length as u64;
+        }
+        self.position(1032).store(tail, Ordering::Release);
+        lost
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut _, (BUFFER_PAGES + 1) * self.page);
+        }
+    }
+}
+
+/// When it is since boot, on the clock the program reads.
+fn monotonic() -> Duration {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now);
+    }
+    Duration::new(now.tv_sec as u64, now.tv_nsec as u32)
+}
+
+/// Attaches to `events` until `duration` is up or Ctrl-C is pressed,
+/// passing each event to `live` as it comes, with how long after the start.
+pub fn record<F: FnMut(Duration, &Line)>(
+    tracing: &Path,
+    events: &[TraceEvent],
+    duration: Duration,
+    mut live: F,
+) -> error::Result<Vec<Line>> {
+    let layouts = events
+        .iter()
+        .map(|event| Layout::read(tracing, *event))
+        .collect::<error::Result<Vec<_>>>()?;
+
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }.max(1) as u32;
+    let map = bpf_fd(
+        BPF_MAP_CREATE,
+        &MapAttr {
+            map_type: BPF_MAP_TYPE_PERF_EVENT_ARRAY,
+            key_size: 4,
+            value_size: 4,
+            max_entries: cpus,
+        },
+    )
+    .map_err(|err| permission_error(err, "creating an eBPF map"))?;
+    let page = mem::page_size() as usize;
+    let mut buffers = Vec::new();
+    for cpu in 0..cpus {
+        let buffer = match Buffer::open(cpu as i32, page) {
+            Ok(buffer) => buffer,
+            // Offline.
+            Err(err) if err.raw_os_error() == Some(libc::ENODEV) => continue,
+            Err(err) => return Err(permission_error(err, "opening a perf buffer")),
+        };
+        let fd = buffer.fd.as_raw_fd() as u32;
+        bpf(
+            BPF_MAP_UPDATE_ELEM,
+            &UpdateAttr {
+                map_fd: map.as_raw_fd() as u32,
+                _pad: 0,
+                key: &cpu as *const u32 as u64,
+                value: &fd as *const u32 as u64,
+                flags: 0,
+            },
+        )
+        .map_err(Error::Io)?;
+        buffers.push(buffer);
+    }
+
+    // Kept open for as long as the programs should stay attached.
+    let _attached = layouts
+        .iter()
+        .enumerate()
+        .map(|(index, layout)| {
+            let program = load(&map, index, layout)?;
+            let fd = perf_event_open(
+                &mut PerfEventAttr {
+                    kind: PERF_TYPE_TRACEPOINT,
+                    config: layout.id,
+                    sample_period: 1,
+                    wakeup_events: 1,
+                    ..PerfEventAttr::default()
+                },
+                0,
+            )
+            .map_err(|err| permission_error(err, "attaching to a tracepoint"))?;
+            ioctl(&fd, PERF_EVENT_IOC_SET_BPF, program.as_raw_fd()).map_err(Error::Io)?;
+            ioctl(&fd, PERF_EVENT_IOC_ENABLE, 0).map_err(Error::Io)?;
+            Ok((program, fd))
+        })
+        .collect::<error::Result<Vec<_>>>()?;
+
+    crate::catch_interrupts();
+    let started = monotonic();
+    let deadline = Instant::now() + duration;
+    let mut lines = Vec::new();
+    let mut lost = 0;
+    let mut samples = Vec::new();
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        let mut fds = buffers
+            .iter()
+            .map(|buffer| libc::pollfd {
+                fd: buffer.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect::<Vec<_>>();
+        let timeout = left.min(Duration::from_millis(200)).as_millis() as libc::c_int;
+        unsafe {
+            libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout);
+        }
+        for buffer in &mut buffers {
+            lost += buffer.drain(&mut samples);
+        }
+        // Each CPU's in order, so sort them into one.
+        let mut decoded = samples
+            .drain(..)
+            .filter_map(|sample| decode_sample(&layouts, &sample))
+            .collect::<Vec<_>>();
+        decoded.sort_by_key(|(at, _)| *at);
+        for (at, line) in decoded {
+            live(at.saturating_sub(started), &line);
+            lines.push(line);
+        }
+    }
+    if lost > 0 {
+        eprintln!(
+            "amdtop: {} events were lost, coming faster than they could be read",
+            lost
+        );
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_fields_in_formats() {
+        let offsets = parse_format(
+            "name: amdgpu_bo_move\n\
+             ID: 1712\n\
+             format:\n\
+             \tfield:unsigned short common_type;\toffset:0;\tsize:2;\tsigned:0;\n\
+             \tfield:int common_pid;\toffset:4;\tsize:4;\tsigned:1;\n\
+             \n\
+             \tfield:struct amdgpu_bo* bo;\toffset:8;\tsize:8;\tsigned:0;\n\
+             \tfield:int old_placement;\toffset:16;\tsize:4;\tsigned:1;\n\
+             \tfield:char name[16];\toffset:20;\tsize:16;\tsigned:0;\n\
+             \n\
+             print fmt: \"bo=%p, from=%d, to=%d, size=%ld\", REC->bo\n",
+        );
+        assert_eq!(offsets["common_pid"], (4, 4));
+        assert_eq!(offsets["old_placement"], (16, 4));
+        assert_eq!(offsets["name"], (20, 16));
+        assert!(!offsets.contains_key("print fmt"));
+    }
+
+    #[test]
+    fn decodes_samples() {
+        let layouts = [Layout {
+            event: TraceEvent::BoMove,
+            id: 1712,
+            fields: vec![(16, 4), (20, 4), (24, 8)],
+        }];
+        let mut sample = vec![0; SAMPLE_BYTES];
+        sample[..8].copy_from_slice(&2_500_000_000u64.to_le_bytes());
+        sample[8..16].copy_from_slice(&(3301u64 << 32 | 3305).to_le_bytes());
+        sample[16..23].copy_from_slice(b"blender");
+        let raw = &mut sample[32..];
+
+        raw[16..20].copy_from_slice(&2u32.to_le_bytes());
+        raw[20..24].copy_from_slice(&1u32.to_le_bytes());
+        raw[24..32].copy_from_slice(&8192u64.to_le_bytes());
+
+        let (at, line) = decode_sample(&layouts, &sample).unwrap();
+        assert_eq!(at, Duration::from_millis(2500));
+        assert_eq!(line.name, "blender");
+        assert_eq!(line.pid, 3301);
+        assert_eq!(
+            line.event,
+            Event::Move {
+                from: 2,
+                to: 1,
+                bytes: 8192
+            }
+        );
+
+        sample[32] = 1;
+        assert!(decode_sample(&layouts, &sample).is_none());
+    }
+}
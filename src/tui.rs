@@ -0,0 +1,505 @@
+//! `amdtop top`: the memory table on a full screen that redraws in place,
+//! with a line of sensor readings per device. `f` adds each ring's last
+//! fences, which is the first thing asked for when a hang is reported,
+//! and `k` amdgpu's last kernel log lines, placed against the refreshes.
+//! When a hang leaves a devcoredump, `s` copies it into the current
+//! directory before the kernel drops it. The arrow keys select a process
+//! and `p` pins it to the top of its table, like `--pin`, so it stays in
+//! sight whatever else allocates more. Processes that appeared since the
+//! last refresh are drawn bold, and ones that exited stay dimmed for a
+//! couple of refreshes before they go, so churn shows. `e` opens a log of
+//! those comings and goings, VRAM filling up and GPU resets, with the time
+//! of each, that Page Up and Page Down scroll through. With `--connect` the screens come from an `amdtop agent` instead.
+
+use crate::{
+    cli::{GlobalArgs, MemArgs},
+    error,
+    events::EventLog,
+    kmsg::KernelLog,
+    mem::{self, DeviceView, Session},
+    meminfo::{self, SystemMemory},
+    remote::Viewer,
+    rings,
+    sensors::Sensors,
+    smoothing::Smoother,
+    source::{self, Device, Sources},
+};
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    queue,
+    style::{Attribute, Attributes, Print, SetAttribute, SetAttributes},
+    terminal::{self, ClearType},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// How many refreshes exited processes stay, at least, dimmed.
+const EXITED_REFRESHES: u32 = 2;
+
+/// How many of the kernel log's lines `k` shows.
+const KERNEL_LOG_LINES: usize = 10;
+/// How many events `e` shows, and Page Up and Page Down scroll by.
+const EVENT_LINES: usize = 10;
+
+/// Puts the terminal into raw mode on the alternate screen, and back again
+/// when dropped, so an error doesn't leave the shell unusable.
+pub struct Terminal;
+
+impl Terminal {
+    pub fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        queue!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+        stdout.flush()?;
+        Ok(Terminal)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        let _ = queue!(stdout, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = stdout.flush();
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// The readings that fit on the line under each device.
+const SENSOR_LINE: &[&str] = &[
+    "gpu_busy",
+    "memory_busy",
+    "shader_clock",
+    "memory_clock",
+    "edge_temperature",
+    "junction_temperature",
+    "smartshift_apu_power",
+    "smartshift_dgpu_power",
+];
+
+fn sensor_line(sensors: &Sensors) -> String {
+    let mut parts = sensors
+        .readings()
+        .iter()
+        .filter(|reading| SENSOR_LINE.contains(&reading.key))
+        .map(|reading| {
+            if reading.value.fract() == 0.0 {
+                format!("{} {} {}", reading.label, reading.value, reading.unit)
+            } else {
+                format!("{} {:.1} {}", reading.label, reading.value, reading.unit)
+            }
+        })
+        .collect::<Vec<_>>();
+    if let Some(gfxoff) = sensors.gfxoff {
+        parts.push(format!("GFX {}", gfxoff));
+    }
+    parts.join(" | ")
+}
+
+/// The fence pane under a device: the last signaled and emitted fence of
+/// every ring, or why they couldn't be read.
+fn write_fence_pane(out: &mut Vec<u8>, global: &GlobalArgs, device: Device) -> io::Result<()> {
+    match source::read_device_file(&global.debugfs_path, device, "amdgpu_fence_info") {
+        Ok(contents) => {
+            let fences = rings::parse_fence_info(&String::from_utf8_lossy(&contents));
+            rings::write_fences(out, &fences)
+        }
+        Err(err) => {
+            let reason = err.to_string();
+            writeln!(
+                out,
+                "  fences: {}",
+                reason.lines().next().unwrap_or_default()
+            )
+        }
+    }
+}
+
+/// Draws `lines` clipped to the terminal, with a status line at the bottom.
+pub fn draw(lines: &[String], status: &str) -> io::Result<()> {
+    let lines = lines
+        .iter()
+        .map(|line| (line.clone(), Attributes::default()))
+        .collect::<Vec<_>>();
+    draw_styled(&lines, status)
+}
+
+/// `draw`, with each line in its own attributes.
+pub fn draw_styled(lines: &[(String, Attributes)], status: &str) -> io::Result<()> {
+    let (columns, rows) = terminal::size()?;
+    let (columns, rows) = (columns as usize, rows as usize);
+    let mut stdout = io::stdout();
+
+    queue!(stdout, cursor::MoveTo(0, 0))?;
+    for (row, (line, attributes)) in lines.iter().take(rows.saturating_sub(1)).enumerate() {
+        let line = line.chars().take(columns).collect::<String>();
+        queue!(
+            stdout,
+            cursor::MoveTo(0, row as u16),
+            SetAttributes(*attributes),
+            Print(line),
+            SetAttribute(Attribute::Reset),
+            terminal::Clear(ClearType::UntilNewLine)
+        )?;
+    }
+    queue!(
+        stdout,
+        terminal::Clear(ClearType::FromCursorDown),
+        cursor::MoveTo(0, rows.saturating_sub(1) as u16),
+        SetAttribute(Attribute::Reverse),
+        Print(format!("{: <1$}", status, columns)),
+        SetAttribute(Attribute::Reset)
+    )?;
+    stdout.flush()
+}
+
+pub fn quits(key: KeyEvent) -> bool {
+    match key.code {
+        KeyCode::Char('q') | KeyCode::Esc => true,
+        KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+        _ => false,
+    }
+}
+
+/// The pid of a process's row in a rendered table.
+fn row_pid(line: &str) -> Option<i32> {
+    line.split(" | ").next()?.trim().parse().ok()
+}
+
+/// What `top` shows for one device: the memory table with its sensor line,
+/// and the fence pane `f` adds under it.
+#[derive(Serialize, Deserialize, Default)]
+pub struct DeviceScreen {
+    pub table: String,
+    pub fences: String,
+}
+
+pub fn device_screens(
+    global: &GlobalArgs,
+    views: &[DeviceView],
+    smoother: &mut Smoother,
+) -> io::Result<Vec<DeviceScreen>> {
+    let mut screens = views
+        .iter()
+        .map(|view| {
+            let mut table = Vec::new();
+            mem::write_table(&mut table, view)?;
+            let mut sensors = Sensors::read(view.device);
+            sensors.read_gfxoff(&global.debugfs_path);
+            smoother.sensors(&mut sensors);
+            let sensors = sensor_line(&sensors);
+            if !sensors.is_empty() {
+                table.extend_from_slice(sensors.as_bytes());
+                table.push(b'\n');
+            }
+            let mut fences = Vec::new();
+            write_fence_pane(&mut fences, global, view.device)?;
+            Ok(DeviceScreen {
+                table: String::from_utf8_lossy(&table).into_owned(),
+                fences: String::from_utf8_lossy(&fences).into_owned(),
+            })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    // System-wide, so it goes under the last device.
+    let warning =
+        SystemMemory::read().and_then(|memory| memory.gtt_warning(meminfo::gtt_in_use(views)));
+    if let (Some(warning), Some(screen)) = (warning, screens.last_mut()) {
+        screen.table += &warning;
+        screen.table.push('\n');
+    }
+    Ok(screens)
+}
+
+/// Where the screens come from: this machine, or an agent elsewhere.
+enum Feed {
+    Local {
+        sources: Sources,
+        session: Box<Session>,
+        smoother: Smoother,
+        kernel_log: Box<KernelLog>,
+        events: EventLog,
+        /// Coredumps not copied yet, with the PCI slot of their device.
+        coredumps: Vec<(String, String)>,
+    },
+    Remote {
+        viewer: Viewer,
+        host: String,
+    },
+}
+
+impl Feed {
+    fn screens(
+        &mut self,
+        global: &GlobalArgs,
+        options: &MemArgs,
+    ) -> error::Result<Vec<DeviceScreen>> {
+        match self {
+            Feed::Local {
+                sources,
+                session,
+                smoother,
+                kernel_log,
+                events,
+                coredumps,
+            } => {
+                let views = mem::refresh(global, options, sources, session)?;
+                let logged = kernel_log.update(&views, session.kernel_records());
+                events.update(&views, &logged);
+                *coredumps = views
+                    .iter()
+                    .filter_map(|view| Some((view, view.device.pci_slot()?)))
+                    .flat_map(|(view, slot)| {
+                        view.coredumps
+                            .iter()
+                            .filter(|coredump| {
+                                coredump.saved_to.is_none() && coredump.error.is_none()
+                            })
+                            .map(move |coredump| (coredump.name.clone(), slot.clone()))
+                    })
+                    .collect();
+                Ok(device_screens(global, &views, smoother)?)
+            }
+            Feed::Remote { viewer, host } => {
+                let frame = viewer.fetch()?;
+                *host = frame.host;
+                Ok(frame.devices)
+            }
+        }
+    }
+}
+
+pub fn run(global: &GlobalArgs, options: &MemArgs) -> error::Result<()> {
+    // Pick sources first so their warnings end up on the normal screen.
+    let mut feed = match &global.connect {
+        Some(address) => Feed::Remote {
+            viewer: Viewer::connect(global, address)?,
+            host: address.clone(),
+        },
+        None => Feed::Local {
+            sources: mem::select_sources(global, options)?,
+            session: Box::new(Session::new(true)),
+            smoother: Smoother::new(global.smoothing),
+            kernel_log: Box::default(),
+            events: EventLog::default(),
+            coredumps: Vec::new(),
+        },
+    };
+    let delay = global.delay.unwrap_or(Duration::from_secs(1));
+    let mut options = options.clone();
+    options.keep_exited = options.keep_exited.max(EXITED_REFRESHES);
+    let mut selected = None;
+    // None until the first refresh, when nothing is new yet.
+    let mut previous: Option<Vec<i32>> = None;
+    let _terminal = Terminal::enter()?;
+    let mut iteration = 0;
+    let mut show_fences = false;
+    let mut show_log = false;
+    let mut show_events = false;
+    // How many events back from the last the pane ends.
+    let mut events_back = 0;
+
+    loop {
+        let screens = feed.screens(global, &options)?;
+
+        let mut lines = Vec::new();
+        for screen in &screens {
+            lines.extend(screen.table.lines().map(str::to_string));
+            if show_fences {
+                lines.extend(screen.fences.lines().map(str::to_string));
+            }
+            lines.push(String::new());
+        }
+        if show_log {
+            lines.push("Kernel log:".to_string());
+            match &feed {
+                Feed::Local { kernel_log, .. } => match kernel_log.error() {
+                    Some(error) => lines.push(format!("  {}", error)),
+                    None => {
+                        let recent = kernel_log.recent().collect::<Vec<_>>();
+                        let shown = &recent[recent.len().saturating_sub(KERNEL_LOG_LINES)..];
+                        lines.extend(shown.iter().map(|line| line.to_string()));
+                    }
+                },
+                Feed::Remote { .. } => {
+                    lines.push("  not available over --connect".to_string());
+                }
+            }
+        }
+        if show_events {
+            lines.push("Events:".to_string());
+            match &feed {
+                Feed::Local { events, .. } => {
+                    let events = events.events().collect::<Vec<_>>();
+                    if events.is_empty() {
+                        lines.push("  none yet".to_string());
+                    }
+                    events_back = events_back.min(events.len().saturating_sub(EVENT_LINES));
+                    let end = events.len() - events_back;
+                    let shown = &events[end.saturating_sub(EVENT_LINES)..end];
+                    lines.extend(shown.iter().map(|event| format!("  {}", event)));
+                }
+                Feed::Remote { .. } => {
+                    lines.push("  not available over --connect".to_string());
+                }
+            }
+        }
+        let pids =
+            lines
+                .iter()
+                .filter_map(|line| row_pid(line))
+                .fold(Vec::new(), |mut pids, pid| {
+                    if !pids.contains(&pid) {
+                        pids.push(pid);
+                    }
+                    pids
+                });
+        let lines = lines
+            .into_iter()
+            .map(|line| {
+                let mut attributes = Attributes::default();
+                if let Some(pid) = row_pid(&line) {
+                    if line.contains("<exited>") {
+                        attributes.set(Attribute::Dim);
+                    } else if previous
+                        .as_ref()
+                        .is_some_and(|previous| !previous.contains(&pid))
+                    {
+                        attributes.set(Attribute::Bold);
+                    }
+                    if options.pin.contains(&pid) {
+                        attributes.set(Attribute::Underlined);
+                    }
+                    if selected == Some(pid) {
+                        attributes.set(Attribute::Reverse);
+                    }
+                }
+                (line, attributes)
+            })
+            .collect::<Vec<_>>();
+        previous = Some(pids.clone());
+        let (host, save) = match &feed {
+            Feed::Remote { host, .. } => (format!(" | {}", host), ""),
+            Feed::Local { coredumps, .. } if !coredumps.is_empty() => {
+                (String::new(), " | s save coredump")
+            }
+            Feed::Local { .. } => (String::new(), ""),
+        };
+        // Pins are options of the session, which an agent keeps to itself.
+        let pin = match &feed {
+            Feed::Local { .. } if options.pin.is_empty() => " | up/down p pin".to_string(),
+            Feed::Local { .. } => format!(" | up/down p pin ({} pinned)", options.pin.len()),
+            Feed::Remote { .. } => String::new(),
+        };
+        draw_styled(
+            &lines,
+            &format!(
+                " amdtop{} | refreshing every {:.1}s | f fences | k kernel log | e events{}{}{} | q to quit",
+                host,
+                delay.as_secs_f64(),
+                if show_events { " PgUp/PgDn" } else { "" },
+                pin,
+                save
+            ),
+        )?;
+
+        iteration += 1;
+        if global.iterations.is_some_and(|count| iteration >= count) {
+            return Ok(());
+        }
+
+        // Wait out the delay, redrawing early if the terminal is resized.
+        let deadline = Instant::now() + delay;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || !event::poll(remaining)? {
+                break;
+            }
+            match event::read()? {
+                Event::Key(key) if quits(key) => return Ok(()),
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('f'),
+                    ..
+                }) => {
+                    show_fences = !show_fences;
+                    break;
+                }
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('k'),
+                    ..
+                }) => {
+                    show_log = !show_log;
+                    break;
+                }
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('e'),
+                    ..
+                }) => {
+                    show_events = !show_events;
+                    events_back = 0;
+                    break;
+                }
+                Event::Key(KeyEvent {
+                    code: KeyCode::PageUp,
+                    ..
+                }) if show_events => {
+                    events_back += EVENT_LINES;
+                    break;
+                }
+                Event::Key(KeyEvent {
+                    code: KeyCode::PageDown,
+                    ..
+                }) if show_events => {
+                    events_back = events_back.saturating_sub(EVENT_LINES);
+                    break;
+                }
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('s'),
+                    ..
+                }) => {
+                    if let Feed::Local {
+                        session, coredumps, ..
+                    } = &mut feed
+                    {
+                        for (name, slot) in coredumps.drain(..) {
+                            session.save_coredump(&name, &slot, Path::new("."));
+                        }
+                    }
+                    break;
+                }
+                Event::Key(KeyEvent {
+                    code: code @ (KeyCode::Up | KeyCode::Down),
+                    ..
+                }) if matches!(feed, Feed::Local { .. }) => {
+                    let at = selected.and_then(|pid| pids.iter().position(|known| *known == pid));
+                    selected = match (at, code) {
+                        (Some(at), KeyCode::Up) => pids.get(at.saturating_sub(1)),
+                        (Some(at), _) => pids.get(at + 1).or(pids.last()),
+                        (None, _) => pids.first(),
+                    }
+                    .copied();
+                    break;
+                }
+                Event::Key(KeyEvent {
+                    code: KeyCode::Char('p'),
+                    ..
+                }) => {
+                    if let Some(pid) = selected.filter(|_| matches!(feed, Feed::Local { .. })) {
+                        match options.pin.iter().position(|pinned| *pinned == pid) {
+                            Some(at) => {
+                                options.pin.remove(at);
+                            }
+                            None => options.pin.push(pid),
+                        }
+                    }
+                    break;
+                }
+                Event::Resize(_, _) => break,
+                _ => {}
+            }
+        }
+    }
+}
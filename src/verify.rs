@@ -0,0 +1,236 @@
+//! `amdtop mem --verify`: what `amdgpu_gem_info` and fdinfo each say a
+//! process holds, side by side.
+//!
+//! The two are counted differently by the kernel: gem_info walks every
+//! client's handles, fdinfo sums what each open file has resident. When
+//! they disagree, one of them (or amdtop) attributes memory wrongly, which
+//! is worth knowing before trusting either.
+
+use crate::{
+    cli::{GlobalArgs, OutputFormat},
+    error::{self, Error},
+    gem_info, output,
+    source::{self, ClientScan, Device},
+    sysroot, FormatBytes,
+};
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{self, Write},
+};
+
+#[derive(Default, Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Totals {
+    pub vram_bytes: u64,
+    pub gtt_bytes: u64,
+}
+
+/// One process on one device, by both sources.
+#[derive(Serialize)]
+pub struct Comparison {
+    pub device: Device,
+    pub pid: i32,
+    pub name: Option<String>,
+    pub gem_info: Totals,
+    /// `None` when we couldn't look at the process's descriptors.
+    pub fdinfo: Option<Totals>,
+    pub agrees: bool,
+}
+
+impl Comparison {
+    /// What fdinfo has over gem_info, per domain that differs.
+    fn differences(&self) -> String {
+        let fdinfo = match self.fdinfo {
+            Some(fdinfo) => fdinfo,
+            None => return "fdinfo unreadable".to_string(),
+        };
+        let differences = [
+            ("VRAM", self.gem_info.vram_bytes, fdinfo.vram_bytes),
+            ("GTT", self.gem_info.gtt_bytes, fdinfo.gtt_bytes),
+        ]
+        .iter()
+        .filter(|(_, gem_info, fdinfo)| gem_info != fdinfo)
+        .map(|(domain, gem_info, fdinfo)| {
+            let sign = if fdinfo > gem_info { '+' } else { '-' };
+            format!(
+                "{} {}{}",
+                domain,
+                sign,
+                FormatBytes::new(gem_info.abs_diff(*fdinfo))
+            )
+        })
+        .collect::<Vec<_>>();
+        if differences.is_empty() {
+            "ok".to_string()
+        } else {
+            differences.join(", ")
+        }
+    }
+}
+
+fn process_name(pid: i32) -> Option<String> {
+    std::fs::read_to_string(sysroot::path(format!("/proc/{}/comm", pid)))
+        .ok()
+        .map(|name| name.trim().to_string())
+}
+
+/// Every process either source knows on `device`. A process gem_info
+/// doesn't list holds nothing by it, so only fdinfo can be missing.
+fn compare(device: Device, gem_info: &HashMap<i32, Totals>, scan: &ClientScan) -> Vec<Comparison> {
+    let mut fdinfo = HashMap::<i32, Totals>::new();
+    // Like the fdinfo source, a shared client counts against its lowest pid.
+    for client in scan.clients.iter().filter(|client| client.device == device) {
+        let totals = fdinfo.entry(client.pids[0]).or_default();
+        totals.vram_bytes += client.vram_bytes;
+        totals.gtt_bytes += client.gtt_bytes;
+    }
+    let pids = gem_info
+        .keys()
+        .chain(fdinfo.keys())
+        .copied()
+        .collect::<BTreeSet<_>>();
+    pids.into_iter()
+        .map(|pid| {
+            let gem_info = gem_info.get(&pid).copied().unwrap_or_default();
+            let fdinfo = Some(fdinfo.get(&pid).copied().unwrap_or_default())
+                .filter(|_| scan.inspected.contains(&pid));
+            Comparison {
+                device,
+                pid,
+                name: process_name(pid),
+                gem_info,
+                fdinfo,
+                agrees: fdinfo.is_none_or(|fdinfo| fdinfo == gem_info),
+            }
+        })
+        .collect()
+}
+
+fn read_comparisons(global: &GlobalArgs) -> error::Result<Vec<Comparison>> {
+    let scan = ClientScan::read()?;
+    let mut comparisons = Vec::new();
+    let mut devices = 0;
+    for (device, format, contents) in source::read_device_gem_infos(&global.debugfs_path)? {
+        if !global.selects(device) {
+            continue;
+        }
+        devices += 1;
+        let (mem_infos, _) = gem_info::parse(&contents, format, gem_info::tgid)?;
+        let gem_info = mem_infos
+            .iter()
+            .filter(|mem_info| mem_info.pid > 0)
+            .map(|mem_info| {
+                let totals = Totals {
+                    vram_bytes: mem_info.vram_bytes,
+                    gtt_bytes: mem_info.gtt_bytes,
+                };
+                (mem_info.pid, totals)
+            })
+            .collect();
+        comparisons.extend(compare(device, &gem_info, &scan));
+    }
+    if devices == 0 {
+        return Err(Error::NoDevice(
+            "no amdgpu device matches --gpu".to_string(),
+        ));
+    }
+    Ok(comparisons)
+}
+
+fn write_table<W: Write>(out: &mut W, comparisons: &[Comparison]) -> io::Result<()> {
+    let mut devices = comparisons
+        .iter()
+        .map(|comparison| comparison.device)
+        .collect::<Vec<_>>();
+    devices.dedup();
+    for device in devices {
+        let rows = comparisons
+            .iter()
+            .filter(|comparison| comparison.device == device)
+            .collect::<Vec<_>>();
+        let disagreeing = rows.iter().filter(|row| !row.agrees).count();
+        writeln!(
+            out,
+            "{} | {} processes, {} disagreeing",
+            device,
+            rows.len(),
+            disagreeing
+        )?;
+        writeln!(
+            out,
+            "{: <10} | {: <20} | {: >15} | {: >15} | {: >15} | {: >15} | FDINFO - GEM_INFO",
+            "PID", "PROCESS", "GEM_INFO VRAM", "FDINFO VRAM", "GEM_INFO GTT", "FDINFO GTT"
+        )?;
+        writeln!(out, "{:-^1$}", "", 140)?;
+        let bytes = |bytes: Option<u64>| {
+            bytes.map_or_else(
+                || "-".to_string(),
+                |bytes| FormatBytes::new(bytes).to_string(),
+            )
+        };
+        for row in rows {
+            writeln!(
+                out,
+                "{: <10} | {: <20} | {: >15} | {: >15} | {: >15} | {: >15} | {}",
+                row.pid,
+                row.name.as_deref().unwrap_or("unknown"),
+                bytes(Some(row.gem_info.vram_bytes)),
+                bytes(row.fdinfo.map(|fdinfo| fdinfo.vram_bytes)),
+                bytes(Some(row.gem_info.gtt_bytes)),
+                bytes(row.fdinfo.map(|fdinfo| fdinfo.gtt_bytes)),
+                row.differences()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn run(global: &GlobalArgs) -> error::Result<()> {
+    let stdout = io::stdout();
+
+    crate::refresh_loop(global, |iteration| {
+        let comparisons = read_comparisons(global)?;
+        let mut out = stdout.lock();
+
+        match global.output {
+            OutputFormat::Table => {
+                if iteration > 0 {
+                    writeln!(out)?;
+                }
+                write_table(&mut out, &comparisons)?;
+            }
+            OutputFormat::Json => output::write_json(&mut out, &comparisons)?,
+            format => return Err(output::unsupported(format, "mem --verify")),
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn says_what_fdinfo_has_over_gem_info() {
+        let comparison = |fdinfo| Comparison {
+            device: Device { minor: 0 },
+            pid: 42,
+            name: None,
+            gem_info: Totals {
+                vram_bytes: 8 << 20,
+                gtt_bytes: 2 << 20,
+            },
+            fdinfo,
+            agrees: false,
+        };
+        let fdinfo = Totals {
+            vram_bytes: 4 << 20,
+            gtt_bytes: 3 << 20,
+        };
+        assert_eq!(
+            comparison(Some(fdinfo)).differences(),
+            "VRAM -4.00 MiB, GTT +1.00 MiB"
+        );
+        assert_eq!(comparison(None).differences(), "fdinfo unreadable");
+    }
+}
@@ -0,0 +1,103 @@
+//! `--watch`: the table redrawn in place every refresh, like `watch amdtop`
+//! but without clearing the screen first, so it doesn't flicker.
+
+use crate::{cli::GlobalArgs, sysroot};
+use crossterm::{
+    cursor::MoveTo,
+    queue,
+    terminal::{self, Clear, ClearType},
+};
+use std::{
+    io::{self, Write},
+    time::Duration,
+};
+
+/// The local time as `2026-10-14 09:30:00`.
+pub fn local_time() -> String {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return String::new();
+    }
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
+
+/// The first `width` visible characters of `line`, keeping its color
+/// escapes.
+fn truncate(line: &str, width: usize) -> String {
+    let mut truncated = String::new();
+    let mut visible = 0;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            truncated.push(c);
+            for c in chars.by_ref() {
+                truncated.push(c);
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+            continue;
+        }
+        if visible == width {
+            // Whatever color was on shouldn't last until the end of the line.
+            truncated += "\x1b[0m";
+            break;
+        }
+        truncated.push(c);
+        visible += 1;
+    }
+    truncated
+}
+
+/// Draws `frame`, one refresh's output, over the previous one: from the top
+/// left, clearing what's left of each line and everything below. Lines are
+/// cut to the terminal so none of them wraps and moves the rest down.
+pub fn redraw<W: Write>(out: &mut W, global: &GlobalArgs, frame: &[u8]) -> io::Result<()> {
+    let (width, height) = terminal::size()
+        .map(|(width, height)| (width as usize, height as usize))
+        .unwrap_or((usize::MAX, usize::MAX));
+    let delay = global.delay.unwrap_or(Duration::from_secs(1));
+    queue!(out, MoveTo(0, 0))?;
+    let mut header = format!("Every {:.1}s    ", delay.as_secs_f64());
+    let host = sysroot::host_name();
+    if !host.is_empty() {
+        header += &format!("{}: ", host);
+    }
+    header += &local_time();
+    let frame = String::from_utf8_lossy(frame);
+    let lines = std::iter::once(header.as_str())
+        .chain(std::iter::once(""))
+        .chain(frame.lines())
+        .take(height.saturating_sub(1).max(1));
+    for line in lines {
+        write!(out, "{}", truncate(line, width))?;
+        queue!(out, Clear(ClearType::UntilNewLine))?;
+        writeln!(out)?;
+    }
+    queue!(out, Clear(ClearType::FromCursorDown))?;
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_around_color_escapes() {
+        assert_eq!(truncate("card0 | VRAM", 5), "card0\x1b[0m");
+        assert_eq!(
+            truncate("\x1b[31mhot\x1b[0m and cold", 5),
+            "\x1b[31mhot\x1b[0m a\x1b[0m"
+        );
+        assert_eq!(truncate("short", 80), "short");
+    }
+}
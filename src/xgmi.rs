@@ -0,0 +1,308 @@
+//! `amdtop xgmi`: XGMI hive membership, links and error counters on
+//! multi-GPU Instinct systems.
+//!
+//! Hive membership and errors come from the device's sysfs directory. The
+//! links themselves, with the bandwidth each one is rated for, come from the
+//! KFD topology, which lists them as `io_links` of type 11. amdgpu doesn't
+//! expose live link throughput in sysfs; that needs the XGMI perf PMU.
+
+use crate::{
+    cli::{GlobalArgs, OutputFormat},
+    error, output,
+    sensors::selected_devices,
+    source::{read_sysfs_u64, Device},
+    sysroot,
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    io::{self, Write},
+    path::Path,
+};
+
+/// The KFD `io_links` type for XGMI.
+const IO_LINK_TYPE_XGMI: u64 = 11;
+
+/// An XGMI link from the device to another node of the hive.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Link {
+    /// The KFD topology node at the other end.
+    pub node: u32,
+    /// The device that node is, if it has a render node here.
+    pub peer: Option<Device>,
+    /// Relative cost of the path, higher for more hops.
+    pub weight: Option<u64>,
+    pub min_bandwidth_mbps: Option<u64>,
+    pub max_bandwidth_mbps: Option<u64>,
+}
+
+/// Uncorrectable and correctable errors, from a RAS `*_err_count` file.
+#[derive(Serialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErrorCount {
+    pub uncorrectable: u64,
+    pub correctable: u64,
+}
+
+impl ErrorCount {
+    /// Parses `ue: 0\nce: 2\n`.
+    fn parse(contents: &str) -> Option<Self> {
+        let mut count = ErrorCount::default();
+        let mut found = false;
+        for line in contents.lines() {
+            let (name, value) = match line.split_once(':') {
+                Some(split) => split,
+                None => continue,
+            };
+            let value = value.trim().parse().ok()?;
+            match name.trim() {
+                "ue" => count.uncorrectable = value,
+                "ce" => count.correctable = value,
+                _ => continue,
+            }
+            found = true;
+        }
+        Some(count).filter(|_| found)
+    }
+}
+
+#[derive(Serialize)]
+pub struct DeviceXgmi {
+    pub device: Device,
+    /// Shared by every device in the hive, `None` if the device isn't in one.
+    pub hive_id: Option<u64>,
+    /// The device's position in the hive.
+    pub physical_id: Option<u64>,
+    /// PCS errors since the last read; reading `xgmi_error` resets it.
+    pub link_errors: Option<u64>,
+    /// What RAS has counted on the WAFL links.
+    pub wafl_errors: Option<ErrorCount>,
+    pub links: Vec<Link>,
+}
+
+/// `key value` lines of a KFD topology `properties` file.
+fn read_properties(path: &Path) -> HashMap<String, u64> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some((key.to_string(), value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// KFD topology nodes by number, with their properties.
+fn topology_nodes() -> Vec<(u32, HashMap<String, u64>, std::path::PathBuf)> {
+    let nodes = match std::fs::read_dir(sysroot::path("/sys/class/kfd/kfd/topology/nodes")) {
+        Ok(nodes) => nodes,
+        Err(_) => return Vec::new(),
+    };
+    nodes
+        .flatten()
+        .filter_map(|node| {
+            let number = node.file_name().to_str()?.parse().ok()?;
+            let path = node.path();
+            Some((number, read_properties(&path.join("properties")), path))
+        })
+        .collect()
+}
+
+/// The XGMI links of every device in the KFD topology.
+fn xgmi_links() -> HashMap<Device, Vec<Link>> {
+    let nodes = topology_nodes();
+    let node_device = |properties: &HashMap<String, u64>| {
+        let minor = properties.get("drm_render_minor")?;
+        Device::from_render_minor(u32::try_from(*minor).ok()?)
+    };
+    let devices = nodes
+        .iter()
+        .filter_map(|(number, properties, _)| Some((*number, node_device(properties)?)))
+        .collect::<HashMap<_, _>>();
+
+    let mut links = HashMap::new();
+    for (number, _, path) in &nodes {
+        let device = match devices.get(number) {
+            Some(device) => *device,
+            None => continue,
+        };
+        let io_links = match std::fs::read_dir(path.join("io_links")) {
+            Ok(io_links) => io_links,
+            Err(_) => continue,
+        };
+        let mut device_links = io_links
+            .flatten()
+            .map(|link| read_properties(&link.path().join("properties")))
+            .filter(|properties| properties.get("type") == Some(&IO_LINK_TYPE_XGMI))
+            .filter_map(|properties| {
+                let node = u32::try_from(*properties.get("node_to")?).ok()?;
+                Some(Link {
+                    node,
+                    peer: devices.get(&node).copied(),
+                    weight: properties.get("weight").copied(),
+                    min_bandwidth_mbps: properties.get("min_bandwidth").copied(),
+                    max_bandwidth_mbps: properties.get("max_bandwidth").copied(),
+                })
+            })
+            .collect::<Vec<_>>();
+        device_links.sort_by_key(|link| link.node);
+        links.insert(device, device_links);
+    }
+    links
+}
+
+impl DeviceXgmi {
+    pub fn read(device: Device, links: &mut HashMap<Device, Vec<Link>>) -> Self {
+        let device_dir = device.sysfs_dir();
+        Self {
+            device,
+            hive_id: read_sysfs_u64(&device_dir.join("xgmi_hive_info/xgmi_hive_id")),
+            physical_id: read_sysfs_u64(&device_dir.join("xgmi_physical_id")),
+            link_errors: read_sysfs_u64(&device_dir.join("xgmi_error")),
+            wafl_errors: std::fs::read_to_string(device_dir.join("ras/xgmi_wafl_err_count"))
+                .ok()
+                .and_then(|contents| ErrorCount::parse(&contents)),
+            links: links.remove(&device).unwrap_or_default(),
+        }
+    }
+
+    /// `(counter, value)` for everything that was read, with links keyed by
+    /// peer, e.g. `link_card1_max_bandwidth`.
+    fn csv_rows(&self) -> Vec<(String, u64)> {
+        let mut rows = Vec::new();
+        let mut single = |counter: &str, value: Option<u64>| {
+            if let Some(value) = value {
+                rows.push((counter.to_string(), value));
+            }
+        };
+        single("hive_id", self.hive_id);
+        single("physical_id", self.physical_id);
+        single("link_errors", self.link_errors);
+        single(
+            "wafl_uncorrectable",
+            self.wafl_errors.map(|wafl| wafl.uncorrectable),
+        );
+        single(
+            "wafl_correctable",
+            self.wafl_errors.map(|wafl| wafl.correctable),
+        );
+        for link in &self.links {
+            let peer = match link.peer {
+                Some(peer) => peer.to_string(),
+                None => format!("node{}", link.node),
+            };
+            let values = [
+                ("weight", link.weight),
+                ("min_bandwidth", link.min_bandwidth_mbps),
+                ("max_bandwidth", link.max_bandwidth_mbps),
+            ];
+            for (name, value) in values {
+                if let Some(value) = value {
+                    rows.push((format!("link_{}_{}", peer, name), value));
+                }
+            }
+        }
+        rows
+    }
+}
+
+fn write_table<W: Write>(out: &mut W, xgmi: &DeviceXgmi) -> io::Result<()> {
+    let hive_id = match xgmi.hive_id {
+        Some(hive_id) => hive_id,
+        None => return writeln!(out, "{} | not in an XGMI hive", xgmi.device),
+    };
+    let mut header = format!("{} | hive {:#x}", xgmi.device, hive_id);
+    if let Some(physical_id) = xgmi.physical_id {
+        header += &format!(" | node {}", physical_id);
+    }
+    if let Some(errors) = xgmi.link_errors {
+        header += &format!(" | errors {}", errors);
+    }
+    if let Some(wafl) = xgmi.wafl_errors {
+        header += &format!(" | WAFL ue {} ce {}", wafl.uncorrectable, wafl.correctable);
+    }
+    writeln!(out, "{}", header)?;
+    if xgmi.links.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(out, "{: <10} | {: >6} | BANDWIDTH", "PEER", "WEIGHT")?;
+    writeln!(out, "{:-^1$}", "", 40)?;
+    for link in &xgmi.links {
+        let peer = match link.peer {
+            Some(peer) => peer.to_string(),
+            None => format!("node {}", link.node),
+        };
+        let weight = link.weight.map(|weight| weight.to_string());
+        let bandwidth = match (link.min_bandwidth_mbps, link.max_bandwidth_mbps) {
+            (Some(min), Some(max)) if min != max => format!("{} – {} MB/s", min, max),
+            (_, Some(max)) => format!("{} MB/s", max),
+            _ => String::new(),
+        };
+        writeln!(
+            out,
+            "{: <10} | {: >6} | {}",
+            peer,
+            weight.as_deref().unwrap_or("-"),
+            bandwidth
+        )?;
+    }
+    Ok(())
+}
+
+pub fn run(global: &GlobalArgs) -> error::Result<()> {
+    let devices = selected_devices(global)?;
+    let stdout = io::stdout();
+
+    crate::refresh_loop(global, |iteration| {
+        let mut links = xgmi_links();
+        let all_xgmi = devices
+            .iter()
+            .map(|device| DeviceXgmi::read(*device, &mut links))
+            .collect::<Vec<_>>();
+        let mut out = stdout.lock();
+
+        match global.output {
+            OutputFormat::Table => {
+                if iteration > 0 {
+                    writeln!(out)?;
+                }
+                for xgmi in &all_xgmi {
+                    write_table(&mut out, xgmi)?;
+                }
+            }
+            OutputFormat::Json => output::write_json(&mut out, &all_xgmi)?,
+            OutputFormat::Csv => {
+                if iteration == 0 {
+                    output::write_csv_row(&mut out, &["device", "counter", "value"])?;
+                }
+                for xgmi in &all_xgmi {
+                    let device = xgmi.device.to_string();
+                    for (counter, value) in xgmi.csv_rows() {
+                        output::write_csv_row(&mut out, &[&device, &counter, &value.to_string()])?;
+                    }
+                }
+            }
+            format => return Err(output::unsupported(format, "xgmi")),
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ras_error_counts() {
+        assert_eq!(
+            ErrorCount::parse("ue: 0\nce: 2\n"),
+            Some(ErrorCount {
+                uncorrectable: 0,
+                correctable: 2
+            })
+        );
+        assert_eq!(ErrorCount::parse(""), None);
+        assert_eq!(ErrorCount::parse("ue: lots\n"), None);
+    }
+}
@@ -0,0 +1,165 @@
+//! `amdtop zabbix`: low-level discovery and item values for a Zabbix agent,
+//! so GPUs and the processes on them can be templated without scripts in
+//! between. In `zabbix_agentd.conf`:
+//!
+//! ```text
+//! UserParameter=amdtop.devices,amdtop zabbix devices
+//! UserParameter=amdtop.processes,amdtop zabbix processes
+//! UserParameter=amdtop.device[*],amdtop zabbix get "$1" "$2"
+//! UserParameter=amdtop.process[*],amdtop zabbix get "$1" "$2" "$3"
+//! ```
+//!
+//! Discovery rules use the first two, with `{#GPU}`, `{#PCI}`, `{#PID}` and
+//! `{#NAME}` in their prototypes; items like `amdtop.device[{#GPU},vram_used_bytes]`
+//! and `amdtop.process[{#GPU},vram_bytes,{#PID}]` use the others.
+
+use crate::{
+    cli::{GlobalArgs, MemArgs, ZabbixArgs, ZabbixQuery},
+    error::{self, Error},
+    mem::{self, DeviceView, Session},
+    output,
+    sensors::{selected_devices, Sensors},
+};
+use serde_json::{json, Value};
+use std::io;
+
+/// Wraps discovered entities the way every Zabbix version accepts.
+fn discovery(entities: Vec<Value>) -> Value {
+    json!({ "data": entities })
+}
+
+fn device_discovery(global: &GlobalArgs) -> error::Result<Value> {
+    Ok(discovery(
+        selected_devices(global)?
+            .into_iter()
+            .map(|device| {
+                json!({
+                    "{#GPU}": device.to_string(),
+                    "{#PCI}": device.pci_slot().unwrap_or_default(),
+                })
+            })
+            .collect(),
+    ))
+}
+
+fn sample(global: &GlobalArgs) -> error::Result<Vec<DeviceView>> {
+    let mut sources = mem::select_sources(global, &MemArgs::default())?;
+    mem::refresh(
+        global,
+        &MemArgs::default(),
+        &mut sources,
+        &mut Session::default(),
+    )
+}
+
+fn process_discovery(global: &GlobalArgs) -> error::Result<Value> {
+    Ok(discovery(
+        sample(global)?
+            .iter()
+            .flat_map(|view| {
+                view.processes.iter().flatten().map(move |process| {
+                    json!({
+                        "{#GPU}": view.device.to_string(),
+                        "{#PID}": process.pid,
+                        "{#NAME}": process.name.as_deref().unwrap_or("unknown"),
+                    })
+                })
+            })
+            .collect(),
+    ))
+}
+
+/// The values `get` knows for a device, other than sensor readings.
+const DEVICE_ITEMS: &[&str] = &[
+    "vram_used_bytes",
+    "vram_total_bytes",
+    "vram_used_percent",
+    "gtt_used_bytes",
+    "unattributed_vram_bytes",
+    "processes",
+];
+
+/// The values `get` knows for a process.
+const PROCESS_ITEMS: &[&str] = &["vram_bytes", "gtt_bytes", "name"];
+
+fn device_item(view: &DeviceView, item: &str) -> Option<Value> {
+    let usage = view.usage;
+    let value = match item {
+        "vram_used_bytes" => json!(usage?.vram_used_bytes),
+        "vram_total_bytes" => json!(usage?.vram_total_bytes),
+        "vram_used_percent" => {
+            let usage = usage.filter(|usage| usage.vram_total_bytes > 0)?;
+            json!(usage.vram_used_bytes as f64 * 100.0 / usage.vram_total_bytes as f64)
+        }
+        "gtt_used_bytes" => json!(usage?.gtt_used_bytes?),
+        "unattributed_vram_bytes" => json!(view.unattributed.map_or(0, |usage| usage.vram_bytes)),
+        "processes" => json!(view.processes.as_ref()?.len()),
+        _ => {
+            let reading = Sensors::read(view.device)
+                .readings()
+                .into_iter()
+                .find(|reading| reading.key == item)?;
+            json!(reading.value)
+        }
+    };
+    Some(value)
+}
+
+fn process_item(view: &DeviceView, item: &str, pid: i32) -> error::Result<Value> {
+    let process = view
+        .processes
+        .iter()
+        .flatten()
+        .find(|process| process.pid == pid)
+        .ok_or_else(|| {
+            Error::NoDevice(format!("process {} has no memory on {}", pid, view.device))
+        })?;
+    match item {
+        "vram_bytes" => Ok(json!(process.vram_bytes)),
+        "gtt_bytes" => Ok(json!(process.gtt_bytes)),
+        "name" => Ok(json!(process.name.as_deref().unwrap_or("unknown"))),
+        _ => Err(Error::InvalidArgument(format!(
+            "no process item {}; try one of {}",
+            item,
+            PROCESS_ITEMS.join(", ")
+        ))),
+    }
+}
+
+/// A value as the agent passes it on: bare, not as JSON.
+fn item_text(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+pub fn run(global: &GlobalArgs, options: &ZabbixArgs) -> error::Result<()> {
+    let stdout = io::stdout();
+    match &options.query {
+        ZabbixQuery::Devices => output::write_json(&mut stdout.lock(), &device_discovery(global)?)?,
+        ZabbixQuery::Processes => {
+            output::write_json(&mut stdout.lock(), &process_discovery(global)?)?
+        }
+        ZabbixQuery::Get { gpu, item, pid } => {
+            let views = sample(global)?;
+            let view = views
+                .iter()
+                .find(|view| gpu.matches(view.device))
+                .ok_or_else(|| Error::NoDevice(format!("no amdgpu device {}", gpu)))?;
+            let value = match pid {
+                Some(pid) => process_item(view, item, *pid)?,
+                None => device_item(view, item).ok_or_else(|| {
+                    Error::InvalidArgument(format!(
+                        "{} has no item {}; try one of {} or a sensor from amdtop sensors --output csv",
+                        view.device,
+                        item,
+                        DEVICE_ITEMS.join(", ")
+                    ))
+                })?,
+            };
+            println!("{}", item_text(&value));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,2939 @@
+use std::{path::Path, process::Command};
+
+fn run(fixture: &str, args: &[&str]) -> std::process::Output {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(fixture);
+    Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(root)
+        .args(args)
+        .output()
+        .expect("failed to run amdtop")
+}
+
+/// A copy of `fixture` that a test may write to, since `amdtop set` changes
+/// files in it.
+fn scratch_fixture(fixture: &str, name: &str) -> std::path::PathBuf {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(fixture);
+    let scratch = Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    let _ = std::fs::remove_dir_all(&scratch);
+    let status = Command::new("cp")
+        .arg("-a")
+        .arg(source)
+        .arg(&scratch)
+        .status()
+        .expect("failed to run cp");
+    assert!(status.success());
+    scratch
+}
+
+fn amdtop(fixture: &str, args: &[&str]) -> String {
+    let output = run(fixture, args);
+    assert!(
+        output.status.success(),
+        "amdtop failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// The cells of the first table row whose first cell is `first`.
+fn row(output: &str, first: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(|line| {
+            line.split('|')
+                .map(|cell| cell.trim().to_string())
+                .collect::<Vec<_>>()
+        })
+        .find(|cells| cells.len() > 1 && cells[0] == first)
+        .unwrap_or_else(|| panic!("no row for {} in:\n{}", first, output))
+}
+
+#[test]
+fn navi21_merges_threads_and_shared_buffers() {
+    let output = amdtop("navi21-linux-6.6", &[]);
+
+    assert!(output.starts_with("card0 | VRAM 860.00 MiB / 15.98 GiB | perf auto\n"));
+
+    let blender = row(&output, "3301");
+    assert_eq!(blender[1], "blender");
+    assert_eq!(blender[2], "/opt/blender/blender");
+    assert_eq!(&blender[3..6], ["832.00 MiB", "768.00 MiB", "64.00 MiB"]);
+    assert!(!output.contains("3305"));
+
+    let gnome_shell = row(&output, "2210");
+    assert_eq!(&gnome_shell[3..6], ["28.00 MiB", "24.00 MiB", "4.00 MiB"]);
+
+    let xorg = row(&output, "1523");
+    assert_eq!(&xorg[3..6], ["50.00 MiB", "48.00 MiB", "2.00 MiB"]);
+}
+
+#[test]
+fn navi21_reconciles_with_device_usage() {
+    let output = amdtop("navi21-linux-6.6", &[]);
+    let kernel = row(&output, "-");
+    assert_eq!(kernel[1], "kernel/unattributed");
+    assert_eq!(&kernel[2..5], ["20.00 MiB", "20.00 MiB", "0"]);
+
+    let output = amdtop("navi21-linux-6.6", &["--no-kernel-row"]);
+    assert!(!output.contains("kernel/unattributed"));
+}
+
+#[test]
+fn aligns_the_rest_and_kernel_rows_with_the_extra_columns() {
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["--top", "1", "--show-age", "--show-cpu", "--show-unit"],
+    );
+    let separators = |prefix: &str| {
+        let line = output
+            .lines()
+            .find(|line| line.starts_with(prefix))
+            .unwrap();
+        line.chars()
+            .enumerate()
+            .filter(|(_, c)| *c == '|')
+            .map(|(at, _)| at)
+            .collect::<Vec<_>>()
+    };
+    let mut header = separators("PID ");
+    // Their name spans PROCESS and PATH.
+    header.remove(1);
+    assert_eq!(separators("           | … and 2 more"), header);
+    assert_eq!(separators("-          | kernel/unattributed"), header);
+}
+
+#[test]
+fn vega10_legacy_format() {
+    let output = amdtop("vega10-linux-5.4", &["--no-kernel-row"]);
+    assert_eq!(&row(&output, "1400")[3..6], ["64.00 MiB", "64.00 MiB", "0"]);
+    assert_eq!(
+        &row(&output, "1001")[3..6],
+        ["5.00 MiB", "4.00 MiB", "1.00 MiB"]
+    );
+}
+
+#[test]
+fn polaris10_pinned_offsets() {
+    let output = amdtop("polaris10-linux-4.15", &["--diagnostics"]);
+    assert_eq!(
+        &row(&output, "900")[3..6],
+        ["8.16 MiB", "7.91 MiB", "256.00 KiB"]
+    );
+}
+
+#[test]
+fn top_aggregates_the_rest() {
+    let output = amdtop("navi21-linux-6.6", &["--top", "1", "--no-kernel-row"]);
+    row(&output, "3301");
+    assert!(output.contains("… and 2 more using 78.00 MiB"));
+    assert!(!output.contains("gnome-shell"));
+}
+
+#[test]
+fn rejects_mem_options_before_a_command() {
+    let output = amdtop("navi21-linux-6.6", &["mem", "--top", "1"]);
+    assert!(output.contains("… and 2 more"));
+
+    let output = run("navi21-linux-6.6", &["--top", "1", "mem"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--top goes after mem"));
+
+    let output = run("navi21-linux-6.6", &["--top", "1", "sensors"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not of sensors"));
+}
+
+#[test]
+fn sorts_by_several_keys() {
+    let pids = |output: &str| {
+        output
+            .lines()
+            .filter_map(|line| line.split('|').next()?.trim().parse::<i32>().ok())
+            .collect::<Vec<_>>()
+    };
+    let output = amdtop("navi21-linux-6.6", &["--sort", "name,pid"]);
+    // Xorg among the lowercase names, not before them.
+    assert_eq!(pids(&output), [3301, 2210, 1523]);
+    let output = amdtop("navi21-linux-6.6", &["--sort", "pid", "--top", "1"]);
+    assert_eq!(pids(&output), [1523]);
+    assert!(output.contains("… and 2 more using 860.00 MiB"));
+}
+
+#[test]
+fn pinned_processes_stay_on_top() {
+    let output = amdtop("navi21-linux-6.6", &["--pin", "2210", "--top", "1"]);
+    let pids = output
+        .lines()
+        .filter_map(|line| line.split('|').next()?.trim().parse::<i32>().ok())
+        .collect::<Vec<_>>();
+    assert_eq!(pids, [2210, 3301]);
+    assert!(output.contains("… and 1 more using 50.00 MiB"));
+}
+
+#[test]
+fn plain_labels_every_value() {
+    let output = amdtop("navi21-linux-6.6", &["--plain", "--top", "1"]);
+    assert!(output.contains("device: card0\nVRAM used: 860.00 MiB\n"));
+    assert!(output.contains("process: 3301\n  name: blender\n"));
+    assert!(output.contains("other processes: 2\n  total: 78.00 MiB\n"));
+    assert!(!output.contains('|'));
+    assert!(!output.contains("---"));
+
+    let output = run("navi21-linux-6.6", &["--plain", "--meters"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn changes_only_leaves_out_what_stayed() {
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["--changes-only", "-d", "0.1", "-n", "3", "--output", "csv"],
+    );
+    let rows = output.lines().skip(1).collect::<Vec<_>>();
+    // The first refresh has nothing to compare with.
+    assert_eq!(rows.len(), 4);
+    assert!(rows.iter().all(|row| row.contains(",0,card0,")));
+}
+
+#[test]
+fn changes_only_says_when_a_process_exits() {
+    use std::io::{BufRead, BufReader};
+
+    let root = scratch_fixture("navi21-linux-6.6", "changes-exit");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .args([
+            "--changes-only",
+            "-d",
+            "0.5",
+            "-n",
+            "2",
+            "--output",
+            "ndjson",
+        ])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .expect("failed to run amdtop");
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+
+    // gnome-shell goes away before the second refresh.
+    let gem_info = root.join("sys/kernel/debug/dri/0/amdgpu_gem_info");
+    let contents = std::fs::read_to_string(&gem_info).unwrap();
+    let (before, after) = contents.split_once("pid     2210").unwrap();
+    let after = &after[after.find("pid     3305").unwrap()..];
+    std::fs::write(&gem_info, format!("{}{}", before, after)).unwrap();
+    std::fs::remove_dir_all(root.join("proc/2210")).unwrap();
+
+    line.clear();
+    stdout.read_line(&mut line).unwrap();
+    assert!(child.wait().unwrap().success());
+    let snapshot: serde_json::Value = serde_json::from_str(&line).unwrap();
+    let processes = snapshot["devices"][0]["processes"].as_array().unwrap();
+    assert_eq!(processes.len(), 1, "{}", line);
+    assert_eq!(processes[0]["pid"], 2210);
+    assert_eq!(processes[0]["name"], "gnome-shell");
+    assert_eq!(processes[0]["exited"], true);
+    assert_eq!(processes[0]["vram_bytes"], 0);
+}
+
+#[test]
+fn adaptive_waits_longer_while_nothing_changes() {
+    let output = run(
+        "navi21-linux-6.6",
+        &["-d", "0.05", "-n", "4", "--adaptive=1", "--diagnostics"],
+    );
+    assert!(output.status.success());
+    let waits = String::from_utf8(output.stderr)
+        .unwrap()
+        .lines()
+        .filter_map(|line| line.strip_prefix("amdtop: --adaptive: refreshing every "))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    assert_eq!(waits, ["0.1s", "0.2s", "0.4s"]);
+
+    let output = run("navi21-linux-6.6", &["sensors", "-d", "1", "--adaptive"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn min_size_hides_small_clients() {
+    let output = amdtop("navi21-linux-6.6", &["--min-size", "40MiB"]);
+    row(&output, "3301");
+    row(&output, "1523");
+    assert!(!output.contains("gnome-shell"));
+}
+
+#[test]
+fn filter_regex_matches_cmdline() {
+    let output = amdtop("navi21-linux-6.6", &["--filter-regex", "factory-startup"]);
+    row(&output, "3301");
+    assert!(!output.contains("Xorg"));
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["--filter-regex", "factory-startup", "--invert-filter"],
+    );
+    row(&output, "1523");
+    assert!(!output.contains("blender"));
+}
+
+#[test]
+fn fdinfo_source_sums_clients() {
+    let output = amdtop("navi21-linux-6.6", &["--source", "fdinfo"]);
+
+    assert!(output.starts_with("card0 | VRAM 860.00 MiB / 15.98 GiB | perf auto\n"));
+    assert_eq!(
+        &row(&output, "3301")[3..6],
+        ["832.00 MiB", "768.00 MiB", "64.00 MiB"]
+    );
+    assert_eq!(
+        &row(&output, "2210")[3..6],
+        ["28.00 MiB", "24.00 MiB", "4.00 MiB"]
+    );
+    assert_eq!(
+        &row(&output, "1523")[3..6],
+        ["50.00 MiB", "48.00 MiB", "2.00 MiB"]
+    );
+}
+
+#[test]
+fn falls_back_to_fdinfo_without_debugfs() {
+    let output = run("navi21-linux-6.6", &["--debugfs-path", "/nonexistent"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("debugfs:"));
+    row(&String::from_utf8_lossy(&output.stdout), "3301");
+}
+
+#[test]
+fn sysfs_source_shows_device_totals_only() {
+    let output = amdtop("navi21-linux-6.6", &["--source", "sysfs"]);
+    assert_eq!(output, "card0 | VRAM 860.00 MiB / 15.98 GiB | perf auto\n");
+}
+
+#[test]
+fn exit_status_says_what_went_wrong() {
+    let output = run(
+        "navi21-linux-6.6",
+        &["--source", "debugfs", "--debugfs-path", "/nonexistent"],
+    );
+    assert_eq!(output.status.code(), Some(3));
+
+    let output = run("navi21-linux-6.6", &["--bogus"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn missing_debugfs_is_explained() {
+    let output = run("does-not-exist", &[]);
+    assert_eq!(output.status.code(), Some(6));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("debugfs is not mounted"));
+}
+
+#[test]
+fn options_fall_back_to_environment() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/navi21-linux-6.6");
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .env("AMDTOP_ROOT", root)
+        .env("AMDTOP_TOP", "1")
+        .env("AMDTOP_NO_KERNEL_ROW", "true")
+        .output()
+        .expect("failed to run amdtop");
+    let output = String::from_utf8(output.stdout).unwrap();
+
+    row(&output, "3301");
+    assert!(output.contains("… and 2 more"));
+    assert!(!output.contains("kernel/unattributed"));
+}
+
+#[test]
+fn prints_completions() {
+    let output = amdtop("navi21-linux-6.6", &["completions", "bash"]);
+    assert!(output.contains("--filter-regex"));
+    assert!(output.contains("complete -F"));
+}
+
+#[test]
+fn sensors_reads_hwmon() {
+    let output = amdtop("navi21-linux-6.6", &["sensors"]);
+    assert!(output.starts_with("card0 | profile BOOTUP_DEFAULT | fan auto | gfx on\n"));
+    assert!(output.contains("Shader clock           1500 MHz\n"));
+    assert!(output.contains("Junction temperature   52 °C (critical 110 °C)\n"));
+    assert!(output.contains("GFX voltage            925 mV\n"));
+    assert!(!output.contains("SoC voltage"));
+    assert!(output.contains("Power                  35 W\n"));
+    assert!(output.contains("Power cap              255 W\n"));
+
+    let output = amdtop("navi21-linux-6.6", &["sensors", "--output", "csv"]);
+    assert!(output.contains("card0,gpu_busy,12,%\n"));
+    assert!(output.contains("card0,memory_temperature_critical,105,°C\n"));
+}
+
+#[test]
+fn doctor_explains_what_is_missing() {
+    let root = scratch_fixture("navi21-linux-6.6", "doctor");
+    std::fs::create_dir_all(root.join("proc/sys/kernel")).unwrap();
+    std::fs::write(
+        root.join("proc/sys/kernel/osrelease"),
+        "5.15.0-91-generic\n",
+    )
+    .unwrap();
+    let output = run(root.to_str().unwrap(), &["doctor"]);
+    assert!(output.status.success());
+    let output = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(row(&output, "note")[1], "kernel");
+    assert!(output.contains("--show-busy needs a newer kernel"));
+    assert_eq!(row(&output, "ok")[1..], ["devices", "card0 (0000:03:00.0)"]);
+    assert!(output.contains("ok   | debugfs      | works, and is used"));
+
+    let output = amdtop("vega10-linux-5.4", &["doctor"]);
+    assert!(output.contains("warn | hwmon card0  | no hwmon directory"));
+}
+
+#[test]
+fn output_follows_the_schema() {
+    let schema: serde_json::Value =
+        serde_json::from_str(&amdtop("navi21-linux-6.6", &["schema"])).unwrap();
+    let defs = &schema["$defs"];
+    let undescribed = |value: &serde_json::Value, def: &str| {
+        let properties = defs[def]["properties"].as_object().unwrap();
+        value
+            .as_object()
+            .unwrap()
+            .keys()
+            .filter(|key| !properties.contains_key(*key))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let views: serde_json::Value =
+        serde_json::from_str(&amdtop("navi21-linux-6.6", &["--output", "json"])).unwrap();
+    for view in views.as_array().unwrap() {
+        assert_eq!(view["schema_version"], defs["schema_version"]["const"]);
+        assert_eq!(undescribed(view, "device"), Vec::<String>::new());
+        for process in view["processes"].as_array().unwrap() {
+            assert_eq!(undescribed(process, "process"), Vec::<String>::new());
+        }
+    }
+
+    let lines = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "ndjson", "--record", "process"],
+    );
+    let line: serde_json::Value = serde_json::from_str(lines.lines().next().unwrap()).unwrap();
+    assert_eq!(line["schema_version"], 2);
+    assert_eq!(line["device"], "card0");
+}
+
+#[test]
+fn version_describes_the_environment() {
+    let output = amdtop("navi21-linux-6.6", &["--version"]);
+    assert_eq!(
+        output.lines().collect::<Vec<_>>(),
+        [
+            concat!("amdtop ", env!("CARGO_PKG_VERSION")),
+            "kernel: unknown",
+            "driver: amdgpu loaded, card0 (0000:03:00.0)",
+            "sources: debugfs, fdinfo, sysfs (kfd, ioctl unavailable)",
+        ]
+    );
+}
+
+#[test]
+fn strict_fails_on_unparsed_lines() {
+    let root = scratch_fixture("navi21-linux-6.6", "strict");
+    let gem_info = root.join("sys/kernel/debug/dri/0/amdgpu_gem_info");
+    let mut contents = std::fs::read_to_string(&gem_info).unwrap();
+    contents += "something new\n";
+    std::fs::write(&gem_info, &contents).unwrap();
+    let root = root.to_str().unwrap();
+
+    assert!(run(root, &[]).status.success());
+    let output = run(root, &["--strict"]);
+    assert_eq!(output.status.code(), Some(5));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let report: serde_json::Value = serde_json::from_str(stderr.lines().next().unwrap()).unwrap();
+    let unparsed = &report["unparsed"][0];
+    assert_eq!(unparsed["device"], "card0");
+    assert_eq!(unparsed["unparsed_lines"], 1);
+    assert_eq!(
+        unparsed["lines"][0],
+        serde_json::json!({ "line": contents.lines().count(), "text": "something new" })
+    );
+    assert!(unparsed["file"]
+        .as_str()
+        .unwrap()
+        .ends_with("dri/0/amdgpu_gem_info"));
+}
+
+#[test]
+fn fw_lists_versions() {
+    let output = amdtop("navi21-linux-6.6", &["fw"]);
+    assert!(output.starts_with("card0 | VBIOS 113-D4120100-100\n"));
+    assert_eq!(row(&output, "smc")[1], "0x003a5800");
+}
+
+#[test]
+fn check_fails_over_limits() {
+    amdtop("navi21-linux-6.6", &["check"]);
+
+    let output = run("navi21-linux-6.6", &["check", "--max-temperature", "50"]);
+    assert_eq!(output.status.code(), Some(7));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("temperature 52.0 °C"));
+}
+
+#[test]
+fn check_works_as_a_nagios_plugin() {
+    let output = run("navi21-linux-6.6", &["check", "--output", "nagios"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "AMDTOP OK - card0 vram 5.3 % | card0_vram=5.3%;;90;0;100\n"
+    );
+
+    let output = run(
+        "navi21-linux-6.6",
+        &[
+            "check",
+            "--output",
+            "nagios",
+            "--warn-temperature",
+            "50",
+            "--max-temperature",
+            "60",
+        ],
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8(output.stdout).unwrap().starts_with(
+        "AMDTOP WARNING - card0 temperature 52.0 °C over 50 °C | \
+         card0_vram=5.3%;;90;0;100 card0_temperature=52.0;50;60\n"
+    ));
+
+    let output = run(
+        "navi21-linux-6.6",
+        &["check", "--output", "nagios", "--max-vram", "5"],
+    );
+    assert_eq!(output.status.code(), Some(2));
+
+    let output = run("navi21-linux-6.6", &["fw", "--output", "nagios"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("fw can't write --output nagios"));
+}
+
+#[test]
+fn zabbix_discovers_and_gets_items() {
+    let devices: serde_json::Value =
+        serde_json::from_str(&amdtop("navi21-linux-6.6", &["zabbix", "devices"])).unwrap();
+    assert_eq!(
+        devices,
+        serde_json::json!({ "data": [{ "{#GPU}": "card0", "{#PCI}": "0000:03:00.0" }] })
+    );
+
+    let processes: serde_json::Value =
+        serde_json::from_str(&amdtop("navi21-linux-6.6", &["zabbix", "processes"])).unwrap();
+    assert!(processes["data"]
+        .as_array()
+        .unwrap()
+        .contains(&serde_json::json!({ "{#GPU}": "card0", "{#PID}": 3301, "{#NAME}": "blender" })));
+
+    let get = |args: &[&str]| amdtop("navi21-linux-6.6", &[&["zabbix", "get"], args].concat());
+    assert_eq!(get(&["card0", "vram_used_bytes"]), "901775360\n");
+    assert_eq!(get(&["0000:03:00.0", "edge_temperature"]), "45.0\n");
+    assert_eq!(get(&["card0", "vram_bytes", "3301"]), "805306368\n");
+    assert_eq!(get(&["card0", "name", "3301"]), "blender\n");
+
+    let output = run("navi21-linux-6.6", &["zabbix", "get", "card0", "bogus"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn meters_head_each_device() {
+    let output = amdtop("navi21-linux-6.6", &["--meters"]);
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(
+        lines[0],
+        "VRAM [||                        5.3%]   GTT  [                          0.5%]"
+    );
+    assert_eq!(
+        lines[1],
+        "BUSY [||||                       12%]   TEMP [||||||||||||||        52/110°C]"
+    );
+    assert!(lines[2].starts_with("card0 | VRAM "));
+}
+
+#[test]
+fn oneline_fits_a_status_bar() {
+    assert_eq!(
+        amdtop("navi21-linux-6.6", &["--oneline"]),
+        "card0 vram 0.8/16GiB gtt 70MiB busy 12% 52°C 35W top:blender 768MiB\n"
+    );
+    assert_eq!(
+        amdtop("vega10-linux-5.4", &["--oneline"]),
+        "card0 vram 0.1/8GiB gtt 1MiB top:firefox 64MiB\n"
+    );
+}
+
+#[test]
+fn speaks_waybars_module_json() {
+    let output = amdtop("navi21-linux-6.6", &["--output", "waybar"]);
+    assert_eq!(output.lines().count(), 1);
+    let module: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(
+        module["text"],
+        "card0 vram 0.8/16GiB gtt 70MiB busy 12% 52°C 35W top:blender 768MiB"
+    );
+    assert_eq!(module["class"], "normal");
+    assert_eq!(module["percentage"], 5);
+    let tooltip = module["tooltip"].as_str().unwrap();
+    assert!(tooltip.starts_with("card0\nblender (3301)  VRAM 768.00 MiB  GTT 64.00 MiB\n"));
+}
+
+#[test]
+fn watch_redraws_in_place() {
+    let output = amdtop("navi21-linux-6.6", &["--watch", "-n", "2", "-d", "0.1"]);
+    let frames = output.split("\x1b[1;1H").collect::<Vec<_>>();
+    assert_eq!(frames.len(), 3);
+    assert!(frames[0].is_empty());
+    for frame in &frames[1..] {
+        assert!(frame.starts_with("Every 0.1s    "));
+        assert!(frame.contains("\ncard0 | VRAM 860.00 MiB / 15.98 GiB | perf auto\x1b[K\n"));
+        assert!(frame.ends_with("\x1b[J") || frame.contains("\x1b[J\n"));
+    }
+
+    let output = run("navi21-linux-6.6", &["fw", "--watch"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn streams_ndjson() {
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "ndjson", "-n", "2", "-d", "0.1"],
+    );
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    for (sequence, line) in lines.into_iter().enumerate() {
+        let snapshot: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert!(snapshot["time"].is_string());
+        assert_eq!(snapshot["sequence"], sequence);
+        assert_eq!(snapshot["devices"][0]["processes"][0]["name"], "blender");
+    }
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "ndjson", "--record", "process"],
+    );
+    let processes = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(processes.len(), 3);
+    assert_eq!(processes[0]["device"], "card0");
+    assert_eq!(processes[0]["pid"], 3301);
+    assert_eq!(processes[0]["vram_bytes"], 805306368u64);
+}
+
+#[test]
+fn replays_part_of_a_recording() {
+    let recorded = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "ndjson", "-n", "3", "-d", "0.1"],
+    );
+    // A minute apart, from 2026-10-14T07:30:00Z.
+    let recording = recorded
+        .lines()
+        .enumerate()
+        .map(|(index, line)| {
+            let mut snapshot: serde_json::Value = serde_json::from_str(line).unwrap();
+            snapshot["time"] = format!("2026-10-14T07:{}:00.000+00:00", 30 + index).into();
+            format!("{}\n", snapshot)
+        })
+        .collect::<String>();
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("replay.ndjson");
+    std::fs::write(&path, &recording).unwrap();
+    let path = path.to_str().unwrap();
+    let first = recording.lines().next().unwrap();
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &[
+            "replay",
+            path,
+            "--from",
+            "2026-10-14T07:30:30Z",
+            "--to",
+            "1791963120",
+        ],
+    );
+    let stamps = output
+        .lines()
+        .filter(|line| line.starts_with("# "))
+        .map(|line| line.rsplit(" | ").next().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(stamps, ["sample 1", "sample 2"]);
+    assert!(output.contains("card0 | VRAM "));
+    assert!(output
+        .lines()
+        .any(|line| line.starts_with("3301 ") && line.contains("/opt/blender/blender")));
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["replay", path, "--to", "1791963000", "--output", "ndjson"],
+    );
+    assert_eq!(output.lines().collect::<Vec<_>>(), [first]);
+
+    let processes = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "ndjson", "--record", "process"],
+    );
+    std::fs::write(path, processes).unwrap();
+    let output = run("navi21-linux-6.6", &["replay", path]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn converts_recordings_to_chrome_traces() {
+    let recorded = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "ndjson", "-n", "2", "-d", "0.1"],
+    );
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("chrome-trace.ndjson");
+    std::fs::write(&path, &recorded).unwrap();
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["replay", path.to_str().unwrap(), "--output", "chrome-trace"],
+    );
+    let trace: serde_json::Value = serde_json::from_str(&output).unwrap();
+    let events = trace["traceEvents"].as_array().unwrap();
+    assert!(events
+        .iter()
+        .any(|event| event["ph"] == "M" && event["args"]["name"] == "blender 3301"));
+    let blender = events
+        .iter()
+        .filter(|event| event["ph"] == "C" && event["pid"] == 3301 && event["name"] == "VRAM")
+        .collect::<Vec<_>>();
+    assert_eq!(blender.len(), 2);
+    assert_eq!(blender[0]["args"]["card0"], 805306368u64);
+}
+
+#[test]
+fn compares_recorded_sessions() {
+    let recorded = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "ndjson", "-n", "2", "-d", "0.1"],
+    );
+    let a = Path::new(env!("CARGO_TARGET_TMPDIR")).join("compare-a.ndjson");
+    std::fs::write(&a, &recorded).unwrap();
+    // As if blender needed 64 MiB more after an update.
+    let patched = recorded
+        .lines()
+        .map(|line| {
+            let mut snapshot: serde_json::Value = serde_json::from_str(line).unwrap();
+            let process = &mut snapshot["devices"][0]["processes"][0];
+            assert_eq!(process["name"], "blender");
+            process["vram_bytes"] = (process["vram_bytes"].as_u64().unwrap() + (64 << 20)).into();
+            format!("{}\n", snapshot)
+        })
+        .collect::<String>();
+    let b = Path::new(env!("CARGO_TARGET_TMPDIR")).join("compare-b.ndjson");
+    std::fs::write(&b, patched).unwrap();
+    let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+    let output = amdtop("navi21-linux-6.6", &["compare", a, b]);
+    assert!(output.contains("2 snapshots"));
+    let blender = output
+        .lines()
+        .find(|line| line.starts_with("blender "))
+        .unwrap();
+    let fields = blender.split(" | ").map(str::trim).collect::<Vec<_>>();
+    assert_eq!(
+        fields[2..],
+        [
+            "768.00 MiB",
+            "832.00 MiB",
+            "+64.00 MiB",
+            "64.00 MiB",
+            "64.00 MiB",
+            "0"
+        ]
+    );
+
+    let output = amdtop("navi21-linux-6.6", &["compare", a, b, "--output", "json"]);
+    let comparison: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(comparison["a"]["snapshots"], 2);
+    let process = &comparison["devices"][0]["processes"][0];
+    assert_eq!(process["name"], "blender");
+    assert_eq!(process["max_vram_bytes"]["b"], 872415232u64);
+}
+
+#[test]
+fn stamps_batch_snapshots() {
+    let is_timestamp = |time: &str| {
+        // `2026-10-14T09:30:00.250+02:00`
+        time.len() == 29 && time.as_bytes()[10] == b'T' && time.as_bytes()[19] == b'.'
+    };
+    let output = amdtop("navi21-linux-6.6", &["-n", "2", "-d", "0.1"]);
+    let stamps = output
+        .lines()
+        .filter_map(|line| line.strip_prefix("# "))
+        .collect::<Vec<_>>();
+    assert_eq!(stamps.len(), 2);
+    let (time, sample) = stamps[1].split_once(" | ").unwrap();
+    assert!(is_timestamp(time), "{}", time);
+    assert_eq!(sample, "sample 1");
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "csv", "-n", "2", "-d", "0.1"],
+    );
+    let lines = output.lines().collect::<Vec<_>>();
+    assert!(lines[0].starts_with("time,sequence,device,pid,"));
+    let fields = lines.last().unwrap().split(',').collect::<Vec<_>>();
+    assert!(is_timestamp(fields[0]));
+    assert_eq!(fields[1..3], ["1", "card0"]);
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "json", "-n", "2", "-d", "0.1"],
+    );
+    let snapshots = serde_json::Deserializer::from_str(&output)
+        .into_iter::<serde_json::Value>()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(snapshots.len(), 2);
+    assert!(is_timestamp(snapshots[1]["time"].as_str().unwrap()));
+    assert_eq!(snapshots[1]["sequence"], 1);
+    assert_eq!(snapshots[1]["devices"][0]["device"], "card0");
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "ndjson", "--record", "process"],
+    );
+    let line: serde_json::Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+    assert!(is_timestamp(line["time"].as_str().unwrap()));
+
+    // A single snapshot stays as it was.
+    let output = amdtop("navi21-linux-6.6", &["--output", "csv"]);
+    assert!(output.starts_with("device,pid,"));
+}
+
+#[test]
+fn nul_terminates_csv_fields() {
+    let output = run("navi21-linux-6.6", &["--output", "csv", "-0"]);
+    assert!(output.status.success());
+    assert!(!output.stdout.contains(&b'\n'));
+    let fields = output.stdout.split(|byte| *byte == 0).collect::<Vec<_>>();
+    assert_eq!(fields.last(), Some(&&b""[..]));
+    let columns = fields.iter().position(|field| field == b"unit").unwrap() + 1;
+    assert_eq!(
+        fields[columns..columns + 4],
+        [&b"card0"[..], b"3301", b"blender", b"/opt/blender/blender"]
+    );
+    assert_eq!(fields[columns + 10], b"GL,HIP");
+
+    let output = run("navi21-linux-6.6", &["-0"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn histogram_buckets_buffers_by_size() {
+    let output = amdtop("navi21-linux-6.6", &["histogram"]);
+    assert!(output.starts_with("card0 | 9 objects\n"));
+    assert_eq!(
+        row(&output, "1.00 MiB - 16.00 MiB"),
+        [
+            "1.00 MiB - 16.00 MiB",
+            "4",
+            "16.00 MiB",
+            "6.00 MiB",
+            "0",
+            &"#".repeat(40)
+        ]
+    );
+    assert_eq!(
+        row(&output, "< 4.00 KiB"),
+        ["< 4.00 KiB", "0", "0", "0", "0", ""]
+    );
+
+    // blender's render thread holds buffers too.
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["histogram", "--pid", "3301", "--output", "json"],
+    );
+    let histograms: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(histograms[0]["pid"], 3301);
+    assert_eq!(histograms[0]["objects"], 3);
+    assert_eq!(histograms[0]["buckets"][5]["vram_bytes"], 805306368u64);
+}
+
+#[test]
+fn lists_the_largest_buffers() {
+    let output = amdtop("navi21-linux-6.6", &["buffers", "--top", "4"]);
+    let table = output.lines().take_while(|line| !line.is_empty());
+    assert_eq!(table.count(), 7);
+    assert_eq!(
+        row(&output, "1523"),
+        [
+            "1523",
+            "Xorg",
+            "0x2",
+            "32.00 MiB",
+            "VRAM invisible",
+            "NO_CPU_ACCESS"
+        ]
+    );
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["buffers", "--pid", "1523", "--output", "json"],
+    );
+    let buffers: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(buffers.as_array().unwrap().len(), 4);
+    assert_eq!(buffers[1]["handle"], 1);
+    assert_eq!(buffers[1]["visible"], true);
+    assert_eq!(
+        buffers[1]["flags"],
+        serde_json::json!(["CPU_ACCESS_REQUIRED", "VRAM_CONTIGUOUS"])
+    );
+    assert_eq!(buffers[2]["dma_buf"], 1234);
+
+    let output = amdtop("navi21-linux-6.6", &["buffers", "--pid", "1523"]);
+    assert!(output.starts_with("card0 | visible VRAM 16.00 MiB\n"));
+    assert!(output.contains(
+        "\n  CPU_ACCESS_REQUIRED  the CPU maps it, so in VRAM it has to fit in the visible part\n"
+    ));
+
+    // Kernels before 5.13 don't say which part of VRAM a buffer is in.
+    let output = amdtop("vega10-linux-5.4", &["buffers"]);
+    assert!(output.starts_with("card0\n"));
+    assert_eq!(row(&output, "1001")[4], "VRAM");
+}
+
+#[test]
+fn tells_pinned_memory_apart() {
+    let output = amdtop("navi21-linux-6.6", &[]);
+    assert!(
+        output.contains("\nPinned, so never evicted: 8.00 MiB of VRAM, held by 1523 (8.00 MiB)\n")
+    );
+
+    let output = amdtop("navi21-linux-6.6", &["--output", "json"]);
+    let views: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(views[0]["pinned"]["vram_bytes"], 8388608);
+    let xorg = views[0]["processes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|process| process["pid"] == 1523)
+        .unwrap();
+    assert_eq!(xorg["pinned"]["vram_bytes"], 8388608);
+    assert_eq!(xorg["pinned"]["gtt_bytes"], 0);
+
+    let output = amdtop("polaris10-linux-4.15", &[]);
+    assert!(output.contains("\nPinned, so never evicted: 7.91 MiB of VRAM, held by 900 "));
+    let output = amdtop("vega10-linux-5.4", &["buffers"]);
+    assert!(output.contains("|        0x2 |        1.00 MiB | GTT            | pinned\n"));
+}
+
+#[test]
+fn shows_vram_evicted_to_gtt() {
+    let output = amdtop("navi21-linux-6.6", &[]);
+    assert!(!output.contains("EVICTED"));
+
+    let root = scratch_fixture("navi21-linux-6.6", "evicted");
+    let fdinfo = root.join("proc/3301/fdinfo/9");
+    let contents = std::fs::read_to_string(&fdinfo)
+        .unwrap()
+        .replace("amd-evicted-vram:\t0 KiB", "amd-evicted-vram:\t131072 KiB");
+    std::fs::write(&fdinfo, contents).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .output()
+        .expect("failed to run amdtop");
+    let output = String::from_utf8_lossy(&output.stdout);
+    assert!(output
+        .lines()
+        .nth(1)
+        .unwrap()
+        .ends_with(" |         EVICTED"));
+    assert_eq!(row(&output, "3301").last().unwrap(), "128.00 MiB");
+    assert_eq!(row(&output, "1523").last().unwrap(), "0");
+}
+
+#[test]
+fn warns_when_gtt_pushes_toward_swap() {
+    let root = scratch_fixture("navi21-linux-6.6", "meminfo");
+    let meminfo = root.join("proc/meminfo");
+    let run = || {
+        let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .output()
+            .expect("failed to run amdtop");
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    std::fs::write(&meminfo, "MemTotal: 1048576 kB\nMemAvailable: 524288 kB\n").unwrap();
+    assert!(!run().contains("close to swapping"));
+
+    // 70 MiB of GTT is most of what's in use, and little is left.
+    std::fs::write(&meminfo, "MemTotal: 102400 kB\nMemAvailable: 5120 kB\n").unwrap();
+    assert!(run().ends_with(
+        "\n! GPU buffers hold 70.00 MiB of system RAM in GTT and only 5.00 MiB (5%) is \
+         available; the system is close to swapping\n"
+    ));
+}
+
+#[test]
+fn verifies_gem_info_against_fdinfo() {
+    let output = amdtop("navi21-linux-6.6", &["--verify"]);
+    assert!(output.starts_with("card0 | 3 processes, 0 disagreeing\n"));
+    assert_eq!(row(&output, "3301").last().unwrap(), "ok");
+
+    let root = scratch_fixture("navi21-linux-6.6", "verify");
+    let fdinfo = root.join("proc/2210/fdinfo/21");
+    let contents = std::fs::read_to_string(&fdinfo)
+        .unwrap()
+        .replace("drm-memory-vram:\t16384 KiB", "drm-memory-vram:\t20480 KiB");
+    std::fs::write(&fdinfo, contents).unwrap();
+    let verify = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .arg("--verify")
+            .args(args)
+            .output()
+            .expect("failed to run amdtop");
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let output = verify(&[]);
+    assert!(output.starts_with("card0 | 3 processes, 1 disagreeing\n"));
+    assert_eq!(
+        row(&output, "2210")[2..],
+        [
+            "24.00 MiB",
+            "28.00 MiB",
+            "4.00 MiB",
+            "4.00 MiB",
+            "VRAM +4.00 MiB"
+        ]
+    );
+
+    let comparisons: serde_json::Value =
+        serde_json::from_str(&verify(&["--output", "json"])).unwrap();
+    let gnome_shell = comparisons
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|comparison| comparison["pid"] == 2210)
+        .unwrap();
+    assert_eq!(gnome_shell["agrees"], false);
+    assert_eq!(gnome_shell["fdinfo"]["vram_bytes"], 29360128);
+
+    // Nothing to compare with where fdinfo can't be read.
+    let output = amdtop("vega10-linux-5.4", &["--verify"]);
+    assert_eq!(row(&output, "1001").last().unwrap(), "fdinfo unreadable");
+}
+
+#[test]
+fn traces_buffer_object_churn() {
+    let root = scratch_fixture("navi21-linux-6.6", "trace");
+    let tracing = root.join("sys/kernel/debug/tracing");
+    for event in ["amdgpu_bo_create", "amdgpu_bo_move"] {
+        let dir = tracing.join("events/amdgpu").join(event);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("enable"), "0\n").unwrap();
+    }
+    std::fs::write(tracing.join("trace"), "stale\n").unwrap();
+    std::fs::write(
+        tracing.join("trace_pipe"),
+        "         blender-3301    [003] ..... 100.000001: amdgpu_bo_create: \
+         bo=00000000a1b2c3d4, pages=2048, type=2, preferred=4, allowed=6, visible=0\n\
+         \x20        blender-3301    [003] ..... 100.000002: amdgpu_bo_move: \
+         bo=00000000a1b2c3d4, from=2, to=1, size=8388608\n\
+         \x20           Xorg-1523    [001] ..... 100.000003: amdgpu_bo_create: \
+         bo=00000000deadbeef, pages=16, type=2, preferred=2, allowed=2, visible=1\n",
+    )
+    .unwrap();
+    let trace = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .args(args)
+            .arg("trace")
+            .args(["--duration", "5s"])
+            .output()
+            .expect("failed to run amdtop");
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let output = trace(&[]);
+    assert!(output.contains(": 3 events\n"));
+    assert_eq!(
+        row(&output, "3301"),
+        ["3301", "blender", "1", "8.00 MiB", "1", "8.00 MiB", "8.00 MiB"]
+    );
+    assert_eq!(row(&output, "1523")[3], "64.00 KiB");
+    // Left as it was found, and the buffer emptied first.
+    let enable = tracing.join("events/amdgpu/amdgpu_bo_move/enable");
+    assert_eq!(std::fs::read_to_string(enable).unwrap(), "0");
+    assert_eq!(std::fs::read_to_string(tracing.join("trace")).unwrap(), "");
+
+    let churn: serde_json::Value = serde_json::from_str(&trace(&["--output", "json"])).unwrap();
+    assert_eq!(churn[0]["moved"]["VRAM->GTT"], 8388608);
+
+    let output = run("navi21-linux-6.6", &["trace", "--events", "bo_free"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn counts_vm_faults_from_the_kernel_log() {
+    let root = scratch_fixture("navi21-linux-6.6", "vm-faults");
+    std::fs::create_dir_all(root.join("dev")).unwrap();
+    std::fs::write(
+        root.join("dev/kmsg"),
+        "6,1,2000000,-;amdgpu 0000:03:00.0: amdgpu: SMU is initialized successfully!\n\
+         3,2,100000000,-;amdgpu 0000:03:00.0: amdgpu: [gfxhub] page fault (src_id:0 ring:24 \
+         vmid:3 pasid:32781, for process blender pid 3301 thread blender pid 3301)\n\
+         \x20SUBSYSTEM=pci\n\
+         3,3,100000001,-;amdgpu 0000:03:00.0: amdgpu:   in page starting at address 0x0000800100200000\n\
+         3,4,590000000,-;amdgpu 0000:03:00.0: amdgpu: [gfxhub] page fault (src_id:0 ring:24 \
+         vmid:3 pasid:32781, for process blender pid 3301 thread blender pid 3305)\n",
+    )
+    .unwrap();
+    std::fs::write(root.join("proc/uptime"), "600.25 2400.00\n").unwrap();
+    let mem = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .args(args)
+            .output()
+            .expect("failed to run amdtop");
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let output = mem(&[]);
+    assert!(output.starts_with(
+        "card0 | VRAM 860.00 MiB / 15.98 GiB | perf auto | 2 VM faults, 1 in the last minute!, \
+         last by blender (3301)\n"
+    ));
+    let views: serde_json::Value = serde_json::from_str(&mem(&["--output", "json"])).unwrap();
+    assert_eq!(views[0]["vm_faults"]["total"], 2);
+
+    // Nothing to say when the log can't be read.
+    let output = amdtop("navi21-linux-6.6", &[]);
+    assert!(output.starts_with("card0 | VRAM 860.00 MiB / 15.98 GiB | perf auto\n"));
+}
+
+#[test]
+fn follows_the_kernel_log() {
+    let root = scratch_fixture("navi21-linux-6.6", "kernel-log");
+    std::fs::create_dir_all(root.join("dev")).unwrap();
+    std::fs::write(
+        root.join("dev/kmsg"),
+        "6,1,2000000,-;usb 1-1: new high-speed USB device number 2\n\
+         4,2,590000000,-;[drm] Fence fallback timer expired on ring gfx_0.0.0\n\
+         3,3,595000000,-;amdgpu 0000:03:00.0: amdgpu: ring gfx_0.0.0 timeout\n",
+    )
+    .unwrap();
+    std::fs::write(root.join("proc/uptime"), "600.25 2400.00\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .arg("--kernel-log")
+        .output()
+        .expect("failed to run amdtop");
+    assert!(output.status.success());
+    let output = String::from_utf8_lossy(&output.stdout);
+    let log = output.split_once("\nKernel log:\n").unwrap().1;
+    // Logged before the first refresh, so there's nothing to place it after.
+    assert_eq!(
+        log,
+        "[  590.000000]       - |               - | [drm] Fence fallback timer expired on ring gfx_0.0.0\n\
+         [  595.000000]       - |               - | amdgpu 0000:03:00.0: amdgpu: ring gfx_0.0.0 timeout\n"
+    );
+
+    let output = run("navi21-linux-6.6", &["--kernel-log", "--output", "json"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn saves_devcoredumps() {
+    let root = scratch_fixture("navi21-linux-6.6", "devcoredump");
+    let dump = root.join("sys/class/devcoredump/devcd1");
+    std::fs::create_dir_all(&dump).unwrap();
+    std::os::unix::fs::symlink("../../../0000:03:00.0", dump.join("failing_device")).unwrap();
+    std::fs::write(dump.join("data"), "**** AMDGPU Device Coredump ****\n").unwrap();
+    let mem = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .args(args)
+            .output()
+            .expect("failed to run amdtop");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    assert!(mem(&[]).starts_with(
+        "card0 | VRAM 860.00 MiB / 15.98 GiB | perf auto | devcd1 waiting, gone 5 minutes after \
+         the hang!\n"
+    ));
+
+    let dir = root.join("dumps");
+    let output = mem(&["--save-coredumps", dir.to_str().unwrap()]);
+    let header = output.lines().next().unwrap();
+    let path = header.split_once(" | devcd1 saved to ").unwrap().1;
+    assert!(path.starts_with(dir.join("amdgpu-0000:03:00.0-").to_str().unwrap()));
+    assert!(path.ends_with("-devcd1.devcoredump"));
+    assert_eq!(
+        std::fs::read_to_string(path).unwrap(),
+        "**** AMDGPU Device Coredump ****\n"
+    );
+
+    let views: serde_json::Value = serde_json::from_str(&mem(&["--output", "json"])).unwrap();
+    assert_eq!(
+        views[0]["coredumps"],
+        serde_json::json!([{ "name": "devcd1" }])
+    );
+}
+
+#[test]
+fn shows_process_ages() {
+    let root = scratch_fixture("navi21-linux-6.6", "process-ages");
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    // Started at boot, two hours and half a minute ago.
+    std::fs::write(
+        root.join("proc/stat"),
+        format!("cpu  1 2 3 4\nbtime {}\n", now - 7230),
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("proc/3301/stat"),
+        "3301 (blender) S 1 1523 1523 0 -1 4194560 92113 0 0 0 4122 911 0 0 20 0 31 0 0 \
+         5035012096 92672 18446744073709551615\n",
+    )
+    .unwrap();
+    let mem = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .args(args)
+            .output()
+            .expect("failed to run amdtop");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let output = mem(&["--show-age"]);
+    let lines = output.lines().collect::<Vec<_>>();
+    assert!(lines[1].ends_with(" |        AGE |     ON GPU"));
+    let blender = lines.iter().find(|line| line.starts_with("3301 ")).unwrap();
+    assert!(blender.ends_with(" |      2h 0m |        >0s"));
+    let xorg = lines.iter().find(|line| line.starts_with("1523 ")).unwrap();
+    assert!(xorg.ends_with(" |          - |        >0s"));
+
+    let views: serde_json::Value =
+        serde_json::from_str(&mem(&["--show-age", "--output", "json"])).unwrap();
+    let blender = &views[0]["processes"][0];
+    assert_eq!(blender["pid"], 3301);
+    assert_eq!(blender["started"], now - 7230);
+    assert_eq!(blender["on_gpu_before_us"], true);
+}
+
+#[test]
+fn shows_cpu_usage_from_the_second_refresh() {
+    let root = scratch_fixture("navi21-linux-6.6", "cpu-usage");
+    std::fs::write(
+        root.join("proc/3301/stat"),
+        "3301 (blender) S 1 3301 3301 0 -1 4194560 92113 0 0 0 4122 911 0 0 20 0 31 0 1263145 \
+         5035012096 92672 18446744073709551615\n",
+    )
+    .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .args(["--show-cpu", "-n", "2", "-d", "0.1"])
+        .output()
+        .expect("failed to run amdtop");
+    assert!(output.status.success());
+    let output = String::from_utf8_lossy(&output.stdout);
+    let rows = output
+        .lines()
+        .filter(|line| line.starts_with("3301 ") && line.contains("/opt/blender/blender"))
+        .collect::<Vec<_>>();
+    assert_eq!(rows.len(), 2);
+    assert!(rows[0].ends_with(" |      -"));
+    // Its CPU time didn't move between the two.
+    assert!(rows[1].ends_with(" |    0.0"));
+    let xorg = output
+        .lines()
+        .rfind(|line| line.starts_with("1523 ") && line.contains("/usr/lib/Xorg"))
+        .unwrap();
+    assert!(xorg.ends_with(" |      -"));
+}
+
+#[test]
+fn computes_custom_columns() {
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &[
+            "--column",
+            "vram_pct=vram / device.vram_total * 100",
+            "--column",
+            "mib=total/1024/1024",
+        ],
+    );
+    let header = output.lines().nth(1).unwrap();
+    assert!(header.ends_with("|     vram_pct |          mib"));
+    let blender = output
+        .lines()
+        .find(|line| line.starts_with("3301 "))
+        .unwrap();
+    assert!(blender.ends_with("|         4.69 |          832"));
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "json", "--column", "rss=rss"],
+    );
+    let devices: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(
+        devices[0]["processes"][0]["columns"],
+        serde_json::json!({ "rss": null })
+    );
+
+    let output = run("navi21-linux-6.6", &["--column", "x=vram % 2"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn adds_fields_from_an_enrich_command() {
+    let script =
+        r#"grep -q '"name":"blender"' && echo '{"3301": {"job": "slurm-4242", "gpus": 2}}'"#;
+    let output = amdtop("navi21-linux-6.6", &["--enrich", script]);
+    let header = output.lines().nth(1).unwrap();
+    assert!(header.ends_with("|             gpus |              job"));
+    let row = |pid: &str| {
+        output
+            .lines()
+            .find(|line| line.starts_with(pid))
+            .unwrap()
+            .to_string()
+    };
+    assert!(row("3301 ").ends_with("|                2 |       slurm-4242"));
+    assert!(row("1523 ").ends_with("|                - |                -"));
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "json", "--enrich", script],
+    );
+    let devices: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(devices[0]["processes"][0]["extra"]["job"], "slurm-4242");
+
+    // A broken command is reported, but doesn't stop the refresh.
+    let output = run("navi21-linux-6.6", &["--enrich", "exit 3"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--enrich: exited with"));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("blender"));
+}
+
+#[test]
+fn shows_resident_memory() {
+    let root = scratch_fixture("navi21-linux-6.6", "resident-memory");
+    std::fs::write(
+        root.join("proc/3301/statm"),
+        "1229251 262144 30521 2202 0 401461 0\n",
+    )
+    .unwrap();
+    let mem = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .args(args)
+            .output()
+            .expect("failed to run amdtop");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    // 262144 pages of 4 KiB.
+    let output = mem(&["--show-rss"]);
+    let lines = output.lines().collect::<Vec<_>>();
+    assert!(lines[1].ends_with(" |             RSS"));
+    assert!(lines[3].starts_with("3301 "));
+    assert!(lines[3].ends_with(" |        1.00 GiB"));
+    assert!(lines[4].ends_with(" |               -"));
+
+    let views: serde_json::Value =
+        serde_json::from_str(&mem(&["--show-rss", "--output", "json"])).unwrap();
+    assert_eq!(views[0]["processes"][0]["rss_bytes"], 1u64 << 30);
+    // Nor is statm read without.
+    let views: serde_json::Value = serde_json::from_str(&mem(&["--output", "json"])).unwrap();
+    assert!(views[0]["processes"][0].get("rss_bytes").is_none());
+}
+
+#[test]
+fn shows_whole_footprints() {
+    let root = scratch_fixture("navi21-linux-6.6", "footprint");
+    std::fs::write(
+        root.join("proc/3301/statm"),
+        "1229251 262144 30521 2202 0 401461 0\n",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("proc/3301/smaps"),
+        "7f3a10000000-7f3a12000000 rw-s 00000000 00:0f 2048       /dev/dri/renderD128\n\
+         Rss:               16384 kB\n",
+    )
+    .unwrap();
+    let mem = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .args(args)
+            .output()
+            .expect("failed to run amdtop");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    // 1 GiB resident, with 768 MiB of VRAM and 64 MiB of GTT.
+    let output = mem(&["--show-footprint"]);
+    let lines = output.lines().collect::<Vec<_>>();
+    assert!(lines[1].ends_with(" |        FOOTPRINT"));
+    assert!(lines[3].starts_with("3301 "));
+    assert!(lines[3].ends_with(" |        ~1.81 GiB"));
+    assert!(output.contains("\n~ counts GTT twice where the process has it mapped"));
+
+    // Of which 16 MiB mapped into blender, so in its RSS too.
+    let output = mem(&["--show-footprint", "--accurate"]);
+    let lines = output.lines().collect::<Vec<_>>();
+    assert!(lines[3].ends_with(" |         1.80 GiB"));
+    assert!(!output.contains("\n~ counts"));
+    let views: serde_json::Value = serde_json::from_str(&mem(&[
+        "--show-footprint",
+        "--accurate",
+        "--output",
+        "json",
+    ]))
+    .unwrap();
+    let blender = &views[0]["processes"][0];
+    assert_eq!(blender["footprint_bytes"], (1u64 << 30) + (816u64 << 20));
+    assert_eq!(blender["footprint_may_double_count"], false);
+
+    assert_eq!(
+        run("navi21-linux-6.6", &["--accurate"]).status.code(),
+        Some(2)
+    );
+}
+
+#[test]
+fn shows_engine_busy_from_the_second_refresh() {
+    let output = amdtop("navi21-linux-6.6", &["--show-busy", "-n", "2", "-d", "0.1"]);
+    let rows = output
+        .lines()
+        .filter(|line| line.starts_with("3301 ") && line.contains("/opt/blender/blender"))
+        .collect::<Vec<_>>();
+    assert_eq!(rows.len(), 2);
+    assert!(rows[0].ends_with(" |            -"));
+    // The fixture's busy times stand still.
+    assert!(rows[1].ends_with(" |       gfx 0%"));
+}
+
+#[test]
+fn shows_allocation_rates_from_the_second_refresh() {
+    let output = amdtop("navi21-linux-6.6", &["--show-rate", "-n", "2", "-d", "0.1"]);
+    let rows = output
+        .lines()
+        .filter(|line| line.starts_with("3301 ") && line.contains("/opt/blender/blender"))
+        .collect::<Vec<_>>();
+    assert_eq!(rows.len(), 2);
+    assert!(rows[0].ends_with(" |               -"));
+    // The fixture's memory stands still.
+    assert!(rows[1].ends_with(" |             0/s"));
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &[
+            "--show-rate",
+            "--smoothing",
+            "avg:3",
+            "--output",
+            "json",
+            "-n",
+            "2",
+            "-d",
+            "0.1",
+        ],
+    );
+    let snapshots = serde_json::Deserializer::from_str(&output)
+        .into_iter::<serde_json::Value>()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let blender = |snapshot: &serde_json::Value| snapshot["devices"][0]["processes"][0].clone();
+    assert!(blender(&snapshots[0])
+        .get("alloc_bytes_per_second")
+        .is_none());
+    assert_eq!(blender(&snapshots[1])["alloc_bytes_per_second"], 0.0);
+}
+
+#[test]
+fn prints_lines_for_exec_plugins() {
+    let output = amdtop("navi21-linux-6.6", &["--output", "telegraf"]);
+    let lines = output.lines().collect::<Vec<_>>();
+    assert!(lines[0].starts_with(
+        "amdtop_device,device=card0 vram_used_bytes=901775360i,vram_total_bytes=17163091968i,"
+    ));
+    assert!(lines[1].starts_with(
+        "amdtop_process,device=card0,pid=3301,name=blender vram_bytes=805306368i,gtt_bytes=67108864i "
+    ));
+    // One timestamp, in nanoseconds, for the whole run.
+    let timestamp = |line: &str| line.rsplit(' ').next().unwrap().to_string();
+    assert!(timestamp(lines[0]).len() >= 19);
+    assert!(lines
+        .iter()
+        .all(|line| timestamp(line) == timestamp(lines[0])));
+
+    let output = amdtop("navi21-linux-6.6", &["sensors", "--output", "telegraf"]);
+    assert!(output.starts_with("amdtop_sensors,device=card0 gpu_busy=12,"));
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/navi21-linux-6.6");
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(root)
+        .args(["sensors", "--output", "collectd"])
+        .env("COLLECTD_HOSTNAME", "gpu-box")
+        .env("COLLECTD_INTERVAL", "30.000")
+        .output()
+        .expect("failed to run amdtop");
+    let output = String::from_utf8(output.stdout).unwrap();
+    let line = output
+        .lines()
+        .find(|line| line.contains("/frequency-shader_clock\""))
+        .unwrap();
+    assert!(line.starts_with("PUTVAL \"gpu-box/amdtop-card0/frequency-shader_clock\" interval=30 "));
+    assert!(line.ends_with(":1500000000"));
+
+    let output = run("navi21-linux-6.6", &["rings", "--output", "collectd"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn mem_prints_json() {
+    let output = amdtop("navi21-linux-6.6", &["mem", "--output", "json"]);
+    assert!(output.contains("\"device\": \"card0\""));
+    assert!(output.contains("\"vram_bytes\": 805306368"));
+}
+
+#[test]
+fn gpu_selects_devices() {
+    let output = amdtop("navi21-linux-6.6", &["--gpu", "0000:03:00.0"]);
+    row(&output, "3301");
+
+    let output = amdtop("navi21-linux-6.6", &["--gpu", "card1"]);
+    assert!(output.is_empty());
+}
+
+#[test]
+fn export_serves_metrics() {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/navi21-linux-6.6");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(root)
+        .args(["export", "--listen", "127.0.0.1:0"])
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("failed to run amdtop");
+
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr.read_line(&mut line).unwrap();
+    let address = line
+        .split("http://")
+        .nth(1)
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or_else(|| panic!("no address in {:?}", line))
+        .to_string();
+
+    let mut stream = std::net::TcpStream::connect(&address).unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("amdtop_vram_used_bytes{device=\"card0\"} 901775360\n"));
+    assert!(response.contains(
+        "amdtop_process_vram_bytes{device=\"card0\",pid=\"3301\",name=\"blender\"} 805306368\n"
+    ));
+    assert!(response.contains("amdtop_edge_temperature_celsius{device=\"card0\"} 45\n"));
+}
+
+/// GETs `path` from an `amdtop export` started with `args` on the navi21
+/// fixture.
+fn scrape(args: &[&str], path: &str, accept: &str) -> String {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/navi21-linux-6.6");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(root)
+        .args(["export", "--listen", "127.0.0.1:0"])
+        .args(args)
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("failed to run amdtop");
+
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr.read_line(&mut line).unwrap();
+    let address = line
+        .split("http://")
+        .nth(1)
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or_else(|| panic!("no address in {:?}", line))
+        .to_string();
+
+    let mut stream = std::net::TcpStream::connect(&address).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept: {}\r\n\r\n",
+        path, accept
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    response
+}
+
+#[test]
+fn export_speaks_openmetrics() {
+    let response = scrape(
+        &[],
+        "/metrics",
+        "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5",
+    );
+    assert!(response.contains(
+        "\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\n"
+    ));
+    assert!(response
+        .contains("# TYPE amdtop_vram_used_bytes gauge\n# UNIT amdtop_vram_used_bytes bytes\n"));
+    assert!(response.contains(
+        "# TYPE amdtop_process_engine_seconds counter\n\
+         # UNIT amdtop_process_engine_seconds seconds\n"
+    ));
+    assert!(response.contains(
+        "amdtop_process_engine_seconds_total{device=\"card0\",pid=\"3301\",name=\"blender\",engine=\"gfx\"} 5.25\n"
+    ));
+    assert!(response.contains("amdtop_shader_clock_hertz{device=\"card0\"} 1500000000\n"));
+    assert!(response.ends_with("# EOF\n"));
+
+    // The classic format has no UNIT or EOF, and names counters with _total.
+    let response = scrape(&[], "/metrics", "text/plain");
+    assert!(response.contains("# TYPE amdtop_process_engine_seconds_total counter\n"));
+    assert!(!response.contains("# UNIT "));
+    assert!(!response.contains("# EOF"));
+
+    let response = scrape(
+        &["--process-labels", "name", "--max-process-series", "1"],
+        "/metrics",
+        "text/plain",
+    );
+    assert!(response
+        .contains("amdtop_process_vram_bytes{device=\"card0\",name=\"blender\"} 805306368\n"));
+    assert!(response.contains("amdtop_process_vram_bytes{device=\"card0\",name=\"other\"} "));
+    assert_eq!(response.matches("amdtop_process_vram_bytes{").count(), 2);
+    assert!(!response.contains("name=\"other\",engine="));
+}
+
+#[test]
+fn export_publishes_to_mqtt() {
+    use std::io::{Read, Write};
+
+    /// Reads one MQTT packet: its first byte and what follows the length.
+    fn read_packet(stream: &mut std::net::TcpStream) -> (u8, Vec<u8>) {
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).unwrap();
+        let kind = byte[0];
+        let (mut length, mut shift) = (0, 0);
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            length |= ((byte[0] & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).unwrap();
+        (kind, body)
+    }
+
+    let broker = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("mqtt://amdtop:s3cret@{}", broker.local_addr().unwrap());
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/navi21-linux-6.6");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(root)
+        .args([
+            "export",
+            "--listen",
+            "127.0.0.1:0",
+            "-d",
+            "0.1",
+            "--mqtt",
+            &url,
+        ])
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .expect("failed to run amdtop");
+
+    let (mut stream, _) = broker.accept().unwrap();
+    let (kind, connect) = read_packet(&mut stream);
+    stream.write_all(&[0x20, 2, 0, 0]).unwrap();
+    let publishes = (0..2)
+        .map(|_| {
+            let (kind, body) = read_packet(&mut stream);
+            let length = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let topic = String::from_utf8(body[2..2 + length].to_vec()).unwrap();
+            let payload: serde_json::Value = serde_json::from_slice(&body[2 + length..]).unwrap();
+            (kind, topic, payload)
+        })
+        .collect::<Vec<_>>();
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert_eq!(kind, 0x10);
+    assert!(connect.starts_with(b"\0\x04MQTT\x04\xc2"));
+    assert!(connect.ends_with(b"\0\x06amdtop\0\x06s3cret"));
+    // Retained, so a dashboard shows something as soon as it subscribes.
+    assert_eq!(publishes[0].0, 0x31);
+    assert_eq!(publishes[0].1, "amdtop/card0");
+    assert_eq!(publishes[0].2["vram_used_bytes"], 901775360);
+    assert_eq!(publishes[0].2["sensors"]["edge_temperature"]["value"], 45.0);
+    assert_eq!(publishes[1].1, "amdtop/card0/processes");
+    assert_eq!(publishes[1].2[0]["name"], "blender");
+}
+
+#[test]
+fn export_streams_snapshots_over_websocket() {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/navi21-linux-6.6");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(root)
+        .args(["export", "--listen", "127.0.0.1:0", "-d", "0.1"])
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("failed to run amdtop");
+
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr.read_line(&mut line).unwrap();
+    let address = line
+        .split("http://")
+        .nth(1)
+        .and_then(|rest| rest.split('/').next())
+        .unwrap_or_else(|| panic!("no address in {:?}", line))
+        .to_string();
+
+    let mut stream = std::net::TcpStream::connect(&address).unwrap();
+    stream
+        .write_all(
+            b"GET /stream HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+              Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+              Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut headers = String::new();
+    while !headers.ends_with("\r\n\r\n") {
+        reader.read_line(&mut headers).unwrap();
+    }
+    // Two snapshots, to see the stream keeps going.
+    let mut snapshots = Vec::new();
+    for _ in 0..2 {
+        let mut header = [0; 2];
+        reader.read_exact(&mut header).unwrap();
+        let length = match header[1] {
+            126 => {
+                let mut length = [0; 2];
+                reader.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            }
+            127 => {
+                let mut length = [0; 8];
+                reader.read_exact(&mut length).unwrap();
+                u64::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        reader.read_exact(&mut payload).unwrap();
+        snapshots.push((header[0], payload));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert!(headers.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+    assert!(headers.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    for (opcode, payload) in snapshots {
+        assert_eq!(opcode, 0x81);
+        let snapshot: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(snapshot["devices"][0]["device"], "card0");
+    }
+}
+
+/// Like `scratch_fixture`, but where anyone can read it, with debugfs only
+/// open to root like on a real system. Running as root, with `SUDO_UID`
+/// set, amdtop drops to `nobody` for the rest of the session, which can't
+/// read it any more. `None` when the tests don't run as root.
+fn root_only_fixture(fixture: &str, name: &str) -> Option<std::path::PathBuf> {
+    if unsafe { libc::geteuid() } != 0 {
+        return None;
+    }
+    let source = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(fixture);
+    let scratch = std::env::temp_dir().join(format!("amdtop-{}", name));
+    let _ = std::fs::remove_dir_all(&scratch);
+    let status = Command::new("cp")
+        .arg("-a")
+        .arg(source)
+        .arg(&scratch)
+        .status()
+        .expect("failed to run cp");
+    assert!(status.success());
+    let status = Command::new("chmod")
+        .args(["-R", "go-rwx"])
+        .arg(scratch.join("sys/kernel/debug"))
+        .status()
+        .expect("failed to run chmod");
+    assert!(status.success());
+    Some(scratch)
+}
+
+/// `amdtop` as `sudo amdtop` runs it for `nobody`.
+fn sudo_amdtop(root: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_amdtop"));
+    command
+        .arg("--root")
+        .arg(root)
+        .env("SUDO_UID", "65534")
+        .env("SUDO_GID", "65534");
+    command
+}
+
+/// Starts `amdtop agent` and asks it for one frame.
+fn agent_frame(mut agent: Command) -> serde_json::Value {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let mut child = agent
+        .args(["agent", "--listen", "127.0.0.1:0"])
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("failed to run amdtop");
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr.read_line(&mut line).unwrap();
+    let address = line
+        .split("listening on ")
+        .nth(1)
+        .and_then(|rest| rest.split(';').next())
+        .unwrap_or_else(|| panic!("no address in {:?}", line))
+        .to_string();
+
+    let mut stream = std::net::TcpStream::connect(address).unwrap();
+    let request = br#"{"version":1}"#;
+    stream
+        .write_all(&(request.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(request).unwrap();
+    let mut length = [0; 4];
+    stream.read_exact(&mut length).unwrap();
+    let mut frame = vec![0; u32::from_be_bytes(length) as usize];
+    stream.read_exact(&mut frame).unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    serde_json::from_slice(&frame).unwrap()
+}
+
+#[test]
+fn top_reads_debugfs_after_dropping_root() {
+    let root = match root_only_fixture("navi21-linux-6.6", "dropping-root") {
+        Some(root) => root,
+        None => return,
+    };
+    let frame = agent_frame(sudo_amdtop(&root));
+    assert_eq!(frame["error"], serde_json::Value::Null);
+    let screen = &frame["devices"][0];
+    let fences = screen["fences"].as_str().unwrap();
+    assert!(!fences.contains("permission denied"), "{}", fences);
+    assert!(fences.contains("gfx_0.0.0"), "{}", fences);
+    assert!(screen["table"].as_str().unwrap().contains("GFX "));
+}
+
+#[test]
+fn keeps_the_users_groups_after_dropping_root() {
+    use std::io::{BufRead, BufReader};
+
+    let root = match root_only_fixture("navi21-linux-6.6", "dropping-groups") {
+        Some(root) => root,
+        None => return,
+    };
+    let groups = Command::new("id")
+        .args(["-G", "65534"])
+        .output()
+        .expect("failed to run id");
+    let mut groups = String::from_utf8_lossy(&groups.stdout)
+        .split_whitespace()
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    groups.sort();
+
+    let mut child = sudo_amdtop(&root)
+        .args(["agent", "--listen", "127.0.0.1:0"])
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("failed to run amdtop");
+    let mut line = String::new();
+    BufReader::new(child.stderr.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let status = std::fs::read_to_string(format!("/proc/{}/status", child.id())).unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(field("Uid:"), ["65534"; 4]);
+    let mut left = field("Groups:");
+    left.sort();
+    assert_eq!(left, groups);
+}
+
+#[test]
+fn doctor_checks_as_root_under_sudo() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = match root_only_fixture("navi21-linux-6.6", "doctor-root") {
+        Some(root) => root,
+        None => return,
+    };
+    // Checked after debugfs, which mustn't switch us to the user first.
+    let status = Command::new("chmod")
+        .args(["-R", "go-rwx"])
+        .arg(root.join("proc"))
+        .status()
+        .expect("failed to run chmod");
+    assert!(status.success());
+
+    let output = sudo_amdtop(&root)
+        .arg("doctor")
+        .output()
+        .expect("failed to run amdtop");
+    let output = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.contains("ok   | fdinfo       | works\n"),
+        "{}",
+        output
+    );
+
+    // Nor start a helper just to see whether it could.
+    let marker = root.join("helper-started");
+    let helper = root.join("helper");
+    std::fs::write(&helper, format!("#!/bin/sh\ntouch {}\n", marker.display())).unwrap();
+    std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let output = sudo_amdtop(&root)
+        .args(["--elevate", "exec", "doctor"])
+        .env("AMDTOP_HELPER", &helper)
+        .output()
+        .expect("failed to run amdtop");
+    assert!(String::from_utf8_lossy(&output.stdout).contains("ok   | debugfs"));
+    assert!(!marker.exists());
+}
+
+#[test]
+fn version_keeps_root_and_starts_no_helper() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = match root_only_fixture("navi21-linux-6.6", "version-root") {
+        Some(root) => root,
+        None => return,
+    };
+    let status = Command::new("chmod")
+        .args(["-R", "go-rwx"])
+        .arg(root.join("proc"))
+        .status()
+        .expect("failed to run chmod");
+    assert!(status.success());
+    let marker = root.join("helper-started");
+    let helper = root.join("helper");
+    std::fs::write(&helper, format!("#!/bin/sh\ntouch {}\n", marker.display())).unwrap();
+    std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    for args in [&["--version"][..], &["--elevate", "exec", "--version"]] {
+        let output = sudo_amdtop(&root)
+            .args(args)
+            .env("AMDTOP_HELPER", &helper)
+            .output()
+            .expect("failed to run amdtop");
+        let output = String::from_utf8_lossy(&output.stdout);
+        assert!(
+            output.contains("sources: debugfs, fdinfo, sysfs "),
+            "{}",
+            output
+        );
+    }
+    assert!(!marker.exists());
+}
+
+#[test]
+fn saves_coredumps_only_root_can_read() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = match root_only_fixture("navi21-linux-6.6", "root-coredumps") {
+        Some(root) => root,
+        None => return,
+    };
+    let dump = root.join("sys/class/devcoredump/devcd1");
+    std::fs::create_dir_all(&dump).unwrap();
+    std::os::unix::fs::symlink("../../../0000:03:00.0", dump.join("failing_device")).unwrap();
+    std::fs::write(dump.join("data"), "**** AMDGPU Device Coredump ****\n").unwrap();
+    std::fs::set_permissions(dump.join("data"), std::fs::Permissions::from_mode(0o600)).unwrap();
+
+    let dir = root.join("dumps");
+    let output = sudo_amdtop(&root)
+        .args(["--save-coredumps", dir.to_str().unwrap()])
+        .output()
+        .expect("failed to run amdtop");
+    assert!(output.status.success());
+    let output = String::from_utf8_lossy(&output.stdout);
+    let header = output.lines().next().unwrap();
+    let path = header
+        .split_once(" | devcd1 saved to ")
+        .unwrap_or_else(|| panic!("not saved: {}", header))
+        .1;
+    assert_eq!(
+        std::fs::read_to_string(path).unwrap(),
+        "**** AMDGPU Device Coredump ****\n"
+    );
+}
+
+#[test]
+fn reads_the_kernel_log_after_dropping_root() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = match root_only_fixture("navi21-linux-6.6", "root-kmsg") {
+        Some(root) => root,
+        None => return,
+    };
+    std::fs::create_dir_all(root.join("dev")).unwrap();
+    std::fs::write(
+        root.join("dev/kmsg"),
+        "3,1,100000000,-;amdgpu 0000:03:00.0: amdgpu: [gfxhub] page fault (src_id:0 ring:24 \
+         vmid:3 pasid:32781, for process blender pid 3301 thread blender pid 3301)\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(
+        root.join("dev/kmsg"),
+        std::fs::Permissions::from_mode(0o600),
+    )
+    .unwrap();
+    let output = sudo_amdtop(&root).output().expect("failed to run amdtop");
+    assert!(output.status.success());
+    let output = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.starts_with(
+            "card0 | VRAM 860.00 MiB / 15.98 GiB | perf auto | 1 VM faults, last by blender (3301)\n"
+        ),
+        "{}",
+        output
+    );
+}
+
+#[test]
+fn viewers_show_what_the_agent_samples() {
+    use std::io::{BufRead, BufReader};
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/navi21-linux-6.6");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(root)
+        .args(["agent", "--listen", "127.0.0.1:0"])
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("failed to run amdtop");
+
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr.read_line(&mut line).unwrap();
+    let address = line
+        .split("listening on ")
+        .nth(1)
+        .and_then(|rest| rest.split(';').next())
+        .unwrap_or_else(|| panic!("no address in {:?}", line))
+        .to_string();
+
+    // The viewer has no --root, so everything it shows came from the agent.
+    let viewer = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .args(["--connect", &address])
+            .args(args)
+            .output()
+            .expect("failed to run amdtop")
+    };
+    let table = viewer(&["mem", "-n", "2", "-d", "0.1"]);
+    let json = viewer(&["mem", "--output", "json"]);
+    let csv = viewer(&["mem", "--output", "csv"]);
+    let fw = viewer(&["fw"]);
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert!(table.status.success());
+    let table = String::from_utf8(table.stdout).unwrap();
+    assert_eq!(
+        table
+            .matches("card0 | VRAM 860.00 MiB / 15.98 GiB | perf auto\n")
+            .count(),
+        2
+    );
+    assert_eq!(row(&table, "3301")[1], "blender");
+    assert!(table.contains("\nGPU "));
+
+    let json: serde_json::Value = serde_json::from_slice(&json.stdout).unwrap();
+    assert_eq!(json[0]["device"], "card0");
+    assert_eq!(json[0]["processes"][0]["name"], "blender");
+
+    assert_eq!(csv.status.code(), Some(2));
+    assert_eq!(fw.status.code(), Some(2));
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn viewers_check_the_agents_certificate() {
+    use std::io::{BufRead, BufReader};
+
+    let scratch = Path::new(env!("CARGO_TARGET_TMPDIR")).join("agent-tls");
+    let _ = std::fs::remove_dir_all(&scratch);
+    std::fs::create_dir_all(&scratch).unwrap();
+    let (cert, key) = (scratch.join("cert.pem"), scratch.join("key.pem"));
+    let status = Command::new("openssl")
+        .args(["req", "-x509", "-newkey", "ec", "-pkeyopt"])
+        .args(["ec_paramgen_curve:P-256", "-nodes", "-days", "1"])
+        .args(["-subj", "/CN=localhost", "-addext"])
+        .args(["subjectAltName=IP:127.0.0.1", "-addext"])
+        .args(["basicConstraints=critical,CA:FALSE", "-keyout"])
+        .arg(&key)
+        .arg("-out")
+        .arg(&cert)
+        .stderr(std::process::Stdio::null())
+        .status()
+        .expect("failed to run openssl");
+    assert!(status.success());
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/navi21-linux-6.6");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(root)
+        .args(["agent", "--listen", "127.0.0.1:0", "--tls-cert"])
+        .arg(&cert)
+        .arg("--tls-key")
+        .arg(&key)
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("failed to run amdtop");
+
+    let mut stderr = BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr.read_line(&mut line).unwrap();
+    assert!(line.contains(" with TLS; view with amdtop top --connect --tls"));
+    let address = line
+        .split("listening on ")
+        .nth(1)
+        .and_then(|rest| rest.split(' ').next())
+        .unwrap_or_else(|| panic!("no address in {:?}", line))
+        .to_string();
+
+    let viewer = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .args(["--connect", &address])
+            .args(args)
+            .args(["mem", "-n", "2", "-d", "0.1"])
+            .output()
+            .expect("failed to run amdtop")
+    };
+    let trusted = viewer(&["--tls", "--tls-ca", cert.to_str().unwrap()]);
+    // Neither the system's CAs nor plain TCP get a frame.
+    let untrusted = viewer(&["--tls"]);
+    let plain = viewer(&[]);
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert!(
+        trusted.status.success(),
+        "{}",
+        String::from_utf8_lossy(&trusted.stderr)
+    );
+    let table = String::from_utf8(trusted.stdout).unwrap();
+    assert_eq!(row(&table, "3301")[1], "blender");
+
+    assert!(!untrusted.status.success());
+    assert!(String::from_utf8_lossy(&untrusted.stderr).contains("TLS with"));
+    assert!(!plain.status.success());
+}
+
+#[test]
+fn sensors_write_mangohud_logs() {
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["sensors", "--output", "mangohud", "-n", "2", "-d", "0.1"],
+    );
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[0], "os,cpu,gpu,ram,kernel,driver,cpuscheduler");
+    assert!(lines[1].contains("AMD GPU card0 (0000:03:00.0)"));
+    let header = lines[2].split(',').collect::<Vec<_>>();
+    assert_eq!(header.len(), 15);
+    for line in &lines[3..] {
+        let fields = header
+            .iter()
+            .zip(line.split(','))
+            .collect::<std::collections::HashMap<_, _>>();
+        assert_eq!(fields[&"gpu_load"], "12");
+        assert_eq!(fields[&"gpu_temp"], "45");
+        assert_eq!(fields[&"gpu_core_clock"], "1500");
+        assert_eq!(fields[&"gpu_power"], "35");
+        assert_eq!(fields[&"gpu_vram_used"], "0.839844");
+    }
+}
+
+#[test]
+fn sensors_cross_check_pm_info() {
+    let output = amdtop("navi21-linux-6.6", &["sensors", "--pm-info"]);
+    assert!(output.contains("  amdgpu_pm_info\n"));
+    assert!(output.contains("    SCLK                 1500 MHz\n"));
+    assert!(output.contains("    VDDGFX               925 mV\n"));
+
+    let output = run(
+        "navi21-linux-6.6",
+        &["sensors", "--pm-info", "--debugfs-path", "/nonexistent"],
+    );
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn set_changes_performance_level() {
+    let root = scratch_fixture("navi21-linux-6.6", "set-perf-level");
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .args(["set", "perf-level", "high"])
+        .output()
+        .expect("failed to run amdtop");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "card0 | perf high\n"
+    );
+
+    let level = std::fs::read_to_string(
+        root.join("sys/class/drm/card0/device/power_dpm_force_performance_level"),
+    )
+    .unwrap();
+    assert_eq!(level, "high");
+
+    let output = run("navi21-linux-6.6", &["set", "perf-level", "fastest"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn set_priority_needs_a_live_gpu_client() {
+    // Found through its descriptors, but there's no kernel to ask.
+    let output = run("navi21-linux-6.6", &["set", "priority", "3301", "low"]);
+    assert_eq!(output.status.code(), Some(6));
+    let output = run("navi21-linux-6.6", &["set", "priority", "4242", "low"]);
+    assert_eq!(output.status.code(), Some(2));
+    let output = run(
+        "navi21-linux-6.6",
+        &["--gpu", "card1", "set", "priority", "3301", "low"],
+    );
+    assert_eq!(output.status.code(), Some(3));
+    let output = run("navi21-linux-6.6", &["set", "priority", "3301", "lowest"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn set_power_cap_stays_within_limits() {
+    let root = scratch_fixture("navi21-linux-6.6", "set-power-cap");
+    let set = |watts: &str| {
+        Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .args(["set", "power-cap", watts])
+            .output()
+            .expect("failed to run amdtop")
+    };
+    let cap = || {
+        std::fs::read_to_string(root.join("sys/class/drm/card0/device/hwmon/hwmon3/power1_cap"))
+            .unwrap()
+    };
+
+    assert!(set("200").status.success());
+    assert_eq!(cap(), "200000000");
+
+    let output = set("400");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("between 0 W and 293 W"));
+    assert_eq!(cap(), "200000000");
+}
+
+#[test]
+fn set_fan_clamps_manual_speeds() {
+    let root = scratch_fixture("navi21-linux-6.6", "set-fan");
+    let hwmon = root.join("sys/class/drm/card0/device/hwmon/hwmon3");
+    let set = |speed: &str| {
+        Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .args(["set", "fan", speed])
+            .output()
+            .expect("failed to run amdtop")
+    };
+    let read = |name: &str| std::fs::read_to_string(hwmon.join(name)).unwrap();
+
+    let output = set("5");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("using 20 %"));
+    assert_eq!(read("pwm1_enable"), "1");
+    assert_eq!(read("pwm1"), "51");
+
+    assert!(set("auto").status.success());
+    assert_eq!(read("pwm1_enable"), "2");
+
+    assert_eq!(set("fast").status.code(), Some(2));
+}
+
+#[test]
+fn sensors_detail_decodes_gpu_metrics() {
+    let output = amdtop("navi21-linux-6.6", &["sensors", "--detail"]);
+    assert!(output.contains("  gpu_metrics v1.3\n"));
+    assert!(output.contains("    Average GFX clock    1480 MHz\n"));
+    assert!(output.contains("    PCIe link speed      16 GT/s\n"));
+    assert!(output.contains("    Throttle status      0x00000000\n"));
+
+    let output = amdtop("navi21-linux-6.6", &["sensors"]);
+    assert!(!output.contains("gpu_metrics"));
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["--output", "csv", "sensors", "--detail"],
+    );
+    assert!(output.contains("card0,gpu_metrics_pcie_link_width,16,lanes\n"));
+}
+
+#[test]
+fn sensors_detail_shows_overdrive() {
+    let output = amdtop("navi21-linux-6.6", &["sensors", "--detail"]);
+    assert!(output.contains("    SCLK                 0: 500 MHz, 1: 2800 MHz\n"));
+    assert!(output.contains("    MCLK range           674 MHz – 1200 MHz\n"));
+    assert!(output.contains("    SCLK                 500 [1500] 2660 MHz\n"));
+    assert!(output.contains("    SOCCLK               [480] 1200 MHz\n"));
+
+    let output = amdtop(
+        "navi21-linux-6.6",
+        &["sensors", "--detail", "--output", "csv"],
+    );
+    assert!(output.contains("card0,dpm_mclk_3,1000,MHz\n"));
+    assert!(output.contains("card0,dpm_fclk_active,2,\n"));
+
+    let output = amdtop("navi21-linux-6.6", &["sensors"]);
+    assert!(!output.contains("Overdrive"));
+}
+
+#[test]
+fn set_power_profile_by_name() {
+    let root = scratch_fixture("navi21-linux-6.6", "set-power-profile");
+    let set = |profile: &str| {
+        Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .args(["set", "power-profile", profile])
+            .output()
+            .expect("failed to run amdtop")
+    };
+
+    let output = set("TURBO");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("expected one of BOOTUP_DEFAULT"));
+
+    let output = set("compute");
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "card0 | profile COMPUTE\n"
+    );
+    let mode =
+        std::fs::read_to_string(root.join("sys/class/drm/card0/device/pp_power_profile_mode"))
+            .unwrap();
+    assert_eq!(mode, "5");
+}
+
+#[test]
+fn sensors_explain_gfxoff() {
+    let root = scratch_fixture("navi21-linux-6.6", "gfxoff");
+    std::fs::write(
+        root.join("sys/kernel/debug/dri/0/amdgpu_gfxoff_status"),
+        [0, 0, 0, 0],
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("sys/class/drm/card0/device/pp_dpm_sclk"),
+        "0: 0Mhz *\n1: 500Mhz\n2: 2800Mhz\n",
+    )
+    .unwrap();
+    // gpu_metrics would win over pp_dpm_sclk.
+    std::fs::remove_file(root.join("sys/class/drm/card0/device/gpu_metrics")).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .arg("sensors")
+        .output()
+        .expect("failed to run amdtop");
+    let output = String::from_utf8_lossy(&output.stdout);
+    assert!(output.contains(" | gfx off (GFXOFF)\n"));
+    assert!(output.contains("Shader clock           0 MHz (GFX powered off)\n"));
+}
+
+#[test]
+fn sensors_show_smartshift_split() {
+    let root = scratch_fixture("navi21-linux-6.6", "smartshift");
+    let device_dir = root.join("sys/class/drm/card0/device");
+    std::fs::write(device_dir.join("smartshift_apu_power"), "35.20\n").unwrap();
+    std::fs::write(device_dir.join("smartshift_dgpu_power"), "64.80\n").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .args(["--output", "csv", "sensors"])
+        .output()
+        .expect("failed to run amdtop");
+    let output = String::from_utf8_lossy(&output.stdout);
+    assert!(output.contains("card0,smartshift_apu_power,35.2,%\n"));
+    assert!(output.contains("card0,smartshift_dgpu_power,64.8,%\n"));
+}
+
+#[test]
+fn xgmi_shows_hive_and_links() {
+    let output = amdtop("navi21-linux-6.6", &["xgmi"]);
+    assert_eq!(output, "card0 | not in an XGMI hive\n");
+
+    let root = scratch_fixture("navi21-linux-6.6", "xgmi");
+    let device_dir = root.join("sys/class/drm/card0/device");
+    std::fs::create_dir_all(device_dir.join("xgmi_hive_info")).unwrap();
+    std::fs::write(device_dir.join("xgmi_hive_info/xgmi_hive_id"), "4660\n").unwrap();
+    std::fs::write(device_dir.join("xgmi_physical_id"), "0\n").unwrap();
+    std::fs::write(device_dir.join("xgmi_error"), "3\n").unwrap();
+    let nodes = root.join("sys/class/kfd/kfd/topology/nodes");
+    std::fs::create_dir_all(nodes.join("1/io_links/0")).unwrap();
+    std::fs::write(nodes.join("1/properties"), "drm_render_minor 128\n").unwrap();
+    std::fs::write(
+        nodes.join("1/io_links/0/properties"),
+        "type 11\nnode_from 1\nnode_to 2\nweight 15\nmin_bandwidth 0\nmax_bandwidth 50000\n",
+    )
+    .unwrap();
+    std::fs::create_dir_all(nodes.join("1/io_links/1")).unwrap();
+    std::fs::write(
+        nodes.join("1/io_links/1/properties"),
+        "type 2\nnode_from 1\nnode_to 0\nweight 20\n",
+    )
+    .unwrap();
+
+    let run_xgmi = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .args(args)
+            .output()
+            .expect("failed to run amdtop");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let output = run_xgmi(&["xgmi"]);
+    assert!(output.starts_with("card0 | hive 0x1234 | node 0 | errors 3\n"));
+    assert!(output.contains("node 2     |     15 | 0 – 50000 MB/s\n"));
+    assert_eq!(output.lines().count(), 4);
+
+    let output = run_xgmi(&["--output", "csv", "xgmi"]);
+    assert!(output.contains("card0,link_node2_max_bandwidth,50000\n"));
+}
+
+#[test]
+fn virtual_functions_leave_power_to_the_host() {
+    let root = scratch_fixture("navi21-linux-6.6", "virtual-function");
+    std::fs::write(root.join("sys/class/drm/card0/device/device"), "0x73ae\n").unwrap();
+    let run_vf = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .args(args)
+            .output()
+            .expect("failed to run amdtop")
+    };
+
+    let output = run_vf(&["sensors"]);
+    let output = String::from_utf8_lossy(&output.stdout);
+    assert!(output.starts_with("card0 | VF\n"));
+    assert!(output.contains("GPU busy"));
+    assert!(!output.contains("Power cap"));
+    assert!(!output.contains("Fan"));
+
+    let output = run_vf(&["mem"]);
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("card0 | VF | VRAM "));
+
+    let output = run_vf(&["set", "fan", "50"]);
+    assert_eq!(output.status.code(), Some(6));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("belongs to the host"));
+}
+
+#[test]
+fn sensors_sample_grbm_busy_bits() {
+    let root = scratch_fixture("navi21-linux-6.6", "grbm");
+    let mut regs = vec![0u8; 0x2010 * 4];
+    let mut set = |register: usize, value: u32| {
+        regs[register * 4..register * 4 + 4].copy_from_slice(&value.to_le_bytes());
+    };
+    set(0x2004, 1 << 31 | 1 << 22);
+    set(0x2005, 1 << 27 | 0x6);
+    set(0x2006, 0x6);
+    std::fs::write(root.join("sys/kernel/debug/dri/0/amdgpu_regs"), regs).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .args(["sensors", "--grbm"])
+        .output()
+        .expect("failed to run amdtop");
+    assert!(output.status.success());
+    let output = String::from_utf8_lossy(&output.stdout);
+    assert!(output.contains("  GRBM busy, 200 samples\n"));
+    assert!(output.contains("    Graphics pipe        100 %\n"));
+    assert!(output.contains("    Command processor    0 %\n"));
+    assert!(output.contains("    SE0                  100 %\n"));
+    assert!(output.contains("    SE1                  0 %\n"));
+    assert!(!output.contains("SE2"));
+}
+
+#[test]
+fn rings_show_pending_jobs_and_stalls() {
+    let output = amdtop("navi21-linux-6.6", &["rings", "-d", "0.1", "-n", "2"]);
+    let mut refreshes = output.split("\n\n");
+    let first = refreshes.next().unwrap();
+    assert!(first.contains("gfx_0.0.0      |    0x100 |    0x140 |     64 |       2 | busy\n"));
+    assert!(first.contains("sdma0          |     0x80 |     0x80 |      0 |       0 | idle"));
+    assert!(refreshes.next().unwrap().contains("|       2 | stalled\n"));
+
+    let output = amdtop("navi21-linux-6.6", &["--output", "csv", "rings"]);
+    assert!(output.contains("card0,gfx_0.0.0,256,320,64,2,busy\n"));
+}
+
+#[test]
+fn mem_counts_clients_and_queues() {
+    let output = amdtop("navi21-linux-6.6", &["--output", "csv", "mem"]);
+    assert!(output.starts_with(
+        "device,pid,name,path,vram_bytes,gtt_bytes,peak_vram_bytes,peak_gtt_bytes,\
+         drm_clients,kfd_queues,apis,compositor,devices,app,\
+         steam_app_id,steam_game,unit\n"
+    ));
+
+    let root = scratch_fixture("navi21-linux-6.6", "kfd-queues");
+    let kfd = root.join("sys/class/kfd/kfd");
+    std::fs::create_dir_all(kfd.join("topology/nodes/1")).unwrap();
+    std::fs::write(kfd.join("topology/nodes/1/gpu_id"), "45678\n").unwrap();
+    std::fs::write(
+        kfd.join("topology/nodes/1/properties"),
+        "drm_render_minor 128\n",
+    )
+    .unwrap();
+    for queue in ["0", "1"] {
+        let queue = kfd.join("proc/3301/queues").join(queue);
+        std::fs::create_dir_all(&queue).unwrap();
+        std::fs::write(queue.join("gpuid"), "45678\n").unwrap();
+    }
+
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .args(["--source", "debugfs", "mem"])
+        .output()
+        .expect("failed to run amdtop");
+    let output = String::from_utf8_lossy(&output.stdout);
+    let blender = row(&output, "3301");
+    assert_eq!(blender[blender.len() - 3..blender.len() - 1], ["1", "2"]);
+    let gnome_shell = row(&output, "2210");
+    assert_eq!(
+        gnome_shell[gnome_shell.len() - 3..gnome_shell.len() - 1],
+        ["2", "0"]
+    );
+}
+
+#[test]
+fn mem_correlates_shared_clients() {
+    let root = scratch_fixture("navi21-linux-6.6", "shared-client");
+    let parent = root.join("proc/3301");
+    let child = root.join("proc/4400");
+    std::fs::create_dir_all(child.join("fd")).unwrap();
+    std::fs::create_dir_all(child.join("fdinfo")).unwrap();
+    std::fs::write(child.join("comm"), "blender-worker\n").unwrap();
+    std::fs::write(
+        child.join("status"),
+        "Name:\tblender-worker\nTgid:\t4400\nPid:\t4400\n",
+    )
+    .unwrap();
+    std::os::unix::fs::symlink("/dev/dri/renderD128", child.join("fd/12")).unwrap();
+    std::fs::copy(parent.join("fdinfo/9"), child.join("fdinfo/12")).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .args(["--source", "fdinfo", "mem"])
+        .output()
+        .expect("failed to run amdtop");
+    let output = String::from_utf8_lossy(&output.stdout);
+    let blender = row(&output, "3301");
+    assert_eq!(&blender[3..6], ["832.00 MiB", "768.00 MiB", "64.00 MiB"]);
+    assert_eq!(blender[blender.len() - 3], "1*");
+    assert!(!output.contains("\n4400 "));
+    assert!(output.ends_with("* 3301 shares a DRM client with 4400; its memory is counted once\n"));
+}
+
+#[test]
+fn mem_shows_apis_from_mapped_libraries() {
+    let output = amdtop("navi21-linux-6.6", &["mem"]);
+    assert_eq!(row(&output, "3301").last().unwrap(), "GL,HIP");
+    assert_eq!(row(&output, "1523").last().unwrap(), "GL,VA");
+    assert_eq!(row(&output, "2210").last().unwrap(), "");
+}
+
+#[test]
+fn mem_tags_the_compositor() {
+    let output = amdtop("navi21-linux-6.6", &["mem"]);
+    assert!(output.contains(
+        "Compositor: 1523 (Xorg), 2210 (Mutter) holds the window and scanout buffers \
+         of the whole session\n"
+    ));
+
+    let output = amdtop("navi21-linux-6.6", &["--output", "json", "mem"]);
+    assert!(output.contains("\"compositor\": \"Mutter\""));
+    assert!(!output.contains("\"compositor\": null"));
+}
+
+/// Adds an APU as `card1` to a scratch fixture, making it a hybrid laptop.
+fn add_integrated_gpu(root: &Path, gem_info: &str) {
+    use std::os::unix::fs::symlink;
+
+    let device = root.join("sys/devices/pci0000:00/0000:00:08.1/0000:c1:00.0");
+    std::fs::create_dir_all(device.join("drm/card1")).unwrap();
+    symlink("../../../../bus/pci/drivers/amdgpu", device.join("driver")).unwrap();
+    symlink("../../../0000:c1:00.0", device.join("drm/card1/device")).unwrap();
+    symlink(
+        "../../devices/pci0000:00/0000:00:08.1/0000:c1:00.0/drm/card1",
+        root.join("sys/class/drm/card1"),
+    )
+    .unwrap();
+    let mut gpu_metrics = vec![0xff; 120];
+    gpu_metrics[..4].copy_from_slice(&[120, 0, 2, 1]);
+    std::fs::write(device.join("gpu_metrics"), gpu_metrics).unwrap();
+    std::fs::write(device.join("mem_info_vram_total"), "536870912\n").unwrap();
+    std::fs::write(device.join("mem_info_vram_used"), "67108864\n").unwrap();
+    std::fs::write(device.join("mem_info_gtt_used"), "1073741824\n").unwrap();
+
+    let debugfs = root.join("sys/kernel/debug/dri/1");
+    std::fs::create_dir_all(&debugfs).unwrap();
+    std::fs::write(debugfs.join("amdgpu_gem_info"), gem_info).unwrap();
+}
+
+/// Adds an Intel iGPU as `card1` to a scratch fixture, with Xorg holding a
+/// client of it.
+fn add_intel_gpu(root: &Path) {
+    use std::os::unix::fs::symlink;
+
+    let device = root.join("sys/devices/pci0000:00/0000:00:02.0");
+    std::fs::create_dir_all(device.join("drm/card1")).unwrap();
+    std::fs::create_dir_all(device.join("drm/renderD129")).unwrap();
+    symlink("../../../bus/pci/drivers/i915", device.join("driver")).unwrap();
+    symlink("../../../0000:00:02.0", device.join("drm/card1/device")).unwrap();
+    symlink(
+        "../../devices/pci0000:00/0000:00:02.0/drm/card1",
+        root.join("sys/class/drm/card1"),
+    )
+    .unwrap();
+
+    symlink("/dev/dri/renderD129", root.join("proc/1523/fd/30")).unwrap();
+    std::fs::write(
+        root.join("proc/1523/fdinfo/30"),
+        "drm-driver:\ti915\n\
+         drm-pdev:\t0000:00:02.0\n\
+         drm-client-id:\t7\n\
+         drm-total-system0:\t40960 KiB\n\
+         drm-resident-system0:\t32768 KiB\n\
+         drm-engine-render:\t25662044 ns\n",
+    )
+    .unwrap();
+}
+
+#[test]
+fn generic_drm_shows_other_drivers_gpus() {
+    let root = scratch_fixture("navi21-linux-6.6", "generic-drm");
+    add_intel_gpu(&root);
+    let root = root.to_str().unwrap();
+
+    let output = amdtop(root, &["--source", "fdinfo", "mem"]);
+    assert!(!output.contains("card1"));
+
+    let output = amdtop(root, &["--source", "fdinfo", "mem", "--generic-drm"]);
+    let (card0, card1) = output.split_once("\ncard1 | ").unwrap();
+    assert!(card0.contains("1523 also has buffers on card1"));
+    assert!(card1.starts_with("i915, generic DRM\n"));
+    assert!(card1.contains("Generic DRM: only what i915's fdinfo says"));
+    assert_eq!(row(card1, "1523")[3..6], ["32.00 MiB", "0", "32.00 MiB"]);
+
+    let views: serde_json::Value = serde_json::from_str(&amdtop(
+        root,
+        &[
+            "--output",
+            "json",
+            "--source",
+            "fdinfo",
+            "mem",
+            "--generic-drm",
+        ],
+    ))
+    .unwrap();
+    assert_eq!(views[1]["device"], "card1");
+    assert_eq!(views[1]["generic_drm"], "i915");
+    assert!(views[0].get("generic_drm").is_none());
+}
+
+#[test]
+fn mem_flags_processes_on_the_wrong_gpu() {
+    let root = scratch_fixture("navi21-linux-6.6", "hybrid");
+    add_integrated_gpu(
+        &root,
+        "pid     3301 command blender:\n\
+         \t\t0x00000001:   1073741824 byte GTT CPU_GTT_USWC\n",
+    );
+    std::fs::write(root.join("proc/3301/environ"), "DRI_PRIME=1\0HOME=/root\0").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .arg("mem")
+        .output()
+        .expect("failed to run amdtop");
+    let output = String::from_utf8_lossy(&output.stdout);
+    let (card0, card1) = output.split_once("\ncard1 | ").unwrap();
+    assert!(card0.ends_with("\n3301 also has buffers on card1"));
+    assert!(card1.contains(
+        "! 3301 asked for DRI_PRIME=1 but most of its memory is on card1 (integrated)\n"
+    ));
+    assert!(card1.contains("3301 also has buffers on card0\n"));
+    assert!(!card0.contains("3305 also"));
+}
+
+#[test]
+fn mem_names_flatpak_and_snap_apps() {
+    let root = scratch_fixture("navi21-linux-6.6", "sandboxed");
+    std::fs::create_dir_all(root.join("proc/3301/root")).unwrap();
+    std::fs::write(
+        root.join("proc/3301/root/.flatpak-info"),
+        "[Application]\nname=org.blender.Blender\nruntime=runtime/org.freedesktop.Platform\n",
+    )
+    .unwrap();
+    // No .flatpak-info we can read, only the cgroup.
+    std::fs::write(
+        root.join("proc/2210/cgroup"),
+        "0::/user.slice/user-1000.slice/user@1000.service/app.slice/\
+         app-flatpak-org.example.Viewer-51234.scope\n",
+    )
+    .unwrap();
+    std::fs::remove_file(root.join("proc/1523/exe")).unwrap();
+    std::os::unix::fs::symlink(
+        "/snap/xorg-snap/42/usr/lib/Xorg",
+        root.join("proc/1523/exe"),
+    )
+    .unwrap();
+
+    let mem = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .arg("mem")
+            .args(args)
+            .output()
+            .expect("failed to run amdtop");
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let output = mem(&[]);
+    assert_eq!(row(&output, "3301")[2], "org.blender.Blender (flatpak)");
+    assert_eq!(row(&output, "2210")[2], "org.example.Viewer (flatpak)");
+    assert_eq!(row(&output, "1523")[2], "xorg-snap (snap)");
+
+    let output = mem(&["--filter-regex", "org\\.blender"]);
+    assert!(output.contains("\n3301 "));
+    assert!(!output.contains("\n2210 "));
+}
+
+#[test]
+fn mem_shows_steam_games() {
+    let root = scratch_fixture("navi21-linux-6.6", "steam");
+    let library = root.join("home/me/.local/share/Steam/steamapps");
+    std::fs::create_dir_all(library.join("common/Cyberpunk 2077")).unwrap();
+    std::fs::write(
+        library.join("appmanifest_1091500.acf"),
+        "\"AppState\"\n{\n\t\"appid\"\t\t\"1091500\"\n\t\"name\"\t\t\"Cyberpunk 2077\"\n}\n",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("proc/3301/environ"),
+        "HOME=/home/me\0SteamAppId=1091500\0STEAM_COMPAT_INSTALL_PATH=\
+         /home/me/.local/share/Steam/steamapps/common/Cyberpunk 2077\0",
+    )
+    .unwrap();
+    std::fs::write(root.join("proc/2210/environ"), "SteamGameId=3541785046\0").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .arg("mem")
+        .output()
+        .expect("failed to run amdtop");
+    let output = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(row(&output, "3301")[2], "Cyberpunk 2077 (steam 1091500)");
+    assert_eq!(row(&output, "2210")[2], "steam app 3541785046");
+}
+
+#[test]
+fn mem_groups_by_systemd_unit() {
+    let root = scratch_fixture("navi21-linux-6.6", "units");
+    let cgroups = [
+        ("1523", "0::/system.slice/display-manager.service\n"),
+        (
+            "2210",
+            "0::/user.slice/user-1000.slice/user@1000.service/session.slice/\
+             org.gnome.Shell@wayland.service\n",
+        ),
+        (
+            "3301",
+            "0::/user.slice/user-1000.slice/user@1000.service/app.slice/\
+             app-gnome-blender-4242.scope\n",
+        ),
+    ];
+    for (pid, cgroup) in cgroups {
+        std::fs::write(root.join("proc").join(pid).join("cgroup"), cgroup).unwrap();
+    }
+    let mem = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+            .arg("--root")
+            .arg(&root)
+            .arg("mem")
+            .args(args)
+            .output()
+            .expect("failed to run amdtop");
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+
+    let output = mem(&["--show-unit"]);
+    assert_eq!(
+        row(&output, "2210").last().unwrap(),
+        "org.gnome.Shell@wayland.service"
+    );
+
+    let output = mem(&["--group-by", "unit"]);
+    assert_eq!(
+        row(&output, "app-gnome-blender-4242.scope"),
+        [
+            "app-gnome-blender-4242.scope",
+            "1",
+            "832.00 MiB",
+            "768.00 MiB",
+            "64.00 MiB"
+        ]
+    );
+    assert_eq!(row(&output, "display-manager.service")[1], "1");
+    assert!(output.contains("\nkernel/unattributed "));
+    assert!(!output.contains("\n3301 "));
+}
+
+#[test]
+fn notifies_systemd_when_ready() {
+    let socket_path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("notify.sock");
+    let _ = std::fs::remove_file(&socket_path);
+    let socket = std::os::unix::net::UnixDatagram::bind(&socket_path).unwrap();
+    socket
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/navi21-linux-6.6");
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(root)
+        .args(["xgmi", "-d", "0.1", "-n", "2"])
+        .env("NOTIFY_SOCKET", &socket_path)
+        .env("WATCHDOG_USEC", "100000")
+        .output()
+        .expect("failed to run amdtop");
+    assert!(output.status.success());
+
+    let mut messages = Vec::new();
+    let mut buffer = [0; 64];
+    socket.set_nonblocking(true).unwrap();
+    while let Ok(length) = socket.recv(&mut buffer) {
+        messages.push(String::from_utf8_lossy(&buffer[..length]).into_owned());
+    }
+    assert_eq!(messages[0], "READY=1");
+    assert!(messages.contains(&"WATCHDOG=1".to_string()));
+}
+
+#[test]
+fn install_service_writes_a_unit() {
+    let root = scratch_fixture("navi21-linux-6.6", "install-service");
+    std::fs::create_dir_all(root.join("etc/systemd/system")).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_amdtop"))
+        .arg("--root")
+        .arg(&root)
+        .args(["install-service", "--listen", "0.0.0.0:9858"])
+        .output()
+        .expect("failed to run amdtop");
+    assert!(output.status.success());
+
+    let unit = std::fs::read_to_string(root.join("etc/systemd/system/amdtop.service")).unwrap();
+    assert!(unit.contains("\nType=notify\n"));
+    assert!(unit.contains(" export --listen 0.0.0.0:9858\n"));
+}